@@ -1,14 +1,30 @@
-use logstreamer::{Action, ActionMessage, Client, Content, OffsetValue, Response, TopicAddress};
-use std::io;
+use logstreamer::{
+    Acks, Action, ActionMessage, Client, Content, Isolation, OffsetValue, Response, TopicAddress,
+};
 use std::env;
+use std::io;
+use std::ops::Range;
 
 fn to_clean_string(input: &[u8]) -> String {
-    String::from_utf8_lossy(&input)
+    String::from_utf8_lossy(input)
         .to_string()
         .replace("\r", "")
         .replace("\n", "")
 }
 
+/// Reads a number from `input[range]`, telling how to type the command when
+/// it's missing or not a number.
+fn parse_number(input: &str, range: Range<usize>, usage: &str) -> Option<u32> {
+    let number = input
+        .as_bytes()
+        .get(range)
+        .and_then(|bytes| to_clean_string(bytes).trim().parse::<u32>().ok());
+    if number.is_none() {
+        eprintln!("Invalid number, usage: {}", usage);
+    }
+    number
+}
+
 fn main() {
     let mut exit = false;
     let args: Vec<String> = env::args().collect();
@@ -22,45 +38,126 @@ fn main() {
     println!("logstreamer client");
     while !exit {
         let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+        if io::stdin().read_line(&mut input).unwrap_or(0) == 0 {
+            return;
+        }
 
         let action = input.as_bytes()[0];
+        let rest = 1..input.len();
 
         let message = match action {
-            // i
-            105 => ActionMessage::new(
-                Action::InitializeController(vec![
-                    String::from("127.0.0.1:8080"),
-                    String::from("127.0.0.1:8081"),
-                    String::from("127.0.0.1:8082"),
-                ]),
-                String::new(),
-            ),
-            // c - consume
-            99 => ActionMessage::new(
-                Action::Consume(
-                    TopicAddress::new(String::from("topic"), 0),
-                    OffsetValue(
-                        to_clean_string(&input.as_bytes()[1..5])
-                            .parse::<u32>()
-                            .unwrap(),
+            // i - initialize the controller, e.g. "i127.0.0.1:8080,127.0.0.1:8081",
+            // with the three brokers on ports 8080 to 8082 by default
+            105 => {
+                let arguments = to_clean_string(&input.as_bytes()[1..]);
+                let broker_list = if arguments.is_empty() {
+                    vec![
+                        String::from("127.0.0.1:8080"),
+                        String::from("127.0.0.1:8081"),
+                        String::from("127.0.0.1:8082"),
+                    ]
+                } else {
+                    arguments.split(',').map(String::from).collect()
+                };
+                ActionMessage::new(Action::InitializeController(broker_list), String::new())
+            }
+            // c - consume, e.g. "c0000  10c1" reads 10 records from offset 0 as c1
+            99 => {
+                let usage = "c<offset, 4 digits><limit, 4 digits><consumer id>";
+                let offset = match parse_number(&input, 1..5, usage) {
+                    Some(offset) => offset,
+                    None => continue,
+                };
+                let limit = match parse_number(&input, 5..9, usage) {
+                    Some(limit) => limit,
+                    None => continue,
+                };
+                ActionMessage::new(
+                    Action::Consume(
+                        TopicAddress::new(String::from("topic"), 0),
+                        OffsetValue(offset),
+                        limit,
+                        Isolation::ReadUncommitted,
                     ),
-                    to_clean_string(&input.as_bytes()[5..9])
-                        .parse::<u32>()
-                        .unwrap(),
-                ),
-                to_clean_string(&input.as_bytes()[9..]),
-            ),
+                    to_clean_string(&input.as_bytes()[9..]),
+                )
+            }
             // p - produce
             112 => ActionMessage::new(
                 Action::Produce(
                     TopicAddress::new(String::from("topic"), 0),
                     vec![Content::new(to_clean_string(&input.as_bytes()[1..]))],
+                    Acks::Leader,
+                    None,
                 ),
                 String::new(),
             ),
             // n - new topic
-            110 => ActionMessage::new(Action::CreateTopic(String::from("topic"), 1), String::new()),
+            110 => ActionMessage::new(
+                Action::CreateTopic(String::from("topic"), 1, 1, Vec::new()),
+                String::new(),
+            ),
+            // g - grow the topic to more partitions, e.g. "g4"
+            103 => match parse_number(&input, rest, "g<partition count>") {
+                Some(partition_count) => ActionMessage::new(
+                    Action::CreatePartitions(String::from("topic"), partition_count),
+                    String::new(),
+                ),
+                None => continue,
+            },
+            // d - delete the topic
+            100 => ActionMessage::new(Action::DeleteTopic(String::from("topic")), String::new()),
+            // k - describe the topic configs, or set one, e.g. "kretention.ms=1000"
+            107 => {
+                let arguments = to_clean_string(&input.as_bytes()[1..]);
+                let action = match arguments.split_once('=') {
+                    Some((key, value)) => Action::AlterConfigs(
+                        String::from("topic"),
+                        vec![(key.to_string(), value.to_string())],
+                    ),
+                    None => Action::DescribeConfigs(String::from("topic")),
+                };
+                ActionMessage::new(action, String::new())
+            }
+            // r - remove broker
+            114 => match parse_number(&input, rest, "r<broker id>") {
+                Some(broker_id) => {
+                    ActionMessage::new(Action::UnregisterBroker(broker_id), String::new())
+                }
+                None => continue,
+            },
+            // m - move a partition, e.g. "m0 1,2" moves partition 0 to brokers 1 and 2
+            109 => {
+                let arguments = to_clean_string(&input.as_bytes()[1..]);
+                let parsed = arguments.split_once(' ').and_then(|(partition, replicas)| {
+                    let partition = partition.parse::<u32>().ok()?;
+                    let replicas = replicas
+                        .split(',')
+                        .map(|replica| replica.parse::<u32>().ok())
+                        .collect::<Option<Vec<u32>>>()?;
+                    Some((partition, replicas))
+                });
+                let (partition, replicas) = match parsed {
+                    Some(parsed) => parsed,
+                    None => {
+                        eprintln!("Invalid arguments, usage: m<partition> <broker id>,...");
+                        continue;
+                    }
+                };
+                ActionMessage::new(
+                    Action::AlterPartitionReassignment(
+                        TopicAddress::new(String::from("topic"), partition),
+                        replicas,
+                    ),
+                    String::new(),
+                )
+            }
+            // b - propose a rebalance
+            98 => ActionMessage::new(Action::Rebalance(false), String::new()),
+            // a - apply a rebalance
+            97 => ActionMessage::new(Action::Rebalance(true), String::new()),
+            // s - shut the broker down
+            115 => ActionMessage::new(Action::Shutdown, String::new()),
             // q - quit
             113 => {
                 exit = true;
@@ -79,6 +176,48 @@ fn main() {
                 }
                 Response::Offset(value) => println!("[offset] {}", value.0),
                 Response::AskTheController(broker) => println!("[ask controller] {}", broker),
+                Response::TopicMetadata(partition_count) => {
+                    println!("[metadata] {} partitions", partition_count)
+                }
+                Response::ProducerId(producer_id) => println!("[producer id] {}", producer_id),
+                Response::OutOfOrderSequence => println!("[out of order sequence]"),
+                Response::DuplicateSequence => println!("[duplicate sequence]"),
+                Response::InvalidTransactionState => println!("[invalid transaction state]"),
+                Response::RecordBatch(batch) => println!(
+                    "[batch: {}] {} records",
+                    batch.first_offset.0,
+                    batch.content_list.len()
+                ),
+                Response::HighWatermark(offset) => println!("[high watermark] {}", offset.0),
+                Response::NotLeader => println!("[not leader]"),
+                Response::PartitionMetadata(state) => println!(
+                    "[partition {}] leader {} epoch {} replicas {:?} isr {:?}",
+                    state.topic.partition,
+                    state.leader,
+                    state.leader_epoch,
+                    state.replicas,
+                    state.isr
+                ),
+                Response::BrokerAddress(broker_id, address) => {
+                    println!("[broker {}] {}", broker_id, address)
+                }
+                Response::BrokerId(broker_id, broker_epoch) => {
+                    println!("[broker id] {} (epoch {})", broker_id, broker_epoch)
+                }
+                Response::StaleBrokerEpoch => println!("[stale broker epoch]"),
+                Response::NotEnoughReplicas => println!("[not enough replicas]"),
+                Response::EpochEndOffset(leader_epoch, offset) => {
+                    println!("[epoch {}] ends at {}", leader_epoch, offset.0)
+                }
+                Response::Reassignment(topic, replicas) => println!(
+                    "[move {}-{}] to {:?}",
+                    topic.name, topic.partition, replicas
+                ),
+                Response::UnknownTopic => println!("[unknown topic]"),
+                Response::Config(key, value) => println!("[config] {} = {}", key, value),
+                Response::InvalidConfig(reason) => println!("[invalid config] {}", reason),
+                Response::RecordTooLarge => println!("[record too large]"),
+                Response::InvalidTopic => println!("[invalid topic name]"),
                 Response::Error => println!("[error]"),
             }
         }
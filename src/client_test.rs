@@ -1,4 +1,6 @@
-use logstreamer::{Action, ActionMessage, Client, Content, OffsetValue, Response, TopicAddress};
+use logstreamer::{
+    Action, ActionMessage, Client, Consumer, Content, Producer, ProducerRecord, TopicAddress,
+};
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
@@ -16,34 +18,28 @@ fn main() {
             let mut client = Client::new(String::from("127.0.0.1:8080"));
 
             let create_topic_message = ActionMessage::new(
-                Action::CreateTopic(String::from("topic"), NUMBER_OF_PRODUCERS),
+                Action::CreateTopic(String::from("topic"), NUMBER_OF_PRODUCERS, 1, Vec::new()),
                 String::new(),
             );
             let _ = client.send_message(create_topic_message);
 
-            let mut content_list = Vec::new();
-            for i in 0..=2_000_000 {
-                if i != 0 && i % 30 == 0 {
-                    let message = ActionMessage::new(
-                        Action::Produce(
-                            TopicAddress::new(String::from("topic"), producer_id),
-                            content_list.clone(),
-                        ),
-                        String::new(),
-                    );
-                    let _ = client.send_message(message);
-                    content_list.clear();
-                }
-                content_list.push(Content::new(format!("nice message {}", i)));
+            let _ = client.send_message(ActionMessage::new(Action::Quit, String::new()));
+
+            let mut producer = Producer::new(String::from("127.0.0.1:8080"));
+            for i in 0..2_000_000 {
+                producer.send(ProducerRecord::with_partition(
+                    String::from("topic"),
+                    producer_id,
+                    Content::new(format!("nice message {}", i)),
+                ));
 
                 if i % 400_000 == 0 {
                     println!("PRODUCED MESSAGE {}: {}", producer_id, i);
                 }
             }
+            producer.close();
             let duration = start.elapsed();
 
-            let _ = client.send_message(ActionMessage::new(Action::Quit, String::new()));
-
             println!("DURATION PRODUCER: {:?}", duration);
         }));
     }
@@ -53,46 +49,38 @@ fn main() {
         let consumer = thread::spawn(move || {
             thread::sleep(Duration::from_millis(500u64 / (consumer_id as u64 + 1)));
             let start = Instant::now();
-            let mut client = Client::new(String::from("127.0.0.1:8080"));
+            let consumer_name = format!("consumer_{}", consumer_id);
+            let mut consumer = Consumer::new(String::from("127.0.0.1:8080"), consumer_name);
+            consumer.set_auto_commit(false);
+            consumer.set_max_poll_records(CONSUMER_LIMIT);
+            consumer.subscribe(TopicAddress::new(String::from("topic"), consumer_id));
+
             let mut i = 0;
             let mut offset_found = false;
-            let consumer_name = format!("consumer_{}", consumer_id);
-            let mut current_offset = 0;
             let mut consumed_messages = 0;
 
             while !offset_found {
-                let response_list = client.send_message(ActionMessage::new(
-                    Action::Consume(
-                        TopicAddress::new(String::from("topic"), consumer_id),
-                        OffsetValue(current_offset),
-                        CONSUMER_LIMIT,
-                    ),
-                    consumer_name.clone(),
-                ));
-
-                let mut last_offset = 0;
-                for response in response_list {
-                    if let Response::Content(offset, content) = &response.response {
-                        last_offset = offset.0;
-                        consumed_messages += 1;
-                        if last_offset == 1_999_999 {
-                            println!("CONSUMER MESSAGE FOUND {}: {}", consumer_id, content.value);
-                            offset_found = true;
-                        } else if i % 400_000 == 0 {
-                            println!(
-                                "CONSUMED MESSAGE (total {}) {}: {} WITH VALUE VALUE: {}",
-                                consumed_messages, consumer_id, offset.0, content.value
-                            );
-                        }
+                for record in consumer.poll() {
+                    consumed_messages += 1;
+                    if record.offset.0 == 1_999_999 {
+                        println!(
+                            "CONSUMER MESSAGE FOUND {}: {}",
+                            consumer_id, record.content.value
+                        );
+                        offset_found = true;
+                    } else if i % 400_000 == 0 {
+                        println!(
+                            "CONSUMED MESSAGE (total {}) {}: {} WITH VALUE VALUE: {}",
+                            consumed_messages, consumer_id, record.offset.0, record.content.value
+                        );
                     }
                     i += 1;
                 }
-                current_offset = last_offset + 1;
             }
 
             let duration = start.elapsed();
 
-            let _ = client.send_message(ActionMessage::new(Action::Quit, String::from("consumer")));
+            consumer.close();
 
             println!(
                 "DURATION CONSUMER (total: {}): {:?}",
@@ -1,64 +1,933 @@
-use crate::core::{Content, OffsetValue, TopicAddress};
-
-struct Buffer<'a> {
+use crate::config::MAX_MESSAGE_BYTES_LIMIT;
+use crate::core::{
+    Acks, BatchHeader, BatchKind, Content, Isolation, OffsetValue, PartitionState, RecordBatch,
+    TopicAddress,
+};
+use crate::metadata::{ClusterMetadata, MetadataRecord};
+use crate::raft::{LogEntry, PersistentState, RaftMessage, Snapshot};
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::{Read, Write};
+
+// A request holds at most one batch of the largest size topics allow, plus
+// the length of every record and the rest of the message.
+const MAX_REQUEST_BYTES: usize = MAX_MESSAGE_BYTES_LIMIT as usize + 1024 * 1024;
+
+pub(crate) struct Buffer<'a> {
     position: usize,
     buffer: &'a [u8],
 }
 
 impl<'a> Buffer<'a> {
-    fn new(buffer: &[u8]) -> Buffer {
+    pub(crate) fn new(buffer: &[u8]) -> Buffer<'_> {
         Buffer {
             position: 0,
             buffer,
         }
     }
-    fn read_u8(&mut self) -> u8 {
-        let data = self.buffer[self.position];
-        self.position += 1;
-        data
+
+    /// Fails with `UnexpectedEof`, without moving on, when fewer than `size`
+    /// bytes are left.
+    fn read_bytes(&mut self, size: usize) -> io::Result<&'a [u8]> {
+        let data = self
+            .position
+            .checked_add(size)
+            .and_then(|end| self.buffer.get(self.position..end))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        self.position += size;
+        Ok(data)
     }
 
-    fn read_string(&mut self) -> String {
-        let string_size = self.read_u32() as usize;
-        let data =
-            String::from_utf8_lossy(&self.buffer[(self.position)..(self.position + string_size)])
-                .to_string();
-        self.position += string_size;
-        data
+    pub(crate) fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
     }
 
-    fn read_u32(&mut self) -> u32 {
-        let data: [u8; 4] = [
-            self.buffer[self.position],
-            self.buffer[self.position + 1],
-            self.buffer[self.position + 2],
-            self.buffer[self.position + 3],
-        ];
-        self.position += 4;
-        u32::from_be_bytes(data)
+    pub(crate) fn read_string(&mut self) -> io::Result<String> {
+        let string_size = self.read_u32()? as usize;
+        Ok(String::from_utf8_lossy(self.read_bytes(string_size)?).to_string())
+    }
+
+    pub(crate) fn read_u32(&mut self) -> io::Result<u32> {
+        let data = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
     }
 
-    fn finished_read(&self) -> bool {
+    pub(crate) fn read_u64(&mut self) -> io::Result<u64> {
+        let data = self.read_bytes(8)?;
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(data);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    pub(crate) fn finished_read(&self) -> bool {
         self.position >= self.buffer.len()
     }
+
+    pub(crate) fn read_batch_header(&mut self) -> io::Result<Option<BatchHeader>> {
+        Ok(match self.read_u8()? {
+            0 => None,
+            1 => Some(BatchHeader::new(self.read_u32()?, self.read_u32()?)),
+            _ => Some(BatchHeader::transactional(
+                self.read_u32()?,
+                self.read_u32()?,
+            )),
+        })
+    }
+
+    /// Reads a batch as written by `write_record_batch`, placing it at `first_offset`.
+    pub(crate) fn read_record_batch(
+        &mut self,
+        first_offset: OffsetValue,
+    ) -> io::Result<RecordBatch> {
+        let kind = BatchKind::from_u8(self.read_u8()?);
+        let leader_epoch = self.read_u32()?;
+        let timestamp = self.read_u64()?;
+        let header = self.read_batch_header()?;
+        let content_list = self.read_content_list()?;
+        let mut batch = RecordBatch::new(first_offset, kind, header, content_list);
+        batch.leader_epoch = leader_epoch;
+        batch.timestamp = timestamp;
+        batch.compacted = self.read_u32_list()?;
+        Ok(batch)
+    }
+
+    fn read_content(&mut self) -> io::Result<Content> {
+        let value = self.read_string()?;
+        Ok(Content {
+            value,
+            key: self.read_optional_string()?,
+        })
+    }
+
+    fn read_content_list(&mut self) -> io::Result<Vec<Content>> {
+        let size = self.read_u32()?;
+        (0..size).map(|_| self.read_content()).collect()
+    }
+
+    fn read_u32_list(&mut self) -> io::Result<Vec<u32>> {
+        let size = self.read_u32()?;
+        (0..size).map(|_| self.read_u32()).collect()
+    }
+
+    fn read_string_list(&mut self) -> io::Result<Vec<String>> {
+        let size = self.read_u32()?;
+        (0..size).map(|_| self.read_string()).collect()
+    }
+
+    fn read_topic(&mut self) -> io::Result<TopicAddress> {
+        Ok(TopicAddress::new(self.read_string()?, self.read_u32()?))
+    }
+
+    fn read_partition_state(&mut self) -> io::Result<PartitionState> {
+        let topic = self.read_topic()?;
+        let leader = self.read_u32()?;
+        let leader_epoch = self.read_u32()?;
+        let replicas = self.read_u32_list()?;
+        let isr = self.read_u32_list()?;
+        let adding_replicas = self.read_u32_list()?;
+        let removing_replicas = self.read_u32_list()?;
+        Ok(PartitionState {
+            topic,
+            leader,
+            leader_epoch,
+            replicas,
+            isr,
+            adding_replicas,
+            removing_replicas,
+        })
+    }
+
+    fn read_partition_state_list(&mut self) -> io::Result<Vec<PartitionState>> {
+        let size = self.read_u32()?;
+        (0..size).map(|_| self.read_partition_state()).collect()
+    }
+
+    fn read_config_list(&mut self) -> io::Result<Vec<(String, String)>> {
+        let size = self.read_u32()?;
+        (0..size)
+            .map(|_| Ok((self.read_string()?, self.read_string()?)))
+            .collect()
+    }
+
+    fn read_optional_u32(&mut self) -> io::Result<Option<u32>> {
+        Ok(match self.read_u8()? {
+            0 => None,
+            _ => Some(self.read_u32()?),
+        })
+    }
+
+    fn read_optional_string(&mut self) -> io::Result<Option<String>> {
+        Ok(match self.read_u8()? {
+            0 => None,
+            _ => Some(self.read_string()?),
+        })
+    }
+
+    fn read_metadata_record(&mut self) -> io::Result<MetadataRecord> {
+        Ok(match self.read_u8()? {
+            1 => MetadataRecord::PartitionStates(self.read_partition_state_list()?),
+            2 => MetadataRecord::TopicConfig(
+                self.read_string()?,
+                self.read_string()?,
+                self.read_string()?,
+            ),
+            3 => MetadataRecord::RegisterBroker(
+                self.read_u32()?,
+                self.read_string()?,
+                self.read_optional_string()?,
+            ),
+            4 => MetadataRecord::UnregisterBroker(self.read_u32()?),
+            5 => MetadataRecord::FenceBroker(self.read_u32()?),
+            6 => MetadataRecord::DeleteTopic(self.read_string()?),
+            _ => MetadataRecord::Noop,
+        })
+    }
+
+    fn read_cluster_metadata(&mut self) -> io::Result<ClusterMetadata> {
+        let mut metadata = ClusterMetadata::new();
+        for _ in 0..self.read_u32()? {
+            let state = self.read_partition_state()?;
+            metadata.partitions.insert(state.topic.clone(), state);
+        }
+        for _ in 0..self.read_u32()? {
+            let topic = self.read_string()?;
+            let mut config = HashMap::new();
+            for _ in 0..self.read_u32()? {
+                config.insert(self.read_string()?, self.read_string()?);
+            }
+            metadata.configs.insert(topic, config);
+        }
+        for _ in 0..self.read_u32()? {
+            metadata
+                .brokers
+                .insert(self.read_u32()?, self.read_string()?);
+        }
+        metadata.next_broker_id = self.read_u32()?;
+        for _ in 0..self.read_u32()? {
+            metadata
+                .broker_epochs
+                .insert(self.read_u32()?, self.read_u32()?);
+        }
+        metadata.next_broker_epoch = self.read_u32()?;
+        for _ in 0..self.read_u32()? {
+            metadata.racks.insert(self.read_u32()?, self.read_string()?);
+        }
+        for _ in 0..self.read_u32()? {
+            metadata.deleted_topics.insert(self.read_string()?);
+        }
+        Ok(metadata)
+    }
+
+    fn read_snapshot(&mut self) -> io::Result<Snapshot> {
+        Ok(Snapshot {
+            last_index: self.read_u32()?,
+            last_term: self.read_u32()?,
+            metadata: self.read_cluster_metadata()?,
+        })
+    }
+
+    fn read_log_entries(&mut self) -> io::Result<Vec<LogEntry>> {
+        let size = self.read_u32()?;
+        (0..size)
+            .map(|_| {
+                Ok(LogEntry {
+                    term: self.read_u32()?,
+                    record: self.read_metadata_record()?,
+                })
+            })
+            .collect()
+    }
+
+    fn read_raft_message(&mut self) -> io::Result<RaftMessage> {
+        Ok(match self.read_u8()? {
+            1 => RaftMessage::RequestVote(self.read_u32()?, self.read_u32()?, self.read_u32()?),
+            2 => RaftMessage::Vote(self.read_u32()?, self.read_u8()? == 1),
+            3 => RaftMessage::AppendEntries(
+                self.read_u32()?,
+                self.read_u32()?,
+                self.read_u32()?,
+                self.read_log_entries()?,
+                self.read_u32()?,
+            ),
+            4 => RaftMessage::AppendResponse(
+                self.read_u32()?,
+                self.read_u8()? == 1,
+                self.read_u32()?,
+            ),
+            _ => RaftMessage::InstallSnapshot(self.read_u32()?, Box::new(self.read_snapshot()?)),
+        })
+    }
+
+    /// Reads the state of a controller as written by `write_persistent_state`.
+    pub(crate) fn read_persistent_state(&mut self) -> io::Result<PersistentState> {
+        Ok(PersistentState {
+            term: self.read_u32()?,
+            voted_for: self.read_optional_u32()?,
+            snapshot: self.read_snapshot()?,
+            entries: self.read_log_entries()?,
+        })
+    }
+
+    fn read_topic_list(&mut self) -> io::Result<Vec<TopicAddress>> {
+        let size = self.read_u32()?;
+        (0..size).map(|_| self.read_topic()).collect()
+    }
+
+    pub(crate) fn read_action(&mut self) -> io::Result<Action> {
+        Ok(match self.read_u8()? {
+            1 => {
+                let topic = self.read_topic()?;
+                let content_list = self.read_content_list()?;
+                let acks = Acks::from_u8(self.read_u8()?);
+                let header = self.read_batch_header()?;
+                Action::Produce(topic, content_list, acks, header)
+            }
+            2 => {
+                let topic = self.read_topic()?;
+                let offset = OffsetValue(self.read_u32()?);
+                let limit = self.read_u32()?;
+                let isolation = Isolation::from_u8(self.read_u8()?);
+                Action::Consume(topic, offset, limit, isolation)
+            }
+            3 => {
+                let topic = self.read_string()?;
+                let partition = self.read_u32()?;
+                let replication_factor = self.read_u32()?;
+                Action::CreateTopic(
+                    topic,
+                    partition,
+                    replication_factor,
+                    self.read_config_list()?,
+                )
+            }
+            4 => Action::InitializeController(self.read_string_list()?),
+            5 => {
+                let broker_id = self.read_u32()?;
+                Action::InitializeBroker(broker_id, self.read_string_list()?)
+            }
+            6 => {
+                let id = self.read_u32()?;
+                let broker_epoch = self.read_u32()?;
+                Action::IamAlive(id, broker_epoch)
+            }
+            7 => {
+                let topic = self.read_topic()?;
+                let offset = OffsetValue(self.read_u32()?);
+                Action::CommitOffset(topic, offset)
+            }
+            8 => Action::CommittedOffset(self.read_topic()?),
+            9 => Action::LatestOffset(self.read_topic()?),
+            10 => Action::Metadata(self.read_string()?),
+            11 => Action::InitProducerId,
+            12 => Action::BeginTransaction(self.read_string()?),
+            13 => {
+                let transactional_id = self.read_string()?;
+                let topic_list = self.read_topic_list()?;
+                Action::AddPartitionsToTransaction(transactional_id, topic_list)
+            }
+            14 => {
+                let transactional_id = self.read_string()?;
+                let topic = self.read_topic()?;
+                let offset = OffsetValue(self.read_u32()?);
+                Action::AddOffsetToTransaction(transactional_id, topic, offset)
+            }
+            15 => {
+                let transactional_id = self.read_string()?;
+                let commit = self.read_u8()? == 1;
+                Action::EndTransaction(transactional_id, commit)
+            }
+            16 => {
+                let broker_epoch = self.read_u32()?;
+                Action::LeaderAndIsr(broker_epoch, self.read_partition_state_list()?)
+            }
+            17 => {
+                let topic = self.read_topic()?;
+                let offset = OffsetValue(self.read_u32()?);
+                let replica_id = self.read_u32()?;
+                let max_records = self.read_u32()?;
+                Action::Fetch(topic, offset, replica_id, max_records)
+            }
+            18 => {
+                let state = self.read_partition_state()?;
+                Action::AlterIsr(state, self.read_u32()?)
+            }
+            19 => {
+                let from = self.read_u32()?;
+                Action::Raft(from, self.read_raft_message()?)
+            }
+            20 => {
+                let broker_id = self.read_optional_u32()?;
+                let address = self.read_string()?;
+                Action::RegisterBroker(broker_id, address, self.read_optional_string()?)
+            }
+            21 => Action::UnregisterBroker(self.read_u32()?),
+            // Never forwarded twice, see `Action::Forwarded`, so nesting is
+            // refused rather than decoded recursively.
+            22 => match self.buffer.get(self.position) {
+                Some(22) => Action::Invalid,
+                _ => Action::Forwarded(Box::new(self.read_action()?)),
+            },
+            23 => {
+                let topic = self.read_string()?;
+                Action::AlterConfigs(topic, self.read_config_list()?)
+            }
+            24 => {
+                let topic = self.read_string()?;
+                Action::TopicConfigs(topic, self.read_config_list()?)
+            }
+            25 => {
+                let topic = self.read_topic()?;
+                Action::OffsetForLeaderEpoch(topic, self.read_u32()?)
+            }
+            26 => Action::Shutdown,
+            27 => {
+                let broker_id = self.read_u32()?;
+                Action::ControlledShutdown(broker_id, self.read_u32()?)
+            }
+            28 => {
+                let topic = self.read_topic()?;
+                Action::AlterPartitionReassignment(topic, self.read_u32_list()?)
+            }
+            29 => Action::Rebalance(self.read_u8()? == 1),
+            30 => {
+                let topic = self.read_string()?;
+                Action::CreatePartitions(topic, self.read_u32()?)
+            }
+            31 => Action::DeleteTopic(self.read_string()?),
+            32 => Action::RemoveTopic(self.read_string()?),
+            33 => Action::DescribeConfigs(self.read_string()?),
+            99 => Action::Quit,
+            _ => Action::Invalid,
+        })
+    }
 }
 
-fn write_string(content: &mut Vec<u8>, value: &String) {
+pub(crate) fn write_string(content: &mut Vec<u8>, value: &String) {
     write_u32(content, value.len() as u32);
     content.extend_from_slice(value.as_bytes());
 }
 
-fn write_u32(content: &mut Vec<u8>, value: u32) {
+pub(crate) fn write_u32(content: &mut Vec<u8>, value: u32) {
     content.extend_from_slice(&value.to_be_bytes());
 }
 
+fn write_u64(content: &mut Vec<u8>, value: u64) {
+    content.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_content(content: &mut Vec<u8>, record: &Content) {
+    write_string(content, &record.value);
+    write_optional_string(content, &record.key);
+}
+
+fn write_content_list(content: &mut Vec<u8>, content_list: &[Content]) {
+    write_u32(content, content_list.len() as u32);
+    for record in content_list {
+        write_content(content, record);
+    }
+}
+
+fn write_topic_list(content: &mut Vec<u8>, topic_list: &[TopicAddress]) {
+    write_u32(content, topic_list.len() as u32);
+    for topic in topic_list {
+        write_string(content, &topic.name);
+        write_u32(content, topic.partition);
+    }
+}
+
+pub(crate) fn write_batch_header(content: &mut Vec<u8>, header: &Option<BatchHeader>) {
+    match header {
+        Some(header) => {
+            content.push(if header.transactional { 2 } else { 1 });
+            write_u32(content, header.producer_id);
+            write_u32(content, header.sequence);
+        }
+        None => content.push(0),
+    }
+}
+
+/// Writes a batch without its offset, which is implied by where it's stored.
+pub(crate) fn write_record_batch(content: &mut Vec<u8>, batch: &RecordBatch) {
+    content.push(batch.kind.as_u8());
+    write_u32(content, batch.leader_epoch);
+    write_u64(content, batch.timestamp);
+    write_batch_header(content, &batch.header);
+    write_content_list(content, &batch.content_list);
+    write_u32_list(content, &batch.compacted);
+}
+
+fn write_u32_list(content: &mut Vec<u8>, value_list: &[u32]) {
+    write_u32(content, value_list.len() as u32);
+    for value in value_list {
+        write_u32(content, *value);
+    }
+}
+
+fn write_partition_state(content: &mut Vec<u8>, state: &PartitionState) {
+    write_string(content, &state.topic.name);
+    write_u32(content, state.topic.partition);
+    write_u32(content, state.leader);
+    write_u32(content, state.leader_epoch);
+    write_u32_list(content, &state.replicas);
+    write_u32_list(content, &state.isr);
+    write_u32_list(content, &state.adding_replicas);
+    write_u32_list(content, &state.removing_replicas);
+}
+
+fn write_config_list(content: &mut Vec<u8>, config_list: &[(String, String)]) {
+    write_u32(content, config_list.len() as u32);
+    for (key, value) in config_list {
+        write_string(content, key);
+        write_string(content, value);
+    }
+}
+
+fn write_optional_u32(content: &mut Vec<u8>, value: Option<u32>) {
+    match value {
+        Some(value) => {
+            content.push(1);
+            write_u32(content, value);
+        }
+        None => content.push(0),
+    }
+}
+
+fn write_optional_string(content: &mut Vec<u8>, value: &Option<String>) {
+    match value {
+        Some(value) => {
+            content.push(1);
+            write_string(content, value);
+        }
+        None => content.push(0),
+    }
+}
+
+fn write_metadata_record(content: &mut Vec<u8>, record: &MetadataRecord) {
+    match record {
+        MetadataRecord::Noop => content.push(0),
+        MetadataRecord::PartitionStates(state_list) => {
+            content.push(1);
+            write_u32(content, state_list.len() as u32);
+            for state in state_list {
+                write_partition_state(content, state);
+            }
+        }
+        MetadataRecord::TopicConfig(topic, key, value) => {
+            content.push(2);
+            write_string(content, topic);
+            write_string(content, key);
+            write_string(content, value);
+        }
+        MetadataRecord::RegisterBroker(broker_id, address, rack) => {
+            content.push(3);
+            write_u32(content, *broker_id);
+            write_string(content, address);
+            write_optional_string(content, rack);
+        }
+        MetadataRecord::UnregisterBroker(broker_id) => {
+            content.push(4);
+            write_u32(content, *broker_id);
+        }
+        MetadataRecord::FenceBroker(broker_id) => {
+            content.push(5);
+            write_u32(content, *broker_id);
+        }
+        MetadataRecord::DeleteTopic(topic) => {
+            content.push(6);
+            write_string(content, topic);
+        }
+    }
+}
+
+fn write_cluster_metadata(content: &mut Vec<u8>, metadata: &ClusterMetadata) {
+    let state_list = metadata.partition_states();
+    write_u32(content, state_list.len() as u32);
+    for state in state_list.iter() {
+        write_partition_state(content, state);
+    }
+
+    let mut topic_list: Vec<&String> = metadata.configs.keys().collect();
+    topic_list.sort();
+    write_u32(content, topic_list.len() as u32);
+    for topic in topic_list {
+        write_string(content, topic);
+        let config = &metadata.configs[topic];
+        let mut key_list: Vec<&String> = config.keys().collect();
+        key_list.sort();
+        write_u32(content, key_list.len() as u32);
+        for key in key_list {
+            write_string(content, key);
+            write_string(content, &config[key]);
+        }
+    }
+
+    let broker_list = metadata.broker_list();
+    write_u32(content, broker_list.len() as u32);
+    for (broker_id, address) in broker_list {
+        write_u32(content, broker_id);
+        write_string(content, &address);
+    }
+    write_u32(content, metadata.next_broker_id);
+
+    let mut epoch_list: Vec<(&u32, &u32)> = metadata.broker_epochs.iter().collect();
+    epoch_list.sort();
+    write_u32(content, epoch_list.len() as u32);
+    for (broker_id, broker_epoch) in epoch_list {
+        write_u32(content, *broker_id);
+        write_u32(content, *broker_epoch);
+    }
+    write_u32(content, metadata.next_broker_epoch);
+
+    let mut rack_list: Vec<(&u32, &String)> = metadata.racks.iter().collect();
+    rack_list.sort();
+    write_u32(content, rack_list.len() as u32);
+    for (broker_id, rack) in rack_list {
+        write_u32(content, *broker_id);
+        write_string(content, rack);
+    }
+
+    let mut deleted_list: Vec<&String> = metadata.deleted_topics.iter().collect();
+    deleted_list.sort();
+    write_u32(content, deleted_list.len() as u32);
+    for topic in deleted_list {
+        write_string(content, topic);
+    }
+}
+
+fn write_snapshot(content: &mut Vec<u8>, snapshot: &Snapshot) {
+    write_u32(content, snapshot.last_index);
+    write_u32(content, snapshot.last_term);
+    write_cluster_metadata(content, &snapshot.metadata);
+}
+
+fn write_log_entries(content: &mut Vec<u8>, entries: &[LogEntry]) {
+    write_u32(content, entries.len() as u32);
+    for entry in entries {
+        write_u32(content, entry.term);
+        write_metadata_record(content, &entry.record);
+    }
+}
+
+fn write_raft_message(content: &mut Vec<u8>, message: &RaftMessage) {
+    match message {
+        RaftMessage::RequestVote(term, last_index, last_term) => {
+            content.push(1);
+            write_u32(content, *term);
+            write_u32(content, *last_index);
+            write_u32(content, *last_term);
+        }
+        RaftMessage::Vote(term, granted) => {
+            content.push(2);
+            write_u32(content, *term);
+            content.push(if *granted { 1 } else { 0 });
+        }
+        RaftMessage::AppendEntries(term, prev_index, prev_term, entries, leader_commit) => {
+            content.push(3);
+            write_u32(content, *term);
+            write_u32(content, *prev_index);
+            write_u32(content, *prev_term);
+            write_log_entries(content, entries);
+            write_u32(content, *leader_commit);
+        }
+        RaftMessage::AppendResponse(term, success, index) => {
+            content.push(4);
+            write_u32(content, *term);
+            content.push(if *success { 1 } else { 0 });
+            write_u32(content, *index);
+        }
+        RaftMessage::InstallSnapshot(term, snapshot) => {
+            content.push(5);
+            write_u32(content, *term);
+            write_snapshot(content, snapshot);
+        }
+    }
+}
+
+pub(crate) fn write_persistent_state(content: &mut Vec<u8>, state: &PersistentState) {
+    write_u32(content, state.term);
+    write_optional_u32(content, state.voted_for);
+    write_snapshot(content, &state.snapshot);
+    write_log_entries(content, &state.entries);
+}
+
+fn write_action(content_vec: &mut Vec<u8>, action: &Action) {
+    match action {
+        Action::Produce(topic, content_list, acks, header) => {
+            content_vec.push(1);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+            write_content_list(content_vec, content_list);
+            content_vec.push(acks.as_u8());
+            write_batch_header(content_vec, header);
+        }
+        Action::Consume(topic, offset, limit, isolation) => {
+            content_vec.push(2);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+            write_u32(content_vec, offset.0);
+            write_u32(content_vec, *limit);
+            content_vec.push(isolation.as_u8());
+        }
+        Action::CreateTopic(topic, partition, replication_factor, config_list) => {
+            content_vec.push(3);
+            write_string(content_vec, topic);
+            write_u32(content_vec, *partition);
+            write_u32(content_vec, *replication_factor);
+            write_config_list(content_vec, config_list);
+        }
+        Action::InitializeController(broker_list) => {
+            content_vec.push(4);
+            write_u32(content_vec, broker_list.len() as u32);
+            for broker in broker_list {
+                write_string(content_vec, broker);
+            }
+        }
+        Action::InitializeBroker(broker_id, broker_list) => {
+            content_vec.push(5);
+            write_u32(content_vec, *broker_id);
+            write_u32(content_vec, broker_list.len() as u32);
+            for broker in broker_list {
+                write_string(content_vec, broker);
+            }
+        }
+        Action::IamAlive(id, broker_epoch) => {
+            content_vec.push(6);
+            write_u32(content_vec, *id);
+            write_u32(content_vec, *broker_epoch);
+        }
+        Action::CommitOffset(topic, offset) => {
+            content_vec.push(7);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+            write_u32(content_vec, offset.0);
+        }
+        Action::CommittedOffset(topic) => {
+            content_vec.push(8);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+        }
+        Action::LatestOffset(topic) => {
+            content_vec.push(9);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+        }
+        Action::Metadata(topic) => {
+            content_vec.push(10);
+            write_string(content_vec, topic);
+        }
+        Action::InitProducerId => content_vec.push(11),
+        Action::BeginTransaction(transactional_id) => {
+            content_vec.push(12);
+            write_string(content_vec, transactional_id);
+        }
+        Action::AddPartitionsToTransaction(transactional_id, topic_list) => {
+            content_vec.push(13);
+            write_string(content_vec, transactional_id);
+            write_topic_list(content_vec, topic_list);
+        }
+        Action::AddOffsetToTransaction(transactional_id, topic, offset) => {
+            content_vec.push(14);
+            write_string(content_vec, transactional_id);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+            write_u32(content_vec, offset.0);
+        }
+        Action::EndTransaction(transactional_id, commit) => {
+            content_vec.push(15);
+            write_string(content_vec, transactional_id);
+            content_vec.push(*commit as u8);
+        }
+        Action::LeaderAndIsr(broker_epoch, state_list) => {
+            content_vec.push(16);
+            write_u32(content_vec, *broker_epoch);
+            write_u32(content_vec, state_list.len() as u32);
+            for state in state_list {
+                write_partition_state(content_vec, state);
+            }
+        }
+        Action::Fetch(topic, offset, replica_id, max_records) => {
+            content_vec.push(17);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+            write_u32(content_vec, offset.0);
+            write_u32(content_vec, *replica_id);
+            write_u32(content_vec, *max_records);
+        }
+        Action::AlterIsr(state, broker_epoch) => {
+            content_vec.push(18);
+            write_partition_state(content_vec, state);
+            write_u32(content_vec, *broker_epoch);
+        }
+        Action::Raft(from, message) => {
+            content_vec.push(19);
+            write_u32(content_vec, *from);
+            write_raft_message(content_vec, message);
+        }
+        Action::RegisterBroker(broker_id, address, rack) => {
+            content_vec.push(20);
+            write_optional_u32(content_vec, *broker_id);
+            write_string(content_vec, address);
+            write_optional_string(content_vec, rack);
+        }
+        Action::UnregisterBroker(broker_id) => {
+            content_vec.push(21);
+            write_u32(content_vec, *broker_id);
+        }
+        Action::Forwarded(action) => {
+            content_vec.push(22);
+            write_action(content_vec, action);
+        }
+        Action::AlterConfigs(topic, config_list) => {
+            content_vec.push(23);
+            write_string(content_vec, topic);
+            write_config_list(content_vec, config_list);
+        }
+        Action::TopicConfigs(topic, config_list) => {
+            content_vec.push(24);
+            write_string(content_vec, topic);
+            write_config_list(content_vec, config_list);
+        }
+        Action::OffsetForLeaderEpoch(topic, leader_epoch) => {
+            content_vec.push(25);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+            write_u32(content_vec, *leader_epoch);
+        }
+        Action::Shutdown => content_vec.push(26),
+        Action::ControlledShutdown(broker_id, broker_epoch) => {
+            content_vec.push(27);
+            write_u32(content_vec, *broker_id);
+            write_u32(content_vec, *broker_epoch);
+        }
+        Action::AlterPartitionReassignment(topic, replicas) => {
+            content_vec.push(28);
+            write_string(content_vec, &topic.name);
+            write_u32(content_vec, topic.partition);
+            write_u32_list(content_vec, replicas);
+        }
+        Action::Rebalance(execute) => {
+            content_vec.push(29);
+            content_vec.push(*execute as u8);
+        }
+        Action::CreatePartitions(topic, partition_count) => {
+            content_vec.push(30);
+            write_string(content_vec, topic);
+            write_u32(content_vec, *partition_count);
+        }
+        Action::DeleteTopic(topic) => {
+            content_vec.push(31);
+            write_string(content_vec, topic);
+        }
+        Action::RemoveTopic(topic) => {
+            content_vec.push(32);
+            write_string(content_vec, topic);
+        }
+        Action::DescribeConfigs(topic) => {
+            content_vec.push(33);
+            write_string(content_vec, topic);
+        }
+        Action::Quit => content_vec.push(99),
+        Action::Invalid => content_vec.push(0),
+    }
+}
+
+// Every message travels as a u32 length followed by its bytes, so several
+// of them can share a connection without waiting for each other's answers.
+fn write_frame(writer: &mut impl Write, content: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(content.len() + 4);
+    write_u32(&mut frame, content.len() as u32);
+    frame.extend_from_slice(content);
+    writer.write_all(&frame[..])?;
+    writer.flush()
+}
+
+/// Refuses frames over `max_size` before reading them. Below it, memory
+/// grows with the bytes that arrive rather than with the size announced.
+fn read_frame(reader: &mut impl Read, max_size: usize) -> io::Result<Vec<u8>> {
+    let mut size = [0; 4];
+    reader.read_exact(&mut size)?;
+    let size = u32::from_be_bytes(size) as usize;
+    if size > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is larger than {}", size, max_size),
+        ));
+    }
+
+    let mut content = Vec::new();
+    reader.take(size as u64).read_to_end(&mut content)?;
+    if content.len() < size {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(content)
+}
+
 pub enum Action {
-    Produce(TopicAddress, Vec<Content>),
-    Consume(TopicAddress, OffsetValue, u32),
-    CreateTopic(String, u32),
+    Produce(TopicAddress, Vec<Content>, Acks, Option<BatchHeader>),
+    Consume(TopicAddress, OffsetValue, u32, Isolation),
+    /// Topic name, number of partitions, replication factor and configs,
+    /// see `TOPIC_CONFIG_DEFAULTS`.
+    CreateTopic(String, u32, u32, Vec<(String, String)>),
     InitializeController(Vec<String>),
     InitializeBroker(u32, Vec<String>),
-    IamAlive(u32),
+    /// Heartbeat of a broker: its id and the epoch it registered with.
+    IamAlive(u32, u32),
+    CommitOffset(TopicAddress, OffsetValue),
+    CommittedOffset(TopicAddress),
+    LatestOffset(TopicAddress),
+    Metadata(String),
+    InitProducerId,
+    BeginTransaction(String),
+    AddPartitionsToTransaction(String, Vec<TopicAddress>),
+    AddOffsetToTransaction(String, TopicAddress, OffsetValue),
+    EndTransaction(String, bool),
+    /// Sent by the controller to tell brokers which partitions they hold and
+    /// who leads them, along with the epoch the recipient registered with.
+    LeaderAndIsr(u32, Vec<PartitionState>),
+    /// A follower asking the leader for batches from an offset: topic,
+    /// offset, follower id and maximum number of records.
+    Fetch(TopicAddress, OffsetValue, u32, u32),
+    /// Sent by a partition leader to the controller when its ISR changes,
+    /// along with the epoch the leader registered with.
+    AlterIsr(PartitionState, u32),
+    /// Between members of the controller quorum: sender id and message.
+    /// Never answered, replies travel as messages of their own.
+    Raft(u32, RaftMessage),
+    /// Sent by a broker to the controller on startup: the id it had before,
+    /// if any, the address other brokers and clients reach it on and its rack.
+    RegisterBroker(Option<u32>, String, Option<String>),
+    /// Takes a broker out of the cluster for good.
+    UnregisterBroker(u32),
+    /// An admin request a broker passed on to the controller. Never passed
+    /// on again, so brokers that disagree on the controller can't bounce it.
+    Forwarded(Box<Action>),
+    /// Sets config keys of a topic, see `TOPIC_CONFIG_DEFAULTS`. Handled by the controller.
+    AlterConfigs(String, Vec<(String, String)>),
+    /// Sent by the controller to tell brokers how a topic is configured.
+    TopicConfigs(String, Vec<(String, String)>),
+    /// Sent by a follower to the partition leader, before fetching from it,
+    /// to learn where a leader epoch ends in the leader's log.
+    OffsetForLeaderEpoch(TopicAddress, u32),
+    /// Asks a broker to shut down gracefully, see `Broker::shutdown`.
+    Shutdown,
+    /// Sent by a broker shutting down to the controller, with its id and
+    /// epoch, so its partitions get other leaders first. Answered with the
+    /// states of the partitions it holds a replica of.
+    ControlledShutdown(u32, u32),
+    /// Moves the replicas of a partition to the given brokers, the first
+    /// one becoming the preferred leader. Handled by the controller.
+    AlterPartitionReassignment(TopicAddress, Vec<u32>),
+    /// Asks the controller for reassignments that even out replicas per
+    /// broker, and to start them when true.
+    Rebalance(bool),
+    /// Grows a topic to the given partition count. Handled by the controller.
+    CreatePartitions(String, u32),
+    /// Deletes a topic with all its data. Handled by the controller.
+    DeleteTopic(String),
+    /// Sent by the controller to tell brokers a topic was deleted.
+    RemoveTopic(String),
+    /// Asks a broker for every config of a topic, set or default.
+    DescribeConfigs(String),
     Quit,
     Invalid,
 }
@@ -76,116 +945,48 @@ impl ActionMessage {
         }
     }
 
+    /// A request cut short decodes to `Action::Invalid`.
     pub fn parse(buffer: &[u8]) -> ActionMessage {
         let mut data = Buffer::new(buffer);
-
-        let action = match data.read_u8() {
-            1 => {
-                let topic = TopicAddress::new(data.read_string(), data.read_u32());
-                let content_length = data.read_u32();
-                let mut content_list = Vec::new();
-                for _ in 0..content_length {
-                    content_list.push(Content::new(data.read_string()))
-                }
-                Action::Produce(topic, content_list)
-            }
-            2 => {
-                let topic = TopicAddress::new(data.read_string(), data.read_u32());
-                let offset = OffsetValue(data.read_u32());
-                let limit = data.read_u32();
-                Action::Consume(topic, offset, limit)
-            }
-            3 => {
-                let topic = data.read_string();
-                let partition = data.read_u32();
-                Action::CreateTopic(topic, partition)
-            }
-            4 => {
-                let mut broker_list = Vec::new();
-                let size = data.read_u32() as usize;
-                for _ in 0..size {
-                    broker_list.push(data.read_string());
-                }
-                Action::InitializeController(broker_list)
-            }
-            5 => {
-                let mut broker_list = Vec::new();
-                let broker_id = data.read_u32();
-                let size = data.read_u32() as usize;
-                for _ in 0..size {
-                    let broker = data.read_string();
-                    broker_list.push(broker);
-                }
-                Action::InitializeBroker(broker_id, broker_list)
-            }
-            6 => {
-                let id = data.read_u32();
-                Action::IamAlive(id)
-            }
-            99 => Action::Quit,
-            _ => Action::Invalid,
-        };
-
-        let consumer_id = data.read_string();
-
-        ActionMessage {
-            action,
-            consumer_id,
+        let parsed = data
+            .read_action()
+            .and_then(|action| Ok((action, data.read_string()?)));
+
+        match parsed {
+            Ok((action, consumer_id)) => ActionMessage {
+                action,
+                consumer_id,
+            },
+            Err(_) => ActionMessage::new(Action::Invalid, String::new()),
         }
     }
 
     pub fn as_vec(&self) -> Vec<u8> {
         let mut content_vec: Vec<u8> = Vec::new();
 
-        match &self.action {
-            Action::Produce(topic, content_list) => {
-                content_vec.push(1);
-                write_string(&mut content_vec, &topic.name);
-                write_u32(&mut content_vec, topic.partition);
-                write_u32(&mut content_vec, content_list.len() as u32);
-                for content in content_list {
-                    write_string(&mut content_vec, &content.value);
-                }
-            }
-            Action::Consume(topic, offset, limit) => {
-                content_vec.push(2);
-                write_string(&mut content_vec, &topic.name);
-                write_u32(&mut content_vec, topic.partition);
-                write_u32(&mut content_vec, offset.0);
-                write_u32(&mut content_vec, *limit);
-            }
-            Action::CreateTopic(topic, partition) => {
-                content_vec.push(3);
-                write_string(&mut content_vec, &topic);
-                write_u32(&mut content_vec, *partition);
-            }
-            Action::InitializeController(broker_list) => {
-                content_vec.push(4);
-                write_u32(&mut content_vec, broker_list.len() as u32);
-                for broker in broker_list {
-                    write_string(&mut content_vec, broker);
-                }
-            }
-            Action::InitializeBroker(broker_id, broker_list) => {
-                content_vec.push(5);
-                write_u32(&mut content_vec, *broker_id);
-                write_u32(&mut content_vec, broker_list.len() as u32);
-                for broker in broker_list {
-                    write_string(&mut content_vec, broker);
-                }
-            }
-            Action::IamAlive(id) => {
-                content_vec.push(6);
-                write_u32(&mut content_vec, *id);
-            }
-            Action::Quit => content_vec.push(99),
-            Action::Invalid => content_vec.push(0),
-        }
+        write_action(&mut content_vec, &self.action);
 
         write_string(&mut content_vec, &self.consumer_id);
 
         content_vec
     }
+
+    /// Fire-and-forget produce requests and Raft messages get no answer from the broker.
+    pub fn expects_response(&self) -> bool {
+        !matches!(
+            self.action,
+            Action::Produce(_, _, Acks::None, _) | Action::Raft(_, _)
+        )
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<ActionMessage> {
+        let content = read_frame(reader, MAX_REQUEST_BYTES)?;
+        Ok(ActionMessage::parse(&content[..]))
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_frame(writer, &self.as_vec()[..])
+    }
 }
 
 pub enum Response {
@@ -193,6 +994,45 @@ pub enum Response {
     Offset(OffsetValue),
     Content(OffsetValue, Content),
     AskTheController(String),
+    TopicMetadata(u32),
+    ProducerId(u32),
+    OutOfOrderSequence,
+    DuplicateSequence,
+    InvalidTransactionState,
+    /// A batch copied by a follower from the partition leader.
+    RecordBatch(RecordBatch),
+    /// Offset up to which a partition is replicated to every in-sync replica.
+    HighWatermark(OffsetValue),
+    /// This broker doesn't lead the partition; metadata tells who does.
+    NotLeader,
+    PartitionMetadata(PartitionState),
+    /// Id and address of a broker, sent along with topic metadata.
+    BrokerAddress(u32, String),
+    /// Id and epoch the controller registered a broker with.
+    BrokerId(u32, u32),
+    /// Fewer replicas are in sync than the topic's `min.insync.replicas`,
+    /// so an `Acks::All` produce can't be acknowledged.
+    NotEnoughReplicas,
+    /// The largest leader epoch up to the one asked for that the leader
+    /// knows of, and the offset it ends at.
+    EpochEndOffset(u32, OffsetValue),
+    /// The broker epoch sent along isn't the current one: the broker was
+    /// fenced or registered again since.
+    StaleBrokerEpoch,
+    /// A partition and the replicas it is being moved to.
+    Reassignment(TopicAddress, Vec<u32>),
+    /// The topic or partition doesn't exist, or was deleted.
+    UnknownTopic,
+    /// A topic config key and its value.
+    Config(String, String),
+    /// A topic config key is unknown or its value invalid, with the reason.
+    InvalidConfig(String),
+    /// The records of a produce hold more bytes than the topic's
+    /// `max.message.bytes`.
+    RecordTooLarge,
+    /// The topic name is empty, `.`, `..` or holds characters other than
+    /// ASCII letters, digits, `.`, `_` and `-`.
+    InvalidTopic,
     Error,
 }
 
@@ -211,27 +1051,76 @@ impl ResponseMessage {
         }
     }
 
-    pub fn parse(buffer: &[u8]) -> Vec<ResponseMessage> {
+    /// Fails with `InvalidData` when a response is cut short.
+    pub fn parse(buffer: &[u8]) -> io::Result<Vec<ResponseMessage>> {
+        ResponseMessage::read_list(&mut Buffer::new(buffer))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn read_list(data: &mut Buffer) -> io::Result<Vec<ResponseMessage>> {
         let mut result_list = Vec::new();
-        let mut data = Buffer::new(buffer);
         let mut read_all = false;
 
         while !read_all {
-            let response = match data.read_u8() {
+            let response = match data.read_u8()? {
                 1 => {
-                    let offset = OffsetValue(data.read_u32());
-                    let content = Content::new(data.read_string());
-                    Response::Content(offset, content)
+                    let offset = OffsetValue(data.read_u32()?);
+                    Response::Content(offset, data.read_content()?)
                 }
                 2 => {
-                    let offset = OffsetValue(data.read_u32());
+                    let offset = OffsetValue(data.read_u32()?);
                     Response::Offset(offset)
                 }
                 3 => Response::Error,
                 4 => {
-                    let broker = data.read_string();
+                    let broker = data.read_string()?;
                     Response::AskTheController(broker)
                 }
+                5 => {
+                    let partition_count = data.read_u32()?;
+                    Response::TopicMetadata(partition_count)
+                }
+                6 => {
+                    let producer_id = data.read_u32()?;
+                    Response::ProducerId(producer_id)
+                }
+                7 => Response::OutOfOrderSequence,
+                8 => Response::DuplicateSequence,
+                9 => Response::InvalidTransactionState,
+                10 => {
+                    let first_offset = OffsetValue(data.read_u32()?);
+                    Response::RecordBatch(data.read_record_batch(first_offset)?)
+                }
+                11 => Response::HighWatermark(OffsetValue(data.read_u32()?)),
+                12 => Response::NotLeader,
+                13 => Response::PartitionMetadata(data.read_partition_state()?),
+                14 => {
+                    let broker_id = data.read_u32()?;
+                    let address = data.read_string()?;
+                    Response::BrokerAddress(broker_id, address)
+                }
+                15 => {
+                    let broker_id = data.read_u32()?;
+                    Response::BrokerId(broker_id, data.read_u32()?)
+                }
+                16 => Response::NotEnoughReplicas,
+                17 => {
+                    let leader_epoch = data.read_u32()?;
+                    Response::EpochEndOffset(leader_epoch, OffsetValue(data.read_u32()?))
+                }
+                18 => Response::StaleBrokerEpoch,
+                19 => {
+                    let topic = data.read_topic()?;
+                    Response::Reassignment(topic, data.read_u32_list()?)
+                }
+                20 => Response::UnknownTopic,
+                21 => {
+                    let key = data.read_string()?;
+                    Response::Config(key, data.read_string()?)
+                }
+                22 => Response::InvalidConfig(data.read_string()?),
+                23 => Response::RecordTooLarge,
+                24 => Response::InvalidTopic,
                 _ => {
                     read_all = true;
                     Response::Empty
@@ -242,7 +1131,7 @@ impl ResponseMessage {
             read_all = read_all || data.finished_read();
         }
 
-        result_list
+        Ok(result_list)
     }
 
     pub fn as_vec(&self) -> Vec<u8> {
@@ -253,7 +1142,7 @@ impl ResponseMessage {
             Response::Content(offset, content) => {
                 content_vec.push(1);
                 write_u32(&mut content_vec, offset.0);
-                write_string(&mut content_vec, &content.value);
+                write_content(&mut content_vec, content);
             }
             Response::Offset(offset) => {
                 content_vec.push(2);
@@ -262,12 +1151,93 @@ impl ResponseMessage {
             Response::Error => content_vec.push(3),
             Response::AskTheController(broker_id) => {
                 content_vec.push(4);
-                write_string(&mut content_vec, &broker_id);
+                write_string(&mut content_vec, broker_id);
+            }
+            Response::TopicMetadata(partition_count) => {
+                content_vec.push(5);
+                write_u32(&mut content_vec, *partition_count);
             }
+            Response::ProducerId(producer_id) => {
+                content_vec.push(6);
+                write_u32(&mut content_vec, *producer_id);
+            }
+            Response::OutOfOrderSequence => content_vec.push(7),
+            Response::DuplicateSequence => content_vec.push(8),
+            Response::InvalidTransactionState => content_vec.push(9),
+            Response::RecordBatch(batch) => {
+                content_vec.push(10);
+                write_u32(&mut content_vec, batch.first_offset.0);
+                write_record_batch(&mut content_vec, batch);
+            }
+            Response::HighWatermark(offset) => {
+                content_vec.push(11);
+                write_u32(&mut content_vec, offset.0);
+            }
+            Response::NotLeader => content_vec.push(12),
+            Response::PartitionMetadata(state) => {
+                content_vec.push(13);
+                write_partition_state(&mut content_vec, state);
+            }
+            Response::BrokerAddress(broker_id, address) => {
+                content_vec.push(14);
+                write_u32(&mut content_vec, *broker_id);
+                write_string(&mut content_vec, address);
+            }
+            Response::BrokerId(broker_id, broker_epoch) => {
+                content_vec.push(15);
+                write_u32(&mut content_vec, *broker_id);
+                write_u32(&mut content_vec, *broker_epoch);
+            }
+            Response::NotEnoughReplicas => content_vec.push(16),
+            Response::EpochEndOffset(leader_epoch, offset) => {
+                content_vec.push(17);
+                write_u32(&mut content_vec, *leader_epoch);
+                write_u32(&mut content_vec, offset.0);
+            }
+            Response::StaleBrokerEpoch => content_vec.push(18),
+            Response::Reassignment(topic, replicas) => {
+                content_vec.push(19);
+                write_string(&mut content_vec, &topic.name);
+                write_u32(&mut content_vec, topic.partition);
+                write_u32_list(&mut content_vec, replicas);
+            }
+            Response::UnknownTopic => content_vec.push(20),
+            Response::Config(key, value) => {
+                content_vec.push(21);
+                write_string(&mut content_vec, key);
+                write_string(&mut content_vec, value);
+            }
+            Response::InvalidConfig(reason) => {
+                content_vec.push(22);
+                write_string(&mut content_vec, reason);
+            }
+            Response::RecordTooLarge => content_vec.push(23),
+            Response::InvalidTopic => content_vec.push(24),
         }
 
         content_vec
     }
+
+    /// Unlike requests, responses have no size limit: a fetch may carry
+    /// many batches of the largest size.
+    pub fn read_list_from(reader: &mut impl Read) -> io::Result<Vec<ResponseMessage>> {
+        let content = read_frame(reader, u32::MAX as usize)?;
+        if content.is_empty() {
+            return Ok(vec![ResponseMessage::new_empty()]);
+        }
+        ResponseMessage::parse(&content[..])
+    }
+
+    pub fn write_list_to(
+        writer: &mut impl Write,
+        response_list: &[ResponseMessage],
+    ) -> io::Result<()> {
+        let mut content = Vec::new();
+        for response in response_list {
+            content.extend(response.as_vec());
+        }
+        write_frame(writer, &content[..])
+    }
 }
 
 #[cfg(test)]
@@ -298,25 +1268,110 @@ mod tests {
         assert_eq!(message.consumer_id, consumer_id);
     }
 
+    #[test]
+    fn should_not_expect_response_for_fire_and_forget_produce() {
+        let topic = TopicAddress::new(String::from("topic"), 0);
+        let content = vec![Content::new(String::from("Message Content"))];
+
+        let fire_and_forget = ActionMessage::new(
+            Action::Produce(topic.clone(), content.clone(), Acks::None, None),
+            String::new(),
+        );
+        let acknowledged = ActionMessage::new(
+            Action::Produce(topic, content, Acks::Leader, None),
+            String::new(),
+        );
+
+        assert!(!fire_and_forget.expects_response());
+        assert!(acknowledged.expects_response());
+    }
+
+    #[test]
+    fn should_read_framed_messages_one_at_a_time() {
+        let mut bytes = Vec::new();
+        ActionMessage::new(Action::IamAlive(1, 0), String::from("first"))
+            .write_to(&mut bytes)
+            .unwrap();
+        ActionMessage::new(Action::Quit, String::from("second"))
+            .write_to(&mut bytes)
+            .unwrap();
+
+        let mut reader = &bytes[..];
+        let first = ActionMessage::read_from(&mut reader).unwrap();
+        let second = ActionMessage::read_from(&mut reader).unwrap();
+
+        assert!(matches!(first.action, Action::IamAlive(1, 0)));
+        assert_eq!(first.consumer_id, "first");
+        assert!(matches!(second.action, Action::Quit));
+        assert_eq!(second.consumer_id, "second");
+        assert!(ActionMessage::read_from(&mut reader).is_err());
+    }
+
+    #[test]
+    fn should_refuse_request_frame_larger_than_the_limit_before_reading_it() {
+        let mut bytes = Vec::new();
+        write_u32(&mut bytes, u32::MAX);
+        bytes.extend_from_slice(&[0; 16]);
+
+        let err = ActionMessage::read_from(&mut &bytes[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut bytes = Vec::new();
+        write_u32(&mut bytes, 1000);
+        bytes.extend_from_slice(&[0; 16]);
+        let err = ResponseMessage::read_list_from(&mut &bytes[..])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn should_read_truncated_request_as_invalid_action() {
+        let bytes = [0, 0, 0, 1, 1];
+        let message = ActionMessage::read_from(&mut &bytes[..]).unwrap();
+        assert!(matches!(message.action, Action::Invalid));
+
+        let mut bytes = ActionMessage::new(
+            Action::CreateTopic(String::from("topic"), 3, 2, Vec::new()),
+            String::from("consumer_id"),
+        )
+        .as_vec();
+        bytes.truncate(bytes.len() - 4);
+        assert!(matches!(
+            ActionMessage::parse(&bytes[..]).action,
+            Action::Invalid
+        ));
+
+        let mut bytes = ResponseMessage::new(Response::Config(
+            String::from("max.message.bytes"),
+            String::from("1000"),
+        ))
+        .as_vec();
+        bytes.truncate(bytes.len() - 1);
+        let err = ResponseMessage::parse(&bytes[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn should_convert_consume_action() {
         let topic = TopicAddress::new(String::from("topic"), 1);
         let consumer_id = String::from("consumer_id");
         let message = ActionMessage::new(
-            Action::Consume(topic, OffsetValue(3), 10),
+            Action::Consume(topic, OffsetValue(3), 10, Isolation::ReadCommitted),
             consumer_id.clone(),
         );
 
         let parsed_message = message.as_vec();
         let message = ActionMessage::parse(&parsed_message[..]);
 
-        if let Action::Consume(parsed_topic, offset, limit) = message.action {
+        if let Action::Consume(parsed_topic, offset, limit, isolation) = message.action {
             assert_eq!(parsed_topic.name, "topic");
             assert_eq!(parsed_topic.partition, 1);
             assert_eq!(offset.0, 3);
             assert_eq!(limit, 10);
+            assert_eq!(isolation, Isolation::ReadCommitted);
         } else {
-            assert!(false);
+            panic!("unexpected action");
         }
 
         assert_eq!(message.consumer_id, consumer_id);
@@ -328,18 +1383,23 @@ mod tests {
         let topic = TopicAddress::new(String::from("topic"), 1);
         let content = vec![Content::new(String::from("Message Content"))];
 
-        let message = ActionMessage::new(Action::Produce(topic, content), consumer_id.clone());
+        let message = ActionMessage::new(
+            Action::Produce(topic, content, Acks::Leader, None),
+            consumer_id.clone(),
+        );
 
         let parsed_message = message.as_vec();
         let message = ActionMessage::parse(&parsed_message[..]);
 
-        if let Action::Produce(parsed_topic, content) = message.action {
+        if let Action::Produce(parsed_topic, content, acks, header) = message.action {
             assert_eq!(parsed_topic.name, "topic");
             assert_eq!(parsed_topic.partition, 1);
+            assert_eq!(acks, Acks::Leader);
+            assert!(header.is_none());
             assert_eq!(content.len(), 1);
             assert_eq!(content.first().unwrap().value, "Message Content");
         } else {
-            assert!(false);
+            panic!("unexpected action");
         }
 
         assert_eq!(message.consumer_id, consumer_id);
@@ -355,20 +1415,25 @@ mod tests {
             Content::new(String::from("Message final")),
         ];
 
-        let message = ActionMessage::new(Action::Produce(topic, content), consumer_id.clone());
+        let message = ActionMessage::new(
+            Action::Produce(topic, content, Acks::All, Some(BatchHeader::new(7, 30))),
+            consumer_id.clone(),
+        );
 
         let parsed_message = message.as_vec();
         let message = ActionMessage::parse(&parsed_message[..]);
 
-        if let Action::Produce(parsed_topic, content) = message.action {
+        if let Action::Produce(parsed_topic, content, acks, header) = message.action {
             assert_eq!(parsed_topic.name, "topic");
             assert_eq!(parsed_topic.partition, 1);
+            assert_eq!(acks, Acks::All);
+            assert_eq!(header, Some(BatchHeader::new(7, 30)));
             assert_eq!(content.len(), 3);
-            assert_eq!(content.get(0).unwrap().value, "Message Content");
+            assert_eq!(content.first().unwrap().value, "Message Content");
             assert_eq!(content.get(1).unwrap().value, "Message other");
             assert_eq!(content.get(2).unwrap().value, "Message final");
         } else {
-            assert!(false);
+            panic!("unexpected action");
         }
 
         assert_eq!(message.consumer_id, consumer_id);
@@ -379,17 +1444,25 @@ mod tests {
         let consumer_id = String::from("consumer_id");
         let topic = String::from("topic");
 
-        let message =
-            ActionMessage::new(Action::CreateTopic(topic.clone(), 1), consumer_id.clone());
+        let config_list = vec![(String::from("retention.ms"), String::from("1000"))];
+
+        let message = ActionMessage::new(
+            Action::CreateTopic(topic.clone(), 1, 3, config_list.clone()),
+            consumer_id.clone(),
+        );
 
         let parsed_message = message.as_vec();
         let message = ActionMessage::parse(&parsed_message[..]);
 
-        if let Action::CreateTopic(parsed_topic, partition) = message.action {
+        if let Action::CreateTopic(parsed_topic, partition, replication_factor, parsed_list) =
+            message.action
+        {
             assert_eq!(parsed_topic, topic);
             assert_eq!(partition, 1);
+            assert_eq!(replication_factor, 3);
+            assert_eq!(parsed_list, config_list);
         } else {
-            assert!(false);
+            panic!("unexpected action");
         }
 
         assert_eq!(message.consumer_id, consumer_id);
@@ -409,24 +1482,25 @@ mod tests {
 
         if let Action::InitializeController(list) = message.action {
             assert_eq!(2, list.len());
-            assert_eq!(list.get(0).unwrap(), "broker1");
+            assert_eq!(list.first().unwrap(), "broker1");
             assert_eq!(list.get(1).unwrap(), "broker2");
         } else {
-            assert!(false);
+            panic!("unexpected action");
         }
     }
 
     #[test]
     fn shoyd_convert_iamalive_action() {
-        let message = ActionMessage::new(Action::IamAlive(10), String::from("consumer_id"));
+        let message = ActionMessage::new(Action::IamAlive(10, 3), String::from("consumer_id"));
 
         let parsed_message = message.as_vec();
         let message = ActionMessage::parse(&parsed_message[..]);
 
-        if let Action::IamAlive(id) = message.action {
+        if let Action::IamAlive(id, broker_epoch) = message.action {
             assert_eq!(id, 10);
+            assert_eq!(broker_epoch, 3);
         } else {
-            assert!(false);
+            panic!("unexpected action");
         }
     }
 
@@ -445,11 +1519,477 @@ mod tests {
         if let Action::InitializeBroker(id, list) = message.action {
             assert_eq!(5, id);
             assert_eq!(2, list.len());
-            assert_eq!(list.get(0).unwrap(), "broker1");
+            assert_eq!(list.first().unwrap(), "broker1");
             assert_eq!(list.get(1).unwrap(), "broker2");
         } else {
-            assert!(false);
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_convert_commit_offset_action() {
+        let topic = TopicAddress::new(String::from("topic"), 2);
+        let message = ActionMessage::new(
+            Action::CommitOffset(topic, OffsetValue(42)),
+            String::from("consumer_id"),
+        );
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::CommitOffset(parsed_topic, offset) = message.action {
+            assert_eq!(parsed_topic.name, "topic");
+            assert_eq!(parsed_topic.partition, 2);
+            assert_eq!(offset.0, 42);
+        } else {
+            panic!("unexpected action");
+        }
+
+        assert_eq!(message.consumer_id, "consumer_id");
+    }
+
+    #[test]
+    fn should_convert_committed_offset_action() {
+        let topic = TopicAddress::new(String::from("topic"), 2);
+        let message =
+            ActionMessage::new(Action::CommittedOffset(topic), String::from("consumer_id"));
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::CommittedOffset(parsed_topic) = message.action {
+            assert_eq!(parsed_topic.name, "topic");
+            assert_eq!(parsed_topic.partition, 2);
+        } else {
+            panic!("unexpected action");
+        }
+
+        assert_eq!(message.consumer_id, "consumer_id");
+    }
+
+    #[test]
+    fn should_convert_latest_offset_action() {
+        let topic = TopicAddress::new(String::from("topic"), 3);
+        let message = ActionMessage::new(Action::LatestOffset(topic), String::new());
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::LatestOffset(parsed_topic) = message.action {
+            assert_eq!(parsed_topic.name, "topic");
+            assert_eq!(parsed_topic.partition, 3);
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_convert_metadata_action() {
+        let message = ActionMessage::new(Action::Metadata(String::from("topic")), String::new());
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::Metadata(topic) = message.action {
+            assert_eq!(topic, "topic");
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_convert_add_partitions_to_transaction_action() {
+        let topic_list = vec![
+            TopicAddress::new(String::from("first"), 0),
+            TopicAddress::new(String::from("second"), 3),
+        ];
+        let message = ActionMessage::new(
+            Action::AddPartitionsToTransaction(String::from("transaction"), topic_list),
+            String::new(),
+        );
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::AddPartitionsToTransaction(transactional_id, topic_list) = message.action {
+            assert_eq!(transactional_id, "transaction");
+            assert_eq!(topic_list.len(), 2);
+            assert_eq!(topic_list[1].name, "second");
+            assert_eq!(topic_list[1].partition, 3);
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_convert_end_transaction_action() {
+        let message = ActionMessage::new(
+            Action::EndTransaction(String::from("transaction"), true),
+            String::new(),
+        );
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::EndTransaction(transactional_id, commit) = message.action {
+            assert_eq!(transactional_id, "transaction");
+            assert!(commit);
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_convert_leader_and_isr_action() {
+        let state = PartitionState {
+            topic: TopicAddress::new(String::from("topic"), 2),
+            leader: 1,
+            leader_epoch: 4,
+            replicas: vec![1, 2, 0],
+            isr: vec![1, 2],
+            adding_replicas: vec![0],
+            removing_replicas: vec![2],
+        };
+        let message =
+            ActionMessage::new(Action::LeaderAndIsr(7, vec![state.clone()]), String::new());
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::LeaderAndIsr(broker_epoch, state_list) = message.action {
+            assert_eq!(broker_epoch, 7);
+            assert_eq!(state_list, vec![state]);
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_convert_fetch_action() {
+        let topic = TopicAddress::new(String::from("topic"), 1);
+        let message = ActionMessage::new(
+            Action::Fetch(topic.clone(), OffsetValue(12), 2, 500),
+            String::new(),
+        );
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::Fetch(parsed_topic, offset, replica_id, max_records) = message.action {
+            assert_eq!(parsed_topic, topic);
+            assert_eq!(offset.0, 12);
+            assert_eq!(replica_id, 2);
+            assert_eq!(max_records, 500);
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_convert_raft_action() {
+        let state = PartitionState::new(TopicAddress::new(String::from("topic"), 0), 1, vec![1, 2]);
+        let entries = vec![
+            LogEntry {
+                term: 2,
+                record: MetadataRecord::PartitionStates(vec![state.clone()]),
+            },
+            LogEntry {
+                term: 3,
+                record: MetadataRecord::TopicConfig(
+                    String::from("topic"),
+                    String::from("retention.ms"),
+                    String::from("1000"),
+                ),
+            },
+            LogEntry {
+                term: 3,
+                record: MetadataRecord::RegisterBroker(
+                    4,
+                    String::from("localhost:8084"),
+                    Some(String::from("rack-a")),
+                ),
+            },
+            LogEntry {
+                term: 3,
+                record: MetadataRecord::UnregisterBroker(1),
+            },
+            LogEntry {
+                term: 3,
+                record: MetadataRecord::RegisterBroker(5, String::from("localhost:8085"), None),
+            },
+            LogEntry {
+                term: 3,
+                record: MetadataRecord::FenceBroker(4),
+            },
+            LogEntry {
+                term: 3,
+                record: MetadataRecord::DeleteTopic(String::from("old")),
+            },
+        ];
+        let mut metadata = ClusterMetadata::new();
+        for entry in entries.iter() {
+            metadata.apply(&entry.record);
+        }
+
+        for raft_message in [
+            RaftMessage::AppendEntries(3, 4, 2, entries, 5),
+            RaftMessage::InstallSnapshot(
+                3,
+                Box::new(Snapshot {
+                    last_index: 6,
+                    last_term: 3,
+                    metadata,
+                }),
+            ),
+            RaftMessage::Vote(3, true),
+        ] {
+            let message = ActionMessage::new(Action::Raft(2, raft_message.clone()), String::new());
+            assert!(!message.expects_response());
+
+            let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+
+            if let Action::Raft(from, parsed) = parsed_message.action {
+                assert_eq!(from, 2);
+                assert_eq!(parsed, raft_message);
+            } else {
+                panic!("unexpected action");
+            }
+        }
+    }
+
+    #[test]
+    fn should_convert_register_broker_action() {
+        for (broker_id, rack) in [(Some(3), Some(String::from("rack-a"))), (None, None)] {
+            let message = ActionMessage::new(
+                Action::RegisterBroker(broker_id, String::from("localhost:8083"), rack.clone()),
+                String::new(),
+            );
+
+            let parsed_message = message.as_vec();
+            let message = ActionMessage::parse(&parsed_message[..]);
+
+            if let Action::RegisterBroker(parsed_id, address, parsed_rack) = message.action {
+                assert_eq!(parsed_id, broker_id);
+                assert_eq!(address, "localhost:8083");
+                assert_eq!(parsed_rack, rack);
+            } else {
+                panic!("unexpected action");
+            }
+        }
+    }
+
+    #[test]
+    fn should_convert_forwarded_action() {
+        let message = ActionMessage::new(
+            Action::Forwarded(Box::new(Action::CreateTopic(
+                String::from("topic"),
+                2,
+                3,
+                Vec::new(),
+            ))),
+            String::from("consumer_id"),
+        );
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        assert_eq!(message.consumer_id, "consumer_id");
+        if let Action::Forwarded(action) = message.action {
+            assert!(matches!(*action, Action::CreateTopic(topic, 2, 3, _) if topic == "topic"));
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_refuse_forwarded_action_nested_in_another() {
+        let mut bytes = Vec::new();
+        write_u32(&mut bytes, 4 * 1024 * 1024);
+        bytes.resize(4 + 4 * 1024 * 1024, 22);
+        let message = ActionMessage::read_from(&mut &bytes[..]).unwrap();
+        assert!(matches!(message.action, Action::Invalid));
+
+        let message = ActionMessage::new(
+            Action::Forwarded(Box::new(Action::Forwarded(Box::new(Action::Shutdown)))),
+            String::new(),
+        );
+        let message = ActionMessage::parse(&message.as_vec()[..]);
+        assert!(matches!(message.action, Action::Invalid));
+    }
+
+    #[test]
+    fn should_convert_alter_configs_action() {
+        let config_list = vec![
+            (String::from("min.insync.replicas"), String::from("2")),
+            (String::from("other"), String::new()),
+        ];
+        let message = ActionMessage::new(
+            Action::AlterConfigs(String::from("topic"), config_list.clone()),
+            String::new(),
+        );
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::AlterConfigs(topic, parsed_list) = message.action {
+            assert_eq!(topic, "topic");
+            assert_eq!(parsed_list, config_list);
+        } else {
+            panic!("unexpected action");
+        }
+    }
+
+    #[test]
+    fn should_convert_shutdown_actions() {
+        let message = ActionMessage::new(Action::Shutdown, String::new());
+        let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+        assert!(matches!(parsed_message.action, Action::Shutdown));
+
+        let message = ActionMessage::new(Action::ControlledShutdown(2, 7), String::new());
+        let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+        assert!(matches!(
+            parsed_message.action,
+            Action::ControlledShutdown(2, 7)
+        ));
+    }
+
+    #[test]
+    fn should_convert_reassignment_actions_and_response() {
+        let topic = TopicAddress::new(String::from("topic"), 1);
+        let message = ActionMessage::new(
+            Action::AlterPartitionReassignment(topic.clone(), vec![2, 3]),
+            String::new(),
+        );
+        let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+        if let Action::AlterPartitionReassignment(parsed_topic, replicas) = parsed_message.action {
+            assert_eq!(parsed_topic, topic);
+            assert_eq!(replicas, vec![2, 3]);
+        } else {
+            panic!("unexpected action");
+        }
+
+        let message = ActionMessage::new(Action::Rebalance(true), String::new());
+        let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+        assert!(matches!(parsed_message.action, Action::Rebalance(true)));
+
+        let message = ActionMessage::new(
+            Action::CreatePartitions(String::from("topic"), 6),
+            String::new(),
+        );
+        let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+        assert!(
+            matches!(parsed_message.action, Action::CreatePartitions(topic, 6) if topic == "topic")
+        );
+
+        let message = ResponseMessage::new(Response::Reassignment(topic.clone(), vec![0]));
+        let parsed_message = ResponseMessage::parse(&message.as_vec()[..]).unwrap();
+        if let Response::Reassignment(parsed_topic, replicas) = &parsed_message[0].response {
+            assert_eq!(*parsed_topic, topic);
+            assert_eq!(*replicas, vec![0]);
+        } else {
+            panic!("unexpected response");
+        }
+    }
+
+    #[test]
+    fn should_convert_describe_configs_action_and_config_responses() {
+        let message = ActionMessage::new(
+            Action::DescribeConfigs(String::from("topic")),
+            String::new(),
+        );
+        let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+        assert!(
+            matches!(parsed_message.action, Action::DescribeConfigs(topic) if topic == "topic")
+        );
+
+        let mut bytes = Vec::new();
+        for response in [
+            Response::Config(String::from("retention.ms"), String::from("1000")),
+            Response::InvalidConfig(String::from("unknown config key a.b")),
+            Response::RecordTooLarge,
+            Response::InvalidTopic,
+        ] {
+            bytes.extend(ResponseMessage::new(response).as_vec());
+        }
+        let parsed_message = ResponseMessage::parse(&bytes[..]).unwrap();
+        assert!(matches!(
+            &parsed_message[0].response,
+            Response::Config(key, value) if key == "retention.ms" && value == "1000"
+        ));
+        assert!(matches!(
+            &parsed_message[1].response,
+            Response::InvalidConfig(reason) if reason == "unknown config key a.b"
+        ));
+        assert!(matches!(
+            parsed_message[2].response,
+            Response::RecordTooLarge
+        ));
+        assert!(matches!(parsed_message[3].response, Response::InvalidTopic));
+    }
+
+    #[test]
+    fn should_convert_topic_deletion_actions_and_response() {
+        let message = ActionMessage::new(Action::DeleteTopic(String::from("topic")), String::new());
+        let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+        assert!(matches!(parsed_message.action, Action::DeleteTopic(topic) if topic == "topic"));
+
+        let message = ActionMessage::new(Action::RemoveTopic(String::from("topic")), String::new());
+        let parsed_message = ActionMessage::parse(&message.as_vec()[..]);
+        assert!(matches!(parsed_message.action, Action::RemoveTopic(topic) if topic == "topic"));
+
+        let message = ResponseMessage::new(Response::UnknownTopic);
+        let parsed_message = ResponseMessage::parse(&message.as_vec()[..]).unwrap();
+        assert!(matches!(parsed_message[0].response, Response::UnknownTopic));
+    }
+
+    #[test]
+    fn should_convert_offset_for_leader_epoch_action_and_response() {
+        let message = ActionMessage::new(
+            Action::OffsetForLeaderEpoch(TopicAddress::new(String::from("topic"), 2), 5),
+            String::new(),
+        );
+
+        let parsed_message = message.as_vec();
+        let message = ActionMessage::parse(&parsed_message[..]);
+
+        if let Action::OffsetForLeaderEpoch(topic, leader_epoch) = message.action {
+            assert_eq!(topic, TopicAddress::new(String::from("topic"), 2));
+            assert_eq!(leader_epoch, 5);
+        } else {
+            panic!("unexpected action");
         }
+
+        let message = ResponseMessage::new(Response::EpochEndOffset(4, OffsetValue(12)));
+        let parsed_message = message.as_vec();
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
+        assert!(matches!(
+            message[0].response,
+            Response::EpochEndOffset(4, OffsetValue(12))
+        ));
+    }
+
+    #[test]
+    fn should_convert_not_enough_replicas_response() {
+        let message = ResponseMessage::new(Response::NotEnoughReplicas);
+
+        let parsed_message = message.as_vec();
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
+        let message = message.first().unwrap();
+
+        assert!(matches!(message.response, Response::NotEnoughReplicas));
+    }
+
+    #[test]
+    fn should_convert_broker_epoch_responses() {
+        let message = ResponseMessage::new(Response::BrokerId(2, 9));
+        let parsed_message = message.as_vec();
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
+        assert!(matches!(message[0].response, Response::BrokerId(2, 9)));
+
+        let message = ResponseMessage::new(Response::StaleBrokerEpoch);
+        let parsed_message = message.as_vec();
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
+        assert!(matches!(message[0].response, Response::StaleBrokerEpoch));
     }
 
     #[test]
@@ -457,7 +1997,7 @@ mod tests {
         let message = ResponseMessage::new(Response::Empty);
 
         let parsed_message = message.as_vec();
-        let message = ResponseMessage::parse(&parsed_message[..]);
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
         let message = message.first().unwrap();
 
         assert!(matches!(message.response, Response::Empty));
@@ -468,7 +2008,7 @@ mod tests {
         let message = ResponseMessage::new(Response::Error);
 
         let parsed_message = message.as_vec();
-        let message = ResponseMessage::parse(&parsed_message[..]);
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
         let message = message.first().unwrap();
 
         assert!(matches!(message.response, Response::Error));
@@ -480,14 +2020,14 @@ mod tests {
         let message = ResponseMessage::new(Response::Content(OffsetValue(100), content));
 
         let parsed_message = message.as_vec();
-        let message = ResponseMessage::parse(&parsed_message[..]);
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
         let message = message.first().unwrap();
 
         if let Response::Content(offset, content) = &message.response {
             assert_eq!(offset.0, 100);
             assert_eq!(content.value, "nice content");
         } else {
-            assert!(false);
+            panic!("unexpected response");
         }
     }
 
@@ -496,13 +2036,13 @@ mod tests {
         let message = ResponseMessage::new(Response::Offset(OffsetValue(100)));
 
         let parsed_message = message.as_vec();
-        let message = ResponseMessage::parse(&parsed_message[..]);
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
         let message = message.first().unwrap();
 
         if let Response::Offset(value) = &message.response {
             assert_eq!(value.0, 100);
         } else {
-            assert!(false);
+            panic!("unexpected response");
         }
     }
 
@@ -512,14 +2052,139 @@ mod tests {
             ResponseMessage::new(Response::AskTheController(String::from("localhost:8080")));
 
         let parsed_message = message.as_vec();
-        let message = ResponseMessage::parse(&parsed_message[..]);
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
         let message = message.first().unwrap();
 
         if let Response::AskTheController(value) = &message.response {
             assert_eq!(value, "localhost:8080");
         } else {
-            assert!(false);
+            panic!("unexpected response");
+        }
+    }
+
+    #[test]
+    fn should_convert_topic_metadata_response() {
+        let message = ResponseMessage::new(Response::TopicMetadata(12));
+
+        let parsed_message = message.as_vec();
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
+        let message = message.first().unwrap();
+
+        if let Response::TopicMetadata(partition_count) = &message.response {
+            assert_eq!(*partition_count, 12);
+        } else {
+            panic!("unexpected response");
+        }
+    }
+
+    #[test]
+    fn should_read_framed_response_list_larger_than_a_read_buffer() {
+        let response_list: Vec<ResponseMessage> = (0..100)
+            .map(|offset| {
+                ResponseMessage::new(Response::Content(
+                    OffsetValue(offset),
+                    Content::new(format!("content number {}", offset)),
+                ))
+            })
+            .collect();
+
+        let mut bytes = Vec::new();
+        ResponseMessage::write_list_to(&mut bytes, &response_list).unwrap();
+        let message_list = ResponseMessage::read_list_from(&mut &bytes[..]).unwrap();
+
+        assert_eq!(message_list.len(), 100);
+        if let Response::Content(offset, content) = &message_list.last().unwrap().response {
+            assert_eq!(offset.0, 99);
+            assert_eq!(content.value, "content number 99");
+        } else {
+            panic!("unexpected response");
+        }
+    }
+
+    #[test]
+    fn should_convert_producer_id_response() {
+        let message = ResponseMessage::new(Response::ProducerId(9));
+
+        let parsed_message = message.as_vec();
+        let message = ResponseMessage::parse(&parsed_message[..]).unwrap();
+        let message = message.first().unwrap();
+
+        if let Response::ProducerId(producer_id) = &message.response {
+            assert_eq!(*producer_id, 9);
+        } else {
+            panic!("unexpected response");
+        }
+    }
+
+    #[test]
+    fn should_convert_record_batch_and_high_watermark_responses() {
+        let mut batch = RecordBatch::new(
+            OffsetValue(7),
+            BatchKind::Data,
+            Some(BatchHeader::transactional(3, 10)),
+            vec![
+                Content::new(String::from("first")),
+                Content::new(String::from("second")),
+            ],
+        );
+        batch.leader_epoch = 4;
+
+        let mut bytes = ResponseMessage::new(Response::HighWatermark(OffsetValue(5))).as_vec();
+        bytes.extend(ResponseMessage::new(Response::RecordBatch(batch)).as_vec());
+        let message_list = ResponseMessage::parse(&bytes[..]).unwrap();
+
+        assert_eq!(message_list.len(), 2);
+        assert!(matches!(
+            message_list[0].response,
+            Response::HighWatermark(OffsetValue(5))
+        ));
+        if let Response::RecordBatch(batch) = &message_list[1].response {
+            assert_eq!(batch.first_offset.0, 7);
+            assert_eq!(batch.kind, BatchKind::Data);
+            assert_eq!(batch.leader_epoch, 4);
+            assert_eq!(batch.header, Some(BatchHeader::transactional(3, 10)));
+            assert_eq!(batch.content_list.len(), 2);
+            assert_eq!(batch.content_list[1].value, "second");
+        } else {
+            panic!("unexpected response");
+        }
+    }
+
+    #[test]
+    fn should_convert_topic_metadata_with_partitions_and_brokers() {
+        let state = PartitionState {
+            topic: TopicAddress::new(String::from("topic"), 0),
+            leader: 2,
+            leader_epoch: 1,
+            replicas: vec![0, 2],
+            isr: vec![2],
+            adding_replicas: Vec::new(),
+            removing_replicas: Vec::new(),
+        };
+        let response_list = vec![
+            ResponseMessage::new(Response::TopicMetadata(1)),
+            ResponseMessage::new(Response::BrokerAddress(2, String::from("localhost:8082"))),
+            ResponseMessage::new(Response::PartitionMetadata(state.clone())),
+            ResponseMessage::new(Response::NotLeader),
+        ];
+
+        let mut bytes = Vec::new();
+        ResponseMessage::write_list_to(&mut bytes, &response_list).unwrap();
+        let message_list = ResponseMessage::read_list_from(&mut &bytes[..]).unwrap();
+
+        assert_eq!(message_list.len(), 4);
+        if let Response::BrokerAddress(broker_id, address) = &message_list[1].response {
+            assert_eq!(*broker_id, 2);
+            assert_eq!(address, "localhost:8082");
+        } else {
+            panic!("unexpected response");
+        }
+        if let Response::PartitionMetadata(parsed_state) = &message_list[2].response {
+            assert_eq!(parsed_state, &state);
+        } else {
+            panic!("unexpected response");
         }
+        assert!(matches!(message_list[3].response, Response::NotLeader));
     }
 
     #[test]
@@ -544,23 +2209,23 @@ mod tests {
             .as_vec(),
         );
 
-        let message_list = ResponseMessage::parse(&bytes[..]);
+        let message_list = ResponseMessage::parse(&bytes[..]).unwrap();
 
         assert_eq!(message_list.len(), 3);
 
-        let message = message_list.get(0).unwrap();
+        let message = message_list.first().unwrap();
         if let Response::Content(offset, content) = &message.response {
             assert_eq!(offset.0, 100);
             assert_eq!(content.value, "nice content");
         } else {
-            assert!(false);
+            panic!("unexpected response");
         }
 
         let message = message_list.get(1).unwrap();
         if let Response::Offset(offset) = &message.response {
             assert_eq!(offset.0, 101);
         } else {
-            assert!(false);
+            panic!("unexpected response");
         }
 
         let message = message_list.get(2).unwrap();
@@ -568,7 +2233,7 @@ mod tests {
             assert_eq!(offset.0, 102);
             assert_eq!(content.value, "last content");
         } else {
-            assert!(false);
+            panic!("unexpected response");
         }
     }
 
@@ -602,7 +2267,7 @@ mod tests {
             .as_vec(),
         );
 
-        let message_list = ResponseMessage::parse(&bytes[..]);
+        let message_list = ResponseMessage::parse(&bytes[..]).unwrap();
 
         assert_eq!(message_list.len(), 4);
 
@@ -611,7 +2276,7 @@ mod tests {
             assert_eq!(offset.0, 100);
             assert_eq!(content.value, "nice content");
         } else {
-            assert!(false);
+            panic!("unexpected response");
         }
 
         let message = message_list.get(1).unwrap();
@@ -619,7 +2284,7 @@ mod tests {
             assert_eq!(offset.0, 101);
             assert_eq!(content.value, "other content");
         } else {
-            assert!(false);
+            panic!("unexpected response");
         }
 
         let message = message_list.get(2).unwrap();
@@ -630,7 +2295,7 @@ mod tests {
             assert_eq!(offset.0, 102);
             assert_eq!(content.value, "last content");
         } else {
-            assert!(false);
+            panic!("unexpected response");
         }
     }
 }
@@ -0,0 +1,210 @@
+use std::io;
+
+// Matches are at least as long as the 4 bytes hashed to find them.
+const MIN_MATCH: usize = 4;
+// The format wants the last match to start at least 12 bytes before the end
+// of the input, and the last 5 bytes to be literals.
+const MATCH_FIND_LIMIT: usize = 12;
+const LAST_LITERALS: usize = 5;
+const MAX_DISTANCE: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+/// Compresses `input` into an LZ4 block. The block doesn't hold the size of
+/// `input`, which `decompress` needs.
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    // Last position + 1 of every hashed 4 bytes, 0 when none yet.
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut position = 0;
+
+    if input.len() > MATCH_FIND_LIMIT {
+        let match_limit = input.len() - MATCH_FIND_LIMIT;
+        let end_limit = input.len() - LAST_LITERALS;
+        while position < match_limit {
+            let sequence = read_u32(input, position);
+            let slot = hash(sequence);
+            let candidate = table[slot].checked_sub(1);
+            table[slot] = position + 1;
+
+            let candidate = match candidate {
+                Some(candidate)
+                    if position - candidate <= MAX_DISTANCE
+                        && read_u32(input, candidate) == sequence =>
+                {
+                    candidate
+                }
+                _ => {
+                    position += 1;
+                    continue;
+                }
+            };
+
+            let mut length = MIN_MATCH;
+            while position + length < end_limit
+                && input[candidate + length] == input[position + length]
+            {
+                length += 1;
+            }
+            write_sequence(
+                &mut output,
+                &input[anchor..position],
+                Some((position - candidate, length)),
+            );
+            position += length;
+            anchor = position;
+        }
+    }
+
+    write_sequence(&mut output, &input[anchor..], None);
+    output
+}
+
+/// Decompresses an LZ4 block back into the `size` bytes it was made from.
+pub(crate) fn decompress(input: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    // A corrupt size mustn't take all memory, and no block grows more than 255 times.
+    let mut output = Vec::with_capacity(size.min(input.len().saturating_mul(255)));
+    let mut position = 0;
+
+    loop {
+        let token = *input.get(position).ok_or_else(corrupt)?;
+        position += 1;
+
+        let mut literal_length = (token >> 4) as usize;
+        if literal_length == 15 {
+            literal_length += read_length(input, &mut position)?;
+        }
+        let literals = position
+            .checked_add(literal_length)
+            .and_then(|end| input.get(position..end))
+            .filter(|literals| output.len() + literals.len() <= size)
+            .ok_or_else(corrupt)?;
+        output.extend_from_slice(literals);
+        position += literal_length;
+        if position == input.len() {
+            break;
+        }
+
+        let distance = input
+            .get(position..position + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+            .filter(|distance| (1..=output.len()).contains(distance))
+            .ok_or_else(corrupt)?;
+        position += 2;
+        let mut match_length = (token & 15) as usize + MIN_MATCH;
+        if token & 15 == 15 {
+            match_length += read_length(input, &mut position)?;
+        }
+        if output.len() + match_length > size {
+            return Err(corrupt());
+        }
+
+        // Byte by byte, since a match may overlap the bytes it produces.
+        let start = output.len() - distance;
+        for index in start..start + match_length {
+            output.push(output[index]);
+        }
+    }
+
+    if output.len() != size {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt LZ4 block")
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn read_u32(input: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes([
+        input[position],
+        input[position + 1],
+        input[position + 2],
+        input[position + 3],
+    ])
+}
+
+// Lengths of 15 and more carry on in bytes of 255 and a last smaller one.
+fn read_length(input: &[u8], position: &mut usize) -> io::Result<usize> {
+    let mut length = 0;
+    loop {
+        let byte = *input.get(*position).ok_or_else(corrupt)?;
+        *position += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+/// Literals followed by a match, given as its distance back and length,
+/// except for the last sequence of a block.
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_length = found.map_or(0, |(_, length)| length - MIN_MATCH);
+    output.push(((literals.len().min(15) as u8) << 4) | match_length.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+
+    if let Some((distance, _)) = found {
+        output.extend_from_slice(&(distance as u16).to_le_bytes());
+        if match_length >= 15 {
+            write_length(output, match_length - 15);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        decompress(&compressed, input.len()).unwrap()
+    }
+
+    #[test]
+    fn should_restore_compressed_bytes() {
+        let repeated = "record value ".repeat(200).into_bytes();
+        let mixed: Vec<u8> = (0..5000u32).map(|n| (n * 7 % 251) as u8).collect();
+        let long_literals: Vec<u8> = (0..300u32).map(|n| (n * 131 % 256) as u8).collect();
+
+        for input in [
+            &b""[..],
+            &b"short"[..],
+            &b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"[..],
+            &repeated[..],
+            &mixed[..],
+            &long_literals[..],
+        ] {
+            assert_eq!(round_trip(input), input);
+        }
+        assert!(compress(&repeated).len() < repeated.len() / 10);
+    }
+
+    #[test]
+    fn should_refuse_corrupt_blocks() {
+        let input = "record value ".repeat(20).into_bytes();
+        let compressed = compress(&input);
+
+        assert!(decompress(&compressed, input.len() + 1).is_err());
+        assert!(decompress(&compressed[..compressed.len() - 1], input.len()).is_err());
+        // A match reaching back before the start of the block.
+        assert!(decompress(&[0x10, b'a', 9, 0], 10).is_err());
+        assert!(decompress(&[], 0).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Topic config key: how many replicas, the leader included, must be in sync
+/// for an `Acks::All` produce to be acknowledged. 1 when unset.
+pub const MIN_INSYNC_REPLICAS: &str = "min.insync.replicas";
+/// Topic config key: the most bytes of record values a produce may carry.
+pub const MAX_MESSAGE_BYTES: &str = "max.message.bytes";
+/// The largest `max.message.bytes` a topic can be given, which also bounds
+/// the requests brokers read.
+pub const MAX_MESSAGE_BYTES_LIMIT: u32 = 16 * 1024 * 1024;
+/// Topic config key: how long records are kept with the `delete` cleanup
+/// policy, in milliseconds. -1 keeps them forever.
+pub const RETENTION_MS: &str = "retention.ms";
+/// Topic config key: `delete` or `compact`, see `CleanupPolicy`.
+pub const CLEANUP_POLICY: &str = "cleanup.policy";
+/// Topic config key: the codec records are stored with, see `CompressionType`.
+pub const COMPRESSION_TYPE: &str = "compression.type";
+
+/// Every topic config key with the value it has when unset, ordered by key.
+pub const TOPIC_CONFIG_DEFAULTS: [(&str, &str); 5] = [
+    (CLEANUP_POLICY, "delete"),
+    (COMPRESSION_TYPE, "producer"),
+    (MAX_MESSAGE_BYTES, "1048576"),
+    (MIN_INSYNC_REPLICAS, "1"),
+    (RETENTION_MS, "604800000"),
+];
+
+/// Checks that `key` is a known topic config and `value` suits it, telling
+/// what is wrong otherwise.
+pub(crate) fn validate_topic_config(key: &str, value: &str) -> Result<(), String> {
+    let valid = match key {
+        MIN_INSYNC_REPLICAS => value.parse::<u32>().is_ok_and(|value| value >= 1),
+        MAX_MESSAGE_BYTES => value
+            .parse::<u32>()
+            .is_ok_and(|value| (1..=MAX_MESSAGE_BYTES_LIMIT).contains(&value)),
+        RETENTION_MS => value.parse::<i64>().is_ok_and(|value| value >= -1),
+        CLEANUP_POLICY => value.parse::<CleanupPolicy>().is_ok(),
+        COMPRESSION_TYPE => value.parse::<CompressionType>().is_ok(),
+        _ => return Err(format!("unknown config key {}", key)),
+    };
+    if !valid {
+        return Err(format!("invalid value '{}' for {}", value, key));
+    }
+    Ok(())
+}
+
+/// Checks every key and value of `config_list`, see `validate_topic_config`.
+pub(crate) fn validate_topic_configs(config_list: &[(String, String)]) -> Result<(), String> {
+    config_list
+        .iter()
+        .try_for_each(|(key, value)| validate_topic_config(key, value))
+}
+
+/// The value a topic config key has when unset.
+pub(crate) fn default_topic_config(key: &str) -> Option<&'static str> {
+    TOPIC_CONFIG_DEFAULTS
+        .iter()
+        .find(|(default_key, _)| *default_key == key)
+        .map(|(_, value)| *value)
+}
+
+/// What a partition does with records it no longer needs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CleanupPolicy {
+    /// Deletes the oldest batches once they are older than `retention.ms`.
+    #[default]
+    Delete,
+    /// Keeps only the latest record of every key.
+    Compact,
+}
+
+impl FromStr for CleanupPolicy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<CleanupPolicy, ()> {
+        match value {
+            "delete" => Ok(CleanupPolicy::Delete),
+            "compact" => Ok(CleanupPolicy::Compact),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The codec batches are stored on disk with. Producers send records
+/// uncompressed, so `producer` stores them as they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionType {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl FromStr for CompressionType {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<CompressionType, ()> {
+        match value {
+            "none" | "producer" => Ok(CompressionType::None),
+            "lz4" => Ok(CompressionType::Lz4),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How a partition log is cleaned and stored, taken from the configs of its topic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    /// How long the `delete` policy keeps records, forever when unset.
+    pub retention: Option<Duration>,
+    pub cleanup_policy: CleanupPolicy,
+    pub compression: CompressionType,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            retention: Some(Duration::from_millis(604_800_000)),
+            cleanup_policy: CleanupPolicy::Delete,
+            compression: CompressionType::None,
+        }
+    }
+}
+
+/// How the controller decides, from their heartbeats, that brokers died.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DetectorKind {
+    /// See `PhiAccrualDetector`, tuned with `phi_threshold`.
+    PhiAccrual,
+    /// See `EventuallyPerfectDetector`, starting from `session_timeout`.
+    EventuallyPerfect,
+}
+
+/// Settings a `Broker` is created with.
+#[derive(Clone)]
+pub struct BrokerConfig {
+    /// Where partitions and producer state are stored. Kept in memory when unset.
+    pub data_dir: Option<PathBuf>,
+    /// How long a follower may go without catching up before it's dropped from the ISR.
+    pub replica_lag_time: Duration,
+    /// How long a follower waits before fetching again when the leader had nothing new.
+    pub replica_fetch_interval: Duration,
+    pub replica_fetch_max_records: u32,
+    /// How long an `Acks::All` produce waits for the in-sync replicas.
+    pub replication_timeout: Duration,
+    /// Id to register with the controller. When unset, the one stored under
+    /// `data_dir` is used, or the controller hands out a new one.
+    pub broker_id: Option<u32>,
+    /// Address registered with the controller, the one the server is bound to when unset.
+    pub advertised_address: Option<String>,
+    /// Rack or zone the broker runs in. The controller puts the replicas of
+    /// a partition in different racks when there are enough of them.
+    pub rack: Option<String>,
+    /// Brokers of a running cluster to register with on startup. When empty,
+    /// the broker waits for `Action::InitializeController` instead.
+    pub bootstrap_brokers: Vec<String>,
+    /// Whether admin requests reaching a broker that isn't the controller are
+    /// passed on to it. When off, the client is told where the controller is.
+    pub forward_to_controller: bool,
+    /// How the controller tells that a broker died.
+    pub failure_detector: DetectorKind,
+    /// How often a broker sends the controller a heartbeat.
+    pub heartbeat_interval: Duration,
+    /// Suspicion level at which the phi accrual detector takes a broker as
+    /// dead. Higher values take longer to notice but are wrong less often.
+    pub phi_threshold: f64,
+    /// How long the eventually perfect detector waits for a heartbeat at first.
+    /// It waits a second longer each time it suspected a broker that was alive.
+    pub session_timeout: Duration,
+    /// The first brokers of the cluster, up to this many, elect the controller among them.
+    pub controller_quorum_size: usize,
+    /// Unit of time of the controller election: a leader is suspected after 10 to 20 ticks.
+    pub controller_tick: Duration,
+    /// How long the controller waits for the quorum to store a metadata change.
+    pub metadata_timeout: Duration,
+    /// Seeds the random election timeouts of the controller quorum, so
+    /// simulated runs can be replayed. Taken from the current time when unset.
+    pub controller_seed: Option<u64>,
+    /// How long connecting to another broker may take.
+    pub peer_connect_timeout: Duration,
+    /// How long another broker may take to answer a request.
+    pub peer_request_timeout: Duration,
+    /// Wait before connecting again to a broker that couldn't be reached,
+    /// doubled after every further failure up to `peer_reconnect_backoff_max`.
+    pub peer_reconnect_backoff: Duration,
+    pub peer_reconnect_backoff_max: Duration,
+    /// How long a broker shutting down waits for the requests it is handling to finish.
+    pub controlled_shutdown_timeout: Duration,
+    /// Records per second a broker copies for the replicas a reassignment
+    /// adds to it, so moving partitions doesn't starve other traffic.
+    /// Unlimited when unset.
+    pub reassignment_throttle: Option<u32>,
+    /// Client and broker connections served at once, each by a thread of
+    /// its own. Further ones are closed right away.
+    pub max_connections: usize,
+    /// Configs topics get when created without them, see `TOPIC_CONFIG_DEFAULTS`.
+    pub topic_defaults: Vec<(String, String)>,
+    /// How often partition logs are cleaned up following the `cleanup.policy`
+    /// of their topic.
+    pub log_cleanup_interval: Duration,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> BrokerConfig {
+        BrokerConfig {
+            data_dir: None,
+            broker_id: None,
+            advertised_address: None,
+            rack: None,
+            bootstrap_brokers: Vec::new(),
+            forward_to_controller: true,
+            failure_detector: DetectorKind::PhiAccrual,
+            heartbeat_interval: Duration::from_secs(1),
+            phi_threshold: 8.0,
+            session_timeout: Duration::from_secs(3),
+            replica_lag_time: Duration::from_secs(10),
+            replica_fetch_interval: Duration::from_millis(50),
+            replica_fetch_max_records: 500,
+            replication_timeout: Duration::from_secs(10),
+            controller_quorum_size: 3,
+            controller_tick: Duration::from_millis(50),
+            metadata_timeout: Duration::from_secs(5),
+            controller_seed: None,
+            peer_connect_timeout: Duration::from_secs(1),
+            peer_request_timeout: Duration::from_secs(10),
+            peer_reconnect_backoff: Duration::from_millis(100),
+            peer_reconnect_backoff_max: Duration::from_secs(2),
+            controlled_shutdown_timeout: Duration::from_secs(30),
+            reassignment_throttle: None,
+            max_connections: 1024,
+            topic_defaults: Vec::new(),
+            log_cleanup_interval: Duration::from_secs(300),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::communication::{write_persistent_state, Buffer};
+use crate::metadata::{ClusterMetadata, MetadataRecord};
+use crate::raft::{PersistentState, RaftMessage, RaftNode};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const STATE_FILE: &str = "controller.state";
+
+/// Raft messages to send, with the id of the member each one goes to.
+pub type Outbox = Vec<(u32, RaftMessage)>;
+
+/// A broker's seat in the controller quorum. Drives its Raft node, keeps the
+/// node's state on disk and wakes up callers waiting for a record to commit.
+pub struct Controller {
+    node: Mutex<RaftNode>,
+    committed: Condvar,
+    path: Option<PathBuf>,
+}
+
+impl Controller {
+    /// Picks up the term, vote and metadata log stored under `data_dir`, if any.
+    /// Election timeouts are drawn from `seed`, or from the current time.
+    pub fn new(
+        id: u32,
+        voters: Vec<u32>,
+        data_dir: Option<&Path>,
+        seed: Option<u64>,
+    ) -> io::Result<Controller> {
+        let path = data_dir.map(|data_dir| data_dir.join(STATE_FILE));
+        let state = match &path {
+            Some(path) if path.exists() => Buffer::new(&fs::read(path)?).read_persistent_state()?,
+            _ => PersistentState::default(),
+        };
+
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_nanos() as u64)
+                .unwrap_or(0)
+        }) ^ id as u64;
+        Ok(Controller {
+            node: Mutex::new(RaftNode::restore(id, voters, seed, state)),
+            committed: Condvar::new(),
+            path,
+        })
+    }
+
+    /// Whether this broker leads the quorum and already committed an entry
+    /// of its own term, so its metadata has everything earlier leaders did.
+    pub fn is_leader(&self) -> bool {
+        let node = self.node.lock().unwrap();
+        node.is_leader() && node.term_at(node.commit_index()) == Some(node.term())
+    }
+
+    pub fn term(&self) -> u32 {
+        self.node.lock().unwrap().term()
+    }
+
+    pub fn leader(&self) -> Option<u32> {
+        self.node.lock().unwrap().leader()
+    }
+
+    pub fn voters(&self) -> Vec<u32> {
+        self.node.lock().unwrap().voters().to_vec()
+    }
+
+    pub fn metadata(&self) -> ClusterMetadata {
+        self.node.lock().unwrap().metadata().clone()
+    }
+
+    /// Lets a tick of `BrokerConfig::controller_tick` pass. Like `step` and
+    /// `campaign`, returns the messages to send to the other members.
+    pub fn tick(&self) -> Outbox {
+        self.update(|node| node.tick())
+    }
+
+    pub fn step(&self, from: u32, message: RaftMessage) -> Outbox {
+        self.update(|node| node.step(from, message))
+    }
+
+    pub fn campaign(&self) -> Outbox {
+        self.update(|node| node.campaign())
+    }
+
+    /// Returns the index and term `record` got, see `wait_for_commit`, or
+    /// `None` when this broker doesn't lead the quorum.
+    pub fn propose(&self, record: MetadataRecord) -> Option<((u32, u32), Outbox)> {
+        let mut proposal = None;
+        let messages = self.update(|node| proposal = node.propose(record));
+        proposal.map(|proposal| (proposal, messages))
+    }
+
+    /// Waits until the entry proposed at `index` in `term` is committed.
+    /// Gives up as soon as this broker stops leading that term.
+    pub fn wait_for_commit(&self, index: u32, term: u32, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut node = self.node.lock().unwrap();
+        loop {
+            if node.term() != term || !node.is_leader() {
+                return false;
+            }
+            if node.commit_index() >= index {
+                return true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            node = self.committed.wait_timeout(node, deadline - now).unwrap().0;
+        }
+    }
+
+    fn update(&self, change: impl FnOnce(&mut RaftNode)) -> Outbox {
+        let mut node = self.node.lock().unwrap();
+        change(&mut node);
+        self.committed.notify_all();
+
+        // Votes and entries must not be given away before they are stored.
+        if node.take_changed() {
+            if let Err(err) = self.store(&node.persistent_state()) {
+                println!("Failed to store controller state\n{}", err);
+                node.take_messages();
+                return Vec::new();
+            }
+        }
+        node.take_messages()
+    }
+
+    fn store(&self, state: &PersistentState) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut bytes = Vec::new();
+        write_persistent_state(&mut bytes, state);
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, bytes)?;
+        fs::rename(temporary_path, path)
+    }
+}
@@ -1,5 +1,10 @@
+// Partition logs are named after their topic, so names must fit in a file name.
+const MAX_TOPIC_NAME_LENGTH: usize = 249;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct OffsetValue(pub u32);
 
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TopicAddress {
     pub name: String,
     pub partition: u32,
@@ -9,15 +14,278 @@ impl TopicAddress {
     pub fn new(name: String, partition: u32) -> TopicAddress {
         TopicAddress { name, partition }
     }
+
+    /// Topic names are made of ASCII letters, digits, `.`, `_` and `-`, and
+    /// can't be `.` or `..`, so partition logs stay inside the data directory.
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= MAX_TOPIC_NAME_LENGTH
+            && name != "."
+            && name != ".."
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+    }
+}
+
+/// How much of the write a produce request waits for before the broker answers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acks {
+    /// Fire-and-forget: the broker doesn't answer at all.
+    None,
+    /// Answer once the partition leader appended the records.
+    Leader,
+    /// Answer once every in-sync replica has the records.
+    All,
+}
+
+impl Acks {
+    pub fn from_u8(value: u8) -> Acks {
+        match value {
+            0 => Acks::None,
+            2 => Acks::All,
+            _ => Acks::Leader,
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Acks::None => 0,
+            Acks::Leader => 1,
+            Acks::All => 2,
+        }
+    }
+}
+
+/// Which records a consumer gets to see from partitions written by transactions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Isolation {
+    ReadUncommitted,
+    /// Hides records of aborted transactions and stops before the first
+    /// record of a transaction that is still open.
+    ReadCommitted,
+}
+
+impl Isolation {
+    pub fn from_u8(value: u8) -> Isolation {
+        match value {
+            1 => Isolation::ReadCommitted,
+            _ => Isolation::ReadUncommitted,
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Isolation::ReadUncommitted => 0,
+            Isolation::ReadCommitted => 1,
+        }
+    }
+}
+
+/// Identifies a produced batch so the broker can drop it when a producer
+/// retries a send that was already appended. `sequence` numbers the first
+/// record of the batch, counting per producer and partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchHeader {
+    pub producer_id: u32,
+    pub sequence: u32,
+    pub transactional: bool,
+}
+
+impl BatchHeader {
+    pub fn new(producer_id: u32, sequence: u32) -> BatchHeader {
+        BatchHeader {
+            producer_id,
+            sequence,
+            transactional: false,
+        }
+    }
+
+    pub fn transactional(producer_id: u32, sequence: u32) -> BatchHeader {
+        BatchHeader {
+            producer_id,
+            sequence,
+            transactional: true,
+        }
+    }
+}
+
+/// What a batch stored in a partition holds: records, or the marker that
+/// commits or aborts a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchKind {
+    Data,
+    Commit,
+    Abort,
+}
+
+impl BatchKind {
+    pub fn from_u8(value: u8) -> BatchKind {
+        match value {
+            1 => BatchKind::Commit,
+            2 => BatchKind::Abort,
+            _ => BatchKind::Data,
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            BatchKind::Data => 0,
+            BatchKind::Commit => 1,
+            BatchKind::Abort => 2,
+        }
+    }
 }
 
 #[derive(Default, Clone)]
 pub struct Content {
     pub value: String,
+    /// Compacted topics keep only the last record of every key.
+    pub key: Option<String>,
 }
 
 impl Content {
     pub fn new(value: String) -> Content {
-        Content { value }
+        Content { value, key: None }
+    }
+
+    pub fn with_key(key: String, value: String) -> Content {
+        Content {
+            value,
+            key: Some(key),
+        }
+    }
+}
+
+/// A record read by a `Consumer`, together with where it came from.
+#[derive(Clone)]
+pub struct ConsumerRecord {
+    pub topic: TopicAddress,
+    pub offset: OffsetValue,
+    pub content: Content,
+}
+
+impl ConsumerRecord {
+    pub fn new(topic: TopicAddress, offset: OffsetValue, content: Content) -> ConsumerRecord {
+        ConsumerRecord {
+            topic,
+            offset,
+            content,
+        }
+    }
+}
+
+/// A record handed to a `Producer`. The partition is chosen by the producer's
+/// partitioner unless it is set explicitly.
+#[derive(Clone)]
+pub struct ProducerRecord {
+    pub topic: String,
+    pub partition: Option<u32>,
+    pub key: Option<String>,
+    pub content: Content,
+}
+
+impl ProducerRecord {
+    pub fn new(topic: String, content: Content) -> ProducerRecord {
+        ProducerRecord {
+            topic,
+            partition: None,
+            key: None,
+            content,
+        }
+    }
+
+    pub fn with_key(topic: String, key: String, content: Content) -> ProducerRecord {
+        ProducerRecord {
+            topic,
+            partition: None,
+            key: Some(key),
+            content,
+        }
+    }
+
+    pub fn with_partition(topic: String, partition: u32, content: Content) -> ProducerRecord {
+        ProducerRecord {
+            topic,
+            partition: Some(partition),
+            key: None,
+            content,
+        }
+    }
+}
+
+/// Records appended together, as they sit in a partition log. Followers copy
+/// whole batches so producer sequences and transactions survive on them.
+#[derive(Clone)]
+pub struct RecordBatch {
+    pub first_offset: OffsetValue,
+    pub kind: BatchKind,
+    pub header: Option<BatchHeader>,
+    pub content_list: Vec<Content>,
+    /// Epoch of the leader that took the records, 0 until the partition is
+    /// appended to.
+    pub leader_epoch: u32,
+    /// When the leader took the records, in milliseconds since the UNIX
+    /// epoch. Retention deletes batches once this is old enough.
+    pub timestamp: u64,
+    /// Positions in `content_list` of the records compaction removed. They
+    /// keep their offsets but are no longer read.
+    pub compacted: Vec<u32>,
+}
+
+impl RecordBatch {
+    pub fn new(
+        first_offset: OffsetValue,
+        kind: BatchKind,
+        header: Option<BatchHeader>,
+        content_list: Vec<Content>,
+    ) -> RecordBatch {
+        RecordBatch {
+            first_offset,
+            kind,
+            header,
+            content_list,
+            leader_epoch: 0,
+            timestamp: 0,
+            compacted: Vec::new(),
+        }
+    }
+
+    /// Offset the record after this batch gets.
+    pub fn next_offset(&self) -> OffsetValue {
+        OffsetValue(self.first_offset.0 + self.content_list.len() as u32)
+    }
+}
+
+/// Which brokers hold a partition, which of them leads it and which are in
+/// sync with the leader. `leader_epoch` grows every time the leader changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionState {
+    pub topic: TopicAddress,
+    pub leader: u32,
+    pub leader_epoch: u32,
+    pub replicas: Vec<u32>,
+    pub isr: Vec<u32>,
+    /// Replicas a reassignment is adding and removing. `replicas` holds
+    /// both until it completes.
+    pub adding_replicas: Vec<u32>,
+    pub removing_replicas: Vec<u32>,
+}
+
+impl PartitionState {
+    pub fn new(topic: TopicAddress, leader: u32, replicas: Vec<u32>) -> PartitionState {
+        PartitionState {
+            topic,
+            leader,
+            leader_epoch: 0,
+            isr: replicas.clone(),
+            replicas,
+            adding_replicas: Vec::new(),
+            removing_replicas: Vec::new(),
+        }
+    }
+
+    pub fn is_reassigning(&self) -> bool {
+        !self.adding_replicas.is_empty() || !self.removing_replicas.is_empty()
     }
 }
@@ -0,0 +1,335 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Intervals kept per peer to estimate when its next heartbeat is due.
+const MAX_SAMPLES: usize = 200;
+/// Brokers send a heartbeat every second, which is assumed until they did.
+const FIRST_HEARTBEAT_ESTIMATE: Duration = Duration::from_secs(1);
+/// Keeps a peer with very regular heartbeats from being suspected on the
+/// slightest delay.
+const MIN_STD_DEVIATION: Duration = Duration::from_millis(200);
+/// Added to the expected interval, so a peer may skip about one heartbeat.
+const ACCEPTABLE_PAUSE: Duration = Duration::from_secs(1);
+
+/// Where failure detectors read the time from, so tests can move it by hand.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Decides from the heartbeats of each peer whether it is still alive.
+pub trait HeartbeatDetector: Send {
+    /// Starts watching `peer`, or starts over, giving it as long as it
+    /// would have right after a heartbeat.
+    fn watch(&mut self, peer: u32);
+
+    fn is_watching(&self, peer: u32) -> bool;
+
+    fn heartbeat(&mut self, peer: u32);
+
+    /// Whether a watched peer should be taken as dead.
+    fn is_suspected(&self, peer: u32) -> bool;
+
+    fn forget(&mut self, peer: u32);
+}
+
+/// Suspects a peer once it misses its timeout. Each time a suspected peer
+/// turns out to be alive its timeout grows, so wrong suspicions eventually stop.
+pub struct EventuallyPerfectDetector {
+    clock: Arc<dyn Clock>,
+    initial_timeout: Duration,
+    increment: Duration,
+    // Last heartbeat and timeout of each peer.
+    peers: HashMap<u32, (Instant, Duration)>,
+}
+
+impl EventuallyPerfectDetector {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        initial_timeout: Duration,
+        increment: Duration,
+    ) -> EventuallyPerfectDetector {
+        EventuallyPerfectDetector {
+            clock,
+            initial_timeout,
+            increment,
+            peers: HashMap::new(),
+        }
+    }
+}
+
+impl HeartbeatDetector for EventuallyPerfectDetector {
+    fn watch(&mut self, peer: u32) {
+        let now = self.clock.now();
+        let initial_timeout = self.initial_timeout;
+        self.peers
+            .entry(peer)
+            .and_modify(|(last_heartbeat, _)| *last_heartbeat = now)
+            .or_insert((now, initial_timeout));
+    }
+
+    fn is_watching(&self, peer: u32) -> bool {
+        self.peers.contains_key(&peer)
+    }
+
+    fn heartbeat(&mut self, peer: u32) {
+        let suspected = self.is_suspected(peer);
+        let now = self.clock.now();
+        let (last_heartbeat, timeout) = self
+            .peers
+            .entry(peer)
+            .or_insert((now, self.initial_timeout));
+        if suspected {
+            *timeout += self.increment;
+        }
+        *last_heartbeat = now;
+    }
+
+    fn is_suspected(&self, peer: u32) -> bool {
+        let now = self.clock.now();
+        self.peers
+            .get(&peer)
+            .is_some_and(|(last_heartbeat, timeout)| now.duration_since(*last_heartbeat) > *timeout)
+    }
+
+    fn forget(&mut self, peer: u32) {
+        self.peers.remove(&peer);
+    }
+}
+
+struct HeartbeatHistory {
+    last_heartbeat: Instant,
+    // Whether `last_heartbeat` is an actual heartbeat rather than the
+    // moment the peer started being watched.
+    received: bool,
+    intervals: VecDeque<f64>,
+}
+
+impl HeartbeatHistory {
+    fn mean_and_std_deviation(&self) -> (f64, f64) {
+        let min_std_deviation = MIN_STD_DEVIATION.as_secs_f64() * 1000.0;
+        if self.intervals.is_empty() {
+            let mean = FIRST_HEARTBEAT_ESTIMATE.as_secs_f64() * 1000.0;
+            return (mean, f64::max(mean / 4.0, min_std_deviation));
+        }
+
+        let count = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / count;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|interval| (interval - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        (mean, f64::max(variance.sqrt(), min_std_deviation))
+    }
+}
+
+/// Learns how far apart the heartbeats of each peer usually are and turns
+/// the time since the last one into phi, the suspicion level: phi = 1 means
+/// about a 10% chance of being wrong when calling the peer dead, phi = 2 a
+/// 1% chance and so on. Peers are suspected once phi reaches the threshold.
+pub struct PhiAccrualDetector {
+    clock: Arc<dyn Clock>,
+    threshold: f64,
+    peers: HashMap<u32, HeartbeatHistory>,
+}
+
+impl PhiAccrualDetector {
+    pub fn new(clock: Arc<dyn Clock>, threshold: f64) -> PhiAccrualDetector {
+        PhiAccrualDetector {
+            clock,
+            threshold,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Zero for peers that aren't watched.
+    pub fn phi(&self, peer: u32) -> f64 {
+        let history = match self.peers.get(&peer) {
+            Some(history) => history,
+            None => return 0.0,
+        };
+
+        let elapsed = self
+            .clock
+            .now()
+            .duration_since(history.last_heartbeat)
+            .as_secs_f64()
+            * 1000.0;
+        let (mean, std_deviation) = history.mean_and_std_deviation();
+        let mean = mean + ACCEPTABLE_PAUSE.as_secs_f64() * 1000.0;
+
+        // Logistic approximation of the normal distribution, which stays
+        // accurate far into the tail where phi is computed.
+        let y = (elapsed - mean) / std_deviation;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+impl HeartbeatDetector for PhiAccrualDetector {
+    fn watch(&mut self, peer: u32) {
+        let now = self.clock.now();
+        let history = self.peers.entry(peer).or_insert(HeartbeatHistory {
+            last_heartbeat: now,
+            received: false,
+            intervals: VecDeque::new(),
+        });
+        history.last_heartbeat = now;
+        history.received = false;
+    }
+
+    fn is_watching(&self, peer: u32) -> bool {
+        self.peers.contains_key(&peer)
+    }
+
+    fn heartbeat(&mut self, peer: u32) {
+        let now = self.clock.now();
+        let history = self.peers.entry(peer).or_insert(HeartbeatHistory {
+            last_heartbeat: now,
+            received: false,
+            intervals: VecDeque::new(),
+        });
+
+        if history.received {
+            let interval = now.duration_since(history.last_heartbeat);
+            history.intervals.push_back(interval.as_secs_f64() * 1000.0);
+            if history.intervals.len() > MAX_SAMPLES {
+                history.intervals.pop_front();
+            }
+        }
+        history.last_heartbeat = now;
+        history.received = true;
+    }
+
+    fn is_suspected(&self, peer: u32) -> bool {
+        self.phi(peer) >= self.threshold
+    }
+
+    fn forget(&mut self, peer: u32) {
+        self.peers.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phi_detector(intervals: &[u64]) -> (Arc<ManualClock>, PhiAccrualDetector) {
+        let clock = Arc::new(ManualClock::new());
+        let mut detector = PhiAccrualDetector::new(clock.clone(), 8.0);
+        detector.heartbeat(1);
+        for interval in intervals {
+            clock.advance(Duration::from_millis(*interval));
+            detector.heartbeat(1);
+        }
+        (clock, detector)
+    }
+
+    #[test]
+    fn should_raise_phi_while_heartbeats_are_missing() {
+        let (clock, detector) = phi_detector(&[1000; 20]);
+
+        let mut last_phi = detector.phi(1);
+        assert!(last_phi < 0.1);
+        for _ in 0..8 {
+            clock.advance(Duration::from_millis(250));
+            assert!(detector.phi(1) >= last_phi);
+            last_phi = detector.phi(1);
+        }
+
+        assert!(!detector.is_suspected(1));
+        clock.advance(Duration::from_secs(2));
+        assert!(detector.is_suspected(1));
+        assert!(!detector.is_suspected(2));
+    }
+
+    #[test]
+    fn should_wait_longer_for_peers_with_slow_irregular_heartbeats() {
+        let (fast_clock, fast) = phi_detector(&[1000; 20]);
+        let (slow_clock, slow) = phi_detector(&[2000, 4000, 2500, 3500, 3000, 2000, 4000, 3000]);
+
+        fast_clock.advance(Duration::from_secs(5));
+        slow_clock.advance(Duration::from_secs(5));
+
+        assert!(fast.is_suspected(1));
+        assert!(!slow.is_suspected(1));
+        assert!(slow.phi(1) < fast.phi(1));
+    }
+
+    #[test]
+    fn should_give_watched_peer_a_full_interval_again() {
+        let (clock, mut detector) = phi_detector(&[1000; 5]);
+        clock.advance(Duration::from_secs(10));
+        assert!(detector.is_suspected(1));
+
+        detector.watch(1);
+        assert!(!detector.is_suspected(1));
+
+        detector.forget(1);
+        assert!(!detector.is_watching(1));
+        assert_eq!(detector.phi(1), 0.0);
+    }
+
+    #[test]
+    fn should_grow_timeout_after_suspecting_live_peer() {
+        let clock = Arc::new(ManualClock::new());
+        let mut detector = EventuallyPerfectDetector::new(
+            clock.clone(),
+            Duration::from_secs(3),
+            Duration::from_secs(1),
+        );
+        detector.watch(1);
+
+        clock.advance(Duration::from_millis(3500));
+        assert!(detector.is_suspected(1));
+
+        detector.heartbeat(1);
+        clock.advance(Duration::from_millis(3500));
+        assert!(!detector.is_suspected(1));
+        clock.advance(Duration::from_millis(600));
+        assert!(detector.is_suspected(1));
+    }
+}
@@ -0,0 +1,79 @@
+use logstreamer::{BrokerConfig, DevCluster};
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+mod signal;
+
+const DEFAULT_SIZE: usize = 3;
+const USAGE: &str = "dev-cluster [<brokers>] [--port <first port>]\n\
+                     \n\
+                     Starts in-memory brokers on free ports, or on consecutive ports from\n\
+                     --port on, initializes the controller and runs until Ctrl-C.\n";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+    let (size, first_port) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}\r\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let broker_list = (0..size)
+        .map(|index| {
+            let port = first_port.map_or(0, |port| port + index as u16);
+            (format!("127.0.0.1:{}", port), BrokerConfig::default())
+        })
+        .collect();
+    signal::catch_terminate();
+    signal::catch_interrupt();
+    let cluster = match DevCluster::on_addresses(broker_list) {
+        Ok(cluster) => cluster,
+        Err(err) => {
+            eprintln!("Failed to start cluster\r\n{}", err);
+            process::exit(1);
+        }
+    };
+    println!(
+        "Started {} brokers, bootstrap with {}",
+        size,
+        cluster.bootstrap_addresses().join(",")
+    );
+
+    while !signal::terminated() {
+        thread::sleep(Duration::from_millis(100));
+    }
+    println!("Stopping cluster");
+    cluster.stop();
+}
+
+fn parse_args(args: &[String]) -> Result<(usize, Option<u16>), String> {
+    let mut size = DEFAULT_SIZE;
+    let mut first_port = None;
+    let mut arg_iter = args.iter();
+    while let Some(arg) = arg_iter.next() {
+        if arg == "--port" {
+            let value = arg_iter.next().ok_or("missing value for --port")?;
+            first_port = match value.parse::<u16>() {
+                Ok(port) if port > 0 => Some(port),
+                _ => return Err(format!("invalid port '{}'", value)),
+            };
+        } else {
+            size = match arg.parse::<usize>() {
+                Ok(size) if size > 0 => size,
+                _ => return Err(format!("invalid number of brokers '{}'", arg)),
+            };
+        }
+    }
+    if first_port.is_some_and(|port| port as usize + size - 1 > u16::MAX as usize) {
+        return Err(String::from("not enough ports after --port"));
+    }
+    Ok((size, first_port))
+}
@@ -1,259 +1,3828 @@
 use crate::communication::{Action, ActionMessage, Response, ResponseMessage};
-use crate::core::{Content, OffsetValue, TopicAddress};
-use crate::storage::Cluster;
-use std::io::prelude::{Read, Write};
-use std::net::TcpStream;
-use std::sync::Mutex;
+use crate::config::{
+    default_topic_config, validate_topic_configs, BrokerConfig, DetectorKind, LogConfig,
+    CLEANUP_POLICY, COMPRESSION_TYPE, MAX_MESSAGE_BYTES, MIN_INSYNC_REPLICAS, RETENTION_MS,
+    TOPIC_CONFIG_DEFAULTS,
+};
+use crate::controller::Controller;
+use crate::core::{
+    Acks, BatchHeader, ConsumerRecord, Content, Isolation, OffsetValue, PartitionState,
+    ProducerRecord, TopicAddress,
+};
+use crate::detector::{
+    Clock, EventuallyPerfectDetector, HeartbeatDetector, PhiAccrualDetector, SystemClock,
+};
+use crate::metadata::{ClusterMetadata, MetadataRecord};
+use crate::placement::place_replicas;
+use crate::pool::{ConnectionPool, Transport};
+use crate::raft::RaftMessage;
+use crate::reassignment::{complete_reassignment, plan_rebalance, start_reassignment};
+use crate::replication::{ReplicaManager, Throttle};
+use crate::storage::{AppendResult, Cluster, Partition};
+use crate::transaction::{TransactionCoordinator, TransactionError};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+
+const MAX_REDIRECTS: usize = 3;
 
 pub struct Client {
     stream: TcpStream,
+    follow_redirects: bool,
+}
+
+impl Client {
+    pub fn new(broker: String) -> Client {
+        Client::connect(broker).unwrap()
+    }
+
+    pub fn connect(broker: String) -> io::Result<Client> {
+        Ok(Client {
+            stream: TcpStream::connect(broker)?,
+            follow_redirects: true,
+        })
+    }
+
+    /// Connects without following redirects, giving up on connecting after
+    /// `connect_timeout` and on any request after `request_timeout`.
+    pub(crate) fn connect_with_timeouts(
+        broker: &str,
+        connect_timeout: Duration,
+        request_timeout: Duration,
+    ) -> io::Result<Client> {
+        let mut last_err = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no address for {}", broker),
+        );
+        for socket_address in broker.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_address, connect_timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(request_timeout))?;
+                    stream.set_write_timeout(Some(request_timeout))?;
+                    return Ok(Client {
+                        stream,
+                        follow_redirects: false,
+                    });
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// When enabled (the default), a request answered with `AskTheController`
+    /// is sent again to the controller, which later requests go to as well.
+    pub fn set_follow_redirects(&mut self, follow_redirects: bool) {
+        self.follow_redirects = follow_redirects;
+    }
+
+    pub fn send_message(&mut self, message: ActionMessage) -> Vec<ResponseMessage> {
+        match self.try_send_message(message) {
+            Ok(response_list) => response_list,
+            Err(err) => {
+                println!("Failed to read stream\n{}", err);
+                vec![ResponseMessage::new_empty()]
+            }
+        }
+    }
+
+    /// Returns no responses for messages the broker doesn't answer, like
+    /// fire-and-forget produce requests.
+    pub fn try_send_message(&mut self, message: ActionMessage) -> io::Result<Vec<ResponseMessage>> {
+        let mut response_list = self.send_once(&message)?;
+        for _ in 0..MAX_REDIRECTS {
+            let controller = match response_list.first().map(|response| &response.response) {
+                Some(Response::AskTheController(controller)) if self.follow_redirects => {
+                    controller.clone()
+                }
+                _ => break,
+            };
+
+            self.stream = TcpStream::connect(controller)?;
+            response_list = self.send_once(&message)?;
+        }
+        Ok(response_list)
+    }
+
+    /// Sends `message` to the broker connected to, ignoring redirects.
+    pub(crate) fn send_once(
+        &mut self,
+        message: &ActionMessage,
+    ) -> io::Result<Vec<ResponseMessage>> {
+        self.write_request(message)?;
+        self.read_response(message)
+    }
+
+    pub(crate) fn write_request(&mut self, message: &ActionMessage) -> io::Result<()> {
+        message.write_to(&mut self.stream)
+    }
+
+    /// Reads the answer to `message`, which is none for fire-and-forget ones.
+    pub(crate) fn read_response(
+        &mut self,
+        message: &ActionMessage,
+    ) -> io::Result<Vec<ResponseMessage>> {
+        if !message.expects_response() {
+            return Ok(Vec::new());
+        }
+
+        ResponseMessage::read_list_from(&mut self.stream)
+    }
+
+    /// Whether the broker closed the connection, checked without waiting.
+    /// Bytes nobody asked for count as closed too, as they would be read
+    /// as the answer to the next request.
+    pub(crate) fn is_closed(&self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let peeked = self.stream.peek(&mut [0; 1]);
+        if self.stream.set_nonblocking(false).is_err() {
+            return true;
+        }
+        !matches!(peeked, Err(err) if err.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+/// Sends requests about a partition to the broker leading it. Leaders are
+/// looked up with `Action::Metadata` and forgotten when the broker answers
+/// `NotLeader` or `UnknownTopic` or can't be reached, so the next request
+/// looks them up again.
+struct LeaderRouter {
+    bootstrap: String,
+    brokers: HashMap<u32, String>,
+    leaders: HashMap<TopicAddress, u32>,
+    // Partition count of each topic as of its last lookup, and when that was.
+    partition_counts: HashMap<String, (u32, Instant)>,
+    clients: HashMap<String, Client>,
+}
+
+impl LeaderRouter {
+    fn new(bootstrap: String) -> LeaderRouter {
+        LeaderRouter {
+            bootstrap,
+            brokers: HashMap::new(),
+            leaders: HashMap::new(),
+            partition_counts: HashMap::new(),
+            clients: HashMap::new(),
+        }
+    }
+
+    fn send(
+        &mut self,
+        topic: &TopicAddress,
+        message: ActionMessage,
+    ) -> io::Result<Vec<ResponseMessage>> {
+        let address = self.leader_address(topic);
+        let result = self.send_to(address, message);
+
+        let not_leader = match &result {
+            Ok(response_list) => response_list.iter().any(|response| {
+                matches!(
+                    response.response,
+                    Response::NotLeader | Response::UnknownTopic
+                )
+            }),
+            Err(_) => true,
+        };
+        if not_leader {
+            self.leaders.remove(topic);
+        }
+        result
+    }
+
+    fn send_to_bootstrap(&mut self, message: ActionMessage) -> io::Result<Vec<ResponseMessage>> {
+        self.send_to(self.bootstrap.clone(), message)
+    }
+
+    fn send_to(
+        &mut self,
+        address: String,
+        message: ActionMessage,
+    ) -> io::Result<Vec<ResponseMessage>> {
+        let mut client = match self.clients.remove(&address) {
+            Some(client) => client,
+            None => Client::connect(address.clone())?,
+        };
+        let response_list = client.try_send_message(message)?;
+        self.clients.insert(address, client);
+        Ok(response_list)
+    }
+
+    /// Falls back to the bootstrap broker for partitions without a known leader.
+    fn leader_address(&mut self, topic: &TopicAddress) -> String {
+        if !self.leaders.contains_key(topic) {
+            self.refresh(&topic.name);
+        }
+
+        self.leaders
+            .get(topic)
+            .and_then(|leader| self.brokers.get(leader))
+            .cloned()
+            .unwrap_or_else(|| self.bootstrap.clone())
+    }
+
+    /// Asks the first broker that answers, starting with the bootstrap one,
+    /// who leads the partitions of `topic_name`.
+    fn refresh(&mut self, topic_name: &str) {
+        let mut addresses = vec![self.bootstrap.clone()];
+        addresses.extend(
+            self.brokers
+                .values()
+                .filter(|address| **address != self.bootstrap)
+                .cloned(),
+        );
+
+        for address in addresses {
+            let message =
+                ActionMessage::new(Action::Metadata(topic_name.to_string()), String::new());
+            if let Ok(response_list) = self.send_to(address, message) {
+                for response in response_list {
+                    match response.response {
+                        Response::TopicMetadata(partition_count) => {
+                            self.partition_counts
+                                .insert(topic_name.to_string(), (partition_count, Instant::now()));
+                        }
+                        Response::UnknownTopic => {
+                            self.partition_counts.remove(topic_name);
+                        }
+                        Response::BrokerAddress(broker_id, address) => {
+                            self.brokers.insert(broker_id, address);
+                        }
+                        Response::PartitionMetadata(state) => {
+                            self.leaders.insert(state.topic, state.leader);
+                        }
+                        _ => {}
+                    }
+                }
+                return;
+            }
+        }
+    }
+
+    fn partition_count(&self, topic_name: &str) -> Option<(u32, Instant)> {
+        self.partition_counts.get(topic_name).copied()
+    }
+
+    fn close(&mut self) {
+        for (_, mut client) in self.clients.drain() {
+            let _ = client.try_send_message(ActionMessage::new(Action::Quit, String::new()));
+        }
+    }
+}
+
+const DEFAULT_MAX_POLL_RECORDS: u32 = 30;
+const DEFAULT_METADATA_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// Reads records from a set of topic partitions, tracking the position on
+/// each of them so callers never have to deal with `Action::Consume` directly.
+/// Records are read from the leader of each partition, offsets are committed
+/// on the broker the consumer was created with.
+pub struct Consumer {
+    router: LeaderRouter,
+    consumer_id: String,
+    positions: Vec<(TopicAddress, OffsetValue)>,
+    // Topics subscribed to as a whole, and when their partitions were last
+    // looked up. `None` asks again on the next poll.
+    topics: HashMap<String, Option<Instant>>,
+    metadata_max_age: Duration,
+    auto_commit: bool,
+    uncommitted: bool,
+    max_poll_records: u32,
+    isolation: Isolation,
+    buffered: VecDeque<ConsumerRecord>,
+}
+
+impl Consumer {
+    /// Connects lazily, so a broker that is down only fails the first request.
+    pub fn new(broker: String, consumer_id: String) -> Consumer {
+        Consumer {
+            router: LeaderRouter::new(broker),
+            consumer_id,
+            positions: Vec::new(),
+            topics: HashMap::new(),
+            metadata_max_age: DEFAULT_METADATA_MAX_AGE,
+            auto_commit: true,
+            uncommitted: false,
+            max_poll_records: DEFAULT_MAX_POLL_RECORDS,
+            isolation: Isolation::ReadUncommitted,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    /// When enabled (the default), positions are committed at the start of
+    /// every `poll` and on `close`, which gives at-least-once delivery.
+    pub fn set_auto_commit(&mut self, auto_commit: bool) {
+        self.auto_commit = auto_commit;
+    }
+
+    pub fn set_max_poll_records(&mut self, max_poll_records: u32) {
+        self.max_poll_records = max_poll_records;
+    }
+
+    pub fn set_isolation(&mut self, isolation: Isolation) {
+        self.isolation = isolation;
+    }
+
+    /// How long the partitions of topics subscribed with `subscribe_topic`
+    /// are used before they're looked up again.
+    pub fn set_metadata_max_age(&mut self, metadata_max_age: Duration) {
+        self.metadata_max_age = metadata_max_age;
+    }
+
+    /// Starts reading `topic` from the offset last committed by this consumer,
+    /// or from the beginning of the partition if nothing was committed yet.
+    pub fn subscribe(&mut self, topic: TopicAddress) {
+        if self
+            .positions
+            .iter()
+            .any(|(subscribed, _)| *subscribed == topic)
+        {
+            return;
+        }
+
+        let offset = self.committed(&topic).unwrap_or(OffsetValue(0));
+        self.positions.push((topic, offset));
+    }
+
+    /// Subscribes to every partition of `topic_name`, including the ones
+    /// added later. Those are found within `metadata_max_age`, or at once when
+    /// a read finds a partition unknown or the leaders are looked up again.
+    pub fn subscribe_topic(&mut self, topic_name: String) {
+        self.topics.entry(topic_name).or_insert(None);
+    }
+
+    pub fn unsubscribe_topic(&mut self, topic_name: &str) {
+        self.topics.remove(topic_name);
+        self.positions
+            .retain(|(subscribed, _)| subscribed.name != topic_name);
+        self.buffered
+            .retain(|record| record.topic.name != topic_name);
+    }
+
+    pub fn unsubscribe(&mut self, topic: &TopicAddress) {
+        self.positions.retain(|(subscribed, _)| subscribed != topic);
+        self.buffered.retain(|record| &record.topic != topic);
+    }
+
+    pub fn subscriptions(&self) -> Vec<TopicAddress> {
+        self.positions
+            .iter()
+            .map(|(topic, _)| topic.clone())
+            .collect()
+    }
+
+    pub fn poll(&mut self) -> Vec<ConsumerRecord> {
+        if !self.buffered.is_empty() {
+            return self.buffered.drain(..).collect();
+        }
+
+        if self.auto_commit {
+            self.commit();
+        }
+        self.subscribe_new_partitions();
+
+        let mut records = Vec::new();
+        for index in 0..self.positions.len() {
+            let (topic, offset) = self.positions[index].clone();
+            // A partition whose leader moved is read again on the next poll.
+            let response_list = self
+                .router
+                .send(
+                    &topic,
+                    ActionMessage::new(
+                        Action::Consume(
+                            topic.clone(),
+                            offset,
+                            self.max_poll_records,
+                            self.isolation,
+                        ),
+                        self.consumer_id.clone(),
+                    ),
+                )
+                .unwrap_or_default();
+
+            for response in response_list {
+                match response.response {
+                    Response::Content(offset, content) => {
+                        self.positions[index].1 = OffsetValue(offset.0 + 1);
+                        self.uncommitted = true;
+                        records.push(ConsumerRecord::new(topic.clone(), offset, content));
+                    }
+                    Response::UnknownTopic => {
+                        if let Some(looked_up_at) = self.topics.get_mut(&topic.name) {
+                            *looked_up_at = None;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        records
+    }
+
+    /// Subscribes to the partitions of topics subscribed as a whole that
+    /// aren't subscribed yet, looking the partition counts up when due.
+    fn subscribe_new_partitions(&mut self) {
+        let topic_names: Vec<String> = self.topics.keys().cloned().collect();
+        for topic_name in topic_names {
+            let due = self.topics[&topic_name]
+                .is_none_or(|looked_up_at| looked_up_at.elapsed() >= self.metadata_max_age);
+            if due {
+                self.router.refresh(&topic_name);
+                self.topics.insert(topic_name.clone(), Some(Instant::now()));
+            }
+
+            if let Some((partition_count, _)) = self.router.partition_count(&topic_name) {
+                for partition in 0..partition_count {
+                    self.subscribe(TopicAddress::new(topic_name.clone(), partition));
+                }
+            }
+        }
+    }
+
+    /// Stores the current position of every subscribed partition on the broker.
+    pub fn commit(&mut self) {
+        if !self.uncommitted {
+            return;
+        }
+
+        // Positions that failed to commit are committed again next time.
+        let mut committed = true;
+        for (topic, offset) in self.positions.iter() {
+            let result = self.router.send_to_bootstrap(ActionMessage::new(
+                Action::CommitOffset(topic.clone(), *offset),
+                self.consumer_id.clone(),
+            ));
+            committed &= result.is_ok_and(|response_list| {
+                response_list
+                    .iter()
+                    .any(|response| matches!(response.response, Response::Offset(_)))
+            });
+        }
+        self.uncommitted = !committed;
+    }
+
+    pub fn committed(&mut self, topic: &TopicAddress) -> Option<OffsetValue> {
+        let response_list = self
+            .router
+            .send_to_bootstrap(ActionMessage::new(
+                Action::CommittedOffset(topic.clone()),
+                self.consumer_id.clone(),
+            ))
+            .unwrap_or_default();
+
+        response_list
+            .into_iter()
+            .find_map(|response| match response.response {
+                Response::Offset(offset) => Some(offset),
+                _ => None,
+            })
+    }
+
+    pub fn position(&self, topic: &TopicAddress) -> Option<OffsetValue> {
+        self.positions
+            .iter()
+            .find(|(subscribed, _)| subscribed == topic)
+            .map(|(_, offset)| *offset)
+    }
+
+    /// Moves the position of a subscribed partition. Does nothing for
+    /// partitions this consumer is not subscribed to.
+    pub fn seek(&mut self, topic: &TopicAddress, offset: OffsetValue) {
+        self.buffered.retain(|record| &record.topic != topic);
+        for (subscribed, position) in self.positions.iter_mut() {
+            if subscribed == topic {
+                *position = offset;
+                self.uncommitted = true;
+            }
+        }
+    }
+
+    pub fn seek_to_beginning(&mut self, topic: &TopicAddress) {
+        self.seek(topic, OffsetValue(0));
+    }
+
+    pub fn seek_to_end(&mut self, topic: &TopicAddress) {
+        let response_list = self
+            .router
+            .send(
+                topic,
+                ActionMessage::new(
+                    Action::LatestOffset(topic.clone()),
+                    self.consumer_id.clone(),
+                ),
+            )
+            .unwrap_or_default();
+
+        for response in response_list {
+            if let Response::Offset(offset) = response.response {
+                self.seek(topic, offset);
+            }
+        }
+    }
+
+    pub fn close(mut self) {
+        if self.auto_commit {
+            self.commit();
+        }
+        self.router.close();
+    }
+}
+
+/// Yields records until a poll comes back empty, so `for record in &mut consumer`
+/// drains everything currently available and can be resumed later.
+impl Iterator for Consumer {
+    type Item = ConsumerRecord;
+
+    fn next(&mut self) -> Option<ConsumerRecord> {
+        if self.buffered.is_empty() {
+            let records = self.poll();
+            self.buffered.extend(records);
+        }
+        self.buffered.pop_front()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProduceError {
+    UnknownTopic,
+    Rejected,
+    Disconnected,
+    Closed,
+    /// The broker is missing earlier batches from this producer.
+    OutOfOrderSequence,
+    /// The batch was stored before, but too long ago to know its offset.
+    DuplicateSequence,
+    /// No transaction was begun, or the broker doesn't know about it.
+    InvalidTransactionState,
+    /// Some record of the transaction failed, so it was aborted instead.
+    TransactionAborted,
+    /// The partition leader kept moving until the retries ran out.
+    NotLeader,
+    /// Too few replicas stayed in sync for the topic's `min.insync.replicas`
+    /// until the retries ran out.
+    NotEnoughReplicas,
+    /// The batch holds more bytes than the topic's `max.message.bytes`.
+    RecordTooLarge,
+}
+
+/// Chooses the partition of records that were sent without an explicit one.
+pub trait Partitioner: Send {
+    fn partition(&mut self, record: &ProducerRecord, partition_count: u32) -> u32;
+}
+
+impl<F> Partitioner for F
+where
+    F: FnMut(&ProducerRecord, u32) -> u32 + Send,
+{
+    fn partition(&mut self, record: &ProducerRecord, partition_count: u32) -> u32 {
+        self(record, partition_count)
+    }
+}
+
+#[derive(Default)]
+pub struct RoundRobinPartitioner {
+    next: u32,
+}
+
+impl RoundRobinPartitioner {
+    pub fn new() -> RoundRobinPartitioner {
+        RoundRobinPartitioner { next: 0 }
+    }
+}
+
+impl Partitioner for RoundRobinPartitioner {
+    fn partition(&mut self, _record: &ProducerRecord, partition_count: u32) -> u32 {
+        let partition = self.next % partition_count;
+        self.next = self.next.wrapping_add(1);
+        partition
+    }
+}
+
+/// Sends records with the same key to the same partition, as long as the
+/// partition count doesn't change. Records without a key are spread round-robin.
+#[derive(Default)]
+pub struct KeyHashPartitioner {
+    round_robin: RoundRobinPartitioner,
+}
+
+impl KeyHashPartitioner {
+    pub fn new() -> KeyHashPartitioner {
+        KeyHashPartitioner {
+            round_robin: RoundRobinPartitioner::new(),
+        }
+    }
+
+    // FNV-1a, so keys map to the same partition across processes and builds.
+    fn hash(key: &str) -> u32 {
+        key.bytes().fold(0x811c_9dc5, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+    }
+}
+
+impl Partitioner for KeyHashPartitioner {
+    fn partition(&mut self, record: &ProducerRecord, partition_count: u32) -> u32 {
+        match &record.key {
+            Some(key) => KeyHashPartitioner::hash(key) % partition_count,
+            None => self.round_robin.partition(record, partition_count),
+        }
+    }
+}
+
+/// The offset of the record, or `None` when it was sent with `Acks::None`
+/// and the broker never told it.
+type ProduceResult = Result<Option<OffsetValue>, ProduceError>;
+
+/// Completes once the batch holding the record was acknowledged by the
+/// broker, or just sent when acknowledgements are off.
+#[derive(Clone, Default)]
+pub struct RecordHandle {
+    state: Arc<(Mutex<Option<ProduceResult>>, Condvar)>,
+}
+
+impl RecordHandle {
+    fn new() -> RecordHandle {
+        RecordHandle {
+            state: Arc::new((Mutex::new(None), Condvar::new())),
+        }
+    }
+
+    fn complete(&self, result: ProduceResult) {
+        let (lock, condvar) = &*self.state;
+        lock.lock().unwrap().replace(result);
+        condvar.notify_all();
+    }
+
+    pub fn try_get(&self) -> Option<ProduceResult> {
+        *self.state.0.lock().unwrap()
+    }
+
+    pub fn wait(&self) -> ProduceResult {
+        let (lock, condvar) = &*self.state;
+        let mut result = lock.lock().unwrap();
+        while result.is_none() {
+            result = condvar.wait(result).unwrap();
+        }
+        result.unwrap()
+    }
+
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<ProduceResult> {
+        let (lock, condvar) = &*self.state;
+        let (result, _) = condvar
+            .wait_timeout_while(lock.lock().unwrap(), timeout, |result| result.is_none())
+            .unwrap();
+        *result
+    }
 }
 
-impl Client {
-    pub fn new(broker: String) -> Client {
-        Client {
-            stream: TcpStream::connect(broker).unwrap(),
+#[derive(Clone)]
+pub struct ProducerConfig {
+    /// Number of records per partition that triggers a send.
+    pub batch_size: usize,
+    /// How long a non-full batch waits for more records before being sent.
+    pub linger: Duration,
+    /// `send` blocks while this many records are waiting to be sent.
+    pub max_buffered_records: usize,
+    pub retries: u32,
+    /// Wait before the first retry, doubled on each following one.
+    pub retry_backoff: Duration,
+    pub acks: Acks,
+    /// Numbers every batch so the broker can discard the ones it already
+    /// stored when a send is retried.
+    pub idempotence: bool,
+    /// Makes every send part of a transaction, see `Producer::begin_transaction`.
+    pub transactional_id: Option<String>,
+    /// How long the partition count of a topic is used before it's asked
+    /// for again, so partitions added meanwhile get records too. It is asked
+    /// for sooner when a send finds the topic or partition unknown, or the
+    /// leaders of the topic are looked up and show another count.
+    pub metadata_max_age: Duration,
+}
+
+impl Default for ProducerConfig {
+    fn default() -> ProducerConfig {
+        ProducerConfig {
+            batch_size: 30,
+            linger: Duration::from_millis(5),
+            max_buffered_records: 10_000,
+            retries: 3,
+            retry_backoff: Duration::from_millis(100),
+            acks: Acks::Leader,
+            idempotence: false,
+            transactional_id: None,
+            metadata_max_age: DEFAULT_METADATA_MAX_AGE,
+        }
+    }
+}
+
+struct PendingBatch {
+    created: Instant,
+    records: Vec<(Content, RecordHandle)>,
+}
+
+#[derive(Default)]
+struct ProducerState {
+    batches: HashMap<TopicAddress, PendingBatch>,
+    // Partition count of each topic and when it was learned.
+    partition_counts: HashMap<String, (u32, Instant)>,
+    // Last count learned of each topic, kept when the one above is dropped
+    // so a change is noticed once the count is learned again.
+    last_partition_counts: HashMap<String, u32>,
+    partition_count_changes: Vec<(String, u32, u32)>,
+    buffered_records: usize,
+    flushing: bool,
+    closed: bool,
+    /// Producer id of the ongoing transaction.
+    transaction: Option<u32>,
+    transaction_failed: bool,
+}
+
+impl ProducerState {
+    fn learn_partition_count(&mut self, topic: &str, partition_count: u32, learned_at: Instant) {
+        self.partition_counts
+            .insert(topic.to_string(), (partition_count, learned_at));
+        let last = self
+            .last_partition_counts
+            .insert(topic.to_string(), partition_count);
+        if let Some(last) = last.filter(|last| *last != partition_count) {
+            self.partition_count_changes
+                .push((topic.to_string(), last, partition_count));
+        }
+    }
+}
+
+#[derive(Default)]
+struct ProducerShared {
+    state: Mutex<ProducerState>,
+    changed: Condvar,
+}
+
+/// Buffers records per partition and sends them from a background thread
+/// once a batch is full or has waited for `linger`.
+pub struct Producer {
+    broker: String,
+    config: ProducerConfig,
+    shared: Arc<ProducerShared>,
+    partitioner: Box<dyn Partitioner>,
+    control_client: Option<Client>,
+    transaction_partitions: HashSet<TopicAddress>,
+    in_transaction: bool,
+    sender: Option<thread::JoinHandle<()>>,
+}
+
+impl Producer {
+    pub fn new(broker: String) -> Producer {
+        Producer::with_config(broker, ProducerConfig::default())
+    }
+
+    pub fn with_config(broker: String, config: ProducerConfig) -> Producer {
+        let shared = Arc::new(ProducerShared::default());
+
+        let sender_shared = shared.clone();
+        let sender_broker = broker.clone();
+        let sender_config = config.clone();
+        let sender = thread::spawn(move || {
+            ProducerSender::new(sender_broker, sender_config, sender_shared).run();
+        });
+
+        Producer {
+            broker,
+            config,
+            shared,
+            partitioner: Box::new(KeyHashPartitioner::new()),
+            control_client: None,
+            transaction_partitions: HashSet::new(),
+            in_transaction: false,
+            sender: Some(sender),
+        }
+    }
+
+    pub fn set_partitioner(&mut self, partitioner: Box<dyn Partitioner>) {
+        self.partitioner = partitioner;
+    }
+
+    pub fn send(&mut self, record: ProducerRecord) -> RecordHandle {
+        let handle = RecordHandle::new();
+
+        let partition = match record.partition {
+            Some(partition) => {
+                // The partition may have been added since the count was learned.
+                let known = self.known_partition_count(&record.topic);
+                if known.is_some_and(|partition_count| partition >= partition_count) {
+                    self.forget_partition_count(&record.topic);
+                }
+                partition
+            }
+            None => match self.partition_count(&record.topic) {
+                Some(partition_count) => self.partitioner.partition(&record, partition_count),
+                None => {
+                    handle.complete(Err(ProduceError::UnknownTopic));
+                    return handle;
+                }
+            },
+        };
+        let topic = TopicAddress::new(record.topic, partition);
+        // Stored with the record, so compacted topics can tell it apart.
+        let content = Content {
+            key: record.key.or(record.content.key),
+            ..record.content
+        };
+
+        if self.config.transactional_id.is_some() {
+            if let Err(err) = self.add_to_transaction(&topic) {
+                handle.complete(Err(err));
+                return handle;
+            }
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        while state.buffered_records >= self.config.max_buffered_records && !state.closed {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+
+        if state.closed {
+            handle.complete(Err(ProduceError::Closed));
+            return handle;
+        }
+
+        state
+            .batches
+            .entry(topic)
+            .or_insert_with(|| PendingBatch {
+                created: Instant::now(),
+                records: Vec::new(),
+            })
+            .records
+            .push((content, handle.clone()));
+        state.buffered_records += 1;
+        self.shared.changed.notify_all();
+
+        handle
+    }
+
+    /// Sends every buffered record right away and waits until all of them
+    /// were acknowledged or failed.
+    pub fn flush(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.flushing = true;
+        self.shared.changed.notify_all();
+        while state.buffered_records > 0 {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        state.flushing = false;
+    }
+
+    /// Starts a transaction: everything sent until `commit_transaction` is
+    /// seen by read-committed consumers all at once, or not at all.
+    pub fn begin_transaction(&mut self) -> Result<(), ProduceError> {
+        let transactional_id = match &self.config.transactional_id {
+            Some(transactional_id) if !self.in_transaction => transactional_id.clone(),
+            _ => return Err(ProduceError::InvalidTransactionState),
+        };
+
+        let response_list = self.control_request(Action::BeginTransaction(transactional_id))?;
+        let producer_id = response_list
+            .into_iter()
+            .find_map(|response| match response.response {
+                Response::ProducerId(producer_id) => Some(Ok(producer_id)),
+                Response::InvalidTransactionState => {
+                    Some(Err(ProduceError::InvalidTransactionState))
+                }
+                Response::Error => Some(Err(ProduceError::Rejected)),
+                _ => None,
+            })
+            .unwrap_or(Err(ProduceError::Rejected))?;
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.transaction = Some(producer_id);
+        state.transaction_failed = false;
+        self.transaction_partitions.clear();
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    /// Commits consumer offsets together with the ongoing transaction, for
+    /// consume-transform-produce loops.
+    pub fn send_offsets_to_transaction(
+        &mut self,
+        consumer_id: String,
+        offsets: Vec<(TopicAddress, OffsetValue)>,
+    ) -> Result<(), ProduceError> {
+        let transactional_id = self.ongoing_transaction()?;
+
+        for (topic, offset) in offsets {
+            let response_list = self.control_request_as(
+                Action::AddOffsetToTransaction(transactional_id.clone(), topic, offset),
+                consumer_id.clone(),
+            )?;
+            Producer::transaction_result(response_list)?;
+        }
+        Ok(())
+    }
+
+    pub fn commit_transaction(&mut self) -> Result<(), ProduceError> {
+        self.end_transaction(true)
+    }
+
+    pub fn abort_transaction(&mut self) -> Result<(), ProduceError> {
+        self.end_transaction(false)
+    }
+
+    fn end_transaction(&mut self, commit: bool) -> Result<(), ProduceError> {
+        let transactional_id = self.ongoing_transaction()?;
+        self.flush();
+
+        let failed = self.shared.state.lock().unwrap().transaction_failed;
+        let result =
+            self.control_request(Action::EndTransaction(transactional_id, commit && !failed));
+
+        self.shared.state.lock().unwrap().transaction = None;
+        self.in_transaction = false;
+
+        Producer::transaction_result(result?)?;
+        if commit && failed {
+            return Err(ProduceError::TransactionAborted);
+        }
+        Ok(())
+    }
+
+    fn ongoing_transaction(&self) -> Result<String, ProduceError> {
+        match &self.config.transactional_id {
+            Some(transactional_id) if self.in_transaction => Ok(transactional_id.clone()),
+            _ => Err(ProduceError::InvalidTransactionState),
+        }
+    }
+
+    fn add_to_transaction(&mut self, topic: &TopicAddress) -> Result<(), ProduceError> {
+        let transactional_id = self.ongoing_transaction()?;
+        if self.transaction_partitions.contains(topic) {
+            return Ok(());
+        }
+
+        let response_list = self.control_request(Action::AddPartitionsToTransaction(
+            transactional_id,
+            vec![topic.clone()],
+        ))?;
+        Producer::transaction_result(response_list)?;
+
+        self.transaction_partitions.insert(topic.clone());
+        Ok(())
+    }
+
+    fn transaction_result(response_list: Vec<ResponseMessage>) -> Result<(), ProduceError> {
+        response_list
+            .into_iter()
+            .find_map(|response| match response.response {
+                Response::InvalidTransactionState => {
+                    Some(Err(ProduceError::InvalidTransactionState))
+                }
+                Response::Error => Some(Err(ProduceError::Rejected)),
+                _ => None,
+            })
+            .unwrap_or(Ok(()))
+    }
+
+    fn control_request(&mut self, action: Action) -> Result<Vec<ResponseMessage>, ProduceError> {
+        self.control_request_as(action, String::new())
+    }
+
+    fn control_request_as(
+        &mut self,
+        action: Action,
+        consumer_id: String,
+    ) -> Result<Vec<ResponseMessage>, ProduceError> {
+        if self.control_client.is_none() {
+            self.control_client = Client::connect(self.broker.clone()).ok();
+        }
+
+        let client = self
+            .control_client
+            .as_mut()
+            .ok_or(ProduceError::Disconnected)?;
+        match client.try_send_message(ActionMessage::new(action, consumer_id)) {
+            Ok(response_list) => Ok(response_list),
+            Err(_) => {
+                self.control_client = None;
+                Err(ProduceError::Disconnected)
+            }
+        }
+    }
+
+    pub fn close(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+
+        if let Some(sender) = self.sender.take() {
+            sender.join().unwrap();
+        }
+
+        if let Some(mut client) = self.control_client.take() {
+            let _ = client.try_send_message(ActionMessage::new(Action::Quit, String::new()));
+        }
+    }
+
+    /// Partitions of `topic`, `None` when it doesn't exist. Keys map to other
+    /// partitions once the count changes, see `take_partition_count_changes`.
+    pub fn partition_count(&mut self, topic: &str) -> Option<u32> {
+        let known = self
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .partition_counts
+            .get(topic)
+            .copied();
+        if let Some((partition_count, learned_at)) = known {
+            if learned_at.elapsed() < self.config.metadata_max_age {
+                return Some(partition_count);
+            }
+        }
+
+        let partition_count = self
+            .control_request(Action::Metadata(topic.to_string()))
+            .ok()
+            .and_then(|response_list| {
+                response_list
+                    .into_iter()
+                    .find_map(|response| match response.response {
+                        Response::TopicMetadata(partition_count) if partition_count > 0 => {
+                            Some(Some(partition_count))
+                        }
+                        Response::UnknownTopic => Some(None),
+                        _ => None,
+                    })
+            });
+        let mut state = self.shared.state.lock().unwrap();
+        match partition_count {
+            Some(Some(partition_count)) => {
+                state.learn_partition_count(topic, partition_count, Instant::now());
+                Some(partition_count)
+            }
+            Some(None) => {
+                state.partition_counts.remove(topic);
+                None
+            }
+            // Keeps using the count known until a broker answers.
+            None => known.map(|(partition_count, _)| partition_count),
+        }
+    }
+
+    /// Topics whose partition count changed since the last call, with the
+    /// count before and after. Records with a key sent to them since may
+    /// have gone to another partition than the ones with that key before.
+    pub fn take_partition_count_changes(&mut self) -> Vec<(String, u32, u32)> {
+        mem::take(&mut self.shared.state.lock().unwrap().partition_count_changes)
+    }
+
+    fn known_partition_count(&self, topic: &str) -> Option<u32> {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .partition_counts
+            .get(topic)
+            .map(|(partition_count, _)| *partition_count)
+    }
+
+    /// Makes the next send to `topic` ask for its partition count again.
+    fn forget_partition_count(&self, topic: &str) {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .partition_counts
+            .remove(topic);
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+struct ProducerSender {
+    config: ProducerConfig,
+    shared: Arc<ProducerShared>,
+    router: LeaderRouter,
+    producer_id: Option<u32>,
+    sequences: HashMap<TopicAddress, u32>,
+}
+
+impl ProducerSender {
+    fn new(broker: String, config: ProducerConfig, shared: Arc<ProducerShared>) -> ProducerSender {
+        ProducerSender {
+            config,
+            shared,
+            router: LeaderRouter::new(broker),
+            producer_id: None,
+            sequences: HashMap::new(),
+        }
+    }
+
+    fn run(&mut self) {
+        while let Some(ready) = self.next_ready_batches() {
+            for (topic, batch) in ready {
+                let sent = batch.records.len();
+                for chunk in batch.records.chunks(self.config.batch_size) {
+                    self.send_chunk(&topic, chunk);
+                }
+
+                let mut state = self.shared.state.lock().unwrap();
+                state.buffered_records -= sent;
+                self.shared.changed.notify_all();
+            }
+        }
+
+        self.router.close();
+    }
+
+    /// Blocks until at least one batch should be sent. Returns `None` once the
+    /// producer was closed and everything buffered was sent.
+    fn next_ready_batches(&self) -> Option<Vec<(TopicAddress, PendingBatch)>> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            let send_all = state.flushing || state.closed;
+            if state.closed && state.batches.is_empty() {
+                return None;
+            }
+
+            let batch_size = self.config.batch_size;
+            let linger = self.config.linger;
+            let ready_topics: Vec<TopicAddress> = state
+                .batches
+                .iter()
+                .filter(|(_, batch)| {
+                    send_all
+                        || batch.records.len() >= batch_size
+                        || batch.created.elapsed() >= linger
+                })
+                .map(|(topic, _)| topic.clone())
+                .collect();
+
+            if !ready_topics.is_empty() {
+                return Some(
+                    ready_topics
+                        .into_iter()
+                        .map(|topic| {
+                            let batch = state.batches.remove(&topic).unwrap();
+                            (topic, batch)
+                        })
+                        .collect(),
+                );
+            }
+
+            let next_deadline = state
+                .batches
+                .values()
+                .map(|batch| linger.saturating_sub(batch.created.elapsed()))
+                .min();
+
+            state = match next_deadline {
+                Some(timeout) => self.shared.changed.wait_timeout(state, timeout).unwrap().0,
+                None => self.shared.changed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn send_chunk(&mut self, topic: &TopicAddress, chunk: &[(Content, RecordHandle)]) {
+        let content_list: Vec<Content> = chunk.iter().map(|(content, _)| content.clone()).collect();
+
+        let result = self.send_with_retries(topic, content_list);
+        self.share_partition_count(topic, result);
+        match result {
+            Ok(Some(last_offset)) => {
+                let first_offset = last_offset.0 + 1 - chunk.len() as u32;
+                for (position, (_, handle)) in (first_offset..).zip(chunk.iter()) {
+                    handle.complete(Ok(Some(OffsetValue(position))));
+                }
+            }
+            Ok(None) => {
+                for (_, handle) in chunk.iter() {
+                    handle.complete(Ok(None));
+                }
+            }
+            Err(err) => {
+                if self.config.transactional_id.is_some() {
+                    self.shared.state.lock().unwrap().transaction_failed = true;
+                }
+                for (_, handle) in chunk.iter() {
+                    handle.complete(Err(err));
+                }
+            }
+        }
+    }
+
+    /// Tells the producer what sending learned about the partitions of the
+    /// topic: it has to ask again after an `UnknownTopic`, and looking the
+    /// leaders up may have shown a newer count than the one it knows.
+    fn share_partition_count(&self, topic: &TopicAddress, result: ProduceResult) {
+        let mut state = self.shared.state.lock().unwrap();
+        if result == Err(ProduceError::UnknownTopic) {
+            state.partition_counts.remove(&topic.name);
+            return;
+        }
+
+        if let Some((partition_count, learned_at)) = self.router.partition_count(&topic.name) {
+            let known = state.partition_counts.get(&topic.name);
+            if known.is_none_or(|(_, known_at)| *known_at < learned_at) {
+                state.learn_partition_count(&topic.name, partition_count, learned_at);
+            }
+        }
+    }
+
+    fn send_with_retries(
+        &mut self,
+        topic: &TopicAddress,
+        content_list: Vec<Content>,
+    ) -> ProduceResult {
+        let mut backoff = self.config.retry_backoff;
+        let mut attempt = 0;
+        let record_count = content_list.len() as u32;
+
+        loop {
+            let result = self.batch_header(topic).and_then(|header| {
+                self.router.send(
+                    topic,
+                    ActionMessage::new(
+                        Action::Produce(
+                            topic.clone(),
+                            content_list.clone(),
+                            self.config.acks,
+                            header,
+                        ),
+                        String::new(),
+                    ),
+                )
+            });
+
+            match result {
+                Ok(_) if self.config.acks == Acks::None => {
+                    self.advance_sequence(topic, record_count);
+                    return Ok(None);
+                }
+                Ok(response_list) => {
+                    let result = response_list
+                        .into_iter()
+                        .find_map(|response| match response.response {
+                            Response::Offset(offset) => Some(Ok(Some(offset))),
+                            Response::OutOfOrderSequence => {
+                                Some(Err(ProduceError::OutOfOrderSequence))
+                            }
+                            Response::DuplicateSequence => {
+                                Some(Err(ProduceError::DuplicateSequence))
+                            }
+                            Response::NotLeader => Some(Err(ProduceError::NotLeader)),
+                            Response::NotEnoughReplicas => {
+                                Some(Err(ProduceError::NotEnoughReplicas))
+                            }
+                            Response::UnknownTopic => Some(Err(ProduceError::UnknownTopic)),
+                            Response::RecordTooLarge => Some(Err(ProduceError::RecordTooLarge)),
+                            Response::Error => Some(Err(ProduceError::Rejected)),
+                            _ => None,
+                        })
+                        .unwrap_or(Err(ProduceError::Rejected));
+
+                    match result {
+                        // The router looks the new leader up before retrying.
+                        Err(ProduceError::NotLeader) if attempt < self.config.retries => {}
+                        // Followers may catch up and rejoin the ISR meanwhile.
+                        Err(ProduceError::NotEnoughReplicas) if attempt < self.config.retries => {}
+                        Ok(_) | Err(ProduceError::DuplicateSequence) => {
+                            self.advance_sequence(topic, record_count);
+                            return result;
+                        }
+                        Err(ProduceError::OutOfOrderSequence) => {
+                            self.reset_producer_id();
+                            return result;
+                        }
+                        _ => return result,
+                    }
+                }
+                Err(err) => {
+                    if attempt >= self.config.retries {
+                        println!(
+                            "[producer] giving up sending to {}-{}\n{}",
+                            topic.name, topic.partition, err
+                        );
+                        // The broker may or may not have the batch, so the
+                        // next ones can't reuse this sequence.
+                        self.reset_producer_id();
+                        return Err(ProduceError::Disconnected);
+                    }
+                }
+            }
+
+            thread::sleep(backoff);
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    fn batch_header(&mut self, topic: &TopicAddress) -> io::Result<Option<BatchHeader>> {
+        if self.config.transactional_id.is_some() {
+            let transaction = self.shared.state.lock().unwrap().transaction;
+            let producer_id = transaction.ok_or_else(|| io::Error::other("no transaction"))?;
+            if self.producer_id != Some(producer_id) {
+                self.producer_id = Some(producer_id);
+                self.sequences.clear();
+            }
+
+            let sequence = self.sequences.get(topic).copied().unwrap_or(0);
+            return Ok(Some(BatchHeader::transactional(producer_id, sequence)));
+        }
+
+        if !self.config.idempotence {
+            return Ok(None);
+        }
+
+        let producer_id = match self.producer_id {
+            Some(producer_id) => producer_id,
+            None => {
+                let response_list = self
+                    .router
+                    .send_to_bootstrap(ActionMessage::new(Action::InitProducerId, String::new()))?;
+                let producer_id = response_list
+                    .into_iter()
+                    .find_map(|response| match response.response {
+                        Response::ProducerId(producer_id) => Some(producer_id),
+                        _ => None,
+                    })
+                    .ok_or_else(|| io::Error::other("broker didn't assign a producer id"))?;
+                self.producer_id = Some(producer_id);
+                producer_id
+            }
+        };
+
+        let sequence = self.sequences.get(topic).copied().unwrap_or(0);
+        Ok(Some(BatchHeader::new(producer_id, sequence)))
+    }
+
+    fn advance_sequence(&mut self, topic: &TopicAddress, record_count: u32) {
+        if self.producer_id.is_some() {
+            let sequence = self.sequences.entry(topic.clone()).or_insert(0);
+            *sequence = sequence.wrapping_add(record_count);
+        }
+    }
+
+    fn reset_producer_id(&mut self) {
+        self.producer_id = None;
+        self.sequences.clear();
+    }
+}
+
+/// Tracks, on the controller, which brokers are alive from the heartbeats
+/// they send it and, on the other brokers, where the controller is and
+/// which brokers are in the cluster.
+struct FailureDetector {
+    id: u32,
+    address: String,
+    brokers: HashMap<u32, String>,
+    // Members of the controller quorum, which doesn't change once set up.
+    quorum: HashMap<u32, String>,
+    // Asked for the controller, besides the known brokers, while it is unknown.
+    bootstrap_brokers: Vec<String>,
+    controller: Option<String>,
+    // Epoch the controller registered this broker with. None until it did,
+    // and again once the controller fenced it.
+    epoch: Option<u32>,
+    next_probe: usize,
+    // Fed with the heartbeats the brokers send to the controller.
+    detector: Box<dyn HeartbeatDetector>,
+    // Brokers this controller already sent every partition state to.
+    synced_brokers: HashSet<u32>,
+    // Addresses of the brokers that failed the last request sent to them.
+    unreachable: HashSet<String>,
+    was_controller: bool,
+}
+
+impl FailureDetector {
+    pub fn new(
+        id: u32,
+        address: String,
+        quorum: HashMap<u32, String>,
+        bootstrap_brokers: Vec<String>,
+        detector: Box<dyn HeartbeatDetector>,
+    ) -> FailureDetector {
+        println!("[initialized {}] starting...", id);
+        FailureDetector {
+            id,
+            address,
+            brokers: HashMap::new(),
+            quorum,
+            bootstrap_brokers,
+            controller: None,
+            epoch: None,
+            next_probe: 0,
+            detector,
+            synced_brokers: HashSet::new(),
+            unreachable: HashSet::new(),
+            was_controller: false,
+        }
+    }
+
+    pub fn address_of(&self, id: u32) -> Option<String> {
+        self.brokers
+            .get(&id)
+            .or_else(|| self.quorum.get(&id))
+            .cloned()
+    }
+
+    /// Brokers the controller takes as alive: itself and the registered
+    /// ones the detector doesn't suspect.
+    pub fn live_brokers(&self) -> Vec<u32> {
+        let mut live_brokers: Vec<u32> = self
+            .brokers
+            .keys()
+            .copied()
+            .filter(|id| {
+                *id == self.id
+                    || (self.detector.is_watching(*id) && !self.detector.is_suspected(*id))
+            })
+            .collect();
+        if !live_brokers.contains(&self.id) {
+            live_brokers.push(self.id);
+        }
+        live_brokers.sort_unstable();
+        live_brokers
+    }
+
+    /// Run every round this broker is the controller, with the brokers
+    /// registered in the metadata.
+    pub fn controller_round(&mut self, brokers: HashMap<u32, String>) -> Vec<u32> {
+        // Other brokers were sending heartbeats to the previous controller,
+        // so they get a full session to find this one. So do new brokers.
+        if !self.was_controller {
+            self.was_controller = true;
+            self.controller = Some(self.address.clone());
+            self.synced_brokers.clear();
+            for id in brokers.keys() {
+                self.detector.watch(*id);
+            }
+        }
+
+        for id in brokers.keys() {
+            if !self.detector.is_watching(*id) {
+                self.detector.watch(*id);
+            }
+        }
+        for id in self.brokers.keys() {
+            if !brokers.contains_key(id) {
+                self.detector.forget(*id);
+            }
+        }
+        self.synced_brokers.retain(|id| brokers.contains_key(id));
+        self.brokers = brokers;
+        self.live_brokers()
+    }
+
+    /// Run every round this broker isn't the controller. Returns where to
+    /// send a heartbeat, guessing among the brokers it knows when the
+    /// controller is unknown.
+    pub fn heartbeat_target(&mut self) -> Option<String> {
+        self.was_controller = false;
+        if self.controller.is_some() {
+            return self.controller.clone();
+        }
+
+        let mut candidates: Vec<String> = Vec::new();
+        let mut known_brokers: Vec<(&u32, &String)> =
+            self.quorum.iter().chain(self.brokers.iter()).collect();
+        known_brokers.sort();
+        for address in known_brokers
+            .into_iter()
+            .map(|(_, address)| address)
+            .chain(self.bootstrap_brokers.iter())
+        {
+            if *address != self.address && !candidates.contains(address) {
+                candidates.push(address.clone());
+            }
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let probe = candidates[self.next_probe % candidates.len()].clone();
+        self.next_probe = self.next_probe.wrapping_add(1);
+        Some(probe)
+    }
+
+    /// Learns where the controller is from the answer to a heartbeat.
+    pub fn controller_moved(&mut self, address: Option<String>) {
+        self.controller = address;
+    }
+
+    /// Takes the brokers the controller answered a heartbeat with. Returns
+    /// false when this broker is no longer one of them.
+    pub fn update_brokers(&mut self, broker_list: Vec<(u32, String)>) -> bool {
+        self.brokers = broker_list.into_iter().collect();
+        self.brokers.contains_key(&self.id)
+    }
+
+    /// A request to the broker at `address` failed. It's no longer taken as
+    /// the controller and, if this is the controller, it is sent every
+    /// partition state again, since it may have missed some.
+    pub fn peer_failed(&mut self, address: &str, err: &io::Error) {
+        if self.unreachable.insert(address.to_string()) {
+            println!("[broker {}] {} unreachable: {}", self.id, address, err);
+        }
+        if self.controller.as_deref() == Some(address) {
+            self.controller = None;
+        }
+        let brokers = &self.brokers;
+        self.synced_brokers
+            .retain(|id| brokers.get(id).map(String::as_str) != Some(address));
+    }
+
+    pub fn peer_reached(&mut self, address: &str) {
+        if self.unreachable.remove(address) {
+            println!("[broker {}] {} reachable again", self.id, address);
+        }
+    }
+
+    pub fn receive_signal(&mut self, id: u32) {
+        if self.brokers.contains_key(&id) {
+            self.detector.heartbeat(id);
+            println!("[received {}] Heartbeat from {}", self.id, id);
+        }
+    }
+}
+
+const BROKER_ID_FILE: &str = "broker_id";
+/// Times a broker shutting down asks the controller to move its partitions
+/// before it gives up and stops anyway.
+const CONTROLLED_SHUTDOWN_ATTEMPTS: usize = 3;
+
+pub struct Broker {
+    cluster: Cluster,
+    transactions: TransactionCoordinator,
+    replicas: ReplicaManager,
+    config: BrokerConfig,
+    // Topic configs as last sent by the controller.
+    topic_configs: RwLock<HashMap<String, HashMap<String, String>>>,
+    // The id configured or stored under the data directory, if any.
+    persisted_id: Option<u32>,
+    // Address to register with while this broker waits to join the cluster.
+    joining: Mutex<Option<String>>,
+    // Held while the controller decides on a membership change, so two
+    // brokers never get the same id.
+    membership: Mutex<()>,
+    failure_detector: Mutex<Option<FailureDetector>>,
+    controller: RwLock<Option<Arc<Controller>>>,
+    // Carries requests to the other brokers, see `ConnectionPool`.
+    peers: Arc<dyn Transport>,
+    clock: Arc<dyn Clock>,
+    // Limits copying for the replicas a reassignment adds to this broker.
+    reassignment_throttle: Option<Throttle>,
+    // Requests being handled, which a broker shutting down waits for.
+    in_flight: AtomicUsize,
+    shutdown_requested: AtomicBool,
+    stopped: AtomicBool,
+}
+
+impl Default for Broker {
+    fn default() -> Broker {
+        Broker::new()
+    }
+}
+
+impl Broker {
+    pub fn new() -> Broker {
+        Broker::with_config(BrokerConfig::default()).unwrap()
+    }
+
+    /// Creates a broker whose partitions are stored under `data_dir`.
+    pub fn open(data_dir: PathBuf) -> io::Result<Broker> {
+        Broker::with_config(BrokerConfig {
+            data_dir: Some(data_dir),
+            ..BrokerConfig::default()
+        })
+    }
+
+    pub fn with_config(config: BrokerConfig) -> io::Result<Broker> {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let peers = Arc::new(ConnectionPool::new(&config, clock.clone()));
+        Broker::with_transport(config, peers, clock)
+    }
+
+    /// Creates a broker that reaches the others through `peers` and measures
+    /// time, like how long brokers go without a heartbeat, with `clock`.
+    pub fn with_transport(
+        config: BrokerConfig,
+        peers: Arc<dyn Transport>,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<Broker> {
+        let (cluster, transactions) = match &config.data_dir {
+            Some(data_dir) => {
+                let cluster = Cluster::open(data_dir.clone())?;
+                let transactions = TransactionCoordinator::open(data_dir, &cluster)?;
+                (cluster, transactions)
+            }
+            None => (Cluster::new(), TransactionCoordinator::new()),
+        };
+
+        let stored_id = match &config.data_dir {
+            Some(data_dir) => match fs::read_to_string(data_dir.join(BROKER_ID_FILE)) {
+                Ok(value) => value.trim().parse::<u32>().ok(),
+                Err(_) => None,
+            },
+            None => None,
+        };
+        let persisted_id = match (config.broker_id, stored_id) {
+            (Some(broker_id), Some(stored_id)) if broker_id != stored_id => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("data directory belongs to broker {}", stored_id),
+                ));
+            }
+            (broker_id, stored_id) => broker_id.or(stored_id),
+        };
+
+        let reassignment_throttle = config
+            .reassignment_throttle
+            .map(|records_per_second| Throttle::new(clock.clone(), records_per_second));
+        Ok(Broker {
+            cluster,
+            transactions,
+            replicas: ReplicaManager::with_clock(clock.clone()),
+            config,
+            topic_configs: RwLock::new(HashMap::new()),
+            persisted_id,
+            joining: Mutex::new(None),
+            membership: Mutex::new(()),
+            failure_detector: Mutex::new(None),
+            controller: RwLock::new(None),
+            peers,
+            reassignment_throttle,
+            clock,
+            in_flight: AtomicUsize::new(0),
+            shutdown_requested: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// Answers a request from a client or another broker.
+    pub fn handle(&self, message: ActionMessage) -> Vec<ResponseMessage> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let response_list = self.handle_action(message);
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        response_list
+    }
+
+    fn handle_action(&self, message: ActionMessage) -> Vec<ResponseMessage> {
+        match message.action {
+            Action::Produce(topic, content, acks, header) => {
+                self.store_data(topic, content, acks, header)
+            }
+            Action::Consume(topic, offset, limit, isolation) => {
+                self.read_data(topic, offset, limit, isolation)
+            }
+            Action::CreateTopic(topic, partition_number, replication_factor, config_list) => {
+                self.add_topic(topic, partition_number, replication_factor, config_list)
+            }
+            Action::InitializeController(brokers) => self.init_controller(brokers),
+            Action::InitializeBroker(id, brokers) => self.init_broker(id, brokers),
+            Action::IamAlive(id, broker_epoch) => self.receive_signal(id, broker_epoch),
+            Action::CommitOffset(topic, offset) => {
+                self.commit_offset(message.consumer_id, topic, offset)
+            }
+            Action::CommittedOffset(topic) => self.committed_offset(message.consumer_id, topic),
+            Action::LatestOffset(topic) => self.latest_offset(topic),
+            Action::Metadata(topic) => self.metadata(topic),
+            Action::InitProducerId => self.init_producer_id(),
+            Action::BeginTransaction(transactional_id) => self.begin_transaction(transactional_id),
+            Action::AddPartitionsToTransaction(transactional_id, topic_list) => {
+                self.add_partitions_to_transaction(transactional_id, topic_list)
+            }
+            Action::AddOffsetToTransaction(transactional_id, topic, offset) => {
+                self.add_offset_to_transaction(transactional_id, message.consumer_id, topic, offset)
+            }
+            Action::EndTransaction(transactional_id, commit) => {
+                self.end_transaction(transactional_id, commit)
+            }
+            Action::LeaderAndIsr(broker_epoch, state_list) => {
+                self.leader_and_isr(broker_epoch, state_list)
+            }
+            Action::Fetch(topic, offset, replica_id, max_records) => {
+                self.fetch(topic, offset, replica_id, max_records)
+            }
+            Action::AlterIsr(state, broker_epoch) => self.alter_isr(state, broker_epoch),
+            Action::Raft(from, raft_message) => self.receive_raft(from, raft_message),
+            Action::RegisterBroker(broker_id, address, rack) => {
+                self.register_broker(broker_id, address, rack)
+            }
+            Action::UnregisterBroker(broker_id) => self.unregister_broker(broker_id),
+            Action::Forwarded(action) => self.receive_forwarded(*action),
+            Action::AlterConfigs(topic, config_list) => self.alter_configs(topic, config_list),
+            Action::TopicConfigs(topic, config_list) => self.topic_configs(topic, config_list),
+            Action::OffsetForLeaderEpoch(topic, leader_epoch) => {
+                self.offset_for_leader_epoch(topic, leader_epoch)
+            }
+            Action::Shutdown => self.request_shutdown(),
+            Action::ControlledShutdown(broker_id, broker_epoch) => {
+                self.controlled_shutdown(broker_id, broker_epoch)
+            }
+            Action::AlterPartitionReassignment(topic, replicas) => {
+                self.alter_partition_reassignment(topic, replicas)
+            }
+            Action::Rebalance(execute) => self.rebalance(execute),
+            Action::CreatePartitions(topic, partition_count) => {
+                self.create_partitions(topic, partition_count)
+            }
+            Action::DeleteTopic(topic) => self.delete_topic(topic),
+            Action::RemoveTopic(topic) => self.remove_topic(topic),
+            Action::DescribeConfigs(topic) => self.describe_configs(topic),
+            Action::Quit | Action::Invalid => Vec::new(),
+        }
+    }
+
+    /// Makes the background loops return and the broker ignore further requests.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Makes the failure detector loop shut this broker down, see `shutdown`.
+    pub fn request_shutdown(&self) -> Vec<ResponseMessage> {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        vec![]
+    }
+
+    /// Stops this broker without leaving what it leads unavailable: the
+    /// controller first moves its leaderships to other in-sync replicas, then
+    /// the requests being handled get `controlled_shutdown_timeout` to finish
+    /// and the logs are flushed. Partitions with no other in-sync replica
+    /// stay with this broker until the controller takes it as dead.
+    pub fn shutdown(&self) {
+        let broker_id = self.broker_id();
+        if let Some(broker_id) = broker_id {
+            self.hand_over_partitions(broker_id);
+        }
+        self.stop();
+
+        let deadline = Instant::now() + self.config.controlled_shutdown_timeout;
+        while self.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        if let Err(err) = self.cluster.flush() {
+            println!("Failed to flush partition logs\n{}", err);
+        }
+        if let Some(broker_id) = broker_id {
+            println!("[broker {}] shut down", broker_id);
+        }
+    }
+
+    fn hand_over_partitions(&self, broker_id: u32) {
+        for _ in 0..CONTROLLED_SHUTDOWN_ATTEMPTS {
+            let broker_epoch = match self.broker_epoch() {
+                Some(broker_epoch) => broker_epoch,
+                None => return,
+            };
+            let action = Action::ControlledShutdown(broker_id, broker_epoch);
+            let response_list = if self.is_controller() {
+                Ok(self.controlled_shutdown(broker_id, broker_epoch))
+            } else {
+                match self.controller_address() {
+                    Some(controller) => self.request(&controller, action),
+                    None => Err(io::Error::other("no controller")),
+                }
+            };
+
+            let mut handed_over = response_list.is_ok();
+            let mut state_list = Vec::new();
+            for response in response_list.unwrap_or_default() {
+                match response.response {
+                    Response::PartitionMetadata(state) => state_list.push(state),
+                    Response::Empty => {}
+                    _ => handed_over = false,
+                }
+            }
+            // The controller doesn't send partition states to fenced
+            // brokers, so this one learns it no longer leads from the answer.
+            if handed_over {
+                self.apply_states(state_list);
+                println!("[broker {}] handed its partitions over", broker_id);
+                return;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+        println!(
+            "[broker {}] shutting down without handing its partitions over",
+            broker_id
+        );
+    }
+
+    #[cfg(test)]
+    pub(crate) fn partition(&self, topic: &TopicAddress) -> Option<Arc<Partition>> {
+        self.cluster.get_partition(topic.clone())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn partition_state(&self, topic: &TopicAddress) -> Option<PartitionState> {
+        self.replicas.state(topic)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn partition_states(&self) -> Vec<PartitionState> {
+        self.replicas.states()
+    }
+
+    pub fn config(&self) -> &BrokerConfig {
+        &self.config
+    }
+
+    /// Whether this broker was part of a cluster before, going by the id
+    /// stored in its data directory.
+    pub fn has_stored_id(&self) -> bool {
+        self.config
+            .data_dir
+            .as_ref()
+            .is_some_and(|data_dir| data_dir.join(BROKER_ID_FILE).exists())
+    }
+
+    pub(crate) fn broker_id(&self) -> Option<u32> {
+        self.failure_detector
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|failure_detector| failure_detector.id)
+    }
+
+    pub(crate) fn broker_epoch(&self) -> Option<u32> {
+        self.failure_detector
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|failure_detector| failure_detector.epoch)
+    }
+
+    fn broker_address(&self, broker_id: u32) -> Option<String> {
+        self.failure_detector
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|failure_detector| failure_detector.address_of(broker_id))
+    }
+
+    /// Id and address of every broker in the cluster, as far as this one knows.
+    pub fn brokers(&self) -> Vec<(u32, String)> {
+        let mut broker_list: Vec<(u32, String)> =
+            match self.failure_detector.lock().unwrap().as_ref() {
+                Some(failure_detector) => failure_detector
+                    .brokers
+                    .iter()
+                    .map(|(broker_id, address)| (*broker_id, address.clone()))
+                    .collect(),
+                None => Vec::new(),
+            };
+        broker_list.sort();
+        broker_list
+    }
+
+    fn send_to_broker(&self, broker_id: u32, action: Action) {
+        if let Some(address) = self.broker_address(broker_id) {
+            self.send_to(&address, action);
+        }
+    }
+
+    /// Sends partition states to a broker along with the epoch the metadata
+    /// has for it. Fenced brokers get every state once they register again.
+    fn send_states(
+        &self,
+        metadata: &ClusterMetadata,
+        broker_id: u32,
+        state_list: Vec<PartitionState>,
+    ) {
+        if let Some(broker_epoch) = metadata.broker_epochs.get(&broker_id) {
+            self.send_to_broker(broker_id, Action::LeaderAndIsr(*broker_epoch, state_list));
+        }
+    }
+
+    fn send_to(&self, address: &str, action: Action) {
+        let _ = self.request(address, action);
+    }
+
+    /// Sends `action` to another broker over a pooled connection, telling the
+    /// failure detector whether it answered. Redirects aren't followed, since
+    /// brokers handle `AskTheController` themselves.
+    fn request(&self, address: &str, action: Action) -> io::Result<Vec<ResponseMessage>> {
+        let result = self.peers.request(address, action);
+        if let Some(failure_detector) = self.failure_detector.lock().unwrap().as_mut() {
+            match &result {
+                Ok(_) => failure_detector.peer_reached(address),
+                Err(err) => failure_detector.peer_failed(address, err),
+            }
+        }
+        result
+    }
+
+    fn send_raft_messages(&self, messages: Vec<(u32, RaftMessage)>) {
+        let broker_id = match self.broker_id() {
+            Some(broker_id) => broker_id,
+            None => return,
+        };
+
+        // Raft copes with lost messages, so an unreachable member is
+        // simply tried again with the next one.
+        for (to, message) in messages {
+            if let Some(address) = self.broker_address(to) {
+                self.send_to(&address, Action::Raft(broker_id, message));
+            }
+        }
+    }
+
+    pub(crate) fn controller(&self) -> Option<Arc<Controller>> {
+        self.controller.read().unwrap().clone()
+    }
+
+    /// Whether this broker leads the controller quorum.
+    pub fn is_controller(&self) -> bool {
+        self.controller()
+            .is_some_and(|controller| controller.is_leader())
+    }
+
+    /// Members of the quorum know the controller from Raft, the other
+    /// brokers from the answers to their heartbeats.
+    fn controller_address(&self) -> Option<String> {
+        match self.controller() {
+            Some(controller) => controller
+                .leader()
+                .and_then(|leader| self.broker_address(leader)),
+            None => self
+                .failure_detector
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|failure_detector| failure_detector.controller.clone()),
+        }
+    }
+
+    /// Tells the client where to send a request only the controller handles.
+    fn ask_the_controller(&self) -> Vec<ResponseMessage> {
+        match self.controller_address() {
+            Some(address) => vec![ResponseMessage::new(Response::AskTheController(address))],
+            None => vec![ResponseMessage::new(Response::Error)],
+        }
+    }
+
+    /// Passes an admin request only the controller handles on to it and
+    /// relays the answer. With forwarding off, tells the client where the
+    /// controller is instead.
+    fn forward_to_controller(&self, action: Action) -> Vec<ResponseMessage> {
+        if !self.config.forward_to_controller {
+            return self.ask_the_controller();
+        }
+
+        let address = match self.controller_address() {
+            Some(address) => address,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+        match self.request(&address, Action::Forwarded(Box::new(action))) {
+            Ok(response_list) => response_list,
+            Err(err) => {
+                println!("Failed to forward request to {}\n{}", address, err);
+                vec![ResponseMessage::new(Response::Error)]
+            }
+        }
+    }
+
+    /// An admin request forwarded by another broker. It isn't forwarded
+    /// again: a broker that isn't the controller either points to it.
+    pub fn receive_forwarded(&self, action: Action) -> Vec<ResponseMessage> {
+        if !self.is_controller() {
+            return self.ask_the_controller();
+        }
+
+        match action {
+            Action::CreateTopic(..)
+            | Action::UnregisterBroker(..)
+            | Action::AlterConfigs(..)
+            | Action::AlterPartitionReassignment(..)
+            | Action::Rebalance(..)
+            | Action::CreatePartitions(..)
+            | Action::DeleteTopic(..) => self.handle(ActionMessage::new(action, String::new())),
+            _ => vec![ResponseMessage::new(Response::Error)],
+        }
+    }
+
+    /// Stores `record` in the metadata log. Returns false when this broker
+    /// doesn't lead the quorum or a majority didn't store it in time.
+    fn commit_metadata(&self, record: MetadataRecord) -> bool {
+        let controller = match self.controller() {
+            Some(controller) => controller,
+            None => return false,
+        };
+        let ((index, term), messages) = match controller.propose(record) {
+            Some(proposal) => proposal,
+            None => return false,
+        };
+
+        self.send_raft_messages(messages);
+        controller.wait_for_commit(index, term, self.config.metadata_timeout)
+    }
+
+    pub fn init_controller(&self, brokers: Vec<String>) -> Vec<ResponseMessage> {
+        for (id, broker) in brokers[1..].iter().enumerate() {
+            let action = Action::InitializeBroker(id as u32 + 1, brokers.clone());
+            if let Err(err) = self.request(broker, action) {
+                println!("Failed to initialize broker {}\n{}", broker, err);
+            }
+        }
+
+        let response_list = self.init_broker(0, brokers);
+
+        // The broker that set the cluster up stands for election first, so
+        // the others don't have to wait for their election timeout.
+        if let Some(controller) = self.controller() {
+            self.send_raft_messages(controller.campaign());
+        }
+        response_list
+    }
+
+    /// The first `controller_quorum_size` brokers also join the controller
+    /// quorum. Every broker then registers with the controller like brokers
+    /// joining later do.
+    pub fn init_broker(&self, id: u32, brokers: Vec<String>) -> Vec<ResponseMessage> {
+        let address = match brokers.get(id as usize) {
+            Some(address) => address.clone(),
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+        if let Err(err) = self.store_broker_id(id) {
+            println!("Failed to store broker id\n{}", err);
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+
+        let quorum_size = usize::min(self.config.controller_quorum_size, brokers.len()) as u32;
+        if id < quorum_size {
+            let voters = (0..quorum_size).collect();
+            let data_dir = self.config.data_dir.as_deref();
+            match Controller::new(id, voters, data_dir, self.config.controller_seed) {
+                Ok(controller) => {
+                    self.controller
+                        .write()
+                        .unwrap()
+                        .replace(Arc::new(controller));
+                }
+                Err(err) => {
+                    println!("Failed to open controller state\n{}", err);
+                    return vec![ResponseMessage::new(Response::Error)];
+                }
+            }
+        }
+
+        let quorum = brokers
+            .into_iter()
+            .take(quorum_size as usize)
+            .enumerate()
+            .map(|(member, address)| (member as u32, address))
+            .collect();
+        let failure_detector = FailureDetector::new(
+            id,
+            address,
+            quorum,
+            self.config.bootstrap_brokers.clone(),
+            self.heartbeat_detector(),
+        );
+        self.failure_detector
+            .lock()
+            .unwrap()
+            .replace(failure_detector);
+        vec![]
+    }
+
+    /// Makes a broker configured with `bootstrap_brokers` register with the
+    /// cluster, advertising `address` unless `advertised_address` is set.
+    /// Run by `Server::start` once the address is bound.
+    pub fn join_cluster(&self, address: String) {
+        if self.config.bootstrap_brokers.is_empty() || self.broker_id().is_some() {
+            return;
+        }
+
+        let address = self.config.advertised_address.clone().unwrap_or(address);
+        self.joining.lock().unwrap().replace(address);
+    }
+
+    fn heartbeat_detector(&self) -> Box<dyn HeartbeatDetector> {
+        let clock = self.clock.clone();
+        match self.config.failure_detector {
+            DetectorKind::PhiAccrual => {
+                Box::new(PhiAccrualDetector::new(clock, self.config.phi_threshold))
+            }
+            DetectorKind::EventuallyPerfect => Box::new(EventuallyPerfectDetector::new(
+                clock,
+                self.config.session_timeout,
+                Duration::from_secs(1),
+            )),
+        }
+    }
+
+    fn store_broker_id(&self, broker_id: u32) -> io::Result<()> {
+        match &self.config.data_dir {
+            Some(data_dir) => fs::write(data_dir.join(BROKER_ID_FILE), broker_id.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Received by the controller from a broker starting up. A broker coming
+    /// back on a new address keeps its id; one without an id gets the id its
+    /// address was registered with, or a new one. Every registration gets a
+    /// new broker epoch. A broker registering while its previous epoch still
+    /// holds was restarted, so that epoch is fenced first and its partitions
+    /// are handled as if it had died.
+    pub fn register_broker(
+        &self,
+        broker_id: Option<u32>,
+        address: String,
+        rack: Option<String>,
+    ) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => return self.ask_the_controller(),
+        };
+
+        let _membership = self.membership.lock().unwrap();
+        let metadata = controller.metadata();
+        let registered_id = metadata
+            .brokers
+            .iter()
+            .find(|(_, registered)| **registered == address)
+            .map(|(registered_id, _)| *registered_id);
+        let broker_id = match (broker_id, registered_id) {
+            (Some(broker_id), Some(registered_id)) if broker_id != registered_id => {
+                return vec![ResponseMessage::new(Response::Error)];
+            }
+            (Some(broker_id), _) | (None, Some(broker_id)) => broker_id,
+            (None, None) => metadata.next_broker_id,
+        };
+
+        if metadata.is_unfenced(broker_id) {
+            if !self.commit_metadata(MetadataRecord::FenceBroker(broker_id)) {
+                return vec![ResponseMessage::new(Response::Error)];
+            }
+            self.elect_leaders(&self.live_brokers());
+        }
+        if !self.commit_metadata(MetadataRecord::RegisterBroker(
+            broker_id,
+            address.clone(),
+            rack,
+        )) {
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+        let broker_epoch = match controller.metadata().broker_epochs.get(&broker_id) {
+            Some(broker_epoch) => *broker_epoch,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+        println!(
+            "[controller] registered broker {} at {} in epoch {}",
+            broker_id, address, broker_epoch
+        );
+
+        // A broker registering again may have lost the partition states, so
+        // it gets all of them again, and a full session to send heartbeats.
+        if let Some(failure_detector) = self.failure_detector.lock().unwrap().as_mut() {
+            failure_detector.synced_brokers.remove(&broker_id);
+            failure_detector.detector.watch(broker_id);
+        }
+        vec![ResponseMessage::new(Response::BrokerId(
+            broker_id,
+            broker_epoch,
+        ))]
+    }
+
+    /// Received by the controller from a broker shutting down: fences it, so
+    /// it no longer leads or counts as in sync, and moves its leaderships to
+    /// other in-sync replicas. Answers with the states of the partitions it
+    /// holds a replica of, since fenced brokers aren't sent any.
+    pub fn controlled_shutdown(&self, broker_id: u32, broker_epoch: u32) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => return self.ask_the_controller(),
+        };
+
+        {
+            let _membership = self.membership.lock().unwrap();
+            match controller.metadata().broker_epochs.get(&broker_id) {
+                Some(current) if *current == broker_epoch => {
+                    if !self.commit_metadata(MetadataRecord::FenceBroker(broker_id)) {
+                        return vec![ResponseMessage::new(Response::Error)];
+                    }
+                    println!("[controller] broker {} shutting down", broker_id);
+                }
+                Some(_) => return vec![ResponseMessage::new(Response::StaleBrokerEpoch)],
+                // Fenced already, maybe by an attempt whose answer got lost.
+                None => {}
+            }
+        }
+
+        self.elect_leaders(&self.live_brokers());
+        controller
+            .metadata()
+            .partition_states()
+            .into_iter()
+            .filter(|state| state.replicas.contains(&broker_id))
+            .map(|state| ResponseMessage::new(Response::PartitionMetadata(state)))
+            .collect()
+    }
+
+    /// Takes a broker out of the cluster. It stops once a heartbeat tells it
+    /// so, and its partitions move to other replicas as if it had died.
+    /// Members of the controller quorum can't be removed.
+    pub fn unregister_broker(&self, broker_id: u32) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => return self.forward_to_controller(Action::UnregisterBroker(broker_id)),
+        };
+
+        let _membership = self.membership.lock().unwrap();
+        if !controller.metadata().brokers.contains_key(&broker_id)
+            || controller.voters().contains(&broker_id)
+            || !self.commit_metadata(MetadataRecord::UnregisterBroker(broker_id))
+        {
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+
+        println!("[controller] unregistered broker {}", broker_id);
+        vec![]
+    }
+
+    /// Asks the broker at `target` to register this one, following it to the
+    /// controller when it isn't. Returns the id and epoch given and the
+    /// controller address.
+    fn register(
+        &self,
+        target: String,
+        broker_id: Option<u32>,
+        address: String,
+    ) -> Option<(u32, u32, String)> {
+        let mut target = target;
+        for _ in 0..2 {
+            let response_list = self
+                .request(
+                    &target,
+                    Action::RegisterBroker(broker_id, address.clone(), self.config.rack.clone()),
+                )
+                .ok()?;
+            match response_list.into_iter().next().map(|r| r.response) {
+                Some(Response::BrokerId(broker_id, broker_epoch)) => {
+                    return Some((broker_id, broker_epoch, target))
+                }
+                Some(Response::AskTheController(controller)) => target = controller,
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /// Registers a broker started with `bootstrap_brokers`, trying each of
+    /// them until one leads to the controller.
+    fn join(&self, address: String) {
+        for target in self.config.bootstrap_brokers.iter() {
+            let (broker_id, broker_epoch, controller) =
+                match self.register(target.clone(), self.persisted_id, address.clone()) {
+                    Some(registration) => registration,
+                    None => continue,
+                };
+            if let Err(err) = self.store_broker_id(broker_id) {
+                println!("Failed to store broker id\n{}", err);
+                return;
+            }
+
+            println!("[broker {}] joined the cluster at {}", broker_id, address);
+            let mut failure_detector = FailureDetector::new(
+                broker_id,
+                address,
+                HashMap::new(),
+                self.config.bootstrap_brokers.clone(),
+                self.heartbeat_detector(),
+            );
+            failure_detector.epoch = Some(broker_epoch);
+            failure_detector.controller_moved(Some(controller));
+            self.failure_detector
+                .lock()
+                .unwrap()
+                .replace(failure_detector);
+            self.joining.lock().unwrap().take();
+            return;
+        }
+    }
+
+    /// A heartbeat from another broker. Only the controller takes them,
+    /// answering with every broker in the cluster; the others point the
+    /// sender to it. Heartbeats a registered broker sends in an epoch that
+    /// no longer holds don't count, it has to register again. One that was
+    /// removed still gets the brokers, to learn it has to stop.
+    pub fn receive_signal(&self, id: u32, broker_epoch: u32) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => return self.ask_the_controller(),
+        };
+        let metadata = controller.metadata();
+        if metadata.brokers.contains_key(&id)
+            && metadata.broker_epochs.get(&id) != Some(&broker_epoch)
+        {
+            return vec![ResponseMessage::new(Response::StaleBrokerEpoch)];
+        }
+
+        let mut locked_failure_detector = self.failure_detector.lock().unwrap();
+        let optional_failure_detector = locked_failure_detector.as_mut();
+        if let Some(failure_detector) = optional_failure_detector {
+            failure_detector.receive_signal(id);
+        }
+
+        metadata
+            .broker_list()
+            .into_iter()
+            .map(|(broker_id, address)| {
+                ResponseMessage::new(Response::BrokerAddress(broker_id, address))
+            })
+            .collect()
+    }
+
+    pub fn receive_raft(&self, from: u32, message: RaftMessage) -> Vec<ResponseMessage> {
+        if let Some(controller) = self.controller() {
+            self.send_raft_messages(controller.step(from, message));
+        }
+        vec![]
+    }
+
+    /// One tick of the controller quorum, run every `controller_tick`.
+    pub fn tick_controller(&self) {
+        self.controller_tick_round();
+        thread::sleep(self.config.controller_tick);
+    }
+
+    /// Cleans the partition logs every `log_cleanup_interval`.
+    pub fn clean_logs(&self) {
+        self.log_cleaning_round();
+        thread::sleep(self.config.log_cleanup_interval);
+    }
+
+    /// Applies the cleanup policy of their topic to every partition log,
+    /// see `Partition::clean`.
+    pub(crate) fn log_cleaning_round(&self) {
+        if let Err(err) = self.cluster.clean(SystemTime::now()) {
+            println!("Failed to clean partition logs\n{}", err);
+        }
+    }
+
+    pub(crate) fn controller_tick_round(&self) {
+        if let Some(controller) = self.controller() {
+            self.send_raft_messages(controller.tick());
+        }
+    }
+
+    pub fn loop_failure_detector(&self) {
+        self.failure_detector_round();
+        thread::sleep(self.config.heartbeat_interval);
+    }
+
+    /// One round of the failure detector: the controller fences dead brokers
+    /// and moves partitions away from them, the others register with it or
+    /// send it a heartbeat.
+    pub(crate) fn failure_detector_round(&self) {
+        if self.shutdown_requested.swap(false, Ordering::SeqCst) {
+            self.shutdown();
+            return;
+        }
+
+        let joining = self.joining.lock().unwrap().clone();
+        if let Some(address) = joining {
+            self.join(address);
+            return;
+        }
+
+        let controller = self.controller();
+        let is_controller = controller
+            .as_ref()
+            .is_some_and(|controller| controller.is_leader());
+        let mut live_brokers = None;
+        let mut registration = None;
+        let mut heartbeat = None;
+        {
+            let mut locked_failure_detector = self.failure_detector.lock().unwrap();
+            let optional_failure_detector = locked_failure_detector.as_mut();
+            if let Some(failure_detector) = optional_failure_detector {
+                if is_controller {
+                    let metadata = controller.as_ref().unwrap().metadata();
+                    let broker_epoch = metadata.broker_epochs.get(&failure_detector.id);
+                    if failure_detector.epoch.is_none()
+                        || failure_detector.epoch.as_ref() != broker_epoch
+                    {
+                        registration =
+                            Some((failure_detector.id, failure_detector.address.clone()));
+                    }
+                    live_brokers = Some(failure_detector.controller_round(metadata.brokers));
+                } else {
+                    if let Some(controller) = &controller {
+                        failure_detector.controller = controller
+                            .leader()
+                            .and_then(|leader| failure_detector.address_of(leader));
+                    }
+                    heartbeat = failure_detector.heartbeat_target().map(|target| {
+                        (
+                            failure_detector.id,
+                            failure_detector.address.clone(),
+                            failure_detector.epoch,
+                            target,
+                        )
+                    });
+                }
+            } else {
+                println!("[loop] Failure detector not instantiated");
+            }
+        }
+
+        if let Some((id, address)) = registration {
+            let response_list = self.register_broker(Some(id), address, self.config.rack.clone());
+            if let Some(Response::BrokerId(_, broker_epoch)) =
+                response_list.first().map(|r| &r.response)
+            {
+                self.set_epoch(Some(*broker_epoch));
+            }
+        }
+        match heartbeat {
+            Some((id, _, Some(broker_epoch), target)) => {
+                self.send_heartbeat(id, broker_epoch, target)
+            }
+            Some((id, address, None, target)) => {
+                let registration = self.register(target, Some(id), address);
+                if let Some(failure_detector) = self.failure_detector.lock().unwrap().as_mut() {
+                    failure_detector.epoch = registration.as_ref().map(|(_, epoch, _)| *epoch);
+                    failure_detector
+                        .controller_moved(registration.map(|(_, _, controller)| controller));
+                }
+            }
+            None => {}
+        }
+        if let Some(live_brokers) = live_brokers {
+            self.fence_brokers();
+            self.elect_leaders(&live_brokers);
+            self.complete_reassignments(&live_brokers);
+            self.sync_brokers(&live_brokers);
+        }
+    }
+
+    fn set_epoch(&self, broker_epoch: Option<u32>) {
+        if let Some(failure_detector) = self.failure_detector.lock().unwrap().as_mut() {
+            failure_detector.epoch = broker_epoch;
+        }
+    }
+
+    /// Sends a heartbeat to `target` and learns from the answer where the
+    /// controller is and which brokers are in the cluster. A broker no longer
+    /// in it was removed and stops. One the controller fenced registers again.
+    fn send_heartbeat(&self, id: u32, broker_epoch: u32, target: String) {
+        // An unreachable target was already reported, see `peer_failed`.
+        let response_list = match self.request(&target, Action::IamAlive(id, broker_epoch)) {
+            Ok(response_list) => response_list,
+            Err(_) => return,
+        };
+
+        let mut removed = false;
+        if let Some(failure_detector) = self.failure_detector.lock().unwrap().as_mut() {
+            let mut controller = Some(target);
+            let mut broker_list = Vec::new();
+            for response in response_list {
+                match response.response {
+                    Response::AskTheController(address) => controller = Some(address),
+                    Response::Error => controller = None,
+                    Response::BrokerAddress(broker_id, address) => {
+                        broker_list.push((broker_id, address))
+                    }
+                    Response::StaleBrokerEpoch => {
+                        println!(
+                            "[broker {}] fenced in epoch {}, registering again",
+                            id, broker_epoch
+                        );
+                        failure_detector.epoch = None;
+                    }
+                    _ => {}
+                }
+            }
+
+            failure_detector.controller_moved(controller);
+            if !broker_list.is_empty() {
+                removed = !failure_detector.update_brokers(broker_list);
+            }
+        }
+
+        if removed {
+            println!("[broker {}] removed from the cluster, stopping", id);
+            self.stop();
+        }
+    }
+
+    /// Run by the controller: fences the brokers the failure detector takes
+    /// as dead, so they can't shrink an ISR or take partition states until
+    /// they register again.
+    fn fence_brokers(&self) {
+        let controller = match self.controller() {
+            Some(controller) => controller,
+            None => return,
+        };
+
+        // Registrations would otherwise get fenced right away.
+        let _membership = self.membership.lock().unwrap();
+        let live_brokers = self.live_brokers();
+        let mut dead_brokers: Vec<u32> = controller
+            .metadata()
+            .broker_epochs
+            .keys()
+            .copied()
+            .filter(|broker| !live_brokers.contains(broker))
+            .collect();
+        dead_brokers.sort_unstable();
+        for broker in dead_brokers {
+            if self.commit_metadata(MetadataRecord::FenceBroker(broker)) {
+                println!("[controller] fenced broker {}", broker);
+            }
+        }
+    }
+
+    /// Run by the controller: hands the partitions led by a dead or fenced
+    /// broker to the first of their in-sync replicas still alive, in a new
+    /// leader epoch, and drops fenced brokers from the ISRs. Partitions
+    /// without a replica to take over are left alone until one comes back.
+    fn elect_leaders(&self, live_brokers: &[u32]) {
+        let (broker_id, controller) = match (self.broker_id(), self.controller()) {
+            (Some(broker_id), Some(controller)) => (broker_id, controller),
+            _ => return,
+        };
+
+        let metadata = controller.metadata();
+        let is_eligible =
+            |broker: &u32| live_brokers.contains(broker) && metadata.is_unfenced(*broker);
+        let mut state_list = Vec::new();
+        for mut state in metadata.partition_states() {
+            if is_eligible(&state.leader) {
+                let isr_size = state.isr.len();
+                state.isr.retain(|replica| metadata.is_unfenced(*replica));
+                if state.isr.len() != isr_size {
+                    state_list.push(state);
+                }
+                continue;
+            }
+
+            state.isr.retain(is_eligible);
+            if let Some(leader) = state.isr.first() {
+                println!(
+                    "[controller {}] moving {}-{} from broker {} to {}",
+                    broker_id, state.topic.name, state.topic.partition, state.leader, leader
+                );
+                state.leader = *leader;
+                state.leader_epoch += 1;
+                state_list.push(state);
+            }
+        }
+
+        if state_list.is_empty()
+            || !self.commit_metadata(MetadataRecord::PartitionStates(state_list.clone()))
+        {
+            return;
+        }
+
+        self.apply_states(state_list.clone());
+        for broker in live_brokers.iter().filter(|broker| **broker != broker_id) {
+            self.send_states(&metadata, *broker, state_list.clone());
+        }
+    }
+
+    /// Run by the controller: finishes the reassignments whose new replicas
+    /// all caught up, see `complete_reassignment`, so the old replicas drop
+    /// the partition.
+    fn complete_reassignments(&self, live_brokers: &[u32]) {
+        let (broker_id, controller) = match (self.broker_id(), self.controller()) {
+            (Some(broker_id), Some(controller)) => (broker_id, controller),
+            _ => return,
+        };
+
+        let metadata = controller.metadata();
+        let can_lead = |broker: u32| live_brokers.contains(&broker) && metadata.is_unfenced(broker);
+        let state_list: Vec<PartitionState> = metadata
+            .partition_states()
+            .iter()
+            .filter_map(|state| complete_reassignment(state, can_lead))
+            .collect();
+        if state_list.is_empty()
+            || !self.commit_metadata(MetadataRecord::PartitionStates(state_list.clone()))
+        {
+            return;
+        }
+
+        for state in &state_list {
+            println!(
+                "[controller {}] moved {}-{} to brokers {:?}",
+                broker_id, state.topic.name, state.topic.partition, state.replicas
+            );
+        }
+        self.apply_states(state_list.clone());
+        for broker in live_brokers.iter().filter(|broker| **broker != broker_id) {
+            self.send_states(&metadata, *broker, state_list.clone());
+        }
+    }
+
+    /// Run by the controller: sends every topic deletion, topic config and
+    /// partition state to the live brokers that may have missed some, because
+    /// they were down or another controller was in charge.
+    fn sync_brokers(&self, live_brokers: &[u32]) {
+        let (broker_id, controller) = match (self.broker_id(), self.controller()) {
+            (Some(broker_id), Some(controller)) => (broker_id, controller),
+            _ => return,
+        };
+        let unsynced_brokers: Vec<u32> = match self.failure_detector.lock().unwrap().as_ref() {
+            Some(failure_detector) => live_brokers
+                .iter()
+                .copied()
+                .filter(|broker| !failure_detector.synced_brokers.contains(broker))
+                .collect(),
+            None => return,
+        };
+        if unsynced_brokers.is_empty() {
+            return;
+        }
+
+        let metadata = controller.metadata();
+        let mut topic_list: Vec<&String> = metadata.configs.keys().collect();
+        topic_list.sort();
+        let mut deleted_list: Vec<&String> = metadata.deleted_topics.iter().collect();
+        deleted_list.sort();
+        let action_list =
+            |broker_epoch| {
+                let mut action_list: Vec<Action> = deleted_list
+                    .iter()
+                    .map(|topic| Action::RemoveTopic(topic.to_string()))
+                    .collect();
+                action_list.extend(topic_list.iter().map(|topic| {
+                    Action::TopicConfigs(topic.to_string(), metadata.config_list(topic))
+                }));
+                action_list.push(Action::LeaderAndIsr(
+                    broker_epoch,
+                    metadata.partition_states(),
+                ));
+                action_list
+            };
+        for broker in unsynced_brokers {
+            let broker_epoch = match metadata.broker_epochs.get(&broker) {
+                Some(broker_epoch) => *broker_epoch,
+                None => continue,
+            };
+            let address = match self.broker_address(broker) {
+                Some(address) => address,
+                None if broker == broker_id => String::new(),
+                None => continue,
+            };
+
+            let synced = action_list(broker_epoch).into_iter().all(|action| {
+                let response_list = if broker == broker_id {
+                    Ok(self.handle(ActionMessage::new(action, String::new())))
+                } else {
+                    self.request(&address, action)
+                };
+                response_list.is_ok_and(|response_list| {
+                    !response_list.iter().any(|response| {
+                        matches!(
+                            response.response,
+                            Response::Error | Response::StaleBrokerEpoch
+                        )
+                    })
+                })
+            });
+            if synced {
+                if let Some(failure_detector) = self.failure_detector.lock().unwrap().as_mut() {
+                    failure_detector.synced_brokers.insert(broker);
+                }
+            }
+        }
+    }
+
+    /// Only the leader of a replicated partition takes writes. `Acks::All`
+    /// is answered once every in-sync replica has the records, and refused
+    /// while fewer replicas than the topic's `min.insync.replicas` are in sync.
+    pub fn store_data(
+        &self,
+        topic: TopicAddress,
+        content: Vec<Content>,
+        acks: Acks,
+        header: Option<BatchHeader>,
+    ) -> Vec<ResponseMessage> {
+        if let Some(header) = header.filter(|header| header.transactional) {
+            if !self
+                .transactions
+                .is_part_of_transaction(header.producer_id, &topic)
+            {
+                return match acks {
+                    Acks::None => vec![],
+                    _ => vec![ResponseMessage::new(Response::InvalidTransactionState)],
+                };
+            }
+        }
+
+        if self.is_follower(&topic) {
+            return match acks {
+                Acks::None => vec![],
+                _ => vec![ResponseMessage::new(Response::NotLeader)],
+            };
+        }
+        let message_bytes: usize = content.iter().map(|content| content.value.len()).sum();
+        if message_bytes > self.topic_config(&topic.name, MAX_MESSAGE_BYTES) {
+            return match acks {
+                Acks::None => vec![],
+                _ => vec![ResponseMessage::new(Response::RecordTooLarge)],
+            };
+        }
+        let partition_state = self.replicas.state(&topic);
+        let min_insync_replicas: usize = self.topic_config(&topic.name, MIN_INSYNC_REPLICAS);
+        if acks == Acks::All && self.in_sync_replica_count(&topic) < min_insync_replicas {
+            return vec![ResponseMessage::new(Response::NotEnoughReplicas)];
+        }
+
+        let mut response = match self.cluster.add_content(topic.clone(), content, header) {
+            Some(Ok(AppendResult::Appended(offset))) => Response::Offset(offset),
+            Some(Ok(AppendResult::Duplicate(Some(offset)))) => Response::Offset(offset),
+            Some(Ok(AppendResult::Duplicate(None))) => Response::DuplicateSequence,
+            Some(Ok(AppendResult::OutOfOrderSequence)) => Response::OutOfOrderSequence,
+            Some(Err(err)) => {
+                println!("Failed to write partition log\n{}", err);
+                Response::Error
+            }
+            None => Response::UnknownTopic,
+        };
+
+        if let (Some(_), Response::Offset(offset)) = (&partition_state, &response) {
+            let partition = self.cluster.get_partition(topic.clone()).unwrap();
+            self.replicas.update_high_watermark(&topic, &partition);
+            if acks == Acks::All {
+                if !partition.wait_for_high_watermark(
+                    OffsetValue(offset.0 + 1),
+                    self.config.replication_timeout,
+                ) {
+                    // Another broker took over meanwhile, which the producer
+                    // retries with, as when leadership moves on shutdown.
+                    response = if self.is_follower(&topic) {
+                        Response::NotLeader
+                    } else {
+                        Response::Error
+                    };
+                } else if self.in_sync_replica_count(&topic) < min_insync_replicas {
+                    // The ISR shrank while waiting, so the records are
+                    // stored but on too few replicas.
+                    response = Response::NotEnoughReplicas;
+                }
+            }
+        }
+
+        match acks {
+            Acks::None => vec![],
+            _ => vec![ResponseMessage::new(response)],
+        }
+    }
+
+    /// The value a topic has for a config key, its default when unset.
+    fn topic_config<T: FromStr + Default>(&self, topic_name: &str, key: &str) -> T {
+        self.topic_configs
+            .read()
+            .unwrap()
+            .get(topic_name)
+            .and_then(|configs| configs.get(key))
+            .and_then(|value| value.parse::<T>().ok())
+            .or_else(|| default_topic_config(key).and_then(|value| value.parse::<T>().ok()))
+            .unwrap_or_default()
+    }
+
+    /// How the partitions of a topic are cleaned and stored, from its configs.
+    fn log_config(&self, topic_name: &str) -> LogConfig {
+        // -1 keeps records forever.
+        let retention_ms = self.topic_config::<i64>(topic_name, RETENTION_MS);
+        LogConfig {
+            retention: Some(retention_ms)
+                .filter(|retention_ms| *retention_ms >= 0)
+                .map(|retention_ms| Duration::from_millis(retention_ms as u64)),
+            cleanup_policy: self.topic_config(topic_name, CLEANUP_POLICY),
+            compression: self.topic_config(topic_name, COMPRESSION_TYPE),
+        }
+    }
+
+    /// The leader counts itself, so an unreplicated partition has one.
+    fn in_sync_replica_count(&self, topic: &TopicAddress) -> usize {
+        self.replicas
+            .state(topic)
+            .map_or(1, |state| state.isr.len())
+    }
+
+    pub fn begin_transaction(&self, transactional_id: String) -> Vec<ResponseMessage> {
+        match self.transactions.begin(&self.cluster, transactional_id) {
+            Ok(producer_id) => vec![ResponseMessage::new(Response::ProducerId(producer_id))],
+            Err(err) => Broker::transaction_error(err),
+        }
+    }
+
+    pub fn add_partitions_to_transaction(
+        &self,
+        transactional_id: String,
+        topic_list: Vec<TopicAddress>,
+    ) -> Vec<ResponseMessage> {
+        if topic_list
+            .iter()
+            .any(|topic| self.cluster.get_partition(topic.clone()).is_none())
+        {
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+
+        match self
+            .transactions
+            .add_partitions(&transactional_id, topic_list)
+        {
+            Ok(()) => vec![],
+            Err(err) => Broker::transaction_error(err),
+        }
+    }
+
+    pub fn add_offset_to_transaction(
+        &self,
+        transactional_id: String,
+        consumer_id: String,
+        topic: TopicAddress,
+        offset: OffsetValue,
+    ) -> Vec<ResponseMessage> {
+        match self
+            .transactions
+            .add_offset(&transactional_id, consumer_id, topic, offset)
+        {
+            Ok(()) => vec![],
+            Err(err) => Broker::transaction_error(err),
+        }
+    }
+
+    pub fn end_transaction(&self, transactional_id: String, commit: bool) -> Vec<ResponseMessage> {
+        match self
+            .transactions
+            .end(&self.cluster, &transactional_id, commit)
+        {
+            Ok(()) => vec![],
+            Err(err) => Broker::transaction_error(err),
+        }
+    }
+
+    fn transaction_error(err: TransactionError) -> Vec<ResponseMessage> {
+        match err {
+            TransactionError::InvalidState => {
+                vec![ResponseMessage::new(Response::InvalidTransactionState)]
+            }
+            TransactionError::Storage(err) => {
+                println!("Failed to store transaction\n{}", err);
+                vec![ResponseMessage::new(Response::Error)]
+            }
+        }
+    }
+
+    pub fn init_producer_id(&self) -> Vec<ResponseMessage> {
+        match self.cluster.next_producer_id() {
+            Ok(producer_id) => vec![ResponseMessage::new(Response::ProducerId(producer_id))],
+            Err(err) => {
+                println!("Failed to store producer id\n{}", err);
+                vec![ResponseMessage::new(Response::Error)]
+            }
+        }
+    }
+
+    pub fn read_data(
+        &self,
+        topic: TopicAddress,
+        offset: OffsetValue,
+        limit: u32,
+        isolation: Isolation,
+    ) -> Vec<ResponseMessage> {
+        if self.is_follower(&topic) {
+            return vec![ResponseMessage::new(Response::NotLeader)];
+        }
+
+        match self.cluster.get_partition(topic.clone()) {
+            Some(partition) => {
+                self.replicas.update_high_watermark(&topic, &partition);
+                partition.read(offset, limit, isolation)
+            }
+            .into_iter()
+            .map(|(offset, content)| ResponseMessage::new(Response::Content(offset, content)))
+            .collect(),
+            None => vec![ResponseMessage::new(Response::UnknownTopic)],
         }
     }
 
-    pub fn send_message(&mut self, message: ActionMessage) -> Vec<ResponseMessage> {
-        self.stream.write_all(&message.as_vec()[..]).unwrap();
-        self.stream.flush().unwrap();
+    pub fn commit_offset(
+        &self,
+        consumer_id: String,
+        topic: TopicAddress,
+        offset: OffsetValue,
+    ) -> Vec<ResponseMessage> {
+        match self.cluster.get_partition(topic.clone()) {
+            Some(_) => {
+                self.cluster.commit_offset(consumer_id, topic, offset);
+                vec![ResponseMessage::new(Response::Offset(offset))]
+            }
+            None => vec![ResponseMessage::new(Response::UnknownTopic)],
+        }
+    }
 
-        let mut buffer = [0; 1024];
-        let _ = match &self.stream.read(&mut buffer) {
-            Ok(value) => value,
-            Err(err) => {
-                println!("Failed to read stream\n{}", err);
-                return vec![ResponseMessage::new_empty()];
+    pub fn committed_offset(
+        &self,
+        consumer_id: String,
+        topic: TopicAddress,
+    ) -> Vec<ResponseMessage> {
+        match self.cluster.committed_offset(consumer_id, topic) {
+            Some(offset) => vec![ResponseMessage::new(Response::Offset(offset))],
+            None => vec![],
+        }
+    }
+
+    /// The offset after the last record consumers can see.
+    pub fn latest_offset(&self, topic: TopicAddress) -> Vec<ResponseMessage> {
+        if self.is_follower(&topic) {
+            return vec![ResponseMessage::new(Response::NotLeader)];
+        }
+
+        match self.cluster.get_partition(topic.clone()) {
+            Some(partition) => {
+                self.replicas.update_high_watermark(&topic, &partition);
+                let offset = partition
+                    .high_watermark()
+                    .unwrap_or_else(|| partition.end_offset());
+                vec![ResponseMessage::new(Response::Offset(offset))]
             }
+            None => vec![ResponseMessage::new(Response::UnknownTopic)],
+        }
+    }
+
+    /// The partition count of `topic`, followed by the address of every
+    /// broker and the leader and ISR of each partition when it is replicated.
+    pub fn metadata(&self, topic: String) -> Vec<ResponseMessage> {
+        let partition_count = self
+            .replicas
+            .partition_count(&topic)
+            .or_else(|| self.cluster.partition_count(&topic));
+        let partition_count = match partition_count {
+            Some(partition_count) => partition_count,
+            None => return vec![ResponseMessage::new(Response::UnknownTopic)],
         };
 
-        ResponseMessage::parse(&buffer)
+        let mut response_list = vec![ResponseMessage::new(Response::TopicMetadata(
+            partition_count,
+        ))];
+        for (broker_id, address) in self.brokers() {
+            response_list.push(ResponseMessage::new(Response::BrokerAddress(
+                broker_id, address,
+            )));
+        }
+
+        let mut state_list: Vec<PartitionState> = self
+            .replicas
+            .states()
+            .into_iter()
+            .filter(|state| state.topic.name == topic)
+            .collect();
+        state_list.sort_by_key(|state| state.topic.partition);
+        response_list.extend(
+            state_list
+                .into_iter()
+                .map(|state| ResponseMessage::new(Response::PartitionMetadata(state))),
+        );
+        response_list
     }
-}
 
-struct FailureDetector {
-    id: u32,
-    trusted: u32,
-    received: bool,
-    brokers: Vec<String>,
-    durations: Vec<Duration>,
-}
+    /// Run by the controller: places the partitions on the live brokers, each
+    /// led by a different one in turn with its followers in other racks, see
+    /// `place_replicas`, and tells every broker so the replicas create them.
+    /// The configs given, completed with the broker's `topic_defaults`, are
+    /// stored first, so they apply from the first record.
+    pub fn add_topic(
+        &self,
+        topic: String,
+        partition_number: u32,
+        replication_factor: u32,
+        config_list: Vec<(String, String)>,
+    ) -> Vec<ResponseMessage> {
+        if !TopicAddress::is_valid_name(&topic) {
+            return vec![ResponseMessage::new(Response::InvalidTopic)];
+        }
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => {
+                return self.forward_to_controller(Action::CreateTopic(
+                    topic,
+                    partition_number,
+                    replication_factor,
+                    config_list,
+                ))
+            }
+        };
+        let broker_id = match self.broker_id() {
+            Some(broker_id) => broker_id,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+        let live_brokers = self.live_brokers();
+        let metadata = controller.metadata();
 
-impl FailureDetector {
-    pub fn new(id: u32, brokers: Vec<String>) -> FailureDetector {
-        let durations = vec![Duration::from_secs(3); brokers.len()];
+        if replication_factor == 0
+            || replication_factor as usize > live_brokers.len()
+            || metadata.has_topic(&topic)
+        {
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+        if let Err(reason) = validate_topic_configs(&config_list) {
+            return vec![ResponseMessage::new(Response::InvalidConfig(reason))];
+        }
+        let mut config_list = config_list;
+        for (key, value) in self.config.topic_defaults.iter() {
+            if !config_list.iter().any(|(given, _)| given == key) {
+                config_list.push((key.clone(), value.clone()));
+            }
+        }
+        if !self.store_topic_configs(broker_id, &controller, &topic, config_list) {
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+        self.place_partitions(
+            broker_id,
+            &metadata,
+            &live_brokers,
+            &topic,
+            0..partition_number,
+            replication_factor,
+        )
+    }
 
-        println!("[initialized {}] starting...", id);
-        FailureDetector {
-            id,
-            trusted: 0,
-            received: true,
-            brokers,
-            durations,
+    /// Run by the controller: grows a topic to `partition_count` partitions.
+    /// The new ones start empty, with as many replicas as the first one,
+    /// while the existing ones keep their records and offsets. Records with
+    /// a key may go to another partition than before, see `KeyHashPartitioner`.
+    pub fn create_partitions(&self, topic: String, partition_count: u32) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => {
+                return self.forward_to_controller(Action::CreatePartitions(topic, partition_count))
+            }
+        };
+        let broker_id = match self.broker_id() {
+            Some(broker_id) => broker_id,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+        let live_brokers = self.live_brokers();
+        let metadata = controller.metadata();
+
+        let state_list: Vec<PartitionState> = metadata
+            .partition_states()
+            .into_iter()
+            .filter(|state| state.topic.name == topic)
+            .collect();
+        let current_count = state_list.len() as u32;
+        // Replicas a reassignment is dropping don't count.
+        let replication_factor = match state_list.first() {
+            Some(state) => (state.replicas.len() - state.removing_replicas.len()) as u32,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+        if partition_count <= current_count || replication_factor as usize > live_brokers.len() {
+            return vec![ResponseMessage::new(Response::Error)];
         }
+
+        println!(
+            "[controller {}] growing {} from {} to {} partitions",
+            broker_id, topic, current_count, partition_count
+        );
+        self.place_partitions(
+            broker_id,
+            &metadata,
+            &live_brokers,
+            &topic,
+            current_count..partition_count,
+            replication_factor,
+        )
     }
 
-    pub fn is_leader(&self) -> bool {
-        self.id == self.trusted
+    /// Places new partitions of a topic, stores them and tells every live
+    /// broker so the replicas create them.
+    fn place_partitions(
+        &self,
+        broker_id: u32,
+        metadata: &ClusterMetadata,
+        live_brokers: &[u32],
+        topic: &str,
+        partitions: Range<u32>,
+        replication_factor: u32,
+    ) -> Vec<ResponseMessage> {
+        // Each batch of partitions starts where the previous one stopped, so
+        // leadership doesn't pile up on the first brokers.
+        let first = metadata.partitions.len();
+        let state_list: Vec<PartitionState> = place_replicas(
+            live_brokers,
+            &metadata.racks,
+            first,
+            partitions.len() as u32,
+            replication_factor,
+        )
+        .into_iter()
+        .zip(partitions)
+        .map(|(replicas, partition)| {
+            PartitionState::new(
+                TopicAddress::new(topic.to_string(), partition),
+                replicas[0],
+                replicas,
+            )
+        })
+        .collect();
+
+        if !self.commit_metadata(MetadataRecord::PartitionStates(state_list.clone())) {
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+
+        let response_list = self.apply_states(state_list.clone());
+        for broker in live_brokers.iter().filter(|broker| **broker != broker_id) {
+            self.send_states(metadata, *broker, state_list.clone());
+        }
+        response_list
     }
 
-    pub fn get_lead_address(&self) -> String {
-        self.brokers.get(self.trusted as usize).unwrap().clone()
+    /// Run by the controller: stores config keys of an existing topic and
+    /// sends its configs to every live broker, which apply them right away.
+    /// Unknown keys and invalid values are refused before anything is stored.
+    pub fn alter_configs(
+        &self,
+        topic: String,
+        config_list: Vec<(String, String)>,
+    ) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => return self.forward_to_controller(Action::AlterConfigs(topic, config_list)),
+        };
+        let broker_id = match self.broker_id() {
+            Some(broker_id) => broker_id,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+
+        if !controller.metadata().has_topic(&topic) {
+            return vec![ResponseMessage::new(Response::UnknownTopic)];
+        }
+        if let Err(reason) = validate_topic_configs(&config_list) {
+            return vec![ResponseMessage::new(Response::InvalidConfig(reason))];
+        }
+        if !self.store_topic_configs(broker_id, &controller, &topic, config_list) {
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+        vec![]
     }
 
-    pub fn run_loop(&mut self) -> Duration {
-        if self.trusted == self.id {
-            self.send_messages();
-            return Duration::from_secs(1);
-        } else if self.trusted < self.id {
-            self.check_received_message();
-            return *self.durations.get(self.trusted as usize).unwrap();
+    /// Stores config keys of a topic and sends its configs to every live
+    /// broker. Returns false when the quorum didn't store them.
+    fn store_topic_configs(
+        &self,
+        broker_id: u32,
+        controller: &Controller,
+        topic: &str,
+        config_list: Vec<(String, String)>,
+    ) -> bool {
+        if config_list.is_empty() {
+            return true;
+        }
+        for (key, value) in config_list {
+            if !self.commit_metadata(MetadataRecord::TopicConfig(topic.to_string(), key, value)) {
+                return false;
+            }
+        }
+
+        let config_list = controller.metadata().config_list(topic);
+        self.topic_configs(topic.to_string(), config_list.clone());
+        for broker in self
+            .live_brokers()
+            .iter()
+            .filter(|broker| **broker != broker_id)
+        {
+            self.send_to_broker(
+                *broker,
+                Action::TopicConfigs(topic.to_string(), config_list.clone()),
+            );
         }
-        Duration::from_secs(10)
+        true
     }
 
-    fn send_messages(&self) {
-        for broker in self.brokers[(self.id as usize + 1)..].iter() {
-            let mut client = Client::new(broker.clone());
-            client.send_message(ActionMessage::new(Action::IamAlive(self.id), String::new()));
-            client.send_message(ActionMessage::new(Action::Quit, String::new()));
-            println!("[sent {}] I am Alive to {}", self.id, &broker);
+    /// Every config key of a topic with the value it has on this broker,
+    /// its default when unset.
+    pub fn describe_configs(&self, topic: String) -> Vec<ResponseMessage> {
+        if self
+            .replicas
+            .partition_count(&topic)
+            .or_else(|| self.cluster.partition_count(&topic))
+            .is_none()
+        {
+            return vec![ResponseMessage::new(Response::UnknownTopic)];
         }
+
+        let topic_configs = self.topic_configs.read().unwrap();
+        let configs = topic_configs.get(&topic);
+        TOPIC_CONFIG_DEFAULTS
+            .iter()
+            .map(|(key, default)| {
+                let value = configs
+                    .and_then(|configs| configs.get(*key))
+                    .map_or(default.to_string(), String::clone);
+                ResponseMessage::new(Response::Config(key.to_string(), value))
+            })
+            .collect()
     }
 
-    fn check_received_message(&mut self) {
-        if self.received {
-            self.received = false;
-            println!(
-                "[validation {}] Message received from {}",
-                self.id, self.trusted
-            );
-        } else {
-            self.trusted += 1;
-            println!(
-                "[validation {}] Message NOT received from {}. Updating trusted to {}",
-                self.id,
-                self.trusted - 1,
-                self.trusted
-            );
+    /// Run by the controller: starts moving a partition to the `replicas`
+    /// given. A leader moved off hands over to the first of them once the
+    /// move completes. Refused for partitions being moved already and for brokers that
+    /// aren't registered.
+    pub fn alter_partition_reassignment(
+        &self,
+        topic: TopicAddress,
+        replicas: Vec<u32>,
+    ) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => {
+                return self
+                    .forward_to_controller(Action::AlterPartitionReassignment(topic, replicas))
+            }
+        };
+
+        let metadata = controller.metadata();
+        let mut distinct = replicas.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if metadata
+            .partitions
+            .get(&topic)
+            .is_none_or(|state| state.is_reassigning())
+            || replicas.is_empty()
+            || distinct.len() != replicas.len()
+            || !replicas
+                .iter()
+                .all(|replica| metadata.brokers.contains_key(replica))
+        {
+            return vec![ResponseMessage::new(Response::Error)];
         }
+        self.start_reassignments(vec![(topic, replicas)])
     }
 
-    pub fn receive_signal(&mut self, id: u32) {
-        if id == self.trusted {
-            self.received = true;
-            println!("[received {}] I am Alive from TRUSTED {}", self.id, id);
-        } else if id < self.trusted {
-            let duration = self.durations.get_mut(id as usize).unwrap();
-            *duration = Duration::from_secs(duration.as_secs() + 1);
-            self.trusted = id;
-            self.received = true;
-            println!(
-                "[received {}] I am Alive from PREVIOUS TRUSTED {}",
-                self.id, id
-            );
-        } else {
-            println!(
-                "[received {}] I am Alive from SOMETHING WRONG {}",
-                self.id, id
-            );
+    /// Run by the controller: proposes reassignments that even out how many
+    /// replicas each broker holds, see `plan_rebalance`, and starts them
+    /// when `execute` is set.
+    pub fn rebalance(&self, execute: bool) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => return self.forward_to_controller(Action::Rebalance(execute)),
+        };
+
+        let plan = plan_rebalance(&controller.metadata());
+        if execute {
+            return self.start_reassignments(plan);
         }
+        plan.into_iter()
+            .map(|(topic, replicas)| ResponseMessage::new(Response::Reassignment(topic, replicas)))
+            .collect()
     }
-}
 
-#[derive(Default)]
-pub struct Broker {
-    cluster: Cluster,
-    failure_detector: Mutex<Option<FailureDetector>>,
-}
+    /// Stores the reassignments and sends them to every live broker, so the
+    /// new replicas start copying the partitions. A partition moved to the
+    /// brokers already holding it is left as it is.
+    fn start_reassignments(&self, plan: Vec<(TopicAddress, Vec<u32>)>) -> Vec<ResponseMessage> {
+        let (broker_id, controller) = match (self.broker_id(), self.controller()) {
+            (Some(broker_id), Some(controller)) => (broker_id, controller),
+            _ => return vec![ResponseMessage::new(Response::Error)],
+        };
 
-impl Broker {
-    pub fn new() -> Broker {
-        let cluster = Cluster::new();
-        let failure_detector = Mutex::new(None);
-        Broker {
-            cluster,
-            failure_detector,
+        let metadata = controller.metadata();
+        let state_list: Vec<PartitionState> = plan
+            .iter()
+            .filter_map(|(topic, replicas)| {
+                metadata
+                    .partitions
+                    .get(topic)
+                    .map(|state| start_reassignment(state, replicas))
+            })
+            .filter(|state| state.is_reassigning())
+            .collect();
+        if !state_list.is_empty() {
+            if !self.commit_metadata(MetadataRecord::PartitionStates(state_list.clone())) {
+                return vec![ResponseMessage::new(Response::Error)];
+            }
+            self.apply_states(state_list.clone());
+            for broker in self
+                .live_brokers()
+                .iter()
+                .filter(|broker| **broker != broker_id)
+            {
+                self.send_states(&metadata, *broker, state_list.clone());
+            }
         }
+
+        plan.into_iter()
+            .map(|(topic, replicas)| ResponseMessage::new(Response::Reassignment(topic, replicas)))
+            .collect()
     }
 
-    pub fn init_controller(&self, brokers: Vec<String>) -> Vec<ResponseMessage> {
-        for (id, broker) in brokers[1..].iter().enumerate() {
-            let mut client = Client::new(broker.clone());
-            client.send_message(ActionMessage::new(
-                Action::InitializeBroker(id as u32 + 1, brokers.clone()),
-                String::new(),
-            ));
-            client.send_message(ActionMessage::new(Action::Quit, String::new()));
+    /// Run by the controller: deletes a topic and tells every live broker to
+    /// drop its partitions. Brokers that miss it drop them once they are
+    /// synced again, see `sync_brokers`.
+    pub fn delete_topic(&self, topic: String) -> Vec<ResponseMessage> {
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => return self.forward_to_controller(Action::DeleteTopic(topic)),
+        };
+        let broker_id = match self.broker_id() {
+            Some(broker_id) => broker_id,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+
+        if !controller.metadata().has_topic(&topic) {
+            return vec![ResponseMessage::new(Response::UnknownTopic)];
+        }
+        if !self.commit_metadata(MetadataRecord::DeleteTopic(topic.clone())) {
+            return vec![ResponseMessage::new(Response::Error)];
         }
 
-        let failure_detector = FailureDetector::new(0, brokers);
-        self.failure_detector
-            .lock()
+        println!("[controller {}] deleted topic {}", broker_id, topic);
+        let response_list = self.remove_topic(topic.clone());
+        for broker in self
+            .live_brokers()
+            .iter()
+            .filter(|broker| **broker != broker_id)
+        {
+            self.send_to_broker(*broker, Action::RemoveTopic(topic.clone()));
+        }
+        response_list
+    }
+
+    /// Drops a topic the controller deleted, along with the offsets
+    /// consumers committed for it. Its files are deleted in the background.
+    pub fn remove_topic(&self, topic: String) -> Vec<ResponseMessage> {
+        self.replicas.remove_topic(&topic);
+        self.topic_configs.write().unwrap().remove(&topic);
+        if let Err(err) = self.cluster.remove_topic(&topic) {
+            println!("Failed to remove topic\n{}", err);
+            return vec![ResponseMessage::new(Response::Error)];
+        }
+        vec![]
+    }
+
+    /// Takes the configs of a topic from the controller.
+    pub fn topic_configs(
+        &self,
+        topic: String,
+        config_list: Vec<(String, String)>,
+    ) -> Vec<ResponseMessage> {
+        self.topic_configs
+            .write()
             .unwrap()
-            .replace(failure_detector);
+            .insert(topic.clone(), config_list.into_iter().collect());
+        self.cluster
+            .configure_topic(&topic, self.log_config(&topic));
         vec![]
     }
 
-    pub fn init_broker(&self, id: u32, brokers: Vec<String>) -> Vec<ResponseMessage> {
-        let failure_detector = FailureDetector::new(id, brokers);
+    fn live_brokers(&self) -> Vec<u32> {
         self.failure_detector
             .lock()
             .unwrap()
-            .replace(failure_detector);
-        vec![]
+            .as_ref()
+            .map(|failure_detector| failure_detector.live_brokers())
+            .unwrap_or_default()
     }
 
-    pub fn receive_signal(&self, id: u32) -> Vec<ResponseMessage> {
-        let mut locked_failure_detector = self.failure_detector.lock().unwrap();
-        let optional_failure_detector = locked_failure_detector.as_mut();
-        if let Some(failure_detector) = optional_failure_detector {
-            failure_detector.receive_signal(id);
+    /// Takes the leader and ISR of partitions from the controller, unless
+    /// they were sent to an earlier epoch of this broker.
+    pub fn leader_and_isr(
+        &self,
+        broker_epoch: u32,
+        state_list: Vec<PartitionState>,
+    ) -> Vec<ResponseMessage> {
+        if self.broker_epoch() != Some(broker_epoch) {
+            return vec![ResponseMessage::new(Response::StaleBrokerEpoch)];
         }
-        vec![]
+        self.apply_states(state_list)
     }
 
-    pub fn loop_failure_detector(&self) {
-        let mut duration = Duration::from_secs(2);
-        {
-            let mut locked_failure_detector = self.failure_detector.lock().unwrap();
-            let optional_failure_detector = locked_failure_detector.as_mut();
-            if let Some(failure_detector) = optional_failure_detector {
-                duration = failure_detector.run_loop();
+    /// Applies partition states decided by the controller, creating the
+    /// partitions this broker holds a replica of.
+    fn apply_states(&self, state_list: Vec<PartitionState>) -> Vec<ResponseMessage> {
+        let broker_id = match self.broker_id() {
+            Some(broker_id) => broker_id,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+
+        for state in state_list {
+            let partition = if state.replicas.contains(&broker_id) {
+                match self.cluster.add_partition(state.topic.clone()) {
+                    Ok(partition) => {
+                        partition.advance_high_watermark(OffsetValue(0));
+                        Some(partition)
+                    }
+                    Err(err) => {
+                        println!("Failed to create partition\n{}", err);
+                        return vec![ResponseMessage::new(Response::Error)];
+                    }
+                }
             } else {
-                println!("[loop] Failure detector not instantiated");
+                None
+            };
+
+            let (leader, leader_epoch) = (state.leader, state.leader_epoch);
+            let topic = state.topic.clone();
+            let is_replica = state.replicas.contains(&broker_id);
+            if !self.replicas.apply(broker_id, state) {
+                continue;
+            }
+            // A reassignment moved the partition off this broker.
+            if !is_replica {
+                if let Err(err) = self.cluster.remove_partition(&topic) {
+                    println!("Failed to remove partition\n{}", err);
+                    return vec![ResponseMessage::new(Response::Error)];
+                }
+                continue;
+            }
+            if leader != broker_id {
+                continue;
+            }
+            if let Some(Err(err)) =
+                partition.map(|partition| partition.assign_leader_epoch(leader_epoch))
+            {
+                println!("Failed to store leader epoch\n{}", err);
+                return vec![ResponseMessage::new(Response::Error)];
             }
         }
-
-        thread::sleep(duration);
+        vec![]
     }
 
-    pub fn store_data(&self, topic: TopicAddress, content: Vec<Content>) -> Vec<ResponseMessage> {
-        match self.cluster.add_content(topic, content) {
-            Some(offset) => vec![ResponseMessage::new(Response::Offset(offset))],
-            None => vec![ResponseMessage::new(Response::Error)],
+    /// Hands a follower the batches from `offset` on, preceded by the high watermark.
+    pub fn fetch(
+        &self,
+        topic: TopicAddress,
+        offset: OffsetValue,
+        replica_id: u32,
+        max_records: u32,
+    ) -> Vec<ResponseMessage> {
+        let partition = match self.led_partition(&topic) {
+            Some(partition) => partition,
+            None if self.is_follower(&topic) => {
+                return vec![ResponseMessage::new(Response::NotLeader)]
+            }
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+
+        if let Some(state) = self
+            .replicas
+            .record_fetch(&topic, replica_id, offset, &partition)
+        {
+            self.report_isr_change(state);
         }
+        self.replicas.update_high_watermark(&topic, &partition);
+
+        let high_watermark = partition
+            .high_watermark()
+            .unwrap_or_else(|| partition.end_offset());
+        let mut response_list = vec![ResponseMessage::new(Response::HighWatermark(
+            high_watermark,
+        ))];
+        response_list.extend(
+            partition
+                .read_batches(offset, max_records)
+                .into_iter()
+                .map(|batch| ResponseMessage::new(Response::RecordBatch(batch))),
+        );
+        response_list
     }
 
-    pub fn read_data(
+    /// Tells a follower where `leader_epoch` ends in the log of this leader,
+    /// see `Partition::epoch_end_offset`.
+    pub fn offset_for_leader_epoch(
         &self,
         topic: TopicAddress,
-        offset: OffsetValue,
-        limit: u32,
+        leader_epoch: u32,
     ) -> Vec<ResponseMessage> {
-        let mut content_list = Vec::new();
+        let partition = match self.led_partition(&topic) {
+            Some(partition) => partition,
+            None if self.is_follower(&topic) => {
+                return vec![ResponseMessage::new(Response::NotLeader)]
+            }
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
 
-        match self.cluster.get_partition(topic) {
-            Some(partition) => {
-                let locked_partition = partition.queue.lock().unwrap();
+        let (found_epoch, end_offset) = partition.epoch_end_offset(leader_epoch);
+        vec![ResponseMessage::new(Response::EpochEndOffset(
+            found_epoch,
+            end_offset,
+        ))]
+    }
 
-                if locked_partition.is_empty() {
-                    return content_list;
-                }
+    /// Received by the controller when a partition leader changes its ISR,
+    /// so the other brokers learn about it too and answer metadata requests
+    /// with the current ISR. Answers nothing once the change is stored.
+    pub fn alter_isr(&self, state: PartitionState, broker_epoch: u32) -> Vec<ResponseMessage> {
+        let broker_id = match self.broker_id() {
+            Some(broker_id) => broker_id,
+            None => return vec![ResponseMessage::new(Response::Error)],
+        };
+        let controller = match self.controller() {
+            Some(controller) if controller.is_leader() => controller,
+            _ => return self.ask_the_controller(),
+        };
 
-                let range_end = usize::min((offset.0 + limit) as usize, locked_partition.len());
-                let range_start = usize::min(offset.0 as usize, range_end - 1);
-                let mut position = offset.0 as u32;
+        // A fenced leader no longer decides anything, and one that was
+        // replaced in the meantime no longer decides the ISR.
+        let metadata = controller.metadata();
+        if metadata.broker_epochs.get(&state.leader) != Some(&broker_epoch) {
+            return vec![ResponseMessage::new(Response::StaleBrokerEpoch)];
+        }
+        let mut current = match metadata.partitions.get(&state.topic) {
+            Some(current)
+                if current.leader == state.leader && current.leader_epoch == state.leader_epoch =>
+            {
+                current.clone()
+            }
+            _ => return vec![ResponseMessage::new(Response::Error)],
+        };
+        // Only the ISR is the leader's to change, so the replicas of a
+        // reassignment it didn't hear of yet are kept.
+        current.isr = state
+            .isr
+            .into_iter()
+            .filter(|replica| current.replicas.contains(replica))
+            .collect();
+        let state = current;
+        if !self.commit_metadata(MetadataRecord::PartitionStates(vec![state.clone()])) {
+            return vec![ResponseMessage::new(Response::Error)];
+        }
 
-                for value in locked_partition[range_start..range_end].iter() {
-                    content_list.push(ResponseMessage::new(Response::Content(
-                        OffsetValue(position),
-                        value.clone(),
-                    )));
-                    position += 1;
-                }
-                content_list
+        if state.leader != broker_id {
+            self.replicas.apply(broker_id, state.clone());
+        }
+        for broker in self.live_brokers() {
+            if broker != state.leader && broker != broker_id {
+                self.send_states(&metadata, broker, vec![state.clone()]);
+            }
+        }
+        vec![]
+    }
+
+    /// One round of replication: copies new batches of the partitions this
+    /// broker follows and drops lagging followers of the ones it leads.
+    pub fn replicate(&self) {
+        if !self.replication_round() {
+            thread::sleep(self.config.replica_fetch_interval);
+        }
+    }
+
+    /// Fetches once from the leader of every partition followed. Returns
+    /// whether any records came in.
+    pub(crate) fn replication_round(&self) -> bool {
+        let broker_id = match self.broker_id() {
+            Some(broker_id) => broker_id,
+            None => return false,
+        };
+
+        let mut fetched = false;
+        for state in self.replicas.followed_partitions(broker_id) {
+            fetched = self.fetch_from_leader(broker_id, state) || fetched;
+        }
+
+        for state in self
+            .replicas
+            .shrink_isr(broker_id, self.config.replica_lag_time)
+        {
+            if !self.report_isr_change(state.clone()) {
+                continue;
+            }
+            self.replicas.update_isr(state.clone());
+            if let Some(partition) = self.cluster.get_partition(state.topic.clone()) {
+                self.replicas
+                    .update_high_watermark(&state.topic, &partition);
             }
-            None => vec![ResponseMessage::new(Response::Error)],
         }
+        fetched
     }
 
-    pub fn add_topic(&self, topic: String, partition_number: u32) -> Vec<ResponseMessage> {
+    fn fetch_from_leader(&self, broker_id: u32, state: PartitionState) -> bool {
+        let partition = self.cluster.get_partition(state.topic.clone());
+        let address = self.broker_address(state.leader);
+        let (partition, address) = match (partition, address) {
+            (Some(partition), Some(address)) => (partition, address),
+            _ => return false,
+        };
+
         if self
-            .failure_detector
-            .lock()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .is_leader()
+            .replicas
+            .needs_truncation(&state.topic, state.leader_epoch)
         {
-            self.cluster.add_topic(topic, partition_number as usize);
-            vec![]
-        } else {
-            vec![ResponseMessage::new(Response::AskTheController(
-                self.failure_detector
-                    .lock()
-                    .unwrap()
-                    .as_ref()
-                    .unwrap()
-                    .get_lead_address(),
-            ))]
+            if !self.truncate_to_leader(&partition, &address, &state.topic) {
+                return false;
+            }
+            self.replicas.truncated(&state.topic, state.leader_epoch);
+        }
+
+        let mut max_records = self.config.replica_fetch_max_records;
+        if let Some(throttle) = &self.reassignment_throttle {
+            if state.adding_replicas.contains(&broker_id) {
+                max_records = throttle.take(max_records);
+                if max_records == 0 {
+                    return false;
+                }
+            }
+        }
+        let action = Action::Fetch(state.topic, partition.end_offset(), broker_id, max_records);
+
+        // An unreachable leader is simply tried again on the next round.
+        let response_list = match self.request(&address, action) {
+            Ok(response_list) => response_list,
+            Err(_) => return false,
+        };
+
+        let mut fetched = false;
+        let mut leader_high_watermark = None;
+        for response in response_list {
+            match response.response {
+                Response::HighWatermark(offset) => leader_high_watermark = Some(offset),
+                Response::RecordBatch(batch) => match partition.append_replicated(batch) {
+                    Ok(true) => fetched = true,
+                    Ok(false) => break,
+                    Err(err) => {
+                        println!("Failed to write replicated batch\n{}", err);
+                        break;
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        if let Some(high_watermark) = leader_high_watermark {
+            let end_offset = partition.end_offset();
+            partition.advance_high_watermark(OffsetValue(u32::min(high_watermark.0, end_offset.0)));
+        }
+        fetched
+    }
+
+    /// Drops the records a former leader took that the current one doesn't
+    /// have. Starting from the latest leader epoch in this log, asks the
+    /// leader where that epoch ends in its own log and cuts there, going back
+    /// an epoch at a time while the leader doesn't know this one. Returns
+    /// false when the leader couldn't tell.
+    fn truncate_to_leader(
+        &self,
+        partition: &Partition,
+        address: &str,
+        topic: &TopicAddress,
+    ) -> bool {
+        let mut leader_epoch = match partition.latest_epoch() {
+            Some(leader_epoch) => leader_epoch,
+            None => return true,
+        };
+
+        loop {
+            let action = Action::OffsetForLeaderEpoch(topic.clone(), leader_epoch);
+            let answer = self
+                .request(address, action)
+                .ok()
+                .and_then(|response_list| {
+                    response_list
+                        .into_iter()
+                        .find_map(|response| match response.response {
+                            Response::EpochEndOffset(found_epoch, end_offset) => {
+                                Some((found_epoch, end_offset))
+                            }
+                            _ => None,
+                        })
+                });
+            let (found_epoch, end_offset) = match answer {
+                Some(answer) => answer,
+                None => return false,
+            };
+
+            let (local_epoch, local_end_offset) = partition.epoch_end_offset(found_epoch);
+            let offset = OffsetValue(u32::min(end_offset.0, local_end_offset.0));
+            if offset.0 < partition.end_offset().0 {
+                println!(
+                    "[broker {}] truncating {}-{} to {} to match its leader",
+                    self.broker_id().unwrap_or(u32::MAX),
+                    topic.name,
+                    topic.partition,
+                    offset.0
+                );
+            }
+            if let Err(err) = partition.truncate_to(offset) {
+                println!("Failed to truncate partition log\n{}", err);
+                return false;
+            }
+            if local_epoch == found_epoch {
+                return true;
+            }
+            leader_epoch = local_epoch;
+        }
+    }
+
+    /// Whether the partition is replicated and led by some other broker.
+    fn is_follower(&self, topic: &TopicAddress) -> bool {
+        self.replicas
+            .state(topic)
+            .is_some_and(|state| Some(state.leader) != self.broker_id())
+    }
+
+    fn led_partition(&self, topic: &TopicAddress) -> Option<Arc<Partition>> {
+        let broker_id = self.broker_id()?;
+        if !self.replicas.is_leader(broker_id, topic) {
+            return None;
+        }
+        self.cluster.get_partition(topic.clone())
+    }
+
+    /// Asks the controller to store the ISR of a partition this broker leads.
+    /// Returns whether it did. A smaller ISR is only used once it did, so a
+    /// leader that was fenced or replaced meanwhile can't shrink the ISR to
+    /// itself and acknowledge records no other replica has.
+    fn report_isr_change(&self, state: PartitionState) -> bool {
+        let broker_epoch = match self.broker_epoch() {
+            Some(broker_epoch) => broker_epoch,
+            None => return false,
+        };
+        if self.is_controller() {
+            return self.alter_isr(state, broker_epoch).is_empty();
+        }
+
+        match self.controller_address() {
+            Some(controller) => self
+                .request(&controller, Action::AlterIsr(state, broker_epoch))
+                .is_ok_and(|response_list| response_list.is_empty()),
+            None => {
+                println!(
+                    "No controller to report the ISR of {}-{} to",
+                    state.topic.name, state.topic.partition
+                );
+                false
+            }
         }
     }
 }
 
-pub struct Controller {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: Option<&str>) -> ProducerRecord {
+        let content = Content::new(String::from("value"));
+        match key {
+            Some(key) => ProducerRecord::with_key(String::from("t"), key.to_string(), content),
+            None => ProducerRecord::new(String::from("t"), content),
+        }
+    }
+
+    #[test]
+    fn should_cycle_through_partitions_round_robin() {
+        let mut partitioner = RoundRobinPartitioner::new();
+        let partitions: Vec<u32> = (0..7)
+            .map(|_| partitioner.partition(&record(None), 3))
+            .collect();
+        assert_eq!(partitions, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn should_map_same_key_to_same_partition_across_partitioners() {
+        let mut first = KeyHashPartitioner::new();
+        let mut second = KeyHashPartitioner::new();
+        for key in ["a", "order-17", "customer/42", ""].iter() {
+            let partition = first.partition(&record(Some(key)), 8);
+            assert!(partition < 8);
+            assert_eq!(partition, first.partition(&record(Some(key)), 8));
+            assert_eq!(partition, second.partition(&record(Some(key)), 8));
+        }
+        assert_eq!(KeyHashPartitioner::hash("a"), 0xe40c_292c);
+    }
+
+    #[test]
+    fn should_spread_keys_evenly_and_records_without_key_round_robin() {
+        let mut partitioner = KeyHashPartitioner::new();
+        let mut counts = [0; 4];
+        for key in 0..4000 {
+            let key = format!("key-{}", key);
+            counts[partitioner.partition(&record(Some(&key)), 4) as usize] += 1;
+        }
+        assert!(counts.iter().all(|count| (800..1200).contains(count)));
+
+        let partitions: Vec<u32> = (0..5)
+            .map(|_| partitioner.partition(&record(None), 4))
+            .collect();
+        assert_eq!(partitions, vec![0, 1, 2, 3, 0]);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::communication::{Action, ActionMessage};
+use crate::config::BrokerConfig;
+use crate::endpoint::Broker;
+use crate::server::Server;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FREE_PORT_ADDRESS: &str = "127.0.0.1:0";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A local cluster for development and tests: every broker runs in this
+/// process, the first one initializes the controller, and all of them are
+/// stopped when the cluster is dropped.
+pub struct DevCluster {
+    servers: Vec<Server>,
+}
+
+impl DevCluster {
+    /// Starts `size` brokers sharing `config` on free ports.
+    pub fn start(size: usize, config: BrokerConfig) -> io::Result<DevCluster> {
+        DevCluster::with_configs(vec![config; size])
+    }
+
+    /// Starts a broker per config on a free port.
+    pub fn with_configs(config_list: Vec<BrokerConfig>) -> io::Result<DevCluster> {
+        DevCluster::on_addresses(
+            config_list
+                .into_iter()
+                .map(|config| (String::from(FREE_PORT_ADDRESS), config))
+                .collect(),
+        )
+    }
+
+    /// Starts a broker per address and config, then waits until the first one
+    /// is the controller and every broker knows all the others.
+    pub fn on_addresses(broker_list: Vec<(String, BrokerConfig)>) -> io::Result<DevCluster> {
+        let size = broker_list.len();
+        let mut cluster = DevCluster {
+            servers: Vec::new(),
+        };
+        for (address, config) in broker_list {
+            let broker = Broker::with_config(config)?;
+            // Already started servers are stopped on drop if this one fails.
+            cluster
+                .servers
+                .push(Server::start(address, Arc::new(broker))?);
+        }
+
+        let first_broker = match cluster.servers.first() {
+            Some(server) => server.broker(),
+            None => return Ok(cluster),
+        };
+        first_broker.handle(ActionMessage::new(
+            Action::InitializeController(cluster.bootstrap_addresses()),
+            String::new(),
+        ));
+
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while !(first_broker.is_controller()
+            && cluster
+                .servers
+                .iter()
+                .all(|server| server.broker().brokers().len() == size))
+        {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "brokers did not form a cluster in time",
+                ));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(cluster)
+    }
+
+    /// Addresses of the brokers, in the order they were started.
+    pub fn bootstrap_addresses(&self) -> Vec<String> {
+        self.servers
+            .iter()
+            .map(|server| server.address().to_string())
+            .collect()
+    }
+
+    pub fn servers(&self) -> &[Server] {
+        &self.servers
+    }
+
+    /// Hands the servers over, so they are no longer stopped on drop.
+    pub fn into_servers(mut self) -> Vec<Server> {
+        mem::take(&mut self.servers)
+    }
+
+    pub fn stop(&self) {
+        for server in &self.servers {
+            server.stop();
+        }
+    }
+}
+
+impl Drop for DevCluster {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::Response;
+    use crate::endpoint::Client;
+
+    #[test]
+    fn should_start_cluster_and_stop_brokers_when_dropped() {
+        let cluster = DevCluster::start(3, BrokerConfig::default()).unwrap();
+        let address_list = cluster.bootstrap_addresses();
+        assert_eq!(3, address_list.len());
+        assert!(cluster.servers()[0].broker().is_controller());
+
+        let response_list = Client::new(address_list[2].clone()).send_message(ActionMessage::new(
+            Action::CreateTopic(String::from("topic"), 3, 3, Vec::new()),
+            String::new(),
+        ));
+        assert!(matches!(response_list[0].response, Response::Empty));
+
+        let broker_list: Vec<Arc<Broker>> = cluster
+            .servers()
+            .iter()
+            .map(|server| server.broker())
+            .collect();
+        drop(cluster);
+        assert!(broker_list.iter().all(|broker| broker.is_stopped()));
+    }
+}
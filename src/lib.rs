@@ -1,9 +1,50 @@
 mod communication;
+mod compression;
+mod config;
+mod controller;
 mod core;
+mod detector;
 mod endpoint;
+mod launcher;
+mod metadata;
+mod placement;
+mod pool;
+mod raft;
+mod reassignment;
+mod replication;
+mod server;
+mod settings;
+#[cfg(test)]
+mod simulation;
 mod storage;
+mod transaction;
 
 pub use crate::communication::{Action, ActionMessage, Response, ResponseMessage};
-pub use crate::core::{Content, OffsetValue, TopicAddress};
-pub use crate::endpoint::{Broker, Client, Controller};
-pub use crate::storage::{Cluster, Partition};
+pub use crate::config::{
+    BrokerConfig, CleanupPolicy, CompressionType, DetectorKind, LogConfig, CLEANUP_POLICY,
+    COMPRESSION_TYPE, MAX_MESSAGE_BYTES, MAX_MESSAGE_BYTES_LIMIT, MIN_INSYNC_REPLICAS,
+    RETENTION_MS, TOPIC_CONFIG_DEFAULTS,
+};
+pub use crate::controller::Controller;
+pub use crate::core::{
+    Acks, BatchHeader, BatchKind, ConsumerRecord, Content, Isolation, OffsetValue, PartitionState,
+    ProducerRecord, RecordBatch, TopicAddress,
+};
+pub use crate::detector::{
+    Clock, EventuallyPerfectDetector, HeartbeatDetector, ManualClock, PhiAccrualDetector,
+    SystemClock,
+};
+pub use crate::endpoint::{
+    Broker, Client, Consumer, KeyHashPartitioner, Partitioner, ProduceError, Producer,
+    ProducerConfig, RecordHandle, RoundRobinPartitioner,
+};
+pub use crate::launcher::DevCluster;
+pub use crate::metadata::{ClusterMetadata, MetadataRecord};
+pub use crate::pool::ConnectionPool;
+pub use crate::raft::{LogEntry, PersistentState, RaftMessage, RaftNode, Role, Snapshot};
+pub use crate::reassignment::plan_rebalance;
+pub use crate::replication::ReplicaManager;
+pub use crate::server::Server;
+pub use crate::settings::{ServiceConfig, SETTINGS};
+pub use crate::storage::{AppendResult, Cluster, LogRecord, Partition, RecordQueue};
+pub use crate::transaction::{TransactionCoordinator, TransactionError};
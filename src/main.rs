@@ -1,85 +1,74 @@
-use logstreamer::{Action, ActionMessage, Broker, ResponseMessage};
+use logstreamer::{Action, ActionMessage, Broker, Server, ServiceConfig};
 use std::env;
-use std::io::prelude::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpStream;
+use std::process;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+mod signal;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let broker_address = match args.get(1) {
-        Some(value) => value.into(),
-        None => String::from("127.0.0.1:8080"),
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        print!("{}", ServiceConfig::usage());
+        return;
+    }
+    let config = match ServiceConfig::load(&args, |name| env::var(name).ok()) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Invalid configuration\r\n{}", err);
+            process::exit(2);
+        }
+    };
+
+    let data_dir = config.broker.data_dir.clone();
+    let broker = match Broker::with_config(config.broker.clone()) {
+        Ok(broker) => Arc::new(broker),
+        Err(err) => panic!("Failed to open data directory {:?}\r\n{}", data_dir, err),
     };
-    
-    let broker = Arc::new(Broker::new());
 
-    let listener = match TcpListener::bind(&broker_address) {
-        Ok(listener) => listener,
+    let server = match Server::start(config.listen_address, broker) {
+        Ok(server) => server,
         Err(err) => panic!("Failed to bind address\r\n{}", err),
     };
-    println!("Started server at {}", &broker_address);
+    println!("Started server at {}", server.address());
+
+    let advertised_address = config
+        .broker
+        .advertised_address
+        .unwrap_or_else(|| server.address().to_string());
+    initialize_controller(&server, advertised_address, config.controller_peers);
 
-    let cloned_broker = broker.clone();
-    thread::spawn(move || loop {
-        cloned_broker.loop_failure_detector();
+    // SIGTERM shuts the broker down gracefully rather than killing it.
+    signal::catch_terminate();
+    let broker = server.broker();
+    thread::spawn(move || {
+        while !signal::terminated() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        broker.request_shutdown();
     });
 
-    for stream in listener.incoming() {
-        let cloned_broker = broker.clone();
-        match stream {
-            Ok(valid_stream) => {
-                thread::spawn(move || {
-                    handle_connection(valid_stream, cloned_broker);
-                });
-            }
-            Err(err) => println!("Failed to process current stream\n{}", err),
-        };
-    }
+    server.wait();
 }
 
-fn handle_connection(mut stream: TcpStream, broker: Arc<Broker>) {
-    loop {
-        let mut buffer = [0; 1024];
-        let _ = match stream.read(&mut buffer) {
-            Ok(value) => value,
-            Err(err) => {
-                println!("Failed to read stream\r\n{}", err);
-                return;
-            }
-        };
-
-        let message = ActionMessage::parse(&buffer);
-
-        let response_list = match message.action {
-            Action::Produce(topic, content) => broker.store_data(topic, content),
-            Action::Consume(topic, offset, limit) => broker.read_data(topic, offset, limit),
-            Action::CreateTopic(topic, partition_number) => {
-                broker.add_topic(topic, partition_number)
-            }
-            Action::InitializeController(brokers) => broker.init_controller(brokers),
-            Action::InitializeBroker(id, brokers) => broker.init_broker(id, brokers),
-            Action::IamAlive(id) => broker.receive_signal(id),
-            Action::Invalid => Vec::new(),
-            Action::Quit => {
-                stream
-                    .write_all(&ResponseMessage::new_empty().as_vec()[..])
-                    .unwrap();
-                stream.flush().unwrap();
-                return;
-            }
-        };
-
-        let mut response_content: Vec<u8> = Vec::new();
-        for response in response_list {
-            response_content.extend(response.as_vec());
-        }
+/// The first of the controller peers sets the cluster up once every peer
+/// listens, unless it was part of the cluster before.
+fn initialize_controller(server: &Server, advertised_address: String, peers: Vec<String>) {
+    let broker = server.broker();
+    if peers.first() != Some(&advertised_address) || broker.has_stored_id() {
+        return;
+    }
 
-        if response_content.is_empty() {
-            response_content.extend(ResponseMessage::new_empty().as_vec());
+    thread::spawn(move || {
+        while !peers.iter().all(|peer| TcpStream::connect(peer).is_ok()) {
+            thread::sleep(Duration::from_millis(500));
         }
-
-        stream.write_all(&response_content[..]).unwrap();
-        stream.flush().unwrap();
-    }
+        println!("Initializing controller with {}", peers.join(", "));
+        broker.handle(ActionMessage::new(
+            Action::InitializeController(peers),
+            String::new(),
+        ));
+    });
 }
@@ -0,0 +1,133 @@
+use crate::core::{PartitionState, TopicAddress};
+use std::collections::{HashMap, HashSet};
+
+/// A change to the cluster metadata, as stored in the controller log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataRecord {
+    /// Written by every new controller so entries from earlier terms get committed.
+    Noop,
+    /// New or updated leader and ISR of partitions, written together.
+    PartitionStates(Vec<PartitionState>),
+    /// Topic name, config key and value.
+    TopicConfig(String, String, String),
+    /// Broker id, the address it advertises and its rack, if any. Gives the
+    /// broker a new epoch.
+    RegisterBroker(u32, String, Option<String>),
+    UnregisterBroker(u32),
+    /// Takes the epoch of a broker taken as dead away, so nothing it sends
+    /// is accepted until it registers again.
+    FenceBroker(u32),
+    /// Removes a topic with all its partitions and configs.
+    DeleteTopic(String),
+}
+
+/// What the controller quorum agreed on: which brokers are in the cluster,
+/// where every partition lives and how each topic is configured.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClusterMetadata {
+    pub partitions: HashMap<TopicAddress, PartitionState>,
+    pub configs: HashMap<String, HashMap<String, String>>,
+    pub brokers: HashMap<u32, String>,
+    /// Rack of the registered brokers that have one.
+    pub racks: HashMap<u32, String>,
+    /// Ids are never handed out twice, even after a broker is removed, since
+    /// partitions may still list the old one as a replica.
+    pub next_broker_id: u32,
+    /// Epoch of every registered broker that isn't fenced. Each registration
+    /// gets a higher one than any before, so requests a broker sent before
+    /// it was fenced or restarted can be told apart.
+    pub broker_epochs: HashMap<u32, u32>,
+    pub next_broker_epoch: u32,
+    /// Topics deleted and not created again since, so brokers that were
+    /// away when it happened still get to drop their data.
+    pub deleted_topics: HashSet<String>,
+}
+
+impl ClusterMetadata {
+    pub fn new() -> ClusterMetadata {
+        ClusterMetadata::default()
+    }
+
+    pub fn apply(&mut self, record: &MetadataRecord) {
+        match record {
+            MetadataRecord::Noop => {}
+            MetadataRecord::PartitionStates(state_list) => {
+                for state in state_list {
+                    self.deleted_topics.remove(&state.topic.name);
+                    self.partitions.insert(state.topic.clone(), state.clone());
+                }
+            }
+            MetadataRecord::TopicConfig(topic, key, value) => {
+                self.configs
+                    .entry(topic.clone())
+                    .or_default()
+                    .insert(key.clone(), value.clone());
+            }
+            MetadataRecord::RegisterBroker(broker_id, address, rack) => {
+                self.brokers.insert(*broker_id, address.clone());
+                match rack {
+                    Some(rack) => self.racks.insert(*broker_id, rack.clone()),
+                    None => self.racks.remove(broker_id),
+                };
+                self.next_broker_id = u32::max(self.next_broker_id, broker_id + 1);
+                self.broker_epochs
+                    .insert(*broker_id, self.next_broker_epoch);
+                self.next_broker_epoch += 1;
+            }
+            MetadataRecord::UnregisterBroker(broker_id) => {
+                self.brokers.remove(broker_id);
+                self.racks.remove(broker_id);
+                self.broker_epochs.remove(broker_id);
+            }
+            MetadataRecord::FenceBroker(broker_id) => {
+                self.broker_epochs.remove(broker_id);
+            }
+            MetadataRecord::DeleteTopic(topic_name) => {
+                self.partitions.retain(|topic, _| topic.name != *topic_name);
+                self.configs.remove(topic_name);
+                self.deleted_topics.insert(topic_name.clone());
+            }
+        }
+    }
+
+    /// Whether `broker_id` is registered and wasn't fenced since.
+    pub fn is_unfenced(&self, broker_id: u32) -> bool {
+        self.broker_epochs.contains_key(&broker_id)
+    }
+
+    pub fn has_topic(&self, topic_name: &str) -> bool {
+        self.partitions.keys().any(|topic| topic.name == topic_name)
+    }
+
+    /// Every registered broker, ordered by id.
+    pub fn broker_list(&self) -> Vec<(u32, String)> {
+        let mut broker_list: Vec<(u32, String)> = self
+            .brokers
+            .iter()
+            .map(|(broker_id, address)| (*broker_id, address.clone()))
+            .collect();
+        broker_list.sort();
+        broker_list
+    }
+
+    /// The configs of a topic, ordered by key.
+    pub fn config_list(&self, topic_name: &str) -> Vec<(String, String)> {
+        let mut config_list: Vec<(String, String)> = self
+            .configs
+            .get(topic_name)
+            .map(|configs| configs.clone().into_iter().collect())
+            .unwrap_or_default();
+        config_list.sort();
+        config_list
+    }
+
+    /// Every partition state, ordered by topic and partition.
+    pub fn partition_states(&self) -> Vec<PartitionState> {
+        let mut state_list: Vec<PartitionState> = self.partitions.values().cloned().collect();
+        state_list.sort_by(|first, second| {
+            (&first.topic.name, first.topic.partition)
+                .cmp(&(&second.topic.name, second.topic.partition))
+        });
+        state_list
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+/// Orders brokers so that consecutive ones sit in different racks for as
+/// long as racks remain: the first broker of every rack, then the second of
+/// every rack and so on. A broker without a rack counts as a rack of its own.
+fn rack_alternated(brokers: &[u32], racks: &HashMap<u32, String>) -> Vec<u32> {
+    let mut rack_list: Vec<Vec<u32>> = Vec::new();
+    let mut positions: HashMap<&String, usize> = HashMap::new();
+    for broker in brokers {
+        match racks.get(broker) {
+            Some(rack) => match positions.get(rack) {
+                Some(position) => rack_list[*position].push(*broker),
+                None => {
+                    positions.insert(rack, rack_list.len());
+                    rack_list.push(vec![*broker]);
+                }
+            },
+            None => rack_list.push(vec![*broker]),
+        }
+    }
+
+    let depth = rack_list.iter().map(Vec::len).max().unwrap_or(0);
+    (0..depth)
+        .flat_map(|position| {
+            rack_list
+                .iter()
+                .filter_map(move |rack_brokers| rack_brokers.get(position).copied())
+        })
+        .collect()
+}
+
+/// Picks the replicas of each of `partition_count` partitions among
+/// `brokers`, the first one leading it. Leadership goes round the brokers
+/// starting at position `first`, and followers are the next brokers in racks
+/// holding no replica of the partition yet. With fewer racks than replicas,
+/// the remaining ones go to the next brokers whatever their rack.
+pub fn place_replicas(
+    brokers: &[u32],
+    racks: &HashMap<u32, String>,
+    first: usize,
+    partition_count: u32,
+    replication_factor: u32,
+) -> Vec<Vec<u32>> {
+    let broker_list = rack_alternated(brokers, racks);
+    let replication_factor = replication_factor as usize;
+    (0..partition_count as usize)
+        .map(|partition| {
+            let candidates: Vec<u32> = (0..broker_list.len())
+                .map(|position| broker_list[(first + partition + position) % broker_list.len()])
+                .collect();
+
+            let mut replicas = Vec::with_capacity(replication_factor);
+            let mut used_racks = Vec::new();
+            for broker in candidates.iter() {
+                let rack = racks.get(broker);
+                if replicas.len() < replication_factor
+                    && rack.is_none_or(|rack| !used_racks.contains(&rack))
+                {
+                    replicas.push(*broker);
+                    used_racks.extend(rack);
+                }
+            }
+            for broker in candidates.iter() {
+                if replicas.len() < replication_factor && !replicas.contains(broker) {
+                    replicas.push(*broker);
+                }
+            }
+            replicas
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn racks(rack_list: &[&str]) -> HashMap<u32, String> {
+        rack_list
+            .iter()
+            .enumerate()
+            .map(|(broker, rack)| (broker as u32, rack.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn should_put_replicas_of_a_partition_in_different_racks() {
+        let racks = racks(&["a", "a", "b", "b", "c", "c"]);
+        let placement = place_replicas(&[0, 1, 2, 3, 4, 5], &racks, 0, 6, 3);
+
+        for replicas in placement.iter() {
+            let mut rack_list: Vec<&String> =
+                replicas.iter().map(|broker| &racks[broker]).collect();
+            rack_list.sort();
+            rack_list.dedup();
+            assert_eq!(rack_list.len(), 3);
+        }
+        let mut leaders: Vec<u32> = placement.iter().map(|replicas| replicas[0]).collect();
+        leaders.sort();
+        assert_eq!(leaders, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn should_use_every_rack_and_then_any_broker_when_racks_run_out() {
+        let racks = racks(&["a", "a", "b", "b"]);
+        for replicas in place_replicas(&[0, 1, 2, 3], &racks, 0, 4, 3) {
+            let mut rack_list: Vec<&String> =
+                replicas.iter().map(|broker| &racks[broker]).collect();
+            rack_list.sort();
+            rack_list.dedup();
+            assert_eq!(rack_list.len(), 2);
+
+            let mut broker_list = replicas.clone();
+            broker_list.sort();
+            broker_list.dedup();
+            assert_eq!(broker_list.len(), 3);
+        }
+    }
+
+    #[test]
+    fn should_go_round_the_brokers_without_racks() {
+        let placement = place_replicas(&[0, 1, 2], &HashMap::new(), 1, 3, 2);
+        assert_eq!(placement, vec![vec![1, 2], vec![2, 0], vec![0, 1]]);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::communication::{Action, ActionMessage, ResponseMessage};
+use crate::config::BrokerConfig;
+use crate::detector::Clock;
+use crate::endpoint::Client;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Idle connections kept per broker. Requests sent at the same time to the
+/// same broker each take a connection of their own.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+#[derive(Default)]
+struct Peer {
+    idle: Vec<Client>,
+    failures: u32,
+    // No connection is attempted before then.
+    retry_at: Option<Instant>,
+}
+
+/// How a broker sends requests to the other brokers of the cluster.
+pub trait Transport: Send + Sync {
+    /// Returns no responses for actions the broker doesn't answer.
+    fn request(&self, address: &str, action: Action) -> io::Result<Vec<ResponseMessage>>;
+}
+
+/// Long-lived connections from a broker to the other brokers of the cluster.
+/// A broker that can't be reached isn't connected to again until a backoff,
+/// growing with every failure, has passed. Requests fail meanwhile.
+pub struct ConnectionPool {
+    clock: Arc<dyn Clock>,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    reconnect_backoff: Duration,
+    max_reconnect_backoff: Duration,
+    peers: Mutex<HashMap<String, Peer>>,
+}
+
+impl ConnectionPool {
+    pub fn new(config: &BrokerConfig, clock: Arc<dyn Clock>) -> ConnectionPool {
+        ConnectionPool {
+            clock,
+            connect_timeout: config.peer_connect_timeout,
+            request_timeout: config.peer_request_timeout,
+            reconnect_backoff: config.peer_reconnect_backoff,
+            max_reconnect_backoff: config.peer_reconnect_backoff_max,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn take_idle(&self, address: &str) -> Option<Client> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get_mut(address)
+            .and_then(|peer| peer.idle.pop())
+    }
+
+    fn retry_at(&self, address: &str) -> Option<Instant> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(address)
+            .and_then(|peer| peer.retry_at)
+    }
+
+    fn release(&self, address: &str, client: Client) {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(address.to_string()).or_default();
+        peer.failures = 0;
+        peer.retry_at = None;
+        if peer.idle.len() < MAX_IDLE_CONNECTIONS {
+            peer.idle.push(client);
+        }
+    }
+
+    fn back_off(&self, address: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(address.to_string()).or_default();
+        let backoff = self
+            .reconnect_backoff
+            .saturating_mul(2u32.saturating_pow(peer.failures))
+            .min(self.max_reconnect_backoff);
+        peer.failures = peer.failures.saturating_add(1);
+        peer.retry_at = Some(self.clock.now() + backoff);
+        peer.idle.clear();
+    }
+}
+
+impl Transport for ConnectionPool {
+    /// A connection kept idle that the broker closed meanwhile, or that
+    /// can't be written to, is replaced by a new one. Once written, a request
+    /// is never sent again, since the broker may already have applied it.
+    fn request(&self, address: &str, action: Action) -> io::Result<Vec<ResponseMessage>> {
+        let message = ActionMessage::new(action, String::new());
+        if let Some(mut client) = self.take_idle(address) {
+            if !client.is_closed() && client.write_request(&message).is_ok() {
+                return match client.read_response(&message) {
+                    Ok(response_list) => {
+                        self.release(address, client);
+                        Ok(response_list)
+                    }
+                    Err(err) => {
+                        self.back_off(address);
+                        Err(err)
+                    }
+                };
+            }
+        }
+
+        if let Some(retry_at) = self.retry_at(address) {
+            if self.clock.now() < retry_at {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("waiting to reconnect to {}", address),
+                ));
+            }
+        }
+
+        let result =
+            Client::connect_with_timeouts(address, self.connect_timeout, self.request_timeout)
+                .and_then(|mut client| {
+                    let response_list = client.send_once(&message)?;
+                    Ok((client, response_list))
+                });
+        match result {
+            Ok((client, response_list)) => {
+                self.release(address, client);
+                Ok(response_list)
+            }
+            Err(err) => {
+                self.back_off(address);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::Response;
+    use crate::core::OffsetValue;
+    use crate::detector::ManualClock;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Answers every request with `Offset(n)`, n counting requests per connection.
+    fn serve(listener: TcpListener) -> Arc<AtomicUsize> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let cloned_connections = connections.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                cloned_connections.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let mut count = 0;
+                    while ActionMessage::read_from(&mut stream).is_ok() {
+                        count += 1;
+                        let response = ResponseMessage::new(Response::Offset(OffsetValue(count)));
+                        if ResponseMessage::write_list_to(&mut stream, &[response]).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        connections
+    }
+
+    /// Answers only the first request on every connection, then closes it or
+    /// keeps reading requests without answering. Counts every request read.
+    fn serve_first_request(listener: TcpListener, close: bool) -> Arc<AtomicUsize> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let cloned_requests = requests.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let requests = cloned_requests.clone();
+                thread::spawn(move || {
+                    let mut answered = false;
+                    while ActionMessage::read_from(&mut stream).is_ok() {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        if !answered {
+                            answered = true;
+                            let response = ResponseMessage::new(Response::Offset(OffsetValue(1)));
+                            let _ = ResponseMessage::write_list_to(&mut stream, &[response]);
+                            if close {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        requests
+    }
+
+    fn answer(result: io::Result<Vec<ResponseMessage>>) -> Response {
+        result.unwrap().remove(0).response
+    }
+
+    #[test]
+    fn should_keep_connection_between_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connections = serve(listener);
+        let pool = ConnectionPool::new(&BrokerConfig::default(), Arc::new(ManualClock::new()));
+
+        for count in 1..=3 {
+            let response = answer(pool.request(&address, Action::Metadata(String::new())));
+            assert!(matches!(response, Response::Offset(OffsetValue(n)) if n == count));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn should_wait_before_reconnecting_to_unreachable_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let clock = Arc::new(ManualClock::new());
+        let pool = ConnectionPool::new(&BrokerConfig::default(), clock.clone());
+
+        assert!(pool.request(&address, Action::IamAlive(1, 0)).is_err());
+        serve(TcpListener::bind(&address).unwrap());
+
+        let result = pool.request(&address, Action::IamAlive(1, 0));
+        assert!(result.is_err_and(|err| err.kind() == io::ErrorKind::NotConnected));
+        clock.advance(Duration::from_millis(100));
+        let response = answer(pool.request(&address, Action::IamAlive(1, 0)));
+        assert!(matches!(response, Response::Offset(OffsetValue(1))));
+    }
+
+    #[test]
+    fn should_not_send_request_again_when_broker_never_answers_it() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = serve_first_request(listener, false);
+        let config = BrokerConfig {
+            peer_request_timeout: Duration::from_millis(200),
+            ..BrokerConfig::default()
+        };
+        let pool = ConnectionPool::new(&config, Arc::new(ManualClock::new()));
+
+        answer(pool.request(&address, Action::IamAlive(1, 0)));
+        assert!(pool.request(&address, Action::IamAlive(1, 0)).is_err());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn should_send_request_on_new_connection_when_broker_closed_idle_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = serve_first_request(listener, true);
+        let pool = ConnectionPool::new(&BrokerConfig::default(), Arc::new(ManualClock::new()));
+
+        answer(pool.request(&address, Action::IamAlive(1, 0)));
+        // Lets the close reach this end before the connection is used again.
+        thread::sleep(Duration::from_millis(100));
+        let response = answer(pool.request(&address, Action::IamAlive(1, 0)));
+        assert!(matches!(response, Response::Offset(OffsetValue(1))));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
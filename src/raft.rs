@@ -0,0 +1,882 @@
+use crate::metadata::{ClusterMetadata, MetadataRecord};
+use std::collections::{HashMap, HashSet};
+
+/// Ticks a follower waits for the leader before standing for election. The
+/// actual timeout is picked at random between this and twice as much.
+const ELECTION_TICKS: u32 = 10;
+const HEARTBEAT_TICKS: u32 = 2;
+/// Applied entries kept in the log before they are folded into the snapshot.
+const SNAPSHOT_THRESHOLD: u32 = 100;
+const MAX_ENTRIES_PER_APPEND: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub term: u32,
+    pub record: MetadataRecord,
+}
+
+/// The metadata as of `last_index`, replacing every entry up to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub last_index: u32,
+    pub last_term: u32,
+    pub metadata: ClusterMetadata,
+}
+
+/// What a node has to find again after a restart to keep its promises.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PersistentState {
+    pub term: u32,
+    pub voted_for: Option<u32>,
+    pub snapshot: Snapshot,
+    pub entries: Vec<LogEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RaftMessage {
+    /// Term, last log index and last log term of the candidate.
+    RequestVote(u32, u32, u32),
+    /// Term and whether the vote was granted.
+    Vote(u32, bool),
+    /// Term, index and term of the entry preceding the entries, the entries
+    /// and the leader commit index.
+    AppendEntries(u32, u32, u32, Vec<LogEntry>, u32),
+    /// Term, whether the entries matched and the last index known to match,
+    /// or a guess of where to retry from when they didn't.
+    AppendResponse(u32, bool, u32),
+    InstallSnapshot(u32, Box<Snapshot>),
+}
+
+impl RaftMessage {
+    pub fn term(&self) -> u32 {
+        match self {
+            RaftMessage::RequestVote(term, _, _)
+            | RaftMessage::Vote(term, _)
+            | RaftMessage::AppendEntries(term, _, _, _, _)
+            | RaftMessage::AppendResponse(term, _, _)
+            | RaftMessage::InstallSnapshot(term, _) => *term,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// One member of the controller quorum. It does no I/O: time passes with
+/// `tick`, messages come in through `step` and go out through
+/// `take_messages`, so the same code runs over TCP and in deterministic tests.
+pub struct RaftNode {
+    id: u32,
+    voters: Vec<u32>,
+    term: u32,
+    voted_for: Option<u32>,
+    snapshot: Snapshot,
+    // The entry at position i has index snapshot.last_index + 1 + i.
+    entries: Vec<LogEntry>,
+    commit_index: u32,
+    applied_index: u32,
+    metadata: ClusterMetadata,
+    role: Role,
+    leader: Option<u32>,
+    elapsed: u32,
+    election_timeout: u32,
+    votes: HashSet<u32>,
+    next_index: HashMap<u32, u32>,
+    match_index: HashMap<u32, u32>,
+    // Voters heard from since the leader last checked it still has a quorum.
+    active: HashSet<u32>,
+    quorum_elapsed: u32,
+    snapshot_threshold: u32,
+    random_state: u64,
+    changed: bool,
+    outbox: Vec<(u32, RaftMessage)>,
+}
+
+impl RaftNode {
+    pub fn new(id: u32, voters: Vec<u32>, seed: u64) -> RaftNode {
+        RaftNode::restore(id, voters, seed, PersistentState::default())
+    }
+
+    pub fn restore(id: u32, voters: Vec<u32>, seed: u64, state: PersistentState) -> RaftNode {
+        let mut node = RaftNode {
+            id,
+            voters,
+            term: state.term,
+            voted_for: state.voted_for,
+            commit_index: state.snapshot.last_index,
+            applied_index: state.snapshot.last_index,
+            metadata: state.snapshot.metadata.clone(),
+            snapshot: state.snapshot,
+            entries: state.entries,
+            role: Role::Follower,
+            leader: None,
+            elapsed: 0,
+            election_timeout: ELECTION_TICKS,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            active: HashSet::new(),
+            quorum_elapsed: 0,
+            snapshot_threshold: SNAPSHOT_THRESHOLD,
+            random_state: seed ^ 0x9e37_79b9_7f4a_7c15,
+            changed: false,
+            outbox: Vec::new(),
+        };
+        node.reset_election_timer();
+        node
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn term(&self) -> u32 {
+        self.term
+    }
+
+    pub fn voters(&self) -> &[u32] {
+        &self.voters
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn leader(&self) -> Option<u32> {
+        self.leader
+    }
+
+    pub fn commit_index(&self) -> u32 {
+        self.commit_index
+    }
+
+    pub fn applied_index(&self) -> u32 {
+        self.applied_index
+    }
+
+    pub fn snapshot_index(&self) -> u32 {
+        self.snapshot.last_index
+    }
+
+    /// The metadata with every committed entry applied.
+    pub fn metadata(&self) -> &ClusterMetadata {
+        &self.metadata
+    }
+
+    pub fn set_snapshot_threshold(&mut self, snapshot_threshold: u32) {
+        self.snapshot_threshold = snapshot_threshold;
+    }
+
+    pub fn persistent_state(&self) -> PersistentState {
+        PersistentState {
+            term: self.term,
+            voted_for: self.voted_for,
+            snapshot: self.snapshot.clone(),
+            entries: self.entries.clone(),
+        }
+    }
+
+    /// Whether the term, vote or log changed since the last call. They have
+    /// to be stored before the pending messages are sent.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    /// Messages to send, with the id of the node each one is for.
+    pub fn take_messages(&mut self) -> Vec<(u32, RaftMessage)> {
+        self.outbox.drain(..).collect()
+    }
+
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        if self.role != Role::Leader {
+            if self.elapsed >= self.election_timeout {
+                self.campaign();
+            }
+            return;
+        }
+
+        // A leader that can't reach a majority steps down instead of
+        // letting clients believe it is still in charge.
+        self.quorum_elapsed += 1;
+        if self.quorum_elapsed >= ELECTION_TICKS {
+            self.quorum_elapsed = 0;
+            if self.active.len() + 1 < self.quorum() {
+                self.become_follower(self.term, None);
+                return;
+            }
+            self.active.clear();
+        }
+
+        if self.elapsed >= HEARTBEAT_TICKS {
+            self.elapsed = 0;
+            self.broadcast_append();
+        }
+    }
+
+    /// Stands for election right away instead of waiting for the timeout.
+    pub fn campaign(&mut self) {
+        if !self.voters.contains(&self.id) {
+            return;
+        }
+
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.votes = [self.id].iter().copied().collect();
+        self.changed = true;
+        self.reset_election_timer();
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+
+        let (last_index, last_term) = (self.last_index(), self.last_term());
+        for peer in self.peers() {
+            self.send(
+                peer,
+                RaftMessage::RequestVote(self.term, last_index, last_term),
+            );
+        }
+    }
+
+    /// Appends `record` when this node leads. Returns its index and term: it
+    /// is committed once the commit index reaches it within the same term.
+    pub fn propose(&mut self, record: MetadataRecord) -> Option<(u32, u32)> {
+        if self.role != Role::Leader {
+            return None;
+        }
+
+        self.entries.push(LogEntry {
+            term: self.term,
+            record,
+        });
+        self.changed = true;
+        self.advance_commit();
+        self.broadcast_append();
+        Some((self.last_index(), self.term))
+    }
+
+    pub fn step(&mut self, from: u32, message: RaftMessage) {
+        if message.term() > self.term {
+            let leader = match message {
+                RaftMessage::AppendEntries(..) | RaftMessage::InstallSnapshot(..) => Some(from),
+                _ => None,
+            };
+            self.become_follower(message.term(), leader);
+        }
+
+        match message {
+            RaftMessage::RequestVote(term, last_index, last_term) => {
+                self.request_vote(from, term, last_index, last_term)
+            }
+            RaftMessage::Vote(term, granted) => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    }
+                }
+            }
+            RaftMessage::AppendEntries(term, prev_index, prev_term, entries, leader_commit) => {
+                self.append_entries(from, term, prev_index, prev_term, entries, leader_commit)
+            }
+            RaftMessage::AppendResponse(term, success, index) => {
+                self.append_response(from, term, success, index)
+            }
+            RaftMessage::InstallSnapshot(term, snapshot) => {
+                self.install_snapshot(from, term, *snapshot)
+            }
+        }
+    }
+
+    /// Term of the entry at `index`, if the log still has it.
+    pub fn term_at(&self, index: u32) -> Option<u32> {
+        if index == self.snapshot.last_index {
+            return Some(self.snapshot.last_term);
+        }
+        if index < self.snapshot.last_index {
+            return None;
+        }
+        self.entries
+            .get((index - self.snapshot.last_index - 1) as usize)
+            .map(|entry| entry.term)
+    }
+
+    pub fn last_index(&self) -> u32 {
+        self.snapshot.last_index + self.entries.len() as u32
+    }
+
+    fn last_term(&self) -> u32 {
+        self.term_at(self.last_index()).unwrap_or(0)
+    }
+
+    fn quorum(&self) -> usize {
+        self.voters.len() / 2 + 1
+    }
+
+    fn peers(&self) -> Vec<u32> {
+        self.voters
+            .iter()
+            .copied()
+            .filter(|voter| *voter != self.id)
+            .collect()
+    }
+
+    fn send(&mut self, to: u32, message: RaftMessage) {
+        self.outbox.push((to, message));
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 7;
+        self.random_state ^= self.random_state << 17;
+        self.random_state
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.elapsed = 0;
+        self.election_timeout =
+            ELECTION_TICKS + (self.next_random() % ELECTION_TICKS as u64) as u32;
+    }
+
+    fn become_follower(&mut self, term: u32, leader: Option<u32>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.changed = true;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_election_timer();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.quorum_elapsed = 0;
+        self.active.clear();
+        self.next_index.clear();
+        self.match_index.clear();
+        for peer in self.peers() {
+            self.next_index.insert(peer, self.last_index() + 1);
+            self.match_index.insert(peer, 0);
+        }
+
+        // Entries of earlier terms only count as committed once an entry of
+        // this term is, see section 5.4.2 of the Raft paper.
+        self.propose(MetadataRecord::Noop);
+    }
+
+    fn request_vote(&mut self, from: u32, term: u32, last_index: u32, last_term: u32) {
+        let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+        let granted = term == self.term
+            && up_to_date
+            && self.voted_for.is_none_or(|voted_for| voted_for == from);
+        if granted {
+            self.voted_for = Some(from);
+            self.changed = true;
+            self.reset_election_timer();
+        }
+        self.send(from, RaftMessage::Vote(self.term, granted));
+    }
+
+    fn accept_leader(&mut self, leader: u32) {
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.reset_election_timer();
+    }
+
+    fn append_entries(
+        &mut self,
+        from: u32,
+        term: u32,
+        mut prev_index: u32,
+        mut prev_term: u32,
+        mut entries: Vec<LogEntry>,
+        leader_commit: u32,
+    ) {
+        if term < self.term {
+            self.send(
+                from,
+                RaftMessage::AppendResponse(self.term, false, self.last_index()),
+            );
+            return;
+        }
+        self.accept_leader(from);
+
+        // Everything in the snapshot is committed, so it matches the leader.
+        if prev_index < self.snapshot.last_index {
+            let skipped = (self.snapshot.last_index - prev_index) as usize;
+            if skipped > entries.len() {
+                self.send(
+                    from,
+                    RaftMessage::AppendResponse(self.term, true, self.snapshot.last_index),
+                );
+                return;
+            }
+            entries.drain(..skipped);
+            prev_index = self.snapshot.last_index;
+            prev_term = self.snapshot.last_term;
+        }
+
+        if self.term_at(prev_index) != Some(prev_term) {
+            let retry_from = u32::min(self.last_index(), prev_index.saturating_sub(1));
+            self.send(
+                from,
+                RaftMessage::AppendResponse(self.term, false, retry_from),
+            );
+            return;
+        }
+
+        let match_index = prev_index + entries.len() as u32;
+        for (index, entry) in (prev_index + 1..).zip(entries) {
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.entries
+                        .truncate((index - self.snapshot.last_index - 1) as usize);
+                    self.entries.push(entry);
+                }
+                None => self.entries.push(entry),
+            }
+            self.changed = true;
+        }
+
+        let commit_index = u32::min(leader_commit, match_index);
+        if commit_index > self.commit_index {
+            self.commit_index = commit_index;
+            self.apply_committed();
+        }
+        self.send(
+            from,
+            RaftMessage::AppendResponse(self.term, true, match_index),
+        );
+    }
+
+    fn append_response(&mut self, from: u32, term: u32, success: bool, index: u32) {
+        if self.role != Role::Leader || term != self.term {
+            return;
+        }
+        self.active.insert(from);
+
+        if success {
+            let match_index = self.match_index.entry(from).or_insert(0);
+            *match_index = u32::max(*match_index, index);
+            let next_index = *match_index + 1;
+            self.next_index.insert(from, next_index);
+            self.advance_commit();
+            if next_index <= self.last_index() {
+                self.send_append(from);
+            }
+        } else {
+            let next_index = self.next_index.get(&from).copied().unwrap_or(1);
+            let next_index = u32::min(next_index.saturating_sub(1), index + 1);
+            self.next_index.insert(from, u32::max(next_index, 1));
+            self.send_append(from);
+        }
+    }
+
+    fn install_snapshot(&mut self, from: u32, term: u32, snapshot: Snapshot) {
+        if term < self.term {
+            self.send(
+                from,
+                RaftMessage::AppendResponse(self.term, false, self.last_index()),
+            );
+            return;
+        }
+        self.accept_leader(from);
+
+        if snapshot.last_index <= self.commit_index {
+            self.send(
+                from,
+                RaftMessage::AppendResponse(self.term, true, self.commit_index),
+            );
+            return;
+        }
+
+        if self.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+            let covered = (snapshot.last_index - self.snapshot.last_index) as usize;
+            self.entries.drain(..covered);
+        } else {
+            self.entries.clear();
+        }
+        let last_index = snapshot.last_index;
+        self.metadata = snapshot.metadata.clone();
+        self.commit_index = last_index;
+        self.applied_index = last_index;
+        self.snapshot = snapshot;
+        self.changed = true;
+        self.send(
+            from,
+            RaftMessage::AppendResponse(self.term, true, last_index),
+        );
+    }
+
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|match_index| **match_index >= index)
+                .count();
+            if replicas >= self.quorum() {
+                self.commit_index = index;
+                self.apply_committed();
+                break;
+            }
+        }
+    }
+
+    fn apply_committed(&mut self) {
+        while self.applied_index < self.commit_index {
+            self.applied_index += 1;
+            let position = (self.applied_index - self.snapshot.last_index - 1) as usize;
+            self.metadata.apply(&self.entries[position].record);
+        }
+
+        if self.applied_index - self.snapshot.last_index >= self.snapshot_threshold {
+            let compacted = (self.applied_index - self.snapshot.last_index) as usize;
+            self.snapshot = Snapshot {
+                last_index: self.applied_index,
+                last_term: self.term_at(self.applied_index).unwrap_or(0),
+                metadata: self.metadata.clone(),
+            };
+            self.entries.drain(..compacted);
+            self.changed = true;
+        }
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, to: u32) {
+        let next_index = self
+            .next_index
+            .get(&to)
+            .copied()
+            .unwrap_or(self.last_index() + 1);
+        if next_index <= self.snapshot.last_index {
+            self.send(
+                to,
+                RaftMessage::InstallSnapshot(self.term, Box::new(self.snapshot.clone())),
+            );
+            return;
+        }
+
+        let prev_index = next_index - 1;
+        let prev_term = self.term_at(prev_index).unwrap_or(0);
+        let start = (next_index - self.snapshot.last_index - 1) as usize;
+        let entries = self.entries[start..]
+            .iter()
+            .take(MAX_ENTRIES_PER_APPEND)
+            .cloned()
+            .collect();
+        self.send(
+            to,
+            RaftMessage::AppendEntries(
+                self.term,
+                prev_index,
+                prev_term,
+                entries,
+                self.commit_index,
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::{write_persistent_state, Buffer};
+
+    /// Runs nodes in lockstep over a lossy network, everything drawn from a
+    /// seed so a failing run can be replayed.
+    struct Simulation {
+        nodes: Vec<RaftNode>,
+        stored: Vec<Vec<u8>>,
+        in_flight: Vec<(u32, u32, RaftMessage)>,
+        random_state: u64,
+        loss_percent: u64,
+        // Messages only get through between nodes of the same group.
+        groups: Vec<u32>,
+        snapshot_threshold: u32,
+        leaders_by_term: HashMap<u32, u32>,
+        committed_terms: HashMap<u32, u32>,
+        // Lowest term of a node seen with the entry committed, so leaders
+        // of later terms must have it.
+        committed_in: HashMap<u32, u32>,
+        applied_metadata: HashMap<u32, ClusterMetadata>,
+    }
+
+    impl Simulation {
+        fn new(size: u32, seed: u64, snapshot_threshold: u32) -> Simulation {
+            let voters: Vec<u32> = (0..size).collect();
+            let nodes = voters
+                .iter()
+                .map(|id| {
+                    let mut node = RaftNode::new(*id, voters.clone(), seed * 31 + *id as u64);
+                    node.set_snapshot_threshold(snapshot_threshold);
+                    node
+                })
+                .collect();
+            Simulation {
+                nodes,
+                stored: vec![Vec::new(); size as usize],
+                in_flight: Vec::new(),
+                random_state: seed + 1,
+                loss_percent: 0,
+                groups: vec![0; size as usize],
+                snapshot_threshold,
+                leaders_by_term: HashMap::new(),
+                committed_terms: HashMap::new(),
+                committed_in: HashMap::new(),
+                applied_metadata: HashMap::new(),
+            }
+        }
+
+        fn next_random(&mut self, bound: u64) -> u64 {
+            self.random_state ^= self.random_state << 13;
+            self.random_state ^= self.random_state >> 7;
+            self.random_state ^= self.random_state << 17;
+            self.random_state % bound
+        }
+
+        fn collect(&mut self, id: usize) {
+            if self.nodes[id].take_changed() {
+                let mut bytes = Vec::new();
+                write_persistent_state(&mut bytes, &self.nodes[id].persistent_state());
+                self.stored[id] = bytes;
+            }
+            for (to, message) in self.nodes[id].take_messages() {
+                self.in_flight.push((id as u32, to, message));
+            }
+        }
+
+        fn round(&mut self) {
+            for id in 0..self.nodes.len() {
+                self.nodes[id].tick();
+                self.collect(id);
+            }
+
+            let mut delivering = std::mem::take(&mut self.in_flight);
+            while !delivering.is_empty() {
+                let position = self.next_random(delivering.len() as u64) as usize;
+                let (from, to, message) = delivering.swap_remove(position);
+                if self.groups[from as usize] != self.groups[to as usize]
+                    || self.next_random(100) < self.loss_percent
+                {
+                    continue;
+                }
+                // Some messages arrive a round late, after newer ones.
+                if self.next_random(10) == 0 {
+                    self.in_flight.push((from, to, message));
+                    continue;
+                }
+                self.nodes[to as usize].step(from, message);
+                self.collect(to as usize);
+            }
+            self.check_invariants();
+        }
+
+        fn run(&mut self, rounds: u32) {
+            for _ in 0..rounds {
+                self.round();
+            }
+        }
+
+        fn restart(&mut self, id: usize) {
+            let state = if self.stored[id].is_empty() {
+                PersistentState::default()
+            } else {
+                Buffer::new(&self.stored[id])
+                    .read_persistent_state()
+                    .unwrap()
+            };
+            let voters = (0..self.nodes.len() as u32).collect();
+            let seed = self.next_random(u64::MAX);
+            self.nodes[id] = RaftNode::restore(id as u32, voters, seed, state);
+            self.nodes[id].set_snapshot_threshold(self.snapshot_threshold);
+        }
+
+        fn leader(&self) -> Option<usize> {
+            self.nodes.iter().position(|node| node.is_leader())
+        }
+
+        fn propose(&mut self, record: MetadataRecord) -> Option<(usize, (u32, u32))> {
+            let leader = self.leader()?;
+            let proposal = self.nodes[leader].propose(record)?;
+            self.collect(leader);
+            Some((leader, proposal))
+        }
+
+        fn check_invariants(&mut self) {
+            for node in self.nodes.iter() {
+                if node.is_leader() {
+                    let leader = *self.leaders_by_term.entry(node.term()).or_insert(node.id());
+                    assert_eq!(leader, node.id(), "two leaders in term {}", node.term());
+                }
+
+                for index in node.snapshot_index() + 1..=node.commit_index() {
+                    let term = node.term_at(index).unwrap();
+                    let committed = *self.committed_terms.entry(index).or_insert(term);
+                    assert_eq!(committed, term, "entry {} committed twice", index);
+                    let committed_in = self.committed_in.entry(index).or_insert(node.term());
+                    *committed_in = u32::min(*committed_in, node.term());
+                }
+
+                let applied = self
+                    .applied_metadata
+                    .entry(node.applied_index())
+                    .or_insert_with(|| node.metadata().clone());
+                assert_eq!(
+                    applied,
+                    node.metadata(),
+                    "diverged at {}",
+                    node.applied_index()
+                );
+            }
+
+            for node in self.nodes.iter().filter(|node| node.is_leader()) {
+                for (index, term) in self.committed_terms.iter() {
+                    if *index > node.snapshot_index() && node.term() > self.committed_in[index] {
+                        assert_eq!(node.term_at(*index), Some(*term), "leader lost {}", index);
+                    }
+                }
+            }
+        }
+
+        fn assert_converged(&self) {
+            assert!(self.leader().is_some());
+            for node in self.nodes.iter() {
+                assert_eq!(node.commit_index(), self.nodes[0].commit_index());
+                assert_eq!(node.metadata(), self.nodes[0].metadata());
+            }
+        }
+    }
+
+    fn config(value: u32) -> MetadataRecord {
+        MetadataRecord::TopicConfig(
+            String::from("topic"),
+            format!("key-{}", value),
+            value.to_string(),
+        )
+    }
+
+    #[test]
+    fn should_elect_one_leader_and_replicate_records() {
+        let mut simulation = Simulation::new(3, 0, SNAPSHOT_THRESHOLD);
+        simulation.run(50);
+
+        for value in 0..5 {
+            assert!(simulation.propose(config(value)).is_some());
+        }
+        simulation.run(20);
+
+        simulation.assert_converged();
+        assert_eq!(simulation.nodes[0].metadata().configs["topic"].len(), 5);
+    }
+
+    #[test]
+    fn should_stay_safe_under_message_loss_partitions_and_restarts() {
+        for seed in 0..20 {
+            let mut simulation = Simulation::new(5, seed, 4);
+            simulation.loss_percent = 20;
+
+            for step in 0..60 {
+                match simulation.next_random(6) {
+                    0 => {
+                        for id in 0..simulation.groups.len() {
+                            simulation.groups[id] = simulation.next_random(2) as u32;
+                        }
+                    }
+                    1 => simulation.groups = vec![0; 5],
+                    2 => {
+                        let id = simulation.next_random(5) as usize;
+                        simulation.restart(id);
+                    }
+                    _ => {
+                        simulation.propose(config(step));
+                    }
+                }
+                simulation.run(10);
+            }
+
+            simulation.groups = vec![0; 5];
+            simulation.loss_percent = 0;
+            simulation.run(100);
+            simulation.propose(config(1000));
+            simulation.run(50);
+            simulation.assert_converged();
+        }
+    }
+
+    #[test]
+    fn should_catch_up_lagging_node_with_snapshot() {
+        let mut simulation = Simulation::new(3, 7, 3);
+        simulation.run(50);
+        let lagging = (simulation.leader().unwrap() + 1) % 3;
+        simulation.groups[lagging] = 1;
+
+        for value in 0..10 {
+            simulation.propose(config(value));
+            simulation.run(5);
+        }
+        assert_eq!(simulation.nodes[lagging].snapshot_index(), 0);
+
+        simulation.groups[lagging] = 0;
+        simulation.run(50);
+
+        simulation.assert_converged();
+        assert!(simulation.nodes[lagging].snapshot_index() > 0);
+        assert_eq!(
+            simulation.nodes[lagging].metadata().configs["topic"].len(),
+            10
+        );
+    }
+
+    #[test]
+    fn should_not_commit_on_leader_cut_off_from_majority() {
+        let mut simulation = Simulation::new(5, 3, SNAPSHOT_THRESHOLD);
+        simulation.run(50);
+        let (old_leader, (index, _)) = simulation.propose(config(0)).unwrap();
+        simulation.run(20);
+        simulation.groups[old_leader] = 1;
+
+        let (_, (lost_index, _)) = simulation.propose(config(1)).unwrap();
+        simulation.run(100);
+
+        assert!(simulation.nodes[old_leader].commit_index() < lost_index);
+        assert!(!simulation.nodes[old_leader].is_leader());
+        assert!(simulation
+            .leader()
+            .is_some_and(|leader| leader != old_leader));
+
+        simulation.groups[old_leader] = 0;
+        simulation.run(50);
+
+        simulation.assert_converged();
+        let configs = &simulation.nodes[old_leader].metadata().configs["topic"];
+        assert!(configs.contains_key("key-0"));
+        assert!(!configs.contains_key("key-1"));
+        assert!(simulation.nodes[old_leader].commit_index() >= index);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::core::{PartitionState, TopicAddress};
+use crate::metadata::ClusterMetadata;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Starts moving a partition to the `target` replicas. Until they caught up
+/// the partition is replicated to the old replicas and the new ones.
+pub fn start_reassignment(state: &PartitionState, target: &[u32]) -> PartitionState {
+    let mut state = state.clone();
+    state.adding_replicas = target
+        .iter()
+        .copied()
+        .filter(|replica| !state.replicas.contains(replica))
+        .collect();
+    state.removing_replicas = state
+        .replicas
+        .iter()
+        .copied()
+        .filter(|replica| !target.contains(replica))
+        .collect();
+    state.replicas = target
+        .iter()
+        .chain(state.removing_replicas.iter())
+        .copied()
+        .collect();
+    state
+}
+
+/// Finishes a reassignment once every target replica is in sync: the old
+/// replicas are dropped and, when the leader is one of them, leadership
+/// moves to the first target replica `can_lead` accepts. The leader epoch
+/// grows, so ISR changes sent for the old replicas are refused. Returns
+/// None while the reassignment can't finish yet.
+pub fn complete_reassignment(
+    state: &PartitionState,
+    can_lead: impl Fn(u32) -> bool,
+) -> Option<PartitionState> {
+    if !state.is_reassigning() {
+        return None;
+    }
+
+    let target: Vec<u32> = state
+        .replicas
+        .iter()
+        .copied()
+        .filter(|replica| !state.removing_replicas.contains(replica))
+        .collect();
+    if !target.iter().all(|replica| state.isr.contains(replica)) {
+        return None;
+    }
+
+    let mut state = state.clone();
+    if !target.contains(&state.leader) {
+        state.leader = target.iter().copied().find(|replica| can_lead(*replica))?;
+    }
+    state.leader_epoch += 1;
+    state.isr.retain(|replica| target.contains(replica));
+    state.replicas = target;
+    state.adding_replicas.clear();
+    state.removing_replicas.clear();
+    Some(state)
+}
+
+/// Proposes moves that even out how many replicas each registered broker
+/// holds. Replicas go one at a time from the broker holding the most to the
+/// one holding the fewest, until those differ by at most one. Partitions
+/// being reassigned already are left alone. Returns the target replicas of
+/// every partition that moves, ordered by topic and partition.
+pub fn plan_rebalance(metadata: &ClusterMetadata) -> Vec<(TopicAddress, Vec<u32>)> {
+    let mut replica_count: HashMap<u32, usize> = metadata
+        .brokers
+        .keys()
+        .map(|broker_id| (*broker_id, 0))
+        .collect();
+    let mut state_list = metadata.partition_states();
+    for replica in state_list.iter().flat_map(|state| state.replicas.iter()) {
+        if let Some(count) = replica_count.get_mut(replica) {
+            *count += 1;
+        }
+    }
+    state_list.retain(|state| !state.is_reassigning());
+
+    let mut moved = vec![false; state_list.len()];
+    loop {
+        let fewest = replica_count
+            .iter()
+            .map(|(broker_id, count)| (*count, *broker_id))
+            .min();
+        let most = replica_count
+            .iter()
+            .map(|(broker_id, count)| (*count, Reverse(*broker_id)))
+            .max();
+        let ((fewest, to), (most, Reverse(from))) = match (fewest, most) {
+            (Some(fewest), Some(most)) => (fewest, most),
+            _ => break,
+        };
+        if most <= fewest + 1 {
+            break;
+        }
+
+        let position = match state_list
+            .iter()
+            .position(|state| state.replicas.contains(&from) && !state.replicas.contains(&to))
+        {
+            Some(position) => position,
+            None => break,
+        };
+        for replica in state_list[position].replicas.iter_mut() {
+            if *replica == from {
+                *replica = to;
+            }
+        }
+        moved[position] = true;
+        *replica_count.get_mut(&from).unwrap() -= 1;
+        *replica_count.get_mut(&to).unwrap() += 1;
+    }
+
+    state_list
+        .into_iter()
+        .zip(moved)
+        .filter(|(_, moved)| *moved)
+        .map(|(state, _)| (state.topic, state.replicas))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::MetadataRecord;
+
+    fn topic(partition: u32) -> TopicAddress {
+        TopicAddress::new(String::from("topic"), partition)
+    }
+
+    #[test]
+    fn should_drop_old_replicas_once_new_ones_are_in_sync() {
+        let state = PartitionState::new(topic(0), 0, vec![0, 1]);
+        let state = start_reassignment(&state, &[1, 2]);
+        assert_eq!(state.replicas, vec![1, 2, 0]);
+        assert_eq!(state.adding_replicas, vec![2]);
+        assert_eq!(state.removing_replicas, vec![0]);
+        assert_eq!(complete_reassignment(&state, |_| true), None);
+
+        let mut caught_up = state.clone();
+        caught_up.isr.push(2);
+        assert_eq!(complete_reassignment(&caught_up, |replica| replica != 1), {
+            let mut completed = PartitionState::new(topic(0), 2, vec![1, 2]);
+            completed.leader_epoch = 1;
+            Some(completed)
+        });
+        assert_eq!(complete_reassignment(&caught_up, |_| false), None);
+    }
+
+    #[test]
+    fn should_plan_moves_that_even_out_replicas_per_broker() {
+        let mut metadata = ClusterMetadata::new();
+        for broker_id in 0..4 {
+            metadata.apply(&MetadataRecord::RegisterBroker(
+                broker_id,
+                format!("localhost:{}", 8080 + broker_id),
+                None,
+            ));
+        }
+        let state_list = (0..4)
+            .map(|partition| PartitionState::new(topic(partition), 0, vec![0, 1]))
+            .collect();
+        metadata.apply(&MetadataRecord::PartitionStates(state_list));
+
+        let plan = plan_rebalance(&metadata);
+        let mut replica_count = HashMap::new();
+        for state in metadata.partition_states() {
+            let replicas = plan
+                .iter()
+                .find(|(moved, _)| *moved == state.topic)
+                .map_or(state.replicas, |(_, replicas)| replicas.clone());
+            for replica in replicas {
+                *replica_count.entry(replica).or_insert(0) += 1;
+            }
+        }
+
+        assert_eq!(plan[0], (topic(0), vec![2, 3]));
+        assert!((0..4).all(|broker_id| replica_count.get(&broker_id) == Some(&2)));
+    }
+}
@@ -0,0 +1,425 @@
+use crate::core::{OffsetValue, PartitionState, TopicAddress};
+use crate::detector::{Clock, SystemClock};
+use crate::storage::Partition;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+struct FollowerProgress {
+    // Unknown until the follower fetches from this leader for the first time.
+    end_offset: Option<OffsetValue>,
+    last_caught_up: Instant,
+}
+
+/// The leader and ISR of every partition the controller told this broker
+/// about and, for the partitions it leads, how far each follower got.
+pub struct ReplicaManager {
+    clock: Arc<dyn Clock>,
+    states: RwLock<HashMap<TopicAddress, PartitionState>>,
+    followers: Mutex<HashMap<TopicAddress, HashMap<u32, FollowerProgress>>>,
+    // Followed partitions whose log wasn't checked against their leader in
+    // this leader epoch yet, with the epoch.
+    unchecked: Mutex<HashMap<TopicAddress, u32>>,
+}
+
+impl ReplicaManager {
+    pub fn new() -> ReplicaManager {
+        ReplicaManager::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Followers lag behind as measured by `clock`.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> ReplicaManager {
+        ReplicaManager {
+            clock,
+            states: RwLock::new(HashMap::new()),
+            followers: Mutex::new(HashMap::new()),
+            unchecked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn state(&self, topic: &TopicAddress) -> Option<PartitionState> {
+        self.states.read().unwrap().get(topic).cloned()
+    }
+
+    pub fn states(&self) -> Vec<PartitionState> {
+        self.states.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn partition_count(&self, topic_name: &str) -> Option<u32> {
+        let count = self
+            .states
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|topic| topic.name == topic_name)
+            .count() as u32;
+        Some(count).filter(|count| *count > 0)
+    }
+
+    /// Stores a state sent by the controller. Returns false, ignoring it,
+    /// when it comes from an older leader epoch than the one already known.
+    pub fn apply(&self, broker_id: u32, state: PartitionState) -> bool {
+        let mut states = self.states.write().unwrap();
+        let current_epoch = states.get(&state.topic).map(|current| current.leader_epoch);
+        if current_epoch.is_some_and(|epoch| epoch > state.leader_epoch) {
+            return false;
+        }
+
+        let mut followers = self.followers.lock().unwrap();
+        if state.leader == broker_id {
+            let progress = followers.entry(state.topic.clone()).or_default();
+            if current_epoch != Some(state.leader_epoch) {
+                progress.clear();
+            }
+
+            progress.retain(|replica, _| state.replicas.contains(replica));
+            for replica in state
+                .replicas
+                .iter()
+                .filter(|replica| **replica != broker_id)
+            {
+                progress.entry(*replica).or_insert(FollowerProgress {
+                    end_offset: None,
+                    last_caught_up: self.clock.now(),
+                });
+            }
+        } else {
+            followers.remove(&state.topic);
+            if current_epoch != Some(state.leader_epoch) && state.replicas.contains(&broker_id) {
+                self.unchecked
+                    .lock()
+                    .unwrap()
+                    .insert(state.topic.clone(), state.leader_epoch);
+            }
+        }
+
+        states.insert(state.topic.clone(), state);
+        true
+    }
+
+    /// Whether this broker follows the partition in `leader_epoch` without
+    /// having truncated its log to match the leader's yet.
+    pub fn needs_truncation(&self, topic: &TopicAddress, leader_epoch: u32) -> bool {
+        self.unchecked.lock().unwrap().get(topic) == Some(&leader_epoch)
+    }
+
+    /// Records that the log of the partition matches the one of its leader
+    /// in `leader_epoch`.
+    pub fn truncated(&self, topic: &TopicAddress, leader_epoch: u32) {
+        let mut unchecked = self.unchecked.lock().unwrap();
+        if unchecked.get(topic) == Some(&leader_epoch) {
+            unchecked.remove(topic);
+        }
+    }
+
+    pub fn is_leader(&self, broker_id: u32, topic: &TopicAddress) -> bool {
+        self.state(topic)
+            .is_some_and(|state| state.leader == broker_id)
+    }
+
+    /// Partitions this broker holds a replica of but doesn't lead, ordered
+    /// by topic and partition so they are always fetched in the same order.
+    pub fn followed_partitions(&self, broker_id: u32) -> Vec<PartitionState> {
+        let mut state_list: Vec<PartitionState> = self
+            .states
+            .read()
+            .unwrap()
+            .values()
+            .filter(|state| state.leader != broker_id && state.replicas.contains(&broker_id))
+            .cloned()
+            .collect();
+        sort_by_topic(&mut state_list);
+        state_list
+    }
+
+    /// Records that a follower fetched from `fetch_offset`, so it has every
+    /// record before it. Returns the new state when that brings the follower
+    /// back into the ISR.
+    pub fn record_fetch(
+        &self,
+        topic: &TopicAddress,
+        replica_id: u32,
+        fetch_offset: OffsetValue,
+        partition: &Partition,
+    ) -> Option<PartitionState> {
+        let mut state = self.state(topic)?;
+        {
+            let mut followers = self.followers.lock().unwrap();
+            let progress = followers.get_mut(topic)?.get_mut(&replica_id)?;
+            progress.end_offset = Some(fetch_offset);
+            if fetch_offset.0 >= partition.end_offset().0 {
+                progress.last_caught_up = self.clock.now();
+            }
+        }
+
+        let high_watermark = partition.high_watermark().unwrap_or(OffsetValue(0));
+        if state.isr.contains(&replica_id) || fetch_offset.0 < high_watermark.0 {
+            return None;
+        }
+
+        state.isr.push(replica_id);
+        self.update_isr(state.clone());
+        Some(state)
+    }
+
+    /// Moves the high watermark of a partition this broker leads up to the
+    /// smallest log end offset in its ISR.
+    pub fn update_high_watermark(&self, topic: &TopicAddress, partition: &Partition) {
+        let state = match self.state(topic) {
+            Some(state) => state,
+            None => return,
+        };
+        let followers = self.followers.lock().unwrap();
+        let progress = match followers.get(topic) {
+            Some(progress) => progress,
+            None => return,
+        };
+
+        let mut high_watermark = partition.end_offset();
+        for replica in state.isr.iter().filter(|replica| **replica != state.leader) {
+            match progress
+                .get(replica)
+                .and_then(|progress| progress.end_offset)
+            {
+                Some(end_offset) => {
+                    high_watermark = OffsetValue(u32::min(high_watermark.0, end_offset.0))
+                }
+                None => return,
+            }
+        }
+
+        partition.advance_high_watermark(high_watermark);
+    }
+
+    /// Finds the partitions this broker leads with followers in the ISR that
+    /// didn't catch up within `max_lag`. Returns them without those followers,
+    /// to be applied with `update_isr` once the controller took them.
+    pub fn shrink_isr(&self, broker_id: u32, max_lag: Duration) -> Vec<PartitionState> {
+        let mut led_states: Vec<PartitionState> = self
+            .states
+            .read()
+            .unwrap()
+            .values()
+            .filter(|state| state.leader == broker_id)
+            .cloned()
+            .collect();
+        sort_by_topic(&mut led_states);
+
+        let now = self.clock.now();
+        let mut changed_states = Vec::new();
+        {
+            let followers = self.followers.lock().unwrap();
+            for mut state in led_states {
+                let progress = match followers.get(&state.topic) {
+                    Some(progress) => progress,
+                    None => continue,
+                };
+
+                let isr_size = state.isr.len();
+                state.isr.retain(|replica| {
+                    *replica == broker_id
+                        || progress.get(replica).is_some_and(|progress| {
+                            now.duration_since(progress.last_caught_up) <= max_lag
+                        })
+                });
+                if state.isr.len() != isr_size {
+                    changed_states.push(state);
+                }
+            }
+        }
+        changed_states
+    }
+
+    /// Takes the ISR of `state` unless the leader epoch moved on meanwhile.
+    pub fn update_isr(&self, state: PartitionState) {
+        let mut states = self.states.write().unwrap();
+        if let Some(current) = states.get_mut(&state.topic) {
+            if current.leader_epoch == state.leader_epoch {
+                current.isr = state.isr;
+            }
+        }
+    }
+
+    /// Forgets every partition of a deleted topic, so it can be created
+    /// again from leader epoch 0.
+    pub fn remove_topic(&self, topic_name: &str) {
+        let mut states = self.states.write().unwrap();
+        let mut followers = self.followers.lock().unwrap();
+        let mut unchecked = self.unchecked.lock().unwrap();
+        states.retain(|topic, _| topic.name != topic_name);
+        followers.retain(|topic, _| topic.name != topic_name);
+        unchecked.retain(|topic, _| topic.name != topic_name);
+    }
+}
+
+/// Limits how many records per second are copied, holding up to one
+/// second's worth when nothing was copied for a while.
+pub struct Throttle {
+    clock: Arc<dyn Clock>,
+    records_per_second: u32,
+    // Records that may be copied now, and when that was last worked out.
+    available: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    pub fn new(clock: Arc<dyn Clock>, records_per_second: u32) -> Throttle {
+        let now = clock.now();
+        Throttle {
+            clock,
+            records_per_second,
+            available: Mutex::new((records_per_second as f64, now)),
+        }
+    }
+
+    /// Takes up to `wanted` records from what may be copied now and returns
+    /// how many were taken, zero when the limit is reached.
+    pub fn take(&self, wanted: u32) -> u32 {
+        let now = self.clock.now();
+        let mut available = self.available.lock().unwrap();
+        let (records, last_refill) = &mut *available;
+        let elapsed = now.duration_since(*last_refill).as_secs_f64();
+        let limit = self.records_per_second as f64;
+        *records = f64::min(*records + elapsed * limit, limit);
+        *last_refill = now;
+
+        let taken = u32::min(wanted, *records as u32);
+        *records -= taken as f64;
+        taken
+    }
+}
+
+fn sort_by_topic(state_list: &mut [PartitionState]) {
+    state_list.sort_by(|first, second| {
+        (&first.topic.name, first.topic.partition)
+            .cmp(&(&second.topic.name, second.topic.partition))
+    });
+}
+
+impl Default for ReplicaManager {
+    fn default() -> ReplicaManager {
+        ReplicaManager::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Content, Isolation};
+    use crate::detector::ManualClock;
+    use std::thread;
+
+    fn topic() -> TopicAddress {
+        TopicAddress::new(String::from("topic"), 0)
+    }
+
+    fn leader_of_three(replicas: &ReplicaManager) -> Partition {
+        let partition = Partition::new();
+        partition.advance_high_watermark(OffsetValue(0));
+        replicas.apply(0, PartitionState::new(topic(), 0, vec![0, 1, 2]));
+        partition
+    }
+
+    #[test]
+    fn should_only_show_records_every_in_sync_replica_has() {
+        let replicas = ReplicaManager::new();
+        let partition = leader_of_three(&replicas);
+        for value in ["a", "b", "c"].iter() {
+            partition
+                .add_content(Content::new(value.to_string()))
+                .unwrap();
+        }
+
+        replicas.update_high_watermark(&topic(), &partition);
+        assert_eq!(
+            partition
+                .read(OffsetValue(0), 10, Isolation::ReadUncommitted)
+                .len(),
+            0
+        );
+
+        replicas.record_fetch(&topic(), 1, OffsetValue(3), &partition);
+        replicas.record_fetch(&topic(), 2, OffsetValue(2), &partition);
+        replicas.update_high_watermark(&topic(), &partition);
+
+        assert_eq!(partition.high_watermark(), Some(OffsetValue(2)));
+        assert_eq!(
+            partition
+                .read(OffsetValue(0), 10, Isolation::ReadUncommitted)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn should_drop_lagging_follower_from_isr_and_take_it_back_once_caught_up() {
+        let replicas = ReplicaManager::new();
+        let partition = leader_of_three(&replicas);
+        partition
+            .add_content(Content::new(String::from("a")))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        replicas.record_fetch(&topic(), 1, OffsetValue(1), &partition);
+        replicas.record_fetch(&topic(), 2, OffsetValue(0), &partition);
+        let changed = replicas.shrink_isr(0, Duration::from_millis(50));
+
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].isr, vec![0, 1]);
+        assert_eq!(replicas.state(&topic()).unwrap().isr, vec![0, 1, 2]);
+        replicas.update_isr(changed[0].clone());
+
+        replicas.update_high_watermark(&topic(), &partition);
+        assert_eq!(partition.high_watermark(), Some(OffsetValue(1)));
+
+        let expanded = replicas.record_fetch(&topic(), 2, OffsetValue(1), &partition);
+        assert_eq!(expanded.map(|state| state.isr), Some(vec![0, 1, 2]));
+        assert_eq!(replicas.state(&topic()).unwrap().isr, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn should_ignore_state_from_older_leader_epoch() {
+        let replicas = ReplicaManager::new();
+        let mut newer = PartitionState::new(topic(), 1, vec![0, 1]);
+        newer.leader_epoch = 2;
+        let mut older = PartitionState::new(topic(), 0, vec![0, 1]);
+        older.leader_epoch = 1;
+
+        assert!(replicas.apply(0, newer));
+        assert!(!replicas.apply(0, older));
+        assert_eq!(replicas.state(&topic()).unwrap().leader, 1);
+        assert_eq!(replicas.followed_partitions(0).len(), 1);
+    }
+
+    #[test]
+    fn should_truncate_follower_once_per_leader_epoch() {
+        let replicas = ReplicaManager::new();
+        let mut state = PartitionState::new(topic(), 1, vec![0, 1]);
+        replicas.apply(0, state.clone());
+        assert!(replicas.needs_truncation(&topic(), 0));
+
+        replicas.truncated(&topic(), 0);
+        state.isr = vec![1];
+        replicas.apply(0, state.clone());
+        assert!(!replicas.needs_truncation(&topic(), 0));
+
+        state.leader_epoch = 1;
+        replicas.apply(0, state);
+        replicas.truncated(&topic(), 0);
+        assert!(replicas.needs_truncation(&topic(), 1));
+    }
+
+    #[test]
+    fn should_throttle_records_to_the_configured_rate() {
+        let clock = Arc::new(ManualClock::new());
+        let throttle = Throttle::new(clock.clone(), 100);
+
+        assert_eq!(throttle.take(60), 60);
+        assert_eq!(throttle.take(60), 40);
+        assert_eq!(throttle.take(60), 0);
+
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(throttle.take(60), 25);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(throttle.take(500), 100);
+    }
+}
@@ -0,0 +1,1474 @@
+use crate::communication::{Action, ActionMessage, ResponseMessage};
+use crate::endpoint::Broker;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+type Connections = Arc<Mutex<HashMap<usize, TcpStream>>>;
+
+/// Frees a connection's place among the `max_connections` once its thread
+/// ends, even when it panics.
+struct ConnectionSlot {
+    id: usize,
+    connections: Connections,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(&self.id);
+        }
+    }
+}
+
+/// Serves a broker over TCP, with a thread per connection plus the broker's
+/// failure detector, replication and controller loops.
+pub struct Server {
+    address: String,
+    broker: Arc<Broker>,
+    connections: Connections,
+    listener_thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Binds `address` and starts serving. Port 0 picks a free port, see
+    /// `address`. A broker configured with bootstrap brokers then joins their
+    /// cluster.
+    pub fn start(address: String, broker: Arc<Broker>) -> io::Result<Server> {
+        let listener = TcpListener::bind(&address)?;
+        let address = listener.local_addr()?.to_string();
+        let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+        broker.join_cluster(address.clone());
+
+        let cloned_broker = broker.clone();
+        thread::spawn(move || {
+            while !cloned_broker.is_stopped() {
+                cloned_broker.loop_failure_detector();
+            }
+        });
+
+        let cloned_broker = broker.clone();
+        thread::spawn(move || {
+            while !cloned_broker.is_stopped() {
+                cloned_broker.replicate();
+            }
+        });
+
+        let cloned_broker = broker.clone();
+        thread::spawn(move || {
+            while !cloned_broker.is_stopped() {
+                cloned_broker.tick_controller();
+            }
+        });
+
+        let cloned_broker = broker.clone();
+        thread::spawn(move || {
+            while !cloned_broker.is_stopped() {
+                cloned_broker.clean_logs();
+            }
+        });
+
+        let max_connections = broker.config().max_connections;
+        let cloned_broker = broker.clone();
+        let cloned_connections = connections.clone();
+        let listener_thread = thread::spawn(move || {
+            let mut next_id = 0;
+            for stream in listener.incoming() {
+                if cloned_broker.is_stopped() {
+                    return;
+                }
+
+                match stream {
+                    Ok(stream) => {
+                        if cloned_connections.lock().unwrap().len() >= max_connections {
+                            println!("Refusing connection, {} already open", max_connections);
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+
+                        let id = next_id;
+                        next_id += 1;
+                        if let Ok(cloned_stream) = stream.try_clone() {
+                            cloned_connections.lock().unwrap().insert(id, cloned_stream);
+                        }
+
+                        let broker = cloned_broker.clone();
+                        let slot = ConnectionSlot {
+                            id,
+                            connections: cloned_connections.clone(),
+                        };
+                        thread::spawn(move || {
+                            let _slot = slot;
+                            handle_connection(stream, &broker);
+                        });
+                    }
+                    Err(err) => println!("Failed to process current stream\n{}", err),
+                }
+            }
+        });
+
+        Ok(Server {
+            address,
+            broker,
+            connections,
+            listener_thread: Some(listener_thread),
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn broker(&self) -> Arc<Broker> {
+        self.broker.clone()
+    }
+
+    /// Blocks for as long as the broker runs, until it is stopped or shut down.
+    pub fn wait(mut self) {
+        while !self.broker.is_stopped() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        self.stop();
+        if let Some(listener_thread) = self.listener_thread.take() {
+            let _ = listener_thread.join();
+        }
+    }
+
+    /// Stops the broker and drops every open connection at once, the way a
+    /// crashed process would.
+    pub fn stop(&self) {
+        self.broker.stop();
+
+        // Wakes the listener up so it notices the broker stopped.
+        let _ = TcpStream::connect(&self.address);
+        for stream in self.connections.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, broker: &Broker) {
+    loop {
+        let message = match ActionMessage::read_from(&mut stream) {
+            Ok(message) => message,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(err) => {
+                if !broker.is_stopped() {
+                    println!("Failed to read stream\r\n{}", err);
+                }
+                return;
+            }
+        };
+
+        if broker.is_stopped() {
+            return;
+        }
+
+        if let Action::Quit = message.action {
+            let _ = ResponseMessage::write_list_to(&mut stream, &[ResponseMessage::new_empty()]);
+            return;
+        }
+
+        let expects_response = message.expects_response();
+        let mut response_list = broker.handle(message);
+        if !expects_response {
+            continue;
+        }
+
+        if response_list.is_empty() {
+            response_list.push(ResponseMessage::new_empty());
+        }
+
+        if ResponseMessage::write_list_to(&mut stream, &response_list).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::Response;
+    use crate::config::{
+        BrokerConfig, CLEANUP_POLICY, COMPRESSION_TYPE, MAX_MESSAGE_BYTES, MIN_INSYNC_REPLICAS,
+        RETENTION_MS,
+    };
+    use crate::core::{Acks, Content, Isolation, OffsetValue, ProducerRecord, TopicAddress};
+    use crate::endpoint::{Client, Consumer, Producer, ProducerConfig, RecordHandle};
+    use crate::launcher::DevCluster;
+    use std::time::{Duration, Instant};
+
+    fn test_config() -> BrokerConfig {
+        BrokerConfig {
+            replica_lag_time: Duration::from_millis(500),
+            replica_fetch_interval: Duration::from_millis(10),
+            replication_timeout: Duration::from_secs(5),
+            ..BrokerConfig::default()
+        }
+    }
+
+    fn start_cluster(size: usize) -> Vec<Server> {
+        start_cluster_with(size, test_config())
+    }
+
+    fn start_cluster_with(size: usize, config: BrokerConfig) -> Vec<Server> {
+        start_brokers(vec![config; size])
+    }
+
+    fn start_brokers(config_list: Vec<BrokerConfig>) -> Vec<Server> {
+        DevCluster::with_configs(config_list)
+            .unwrap()
+            .into_servers()
+    }
+
+    fn request(server: &Server, action: Action) -> Vec<Response> {
+        Client::new(server.address().to_string())
+            .send_message(ActionMessage::new(action, String::new()))
+            .into_iter()
+            .map(|message| message.response)
+            .collect()
+    }
+
+    fn produce(server: &Server, topic: &TopicAddress, values: &[&str]) -> Vec<Response> {
+        let content_list = values
+            .iter()
+            .map(|value| Content::new(value.to_string()))
+            .collect();
+        request(
+            server,
+            Action::Produce(topic.clone(), content_list, Acks::All, None),
+        )
+    }
+
+    fn stored_values(server: &Server, topic: &TopicAddress) -> Vec<String> {
+        match server.broker().partition(topic) {
+            Some(partition) => partition
+                .queue
+                .lock()
+                .unwrap()
+                .records
+                .iter()
+                .map(|record| record.content.value.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn polled_values(consumer: &mut Consumer) -> Vec<String> {
+        consumer
+            .poll()
+            .into_iter()
+            .map(|record| record.content.value)
+            .collect()
+    }
+
+    /// Polls until `count` records arrived, or for 5 seconds.
+    fn poll_until(consumer: &mut Consumer, count: usize) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut values = Vec::new();
+        while values.len() < count && Instant::now() < deadline {
+            values.extend(polled_values(consumer));
+            thread::sleep(Duration::from_millis(10));
+        }
+        values
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) -> bool {
+        wait_for(Duration::from_secs(5), condition)
+    }
+
+    fn wait_for(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn should_free_connection_slot_when_connection_thread_panics() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+        connections.lock().unwrap().insert(7, stream);
+
+        let slot = ConnectionSlot {
+            id: 7,
+            connections: connections.clone(),
+        };
+        let result = thread::spawn(move || {
+            let _slot = slot;
+            panic!("connection failed");
+        })
+        .join();
+
+        assert!(result.is_err());
+        assert!(connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_keep_acknowledged_records_on_followers_when_leader_dies() {
+        let server_list = start_cluster(3);
+        let topic = TopicAddress::new(String::from("replicated"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 3, Vec::new()),
+        );
+
+        for (batch, values) in [["a", "b"], ["c", "d"], ["e", "f"]].iter().enumerate() {
+            let response_list = produce(&server_list[0], &topic, values);
+            assert!(matches!(
+                response_list[0],
+                Response::Offset(OffsetValue(offset)) if offset == batch as u32 * 2 + 1
+            ));
+        }
+        server_list[0].stop();
+
+        for follower in server_list[1..].iter() {
+            assert_eq!(
+                stored_values(follower, &topic),
+                vec!["a", "b", "c", "d", "e", "f"]
+            );
+            follower.stop();
+        }
+    }
+
+    #[test]
+    fn should_drop_dead_follower_from_isr_and_keep_accepting_acks_all() {
+        let server_list = start_cluster(3);
+        let topic = TopicAddress::new(String::from("shrinking"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 3, Vec::new()),
+        );
+        produce(&server_list[0], &topic, &["a"]);
+        server_list[2].stop();
+
+        let response_list = produce(&server_list[0], &topic, &["b"]);
+
+        assert!(matches!(response_list[0], Response::Offset(OffsetValue(1))));
+        let leader = server_list[0].broker();
+        assert_eq!(leader.partition_state(&topic).unwrap().isr, vec![0, 1]);
+        let follower = server_list[1].broker();
+        assert!(wait_until(|| follower
+            .partition_state(&topic)
+            .is_some_and(|state| state.isr == vec![0, 1])));
+        assert_eq!(stored_values(&server_list[1], &topic), vec!["a", "b"]);
+
+        server_list[0].stop();
+        server_list[1].stop();
+    }
+
+    #[test]
+    fn should_show_consumers_only_replicated_records() {
+        let server_list = start_cluster(2);
+        let topic = TopicAddress::new(String::from("visible"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 2, Vec::new()),
+        );
+        produce(&server_list[0], &topic, &["a", "b"]);
+        server_list[1].stop();
+
+        let leader = server_list[0].broker();
+        leader
+            .partition(&topic)
+            .unwrap()
+            .add_content(Content::new(String::from("unreplicated")))
+            .unwrap();
+        let response_list = request(
+            &server_list[0],
+            Action::Consume(topic, OffsetValue(0), 10, Isolation::ReadUncommitted),
+        );
+
+        assert_eq!(response_list.len(), 2);
+        server_list[0].stop();
+    }
+
+    #[test]
+    fn should_redeliver_records_polled_after_last_auto_commit_when_consumer_reopens() {
+        let server_list = start_cluster(1);
+        let topic = TopicAddress::new(String::from("redelivered"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 1, Vec::new()),
+        );
+        produce(&server_list[0], &topic, &["a", "b", "c", "d", "e"]);
+
+        let address = server_list[0].address().to_string();
+        let mut consumer = Consumer::new(address.clone(), String::from("c1"));
+        consumer.set_max_poll_records(2);
+        consumer.subscribe(topic.clone());
+        assert_eq!(polled_values(&mut consumer), vec!["a", "b"]);
+        // Commits offset 2 before reading on, then goes away without closing.
+        assert_eq!(polled_values(&mut consumer), vec!["c", "d"]);
+        drop(consumer);
+
+        let mut consumer = Consumer::new(address, String::from("c1"));
+        consumer.subscribe(topic.clone());
+        assert_eq!(consumer.position(&topic), Some(OffsetValue(2)));
+        assert_eq!(polled_values(&mut consumer), vec!["c", "d", "e"]);
+        consumer.close();
+
+        server_list[0].stop();
+    }
+
+    #[test]
+    fn should_poll_from_new_position_after_seek() {
+        let server_list = start_cluster(1);
+        let topic = TopicAddress::new(String::from("sought"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 1, Vec::new()),
+        );
+        produce(&server_list[0], &topic, &["a", "b", "c"]);
+
+        let mut consumer = Consumer::new(server_list[0].address().to_string(), String::from("c1"));
+        consumer.subscribe(topic.clone());
+        assert_eq!(polled_values(&mut consumer), vec!["a", "b", "c"]);
+
+        consumer.seek(&topic, OffsetValue(1));
+        assert_eq!(polled_values(&mut consumer), vec!["b", "c"]);
+        consumer.seek_to_beginning(&topic);
+        assert_eq!(polled_values(&mut consumer), vec!["a", "b", "c"]);
+        consumer.seek_to_end(&topic);
+        assert!(consumer.poll().is_empty());
+
+        consumer.close();
+        server_list[0].stop();
+    }
+
+    #[test]
+    fn should_return_at_most_max_poll_records_per_poll() {
+        let server_list = start_cluster(1);
+        let topic = TopicAddress::new(String::from("capped"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 1, Vec::new()),
+        );
+        produce(&server_list[0], &topic, &["a", "b", "c", "d", "e"]);
+
+        let mut consumer = Consumer::new(server_list[0].address().to_string(), String::from("c1"));
+        consumer.set_max_poll_records(2);
+        consumer.subscribe(topic.clone());
+        assert_eq!(polled_values(&mut consumer), vec!["a", "b"]);
+        assert_eq!(polled_values(&mut consumer), vec!["c", "d"]);
+        assert_eq!(polled_values(&mut consumer), vec!["e"]);
+        assert!(consumer.poll().is_empty());
+
+        consumer.close();
+        server_list[0].stop();
+    }
+
+    #[test]
+    fn should_create_consumer_while_broker_is_down() {
+        let server_list = start_cluster(1);
+        let address = server_list[0].address().to_string();
+        server_list[0].stop();
+        assert!(wait_until(|| TcpStream::connect(&address).is_err()));
+
+        let mut consumer = Consumer::new(address, String::from("c1"));
+        consumer.subscribe(TopicAddress::new(String::from("t"), 0));
+        assert!(consumer.poll().is_empty());
+    }
+
+    #[test]
+    fn should_send_records_as_one_batch_once_full_or_after_linger() {
+        let server_list = start_cluster(1);
+        let topic = TopicAddress::new(String::from("batched"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 2, 1, Vec::new()),
+        );
+        let send = |producer: &mut Producer, partition, value: &str| {
+            producer.send(ProducerRecord::with_partition(
+                topic.name.clone(),
+                partition,
+                Content::new(value.to_string()),
+            ))
+        };
+        let batch_count = |partition| {
+            let topic = TopicAddress::new(topic.name.clone(), partition);
+            let partition = server_list[0].broker().partition(&topic).unwrap();
+            partition.read_batches(OffsetValue(0), 100).len()
+        };
+
+        let config = ProducerConfig {
+            batch_size: 3,
+            linger: Duration::from_secs(60),
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[0].address().to_string(), config);
+        let handle_list = [send(&mut producer, 0, "a"), send(&mut producer, 0, "b")];
+        assert_eq!(
+            handle_list[0].wait_timeout(Duration::from_millis(200)),
+            None
+        );
+        let handle = send(&mut producer, 0, "c");
+        assert_eq!(handle.wait(), Ok(Some(OffsetValue(2))));
+        assert_eq!(handle_list[0].try_get(), Some(Ok(Some(OffsetValue(0)))));
+        assert_eq!(handle_list[1].try_get(), Some(Ok(Some(OffsetValue(1)))));
+        assert_eq!(batch_count(0), 1);
+        producer.close();
+
+        let config = ProducerConfig {
+            batch_size: 100,
+            linger: Duration::from_millis(300),
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[0].address().to_string(), config);
+        let started = Instant::now();
+        let handle_list = [send(&mut producer, 1, "d"), send(&mut producer, 1, "e")];
+        assert_eq!(handle_list[1].wait(), Ok(Some(OffsetValue(1))));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(handle_list[0].try_get(), Some(Ok(Some(OffsetValue(0)))));
+        assert_eq!(batch_count(1), 1);
+        producer.close();
+
+        server_list[0].stop();
+    }
+
+    #[test]
+    fn should_complete_every_outstanding_handle_on_flush() {
+        let server_list = start_cluster(1);
+        let topic_name = String::from("flushed");
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic_name.clone(), 2, 1, Vec::new()),
+        );
+
+        let config = ProducerConfig {
+            linger: Duration::from_secs(60),
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[0].address().to_string(), config);
+        let handle_list: Vec<RecordHandle> = (0..10)
+            .map(|value| {
+                producer.send(ProducerRecord::with_partition(
+                    topic_name.clone(),
+                    value % 2,
+                    Content::new(value.to_string()),
+                ))
+            })
+            .collect();
+        assert!(handle_list.iter().all(|handle| handle.try_get().is_none()));
+
+        producer.flush();
+        for (value, handle) in handle_list.iter().enumerate() {
+            assert_eq!(
+                handle.try_get(),
+                Some(Ok(Some(OffsetValue(value as u32 / 2))))
+            );
+        }
+
+        producer.close();
+        server_list[0].stop();
+    }
+
+    #[test]
+    fn should_complete_fire_and_forget_sends_without_an_offset() {
+        let server_list = start_cluster(1);
+        let topic = TopicAddress::new(String::from("unacknowledged"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 1, Vec::new()),
+        );
+
+        let config = ProducerConfig {
+            acks: Acks::None,
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[0].address().to_string(), config);
+        let handle = producer.send(ProducerRecord::new(
+            topic.name.clone(),
+            Content::new(String::from("a")),
+        ));
+        producer.flush();
+        assert_eq!(handle.try_get(), Some(Ok(None)));
+        assert!(wait_until(
+            || stored_values(&server_list[0], &topic) == vec!["a"]
+        ));
+
+        producer.close();
+        server_list[0].stop();
+    }
+
+    #[test]
+    fn should_refuse_acks_all_while_fewer_replicas_than_min_insync_are_in_sync() {
+        let server_list = start_cluster(3);
+        let topic = TopicAddress::new(String::from("guarded"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 2, Vec::new()),
+        );
+        let min_insync = |value: &str| vec![(String::from(MIN_INSYNC_REPLICAS), value.to_string())];
+        for config_list in [
+            min_insync("0"),
+            vec![(String::from("a.b"), String::from("1"))],
+        ] {
+            let response_list = request(
+                &server_list[2],
+                Action::AlterConfigs(topic.name.clone(), config_list),
+            );
+            assert!(matches!(response_list[0], Response::InvalidConfig(_)));
+        }
+        let response_list = request(
+            &server_list[2],
+            Action::AlterConfigs(topic.name.clone(), min_insync("2")),
+        );
+        assert!(matches!(response_list[0], Response::Empty));
+
+        assert!(matches!(
+            produce(&server_list[0], &topic, &["a"])[0],
+            Response::Offset(OffsetValue(0))
+        ));
+        server_list[1].stop();
+        for value in ["b", "c"] {
+            let response_list = produce(&server_list[0], &topic, &[value]);
+            assert!(matches!(response_list[0], Response::NotEnoughReplicas));
+        }
+        let response_list = request(
+            &server_list[0],
+            Action::Produce(
+                topic.clone(),
+                vec![Content::new(String::from("d"))],
+                Acks::Leader,
+                None,
+            ),
+        );
+        assert!(matches!(response_list[0], Response::Offset(_)));
+
+        assert!(wait_until(|| request(
+            &server_list[2],
+            Action::Metadata(topic.name.clone())
+        )
+        .iter()
+        .any(|response| matches!(
+            response,
+            Response::PartitionMetadata(state) if state.isr == vec![0]
+        ))));
+
+        request(
+            &server_list[2],
+            Action::AlterConfigs(topic.name.clone(), min_insync("1")),
+        );
+        assert!(matches!(
+            produce(&server_list[0], &topic, &["e"])[0],
+            Response::Offset(_)
+        ));
+
+        server_list[0].stop();
+        server_list[2].stop();
+    }
+
+    #[test]
+    fn should_move_leadership_to_in_sync_replica_when_leader_dies() {
+        let server_list = start_cluster(3);
+        let topic = TopicAddress::new(String::from("failover"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 3, Vec::new()),
+        );
+
+        let config = ProducerConfig {
+            acks: Acks::All,
+            retries: 6,
+            retry_backoff: Duration::from_millis(500),
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[0].address().to_string(), config);
+        let mut send = |value: &str| {
+            producer
+                .send(ProducerRecord::new(
+                    topic.name.clone(),
+                    Content::new(value.to_string()),
+                ))
+                .wait()
+        };
+        assert_eq!(send("a"), Ok(Some(OffsetValue(0))));
+        server_list[0].stop();
+
+        assert_eq!(send("b"), Ok(Some(OffsetValue(1))));
+        let new_leader = server_list[1].broker();
+        let state = new_leader.partition_state(&topic).unwrap();
+        assert_eq!((state.leader, state.leader_epoch), (1, 1));
+        assert_eq!(state.isr, vec![1, 2]);
+        let follower = server_list[2].broker();
+        assert!(wait_until(|| follower
+            .partition_state(&topic)
+            .is_some_and(|follower_state| follower_state == state)));
+
+        let response_list = produce(&server_list[2], &topic, &["c"]);
+        assert!(matches!(response_list[0], Response::NotLeader));
+
+        let mut consumer = Consumer::new(server_list[2].address().to_string(), String::from("c1"));
+        consumer.subscribe(topic.clone());
+        let values: Vec<String> = (&mut consumer).map(|record| record.content.value).collect();
+        assert_eq!(values, vec!["a", "b"]);
+
+        server_list[1].stop();
+        server_list[2].stop();
+    }
+
+    #[test]
+    fn should_let_broker_join_running_cluster_and_remove_it() {
+        let server_list = start_cluster(3);
+        let config = BrokerConfig {
+            bootstrap_brokers: vec![server_list[2].address().to_string()],
+            ..test_config()
+        };
+        let broker = Broker::with_config(config).unwrap();
+        let new_server = Server::start(String::from("127.0.0.1:0"), Arc::new(broker)).unwrap();
+
+        let expected_brokers: Vec<(u32, String)> = server_list
+            .iter()
+            .chain(Some(&new_server))
+            .enumerate()
+            .map(|(broker_id, server)| (broker_id as u32, server.address().to_string()))
+            .collect();
+        assert!(wait_until(|| server_list
+            .iter()
+            .chain(Some(&new_server))
+            .all(|server| server.broker().brokers() == expected_brokers)));
+
+        let topic = TopicAddress::new(String::from("everywhere"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 4, Vec::new()),
+        );
+        produce(&server_list[0], &topic, &["a"]);
+        assert_eq!(stored_values(&new_server, &topic), vec!["a"]);
+
+        let response_list = request(&server_list[0], Action::UnregisterBroker(0));
+        assert!(matches!(response_list[0], Response::Error));
+        request(&server_list[0], Action::UnregisterBroker(3));
+
+        let new_broker = new_server.broker();
+        assert!(wait_until(|| new_broker.is_stopped()));
+        assert!(wait_until(|| server_list
+            .iter()
+            .all(
+                |server| server.broker().brokers() == expected_brokers[..3]
+            )));
+
+        for server in server_list.iter().chain(Some(&new_server)) {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_spread_partitions_over_brokers_and_route_producers_to_leaders() {
+        let server_list = start_cluster(3);
+        let topic_name = String::from("spread");
+        let topic = |partition| TopicAddress::new(topic_name.clone(), partition);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic_name.clone(), 3, 2, Vec::new()),
+        );
+
+        for partition in 0..3 {
+            let state = server_list[0]
+                .broker()
+                .partition_state(&topic(partition))
+                .unwrap();
+            assert_eq!(state.leader, partition);
+            assert_eq!(state.replicas, vec![partition, (partition + 1) % 3]);
+            for (broker_id, server) in server_list.iter().enumerate() {
+                let broker = server.broker();
+                assert_eq!(
+                    broker.partition_state(&topic(partition)),
+                    Some(state.clone())
+                );
+                assert_eq!(
+                    broker.partition(&topic(partition)).is_some(),
+                    state.replicas.contains(&(broker_id as u32))
+                );
+            }
+        }
+
+        let config = ProducerConfig {
+            acks: Acks::All,
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[2].address().to_string(), config);
+        for partition in 0..3 {
+            let handle = producer.send(ProducerRecord::with_partition(
+                topic_name.clone(),
+                partition,
+                Content::new(partition.to_string()),
+            ));
+            assert_eq!(handle.wait(), Ok(Some(OffsetValue(0))));
+        }
+        for partition in 0..3 {
+            assert_eq!(
+                stored_values(&server_list[partition as usize], &topic(partition)),
+                vec![partition.to_string()]
+            );
+        }
+
+        let config = BrokerConfig {
+            bootstrap_brokers: vec![server_list[1].address().to_string()],
+            ..test_config()
+        };
+        let broker = Broker::with_config(config).unwrap();
+        let new_server = Server::start(String::from("127.0.0.1:0"), Arc::new(broker)).unwrap();
+        let new_broker = new_server.broker();
+        assert!(wait_until(|| (0..3).all(|partition| new_broker
+            .partition_state(&topic(partition))
+            .is_some())));
+
+        producer.close();
+        for server in server_list.iter().chain(Some(&new_server)) {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_hand_partitions_over_on_shutdown_without_failing_produces() {
+        let server_list = start_cluster(3);
+        let topic_name = String::from("rolling");
+        let topic = TopicAddress::new(topic_name.clone(), 1);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic_name.clone(), 3, 3, Vec::new()),
+        );
+        let leader = server_list[1].broker();
+        assert_eq!(leader.partition_state(&topic).unwrap().leader, 1);
+
+        let config = ProducerConfig {
+            acks: Acks::All,
+            idempotence: true,
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[0].address().to_string(), config);
+        let mut sent = Vec::new();
+        let mut send = |producer: &mut Producer| {
+            let value = sent.len().to_string();
+            let handle = producer.send(ProducerRecord::with_partition(
+                topic_name.clone(),
+                1,
+                Content::new(value.clone()),
+            ));
+            assert!(handle.wait().is_ok(), "failed to send {}", value);
+            sent.push(value);
+        };
+
+        for _ in 0..5 {
+            send(&mut producer);
+        }
+        request(&server_list[1], Action::Shutdown);
+        while !leader.is_stopped() {
+            send(&mut producer);
+        }
+        for _ in 0..5 {
+            send(&mut producer);
+        }
+
+        let state = server_list[0].broker().partition_state(&topic).unwrap();
+        assert_ne!(state.leader, 1);
+        assert!(!state.isr.contains(&1));
+        let new_leader = &server_list[state.leader as usize];
+        assert_eq!(stored_values(new_leader, &topic), sent);
+
+        // Restarted, it registers again and catches up.
+        server_list[1].stop();
+        let config = BrokerConfig {
+            broker_id: Some(1),
+            bootstrap_brokers: vec![server_list[0].address().to_string()],
+            ..test_config()
+        };
+        let broker = Broker::with_config(config).unwrap();
+        let restarted = Server::start(String::from("127.0.0.1:0"), Arc::new(broker)).unwrap();
+        let controller = server_list[0].broker();
+        assert!(wait_until(|| controller
+            .partition_state(&topic)
+            .is_some_and(|state| state.isr.contains(&1))));
+        assert_eq!(stored_values(&restarted, &topic), sent);
+
+        producer.close();
+        for server in [&server_list[0], &server_list[2], &restarted] {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_move_partition_to_new_replicas_at_throttled_rate() {
+        let config = BrokerConfig {
+            reassignment_throttle: Some(100),
+            ..test_config()
+        };
+        let server_list = start_cluster_with(4, config);
+        let topic = TopicAddress::new(String::from("moving"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 2, Vec::new()),
+        );
+        let mut sent = Vec::new();
+        for batch in 0..30 {
+            let values: Vec<String> = (0..10)
+                .map(|value| (batch * 10 + value).to_string())
+                .collect();
+            let values: Vec<&str> = values.iter().map(|value| value.as_str()).collect();
+            produce(&server_list[0], &topic, &values);
+            sent.extend(values.iter().map(|value| value.to_string()));
+        }
+
+        let response_list = request(
+            &server_list[1],
+            Action::AlterPartitionReassignment(topic.clone(), vec![2, 3]),
+        );
+        assert!(matches!(
+            &response_list[0],
+            Response::Reassignment(_, replicas) if *replicas == vec![2, 3]
+        ));
+        assert!(stored_values(&server_list[2], &topic).len() < sent.len());
+
+        let controller = server_list[0].broker();
+        assert!(wait_for(Duration::from_secs(10), || controller
+            .partition_state(&topic)
+            .is_some_and(
+                |state| state.replicas == vec![2, 3] && !state.is_reassigning()
+            )));
+        let state = controller.partition_state(&topic).unwrap();
+        assert_eq!(state.leader, 2);
+        assert_eq!(stored_values(&server_list[2], &topic), sent);
+        assert!(wait_until(|| server_list[..2]
+            .iter()
+            .all(|server| server.broker().partition(&topic).is_none())));
+
+        let response_list = produce(&server_list[2], &topic, &["after"]);
+        assert!(matches!(
+            response_list[0],
+            Response::Offset(OffsetValue(300))
+        ));
+        assert!(wait_until(
+            || stored_values(&server_list[3], &topic).len() == 301
+        ));
+
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_even_out_replicas_per_broker_on_rebalance() {
+        let server_list = start_cluster(4);
+        let topic_name = String::from("uneven");
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic_name.clone(), 2, 2, Vec::new()),
+        );
+
+        let response_list = request(&server_list[3], Action::Rebalance(false));
+        assert_eq!(response_list.len(), 1);
+        let topic = TopicAddress::new(topic_name, 0);
+        assert!(matches!(
+            &response_list[0],
+            Response::Reassignment(moved, replicas) if *moved == topic && *replicas == vec![0, 3]
+        ));
+
+        request(&server_list[3], Action::Rebalance(true));
+        assert!(wait_until(|| server_list[3]
+            .broker()
+            .partition(&topic)
+            .is_some()));
+        let controller = server_list[0].broker();
+        assert!(wait_until(|| controller
+            .partition_state(&topic)
+            .is_some_and(|state| state.replicas == vec![0, 3])));
+        assert!(wait_until(|| server_list[1]
+            .broker()
+            .partition(&topic)
+            .is_none()));
+        let response_list = request(&server_list[3], Action::Rebalance(false));
+        assert!(matches!(response_list[..], [Response::Empty]));
+
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_place_replicas_of_a_partition_in_different_racks() {
+        let rack_list = ["east", "east", "west", "west"];
+        let config_list = rack_list
+            .iter()
+            .map(|rack| BrokerConfig {
+                rack: Some(rack.to_string()),
+                ..test_config()
+            })
+            .collect();
+        let server_list = start_brokers(config_list);
+        let controller = server_list[0].broker();
+        assert!(wait_until(|| controller
+            .controller()
+            .is_some_and(|quorum| quorum.metadata().racks.len() == 4)));
+
+        request(
+            &server_list[1],
+            Action::CreateTopic(String::from("racked"), 4, 2, Vec::new()),
+        );
+        for partition in 0..4 {
+            let topic = TopicAddress::new(String::from("racked"), partition);
+            let replicas = controller.partition_state(&topic).unwrap().replicas;
+            assert_ne!(
+                rack_list[replicas[0] as usize],
+                rack_list[replicas[1] as usize]
+            );
+        }
+
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_add_partitions_to_topic_keeping_existing_records() {
+        let server_list = start_cluster(3);
+        let topic_name = String::from("growing");
+        let topic = |partition| TopicAddress::new(topic_name.clone(), partition);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic_name.clone(), 2, 2, Vec::new()),
+        );
+        let config = ProducerConfig {
+            acks: Acks::All,
+            metadata_max_age: Duration::from_millis(100),
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[1].address().to_string(), config);
+        let send = |producer: &mut Producer, value: &str| {
+            let record = ProducerRecord::new(topic_name.clone(), Content::new(value.to_string()));
+            producer.send(record).wait().unwrap()
+        };
+        send(&mut producer, "a");
+        send(&mut producer, "b");
+
+        let response_list = request(
+            &server_list[2],
+            Action::CreatePartitions(topic_name.clone(), 1),
+        );
+        assert!(matches!(response_list[0], Response::Error));
+        let response_list = request(
+            &server_list[2],
+            Action::CreatePartitions(topic_name.clone(), 4),
+        );
+        assert!(matches!(response_list[0], Response::Empty));
+
+        let controller = server_list[0].broker();
+        for partition in 2..4 {
+            let state = controller.partition_state(&topic(partition)).unwrap();
+            assert_eq!(state.replicas.len(), 2);
+            let leader = &server_list[state.leader as usize];
+            assert!(wait_until(|| leader
+                .broker()
+                .partition(&topic(partition))
+                .is_some()));
+        }
+        let response_list = request(&server_list[1], Action::Metadata(topic_name.clone()));
+        assert!(matches!(response_list[0], Response::TopicMetadata(4)));
+        assert_eq!(
+            stored_values(&server_list[0], &topic(0))
+                .into_iter()
+                .chain(stored_values(&server_list[1], &topic(1)))
+                .collect::<Vec<String>>(),
+            vec!["a", "b"]
+        );
+
+        thread::sleep(Duration::from_millis(100));
+        for value in ["c", "d", "e", "f"] {
+            send(&mut producer, value);
+        }
+        let new_leader = controller.partition_state(&topic(3)).unwrap().leader;
+        assert!(!stored_values(&server_list[new_leader as usize], &topic(3)).is_empty());
+
+        producer.close();
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_send_to_and_read_from_added_partitions_before_metadata_max_age() {
+        let server_list = start_cluster(3);
+        let topic_name = String::from("followed");
+        let topic = |partition| TopicAddress::new(topic_name.clone(), partition);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic_name.clone(), 2, 2, Vec::new()),
+        );
+        let config = ProducerConfig {
+            acks: Acks::All,
+            ..ProducerConfig::default()
+        };
+        let mut producer = Producer::with_config(server_list[1].address().to_string(), config);
+        let content = |value: &str| Content::new(value.to_string());
+        for value in ["a", "b"].iter() {
+            let record = ProducerRecord::new(topic_name.clone(), content(value));
+            producer.send(record).wait().unwrap();
+        }
+        assert!(producer.take_partition_count_changes().is_empty());
+
+        let mut consumer = Consumer::new(server_list[2].address().to_string(), String::from("c1"));
+        consumer.set_metadata_max_age(Duration::from_millis(200));
+        consumer.subscribe_topic(topic_name.clone());
+        assert_eq!(poll_until(&mut consumer, 2), vec!["a", "b"]);
+
+        request(
+            &server_list[2],
+            Action::CreatePartitions(topic_name.clone(), 4),
+        );
+        for partition in 2..4 {
+            let state = server_list[0].broker().partition_state(&topic(partition));
+            let leader = &server_list[state.unwrap().leader as usize];
+            assert!(wait_until(|| leader
+                .broker()
+                .partition(&topic(partition))
+                .is_some()));
+        }
+
+        // Sending to a partition past the known count looks the topic up again.
+        let record = ProducerRecord::with_partition(topic_name.clone(), 3, content("c"));
+        assert_eq!(producer.send(record).wait(), Ok(Some(OffsetValue(0))));
+        for value in ["d", "e", "f", "g"].iter() {
+            let record = ProducerRecord::new(topic_name.clone(), content(value));
+            producer.send(record).wait().unwrap();
+        }
+        assert_eq!(producer.partition_count(&topic_name), Some(4));
+        assert_eq!(
+            producer.take_partition_count_changes(),
+            vec![(topic_name.clone(), 2, 4)]
+        );
+        assert!(producer.take_partition_count_changes().is_empty());
+        let state = server_list[0].broker().partition_state(&topic(2)).unwrap();
+        assert!(!stored_values(&server_list[state.leader as usize], &topic(2)).is_empty());
+
+        let mut values = poll_until(&mut consumer, 5);
+        values.sort();
+        assert_eq!(values, vec!["c", "d", "e", "f", "g"]);
+        assert_eq!(consumer.subscriptions().len(), 4);
+
+        consumer.close();
+        producer.close();
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_apply_topic_configs_given_at_creation_and_altered_later() {
+        let server_list = start_cluster(3);
+        let topic = TopicAddress::new(String::from("configured"), 0);
+        let config = |key: &str, value: &str| (key.to_string(), value.to_string());
+
+        let response_list = request(
+            &server_list[1],
+            Action::CreateTopic(
+                topic.name.clone(),
+                1,
+                2,
+                vec![config(RETENTION_MS, "1000"), config("segment.bytes", "1")],
+            ),
+        );
+        assert!(matches!(
+            &response_list[0],
+            Response::InvalidConfig(reason) if reason.contains("segment.bytes")
+        ));
+        let response_list = request(&server_list[1], Action::Metadata(topic.name.clone()));
+        assert!(matches!(response_list[0], Response::UnknownTopic));
+
+        request(
+            &server_list[1],
+            Action::CreateTopic(
+                topic.name.clone(),
+                1,
+                2,
+                vec![config(RETENTION_MS, "1000"), config(MAX_MESSAGE_BYTES, "4")],
+            ),
+        );
+        let describe = |server: &Server| {
+            request(server, Action::DescribeConfigs(topic.name.clone()))
+                .into_iter()
+                .filter_map(|response| match response {
+                    Response::Config(key, value) => Some((key, value)),
+                    _ => None,
+                })
+                .collect::<Vec<(String, String)>>()
+        };
+        assert!(wait_until(|| server_list.iter().all(|server| {
+            describe(server).contains(&config(RETENTION_MS, "1000"))
+        })));
+        let config_list = describe(&server_list[2]);
+        assert!(config_list.contains(&config(MIN_INSYNC_REPLICAS, "1")));
+        assert!(config_list.contains(&config(MAX_MESSAGE_BYTES, "4")));
+
+        let leader = &server_list[server_list[0]
+            .broker()
+            .partition_state(&topic)
+            .unwrap()
+            .leader as usize];
+        assert!(matches!(
+            produce(leader, &topic, &["ab", "cd"])[0],
+            Response::Offset(_)
+        ));
+        assert!(matches!(
+            produce(leader, &topic, &["abcde"])[0],
+            Response::RecordTooLarge
+        ));
+
+        let response_list = request(
+            &server_list[2],
+            Action::AlterConfigs(topic.name.clone(), vec![config(RETENTION_MS, "soon")]),
+        );
+        assert!(matches!(
+            &response_list[0],
+            Response::InvalidConfig(reason) if reason.contains(RETENTION_MS)
+        ));
+        request(
+            &server_list[2],
+            Action::AlterConfigs(topic.name.clone(), vec![config(MAX_MESSAGE_BYTES, "10")]),
+        );
+        assert!(matches!(
+            produce(leader, &topic, &["abcde"])[0],
+            Response::Offset(_)
+        ));
+
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_clean_logs_as_their_topic_configs_ask() {
+        let server_list = start_cluster(1);
+        let server = &server_list[0];
+        let topic = TopicAddress::new(String::from("cleaned"), 0);
+        let config = |key: &str, value: &str| (key.to_string(), value.to_string());
+        let keyed = |key: &str, value: &str| Content::with_key(key.to_string(), value.to_string());
+        let consumed = || {
+            request(
+                server,
+                Action::Consume(
+                    topic.clone(),
+                    OffsetValue(0),
+                    10,
+                    Isolation::ReadUncommitted,
+                ),
+            )
+            .into_iter()
+            .filter_map(|response| match response {
+                Response::Content(offset, content) => Some((offset.0, content.value)),
+                _ => None,
+            })
+            .collect::<Vec<(u32, String)>>()
+        };
+
+        let response_list = request(
+            server,
+            Action::CreateTopic(
+                topic.name.clone(),
+                1,
+                1,
+                vec![config(COMPRESSION_TYPE, "gzip")],
+            ),
+        );
+        assert!(matches!(
+            &response_list[0],
+            Response::InvalidConfig(reason) if reason.contains(COMPRESSION_TYPE)
+        ));
+        request(
+            server,
+            Action::CreateTopic(
+                topic.name.clone(),
+                1,
+                1,
+                vec![
+                    config(CLEANUP_POLICY, "compact"),
+                    config(COMPRESSION_TYPE, "lz4"),
+                ],
+            ),
+        );
+        for content_list in [
+            vec![keyed("a", "a1"), keyed("b", "b1"), keyed("a", "a2")],
+            vec![Content::new(String::from("unkeyed"))],
+            vec![keyed("b", "b2")],
+        ] {
+            request(
+                server,
+                Action::Produce(topic.clone(), content_list, Acks::All, None),
+            );
+        }
+
+        server.broker().log_cleaning_round();
+        assert_eq!(
+            consumed(),
+            vec![
+                (2, String::from("a2")),
+                (3, String::from("unkeyed")),
+                (4, String::from("b2"))
+            ]
+        );
+
+        request(
+            server,
+            Action::AlterConfigs(
+                topic.name.clone(),
+                vec![config(CLEANUP_POLICY, "delete"), config(RETENTION_MS, "0")],
+            ),
+        );
+        server.broker().log_cleaning_round();
+        let partition = server.broker().partition(&topic).unwrap();
+        assert_eq!(partition.start_offset(), OffsetValue(4));
+        assert_eq!(consumed(), vec![(4, String::from("b2"))]);
+
+        server.stop();
+    }
+
+    #[test]
+    fn should_delete_topic_on_every_broker_and_refuse_requests_for_it() {
+        let server_list = start_cluster(3);
+        let topic = TopicAddress::new(String::from("doomed"), 0);
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 2, 2, Vec::new()),
+        );
+        let controller = server_list[0].broker();
+        let leader = &server_list[controller.partition_state(&topic).unwrap().leader as usize];
+        assert!(matches!(
+            produce(leader, &topic, &["a", "b"])[0],
+            Response::Offset(_)
+        ));
+        request(leader, Action::CommitOffset(topic.clone(), OffsetValue(2)));
+
+        let response_list = request(&server_list[2], Action::DeleteTopic(topic.name.clone()));
+        assert!(matches!(response_list[0], Response::Empty));
+        assert!(wait_until(|| server_list.iter().all(|server| {
+            let broker = server.broker();
+            broker.partition(&topic).is_none() && broker.partition_states().is_empty()
+        })));
+        assert!(controller
+            .controller()
+            .is_some_and(|quorum| !quorum.metadata().has_topic(&topic.name)));
+
+        assert!(matches!(
+            produce(leader, &topic, &["c"])[0],
+            Response::UnknownTopic
+        ));
+        let response_list = request(
+            leader,
+            Action::Consume(
+                topic.clone(),
+                OffsetValue(0),
+                10,
+                Isolation::ReadUncommitted,
+            ),
+        );
+        assert!(matches!(response_list[0], Response::UnknownTopic));
+        let response_list = request(leader, Action::LatestOffset(topic.clone()));
+        assert!(matches!(response_list[0], Response::UnknownTopic));
+        let response_list = request(leader, Action::CommittedOffset(topic.clone()));
+        assert!(matches!(response_list[0], Response::Empty));
+        let response_list = request(&server_list[1], Action::DeleteTopic(topic.name.clone()));
+        assert!(matches!(response_list[0], Response::UnknownTopic));
+
+        request(
+            &server_list[0],
+            Action::CreateTopic(topic.name.clone(), 1, 2, Vec::new()),
+        );
+        let leader = &server_list[controller.partition_state(&topic).unwrap().leader as usize];
+        assert!(matches!(
+            produce(leader, &topic, &["d"])[0],
+            Response::Offset(OffsetValue(0))
+        ));
+
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_refuse_topic_names_that_could_leave_the_data_directory() {
+        let server_list = start_cluster(2);
+
+        for name in ["", ".", "..", "../../etc/x", "a/b", "a\\b", "topic name"].iter() {
+            let response_list = request(
+                &server_list[1],
+                Action::CreateTopic(name.to_string(), 1, 1, Vec::new()),
+            );
+            assert!(
+                matches!(response_list[0], Response::InvalidTopic),
+                "accepted {:?}",
+                name
+            );
+        }
+        let response_list = request(
+            &server_list[1],
+            Action::CreateTopic(String::from("Orders.v2_eu-1"), 1, 1, Vec::new()),
+        );
+        assert!(matches!(response_list[0], Response::Empty));
+
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_forward_admin_requests_to_controller() {
+        let server_list = start_cluster(3);
+
+        let response_list = request(
+            &server_list[2],
+            Action::CreateTopic(String::from("t"), 2, 3, Vec::new()),
+        );
+
+        assert!(matches!(response_list[0], Response::Empty));
+        assert!(server_list[0]
+            .broker()
+            .partition_state(&TopicAddress::new(String::from("t"), 1))
+            .is_some());
+        let response_list = request(
+            &server_list[1],
+            Action::CreateTopic(String::from("t"), 2, 3, Vec::new()),
+        );
+        assert!(matches!(response_list[0], Response::Error));
+
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+
+    #[test]
+    fn should_follow_redirect_to_controller_when_forwarding_is_off() {
+        let config = BrokerConfig {
+            forward_to_controller: false,
+            ..test_config()
+        };
+        let server_list = start_cluster_with(3, config);
+        let action = || Action::CreateTopic(String::from("t"), 1, 3, Vec::new());
+
+        let mut client = Client::new(server_list[1].address().to_string());
+        client.set_follow_redirects(false);
+        let response_list = client.send_message(ActionMessage::new(action(), String::new()));
+        assert!(matches!(
+            &response_list[0].response,
+            Response::AskTheController(address) if address == server_list[0].address()
+        ));
+
+        let response_list = request(&server_list[1], action());
+        assert!(matches!(response_list[0], Response::Empty));
+        assert!(server_list[1]
+            .broker()
+            .partition_state(&TopicAddress::new(String::from("t"), 0))
+            .is_some());
+
+        for server in server_list.iter() {
+            server.stop();
+        }
+    }
+}
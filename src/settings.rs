@@ -0,0 +1,623 @@
+use crate::config::{validate_topic_config, BrokerConfig, DetectorKind};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const ENV_PREFIX: &str = "LOGSTREAMER_";
+const CONFIG_SETTING: &str = "config";
+const TOPIC_DEFAULTS_SECTION: &str = "topic_defaults";
+// Positional arguments the service took before flags existed, in order.
+const POSITIONAL_SETTINGS: [&str; 4] = ["listen_address", "data_dir", "bootstrap_brokers", "rack"];
+
+/// Every setting, named as in the configuration file. Flags write them with
+/// dashes, `--listen-address`, and environment variables in upper case with
+/// a prefix, `LOGSTREAMER_LISTEN_ADDRESS`. Durations are in milliseconds and
+/// lists take commas outside the file.
+pub const SETTINGS: [&str; 24] = [
+    "listen_address",
+    "advertised_address",
+    "broker_id",
+    "data_dir",
+    "rack",
+    "bootstrap_brokers",
+    "controller_peers",
+    "forward_to_controller",
+    "failure_detector",
+    "phi_threshold",
+    "session_timeout_ms",
+    "heartbeat_interval_ms",
+    "controller_quorum_size",
+    "controller_tick_ms",
+    "metadata_timeout_ms",
+    "replica_lag_time_ms",
+    "replica_fetch_interval_ms",
+    "replica_fetch_max_records",
+    "replication_timeout_ms",
+    "reassignment_throttle",
+    "controlled_shutdown_timeout_ms",
+    "peer_request_timeout_ms",
+    "max_connections",
+    "log_cleanup_interval_ms",
+];
+
+/// A value read from the configuration file, or the text of a flag or
+/// environment variable, which is converted to whatever the setting needs.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Text(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    List(Vec<String>),
+}
+
+impl Value {
+    fn text(self) -> Result<String, String> {
+        match self {
+            Value::Text(text) => Ok(text),
+            _ => Err(String::from("expected a string")),
+        }
+    }
+
+    fn number<T: FromStr>(self) -> Result<T, String> {
+        let text = match self {
+            Value::Integer(value) => value.to_string(),
+            Value::Text(text) => text.trim().to_string(),
+            _ => String::new(),
+        };
+        text.parse::<T>()
+            .map_err(|_| String::from("expected a positive whole number"))
+    }
+
+    fn float(self) -> Result<f64, String> {
+        match self {
+            Value::Float(value) => Ok(value),
+            Value::Integer(value) => Ok(value as f64),
+            Value::Text(text) => text
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| String::from("expected a number")),
+            _ => Err(String::from("expected a number")),
+        }
+    }
+
+    fn boolean(self) -> Result<bool, String> {
+        match self {
+            Value::Boolean(value) => Ok(value),
+            Value::Text(text) if text.trim() == "true" => Ok(true),
+            Value::Text(text) if text.trim() == "false" => Ok(false),
+            _ => Err(String::from("expected true or false")),
+        }
+    }
+
+    fn duration(self) -> Result<Duration, String> {
+        self.number::<u64>().map(Duration::from_millis)
+    }
+
+    fn list(self) -> Result<Vec<String>, String> {
+        match self {
+            Value::List(list) => Ok(list),
+            Value::Text(text) => Ok(text
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()),
+            _ => Err(String::from("expected a list of strings")),
+        }
+    }
+}
+
+/// How a `service` process runs: where it listens, which brokers set the
+/// cluster up and the settings of its broker.
+#[derive(Clone)]
+pub struct ServiceConfig {
+    pub listen_address: String,
+    /// Brokers the controller quorum is initialized with. The first of them
+    /// sends `Action::InitializeController` once all of them listen.
+    pub controller_peers: Vec<String>,
+    pub broker: BrokerConfig,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> ServiceConfig {
+        ServiceConfig {
+            listen_address: String::from("127.0.0.1:8080"),
+            controller_peers: Vec::new(),
+            broker: BrokerConfig::default(),
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// Reads the TOML file named by `--config` or `LOGSTREAMER_CONFIG`, then
+    /// the environment variables `env` finds, then `args`, each overriding
+    /// the ones before. Errors tell which setting is wrong and where it was set.
+    pub fn load(
+        args: &[String],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<ServiceConfig, String> {
+        let flags = parse_args(args)?;
+        let mut config = ServiceConfig::default();
+
+        let config_path = flags
+            .iter()
+            .rev()
+            .find(|(key, _)| key == CONFIG_SETTING)
+            .map(|(_, path)| path.clone())
+            .or_else(|| env(&env_name(CONFIG_SETTING)));
+        if let Some(path) = config_path {
+            let text = fs::read_to_string(&path)
+                .map_err(|err| format!("cannot read {}: {}", path, err))?;
+            config
+                .apply_file(&text)
+                .map_err(|err| format!("{}, {}", path, err))?;
+        }
+
+        for key in SETTINGS.iter() {
+            if let Some(value) = env(&env_name(key)) {
+                config
+                    .apply(key, Value::Text(value))
+                    .map_err(|err| format!("{}: {}", env_name(key), err))?;
+            }
+        }
+        for (key, value) in flags {
+            if key != CONFIG_SETTING {
+                config
+                    .apply(&key, Value::Text(value))
+                    .map_err(|err| format!("--{}: {}", key.replace('_', "-"), err))?;
+            }
+        }
+
+        if config.broker.data_dir.is_none() {
+            config.broker.data_dir =
+                Some(PathBuf::from("data").join(config.listen_address.replace(':', "_")));
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Lists the flags the service takes.
+    pub fn usage() -> String {
+        let mut usage = String::from(
+            "service [--config <file>] [--<setting> <value>]...\n\
+             \n\
+             Settings, also read from the file and LOGSTREAMER_<SETTING>:\n",
+        );
+        for key in SETTINGS.iter() {
+            usage.push_str(&format!("  --{}\n", key.replace('_', "-")));
+        }
+        usage
+    }
+
+    fn apply_file(&mut self, text: &str) -> Result<(), String> {
+        for (line_number, section, key, value) in parse_toml(text)? {
+            let result = match section.as_str() {
+                "" => self.apply(&key, value),
+                TOPIC_DEFAULTS_SECTION => self.apply_topic_default(key, value),
+                _ => Err(format!("unknown section [{}]", section)),
+            };
+            result.map_err(|err| format!("line {}: {}", line_number, err))?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, key: &str, value: Value) -> Result<(), String> {
+        let broker = &mut self.broker;
+        match key {
+            "listen_address" => self.listen_address = value.text()?,
+            "advertised_address" => broker.advertised_address = Some(value.text()?),
+            "broker_id" => broker.broker_id = Some(value.number()?),
+            "data_dir" => broker.data_dir = Some(PathBuf::from(value.text()?)),
+            "rack" => broker.rack = Some(value.text()?),
+            "bootstrap_brokers" => broker.bootstrap_brokers = value.list()?,
+            "controller_peers" => self.controller_peers = value.list()?,
+            "forward_to_controller" => broker.forward_to_controller = value.boolean()?,
+            "failure_detector" => {
+                broker.failure_detector = match value.text()?.as_str() {
+                    "phi_accrual" => DetectorKind::PhiAccrual,
+                    "eventually_perfect" => DetectorKind::EventuallyPerfect,
+                    _ => return Err(String::from("expected phi_accrual or eventually_perfect")),
+                }
+            }
+            "phi_threshold" => broker.phi_threshold = value.float()?,
+            "session_timeout_ms" => broker.session_timeout = value.duration()?,
+            "heartbeat_interval_ms" => broker.heartbeat_interval = value.duration()?,
+            "controller_quorum_size" => broker.controller_quorum_size = value.number()?,
+            "controller_tick_ms" => broker.controller_tick = value.duration()?,
+            "metadata_timeout_ms" => broker.metadata_timeout = value.duration()?,
+            "replica_lag_time_ms" => broker.replica_lag_time = value.duration()?,
+            "replica_fetch_interval_ms" => broker.replica_fetch_interval = value.duration()?,
+            "replica_fetch_max_records" => broker.replica_fetch_max_records = value.number()?,
+            "replication_timeout_ms" => broker.replication_timeout = value.duration()?,
+            "reassignment_throttle" => broker.reassignment_throttle = Some(value.number()?),
+            "controlled_shutdown_timeout_ms" => {
+                broker.controlled_shutdown_timeout = value.duration()?
+            }
+            "peer_request_timeout_ms" => broker.peer_request_timeout = value.duration()?,
+            "max_connections" => broker.max_connections = value.number()?,
+            "log_cleanup_interval_ms" => broker.log_cleanup_interval = value.duration()?,
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    fn apply_topic_default(&mut self, key: String, value: Value) -> Result<(), String> {
+        let value = match value {
+            Value::Text(text) => text,
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::Boolean(value) => value.to_string(),
+            Value::List(_) => return Err(format!("{} can't be a list", key)),
+        };
+        validate_topic_config(&key, &value)?;
+
+        let topic_defaults = &mut self.broker.topic_defaults;
+        topic_defaults.retain(|(default_key, _)| *default_key != key);
+        topic_defaults.push((key, value));
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let broker = &self.broker;
+        check_address("listen_address", &self.listen_address)?;
+        if let Some(address) = &broker.advertised_address {
+            check_address("advertised_address", address)?;
+        }
+        for address in broker.bootstrap_brokers.iter() {
+            check_address("bootstrap_brokers", address)?;
+        }
+        for address in self.controller_peers.iter() {
+            check_address("controller_peers", address)?;
+        }
+        if !self.controller_peers.is_empty() && !broker.bootstrap_brokers.is_empty() {
+            return Err(String::from(
+                "set either controller_peers, to set a cluster up, or bootstrap_brokers, \
+                 to join one",
+            ));
+        }
+
+        let must_be_positive = [
+            ("controller_quorum_size", broker.controller_quorum_size == 0),
+            ("max_connections", broker.max_connections == 0),
+            (
+                "replica_fetch_max_records",
+                broker.replica_fetch_max_records == 0,
+            ),
+            ("heartbeat_interval_ms", broker.heartbeat_interval.is_zero()),
+            ("controller_tick_ms", broker.controller_tick.is_zero()),
+            (
+                "log_cleanup_interval_ms",
+                broker.log_cleanup_interval.is_zero(),
+            ),
+            ("phi_threshold", broker.phi_threshold <= 0.0),
+        ];
+        match must_be_positive.iter().find(|(_, invalid)| *invalid) {
+            Some((key, _)) => Err(format!("{} must be greater than 0", key)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+fn check_address(key: &str, address: &str) -> Result<(), String> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(format!("{} must be host:port, got '{}'", key, address)),
+    }
+}
+
+/// Turns `--some-setting value`, `--some-setting=value` and the positional
+/// arguments into setting names and values.
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut flags = Vec::new();
+    let mut positional = POSITIONAL_SETTINGS.iter();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(flag) => {
+                let (name, value) = match flag.split_once('=') {
+                    Some((name, value)) => (name, value.to_string()),
+                    None => match args.next() {
+                        Some(value) => (flag, value.clone()),
+                        None => return Err(format!("--{} needs a value", flag)),
+                    },
+                };
+                let key = name.replace('-', "_");
+                if key != CONFIG_SETTING && !SETTINGS.contains(&key.as_str()) {
+                    return Err(format!("unknown flag --{}", name));
+                }
+                flags.push((key, value));
+            }
+            None => match positional.next() {
+                Some(key) => flags.push((key.to_string(), arg.clone())),
+                None => return Err(format!("unexpected argument '{}'", arg)),
+            },
+        }
+    }
+    Ok(flags)
+}
+
+/// Reads the part of TOML the configuration needs: `[section]` headers and
+/// `key = value` lines holding strings, numbers, booleans or lists of
+/// strings. Returns the line, section, key and value of every entry.
+fn parse_toml(text: &str) -> Result<Vec<(usize, String, String, Value)>, String> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let line_number = index + 1;
+        let line = strip_comment(line).trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            section = match header.strip_suffix(']') {
+                Some(name) => name.trim().to_string(),
+                None => return Err(format!("line {}: missing ] after section", line_number)),
+            };
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return Err(format!("line {}: expected key = value", line_number)),
+        };
+        let key = match key.strip_prefix('"').and_then(|key| key.strip_suffix('"')) {
+            Some(key) => key.to_string(),
+            None if !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c)) =>
+            {
+                key.to_string()
+            }
+            None => return Err(format!("line {}: invalid key '{}'", line_number, key)),
+        };
+
+        // Lists may go on over several lines.
+        let mut value = value.to_string();
+        while value.starts_with('[') && !value.ends_with(']') {
+            match lines.next() {
+                Some((_, next)) => value.push_str(strip_comment(next).trim()),
+                None => return Err(format!("line {}: missing ] after list", line_number)),
+            }
+        }
+        let value = parse_value(&value).map_err(|err| format!("line {}: {}", line_number, err))?;
+        entries.push((line_number, section.clone(), key, value));
+    }
+    Ok(entries)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (position, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..position],
+            None => {}
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    if let Some(list) = text
+        .strip_prefix('[')
+        .and_then(|list| list.strip_suffix(']'))
+    {
+        return split_list(list)
+            .into_iter()
+            .map(|item| match parse_value(&item)? {
+                Value::Text(text) => Ok(text),
+                _ => Err(String::from("lists may only hold strings")),
+            })
+            .collect::<Result<Vec<String>, String>>()
+            .map(Value::List);
+    }
+    if let Some(literal) = text
+        .strip_prefix('\'')
+        .and_then(|text| text.strip_suffix('\''))
+    {
+        return Ok(Value::Text(literal.to_string()));
+    }
+    if let Some(quoted) = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+    {
+        return unescape(quoted).map(Value::Text);
+    }
+
+    match text {
+        "true" => Ok(Value::Boolean(true)),
+        "false" => Ok(Value::Boolean(false)),
+        _ => {
+            let number = text.replace('_', "");
+            if let Ok(value) = number.parse::<i64>() {
+                Ok(Value::Integer(value))
+            } else if let Ok(value) = number.parse::<f64>() {
+                Ok(Value::Float(value))
+            } else {
+                Err(format!("invalid value {}", text))
+            }
+        }
+    }
+}
+
+/// Splits the items of a list on the commas outside strings.
+fn split_list(list: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut quote = None;
+    for c in list.chars() {
+        match quote {
+            Some(open) if c == open && !item.ends_with('\\') => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ',' => {
+                items.push(item.trim().to_string());
+                item.clear();
+                continue;
+            }
+            None => {}
+        }
+        item.push(c);
+    }
+    items.push(item.trim().to_string());
+    // A trailing comma is allowed.
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+fn unescape(text: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('"') => unescaped.push('"'),
+            Some('\\') => unescaped.push('\\'),
+            _ => return Err(format!("invalid escape in \"{}\"", text)),
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn config_file(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!("logstreamer_settings_{}.toml", name));
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn should_let_environment_override_file_and_flags_override_both() {
+        let path = config_file(
+            "precedence",
+            "# a broker of the dev cluster\n\
+             listen_address = \"127.0.0.1:9090\"\n\
+             broker_id = 2\n\
+             controller_peers = [\n    \"127.0.0.1:9090\", # this one\n    \"127.0.0.1:9091\",\n]\n\
+             heartbeat_interval_ms = 500\n\
+             failure_detector = \"eventually_perfect\"\n\
+             \n\
+             [topic_defaults]\n\
+             \"min.insync.replicas\" = 2\n\
+             retention.ms = 60_000\n",
+        );
+        let env: HashMap<&str, &str> = vec![
+            ("LOGSTREAMER_CONFIG", path.as_str()),
+            ("LOGSTREAMER_BROKER_ID", "3"),
+            ("LOGSTREAMER_HEARTBEAT_INTERVAL_MS", "700"),
+        ]
+        .into_iter()
+        .collect();
+
+        let config = ServiceConfig::load(
+            &args(&["--heartbeat-interval-ms", "900", "--rack=a"]),
+            |name| env.get(name).map(|value| value.to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(config.listen_address, "127.0.0.1:9090");
+        assert_eq!(
+            config.controller_peers,
+            vec!["127.0.0.1:9090", "127.0.0.1:9091"]
+        );
+        assert_eq!(config.broker.broker_id, Some(3));
+        assert_eq!(config.broker.heartbeat_interval, Duration::from_millis(900));
+        assert_eq!(config.broker.rack, Some(String::from("a")));
+        assert_eq!(
+            config.broker.failure_detector,
+            DetectorKind::EventuallyPerfect
+        );
+        assert_eq!(
+            config.broker.topic_defaults,
+            vec![
+                (String::from("min.insync.replicas"), String::from("2")),
+                (String::from("retention.ms"), String::from("60000")),
+            ]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_keep_positional_arguments_and_default_data_dir() {
+        let config = ServiceConfig::load(&args(&["127.0.0.1:8081"]), |_| None).unwrap();
+        assert_eq!(config.listen_address, "127.0.0.1:8081");
+        assert_eq!(
+            config.broker.data_dir,
+            Some(PathBuf::from("data").join("127.0.0.1_8081"))
+        );
+
+        let config = ServiceConfig::load(
+            &args(&["127.0.0.1:8082", "data/b", "127.0.0.1:8080,127.0.0.1:8081"]),
+            |_| None,
+        )
+        .unwrap();
+        assert_eq!(config.broker.data_dir, Some(PathBuf::from("data/b")));
+        assert_eq!(config.broker.bootstrap_brokers.len(), 2);
+    }
+
+    #[test]
+    fn should_tell_what_is_wrong_with_invalid_settings() {
+        let error = |list: &[&str]| ServiceConfig::load(&args(list), |_| None).err().unwrap();
+        assert_eq!(error(&["--listen-port", "1"]), "unknown flag --listen-port");
+        assert_eq!(
+            error(&["--broker-id", "one"]),
+            "--broker-id: expected a positive whole number"
+        );
+        assert_eq!(
+            error(&["--listen-address", "localhost"]),
+            "listen_address must be host:port, got 'localhost'"
+        );
+        assert_eq!(
+            error(&["--max-connections", "0"]),
+            "max_connections must be greater than 0"
+        );
+
+        for (name, text, expected) in [
+            ("key", "listen = 1\n", "line 1: unknown setting listen"),
+            ("line", "\nbroker_id 1\n", "line 2: expected key = value"),
+            (
+                "list",
+                "bootstrap_brokers = [1]\n",
+                "line 1: lists may only hold strings",
+            ),
+            (
+                "topic",
+                "[topic_defaults]\ncleanup.policy = \"never\"\n",
+                "line 2: invalid value 'never' for cleanup.policy",
+            ),
+        ] {
+            let path = config_file(name, text);
+            assert_eq!(
+                error(&["--config", &path]),
+                format!("{}, {}", path, expected)
+            );
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,48 @@
+#[cfg(unix)]
+mod unix {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    pub const SIGINT: i32 = 2;
+    pub const SIGTERM: i32 = 15;
+    static TERMINATED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    extern "C" fn on_terminate(_: i32) {
+        TERMINATED.store(true, Ordering::SeqCst);
+    }
+
+    pub fn catch(signum: i32) {
+        unsafe {
+            signal(signum, on_terminate);
+        }
+    }
+
+    pub fn terminated() -> bool {
+        TERMINATED.load(Ordering::SeqCst)
+    }
+}
+
+/// Makes SIGTERM only raise a flag, read with `terminated`.
+pub fn catch_terminate() {
+    #[cfg(unix)]
+    unix::catch(unix::SIGTERM);
+}
+
+/// Makes Ctrl-C raise the same flag as SIGTERM.
+// The service binary keeps the default Ctrl-C behaviour.
+#[allow(dead_code)]
+pub fn catch_interrupt() {
+    #[cfg(unix)]
+    unix::catch(unix::SIGINT);
+}
+
+#[cfg(unix)]
+pub use self::unix::terminated;
+
+#[cfg(not(unix))]
+pub fn terminated() -> bool {
+    false
+}
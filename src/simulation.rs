@@ -0,0 +1,863 @@
+use crate::communication::{Action, ActionMessage, Response, ResponseMessage};
+use crate::config::BrokerConfig;
+use crate::core::{Acks, Content, Isolation, OffsetValue, TopicAddress};
+use crate::detector::ManualClock;
+use crate::endpoint::Broker;
+use crate::pool::Transport;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// Virtual time that passes in every step, one controller tick.
+const STEP: Duration = Duration::from_millis(50);
+/// Brokers run their failure detector once every this many steps, so
+/// about once a second as they do outside simulations.
+const STEPS_PER_ROUND: u64 = 20;
+
+/// Xorshift, so everything drawn only depends on the seed.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+
+    fn percent(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+}
+
+struct NetworkState {
+    brokers: HashMap<String, Weak<Broker>>,
+    random: Random,
+    // Brokers only reach the ones in the same group.
+    groups: HashMap<String, u32>,
+    crashed: HashSet<String>,
+    loss_percent: u64,
+    delay_percent: u64,
+    // Messages held back: sender, receiver and action.
+    delayed: Vec<(String, String, Action)>,
+}
+
+impl NetworkState {
+    fn connected(&self, from: &str, to: &str) -> bool {
+        !self.crashed.contains(from)
+            && !self.crashed.contains(to)
+            && self.groups.get(from) == self.groups.get(to)
+    }
+}
+
+/// Carries requests between the brokers of a simulation in memory, handling
+/// them on the sender's thread. Requests and answers are lost, and messages
+/// nobody waits an answer for are held back, as often as configured.
+struct Network {
+    state: Mutex<NetworkState>,
+}
+
+impl Network {
+    fn send(&self, from: &str, to: &str, action: Action) -> io::Result<Vec<ResponseMessage>> {
+        let message = ActionMessage::new(action, String::new());
+        let expects_response = message.expects_response();
+        {
+            let mut state = self.state.lock().unwrap();
+            let loss_percent = state.loss_percent;
+            let delay_percent = state.delay_percent;
+            if state.random.percent(loss_percent) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "request lost"));
+            }
+            if !expects_response && state.random.percent(delay_percent) {
+                state
+                    .delayed
+                    .push((from.to_string(), to.to_string(), message.action));
+                return Ok(Vec::new());
+            }
+        }
+
+        let mut response_list = self.deliver(from, to, message)?;
+        if !expects_response {
+            return Ok(Vec::new());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let loss_percent = state.loss_percent;
+        if state.random.percent(loss_percent) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "response lost"));
+        }
+        if response_list.is_empty() {
+            response_list.push(ResponseMessage::new_empty());
+        }
+        Ok(response_list)
+    }
+
+    fn deliver(
+        &self,
+        from: &str,
+        to: &str,
+        message: ActionMessage,
+    ) -> io::Result<Vec<ResponseMessage>> {
+        let broker = {
+            let state = self.state.lock().unwrap();
+            if !state.connected(from, to) {
+                None
+            } else {
+                state.brokers.get(to).and_then(Weak::upgrade)
+            }
+        };
+
+        match broker {
+            Some(broker) => Ok(broker.handle(message)),
+            None => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("{} can't reach {}", from, to),
+            )),
+        }
+    }
+
+    /// Hands about half of the messages held back over, in a random order.
+    fn deliver_delayed(&self) {
+        let mut ready = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let mut delayed = std::mem::take(&mut state.delayed);
+            while !delayed.is_empty() {
+                let position = state.random.below(delayed.len() as u64) as usize;
+                let message = delayed.swap_remove(position);
+                if state.random.percent(50) {
+                    ready.push(message);
+                } else {
+                    state.delayed.push(message);
+                }
+            }
+        }
+
+        for (from, to, action) in ready {
+            let _ = self.deliver(&from, &to, ActionMessage::new(action, String::new()));
+        }
+    }
+}
+
+/// How a simulated broker reaches the others.
+struct Endpoint {
+    network: Arc<Network>,
+    address: String,
+}
+
+impl Transport for Endpoint {
+    fn request(&self, address: &str, action: Action) -> io::Result<Vec<ResponseMessage>> {
+        self.network.send(&self.address, address, action)
+    }
+}
+
+/// Runs brokers in one thread over a simulated network and a virtual clock,
+/// checking after every step that there is one leader per term and that no
+/// acknowledged record, or record a consumer saw, is lost. The order brokers run in and the faults the
+/// network injects are drawn from the seed, so a failing run can be replayed.
+pub struct Simulation {
+    seed: u64,
+    network: Arc<Network>,
+    clock: Arc<ManualClock>,
+    brokers: Vec<Arc<Broker>>,
+    addresses: Vec<String>,
+    random: Random,
+    step: u64,
+    // Broker leading the controller quorum in each term.
+    controller_leaders: HashMap<u32, u32>,
+    // Broker leading each partition in each leader epoch.
+    partition_leaders: HashMap<(TopicAddress, u32), u32>,
+    next_value: u32,
+    // Records produced but not acknowledged yet: the broker that took them,
+    // its leader epoch, where they went and their value.
+    pending: Vec<(usize, u32, TopicAddress, OffsetValue, String)>,
+    acknowledged: Vec<(TopicAddress, OffsetValue, String)>,
+    // Every record consumers were handed.
+    consumed: HashMap<(TopicAddress, u32), String>,
+    trace: Vec<String>,
+}
+
+impl Simulation {
+    /// Sets a cluster of `size` brokers up, the first `controller_quorum_size`
+    /// of them electing the controller.
+    pub fn new(size: usize, seed: u64) -> Simulation {
+        let network = Arc::new(Network {
+            state: Mutex::new(NetworkState {
+                brokers: HashMap::new(),
+                random: Random::new(seed),
+                groups: HashMap::new(),
+                crashed: HashSet::new(),
+                loss_percent: 0,
+                delay_percent: 0,
+                delayed: Vec::new(),
+            }),
+        });
+        let clock = Arc::new(ManualClock::new());
+
+        // Nothing waits, since answers only come in once the caller returns.
+        let config = BrokerConfig {
+            metadata_timeout: Duration::from_secs(0),
+            replication_timeout: Duration::from_secs(0),
+            controller_seed: Some(seed),
+            ..BrokerConfig::default()
+        };
+
+        let addresses: Vec<String> = (0..size).map(|id| format!("broker-{}", id)).collect();
+        let mut brokers = Vec::new();
+        for address in addresses.iter() {
+            let endpoint = Arc::new(Endpoint {
+                network: network.clone(),
+                address: address.clone(),
+            });
+            let broker =
+                Arc::new(Broker::with_transport(config.clone(), endpoint, clock.clone()).unwrap());
+            let mut state = network.state.lock().unwrap();
+            state
+                .brokers
+                .insert(address.clone(), Arc::downgrade(&broker));
+            state.groups.insert(address.clone(), 0);
+            brokers.push(broker);
+        }
+
+        brokers[0].handle(ActionMessage::new(
+            Action::InitializeController(addresses.clone()),
+            String::new(),
+        ));
+
+        Simulation {
+            seed,
+            network,
+            clock,
+            brokers,
+            addresses,
+            random: Random::new(seed.wrapping_add(1)),
+            step: 0,
+            controller_leaders: HashMap::new(),
+            partition_leaders: HashMap::new(),
+            next_value: 0,
+            pending: Vec::new(),
+            acknowledged: Vec::new(),
+            consumed: HashMap::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.brokers.len()
+    }
+
+    pub fn random(&mut self, bound: u64) -> u64 {
+        self.random.below(bound)
+    }
+
+    pub fn live(&self) -> Vec<usize> {
+        let state = self.network.state.lock().unwrap();
+        (0..self.brokers.len())
+            .filter(|index| !state.crashed.contains(&self.addresses[*index]))
+            .collect()
+    }
+
+    pub fn set_loss(&self, loss_percent: u64) {
+        self.network.state.lock().unwrap().loss_percent = loss_percent;
+    }
+
+    pub fn set_delay(&self, delay_percent: u64) {
+        self.network.state.lock().unwrap().delay_percent = delay_percent;
+    }
+
+    /// Cuts `brokers` off from the others.
+    pub fn isolate(&self, brokers: &[usize]) {
+        let mut state = self.network.state.lock().unwrap();
+        for (index, address) in self.addresses.iter().enumerate() {
+            let group = if brokers.contains(&index) { 1 } else { 0 };
+            state.groups.insert(address.clone(), group);
+        }
+    }
+
+    pub fn heal(&self) {
+        self.isolate(&[]);
+    }
+
+    /// Stops a broker for good, dropping whatever it didn't send yet.
+    pub fn crash(&self, index: usize) {
+        self.brokers[index].stop();
+        self.network
+            .state
+            .lock()
+            .unwrap()
+            .crashed
+            .insert(self.addresses[index].clone());
+    }
+
+    /// Lets `STEP` pass: every live broker, in a random order, ticks its
+    /// controller and fetches from the leaders it follows, then part of
+    /// the delayed messages arrive.
+    pub fn step(&mut self) {
+        self.step += 1;
+        self.clock.advance(STEP);
+
+        let mut order = self.live();
+        let mut shuffled = Vec::new();
+        while !order.is_empty() {
+            let position = self.random.below(order.len() as u64) as usize;
+            shuffled.push(order.swap_remove(position));
+        }
+
+        for index in shuffled {
+            let broker = self.brokers[index].clone();
+            broker.controller_tick_round();
+            broker.replication_round();
+            if (self.step + index as u64).is_multiple_of(STEPS_PER_ROUND) {
+                broker.failure_detector_round();
+            }
+        }
+        self.network.deliver_delayed();
+
+        self.acknowledge();
+        self.check_invariants();
+        self.record_trace();
+    }
+
+    pub fn run(&mut self, steps: u64) {
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// Runs until `condition` holds, for up to `max_steps`.
+    pub fn run_until(&mut self, max_steps: u64, condition: impl Fn(&Simulation) -> bool) -> bool {
+        for _ in 0..max_steps {
+            if condition(self) {
+                return true;
+            }
+            self.step();
+        }
+        condition(self)
+    }
+
+    /// The live broker leading the controller quorum, if any.
+    pub fn controller(&self) -> Option<usize> {
+        self.live().into_iter().find(|index| {
+            self.brokers[*index]
+                .controller()
+                .is_some_and(|controller| controller.is_leader())
+        })
+    }
+
+    /// Whether every live broker registered with the controller knows about
+    /// all the others.
+    pub fn has_formed(&self) -> bool {
+        let live = self.live();
+        self.controller().is_some()
+            && live
+                .iter()
+                .all(|index| self.brokers[*index].brokers().len() == self.brokers.len())
+    }
+
+    /// Asks the controller to create `topic` until it did.
+    pub fn create_topic(&mut self, topic: &str, partitions: u32, replication_factor: u32) -> bool {
+        for _ in 0..100 {
+            if let Some(controller) = self.controller() {
+                let broker = &self.brokers[controller];
+                if broker.controller().unwrap().metadata().has_topic(topic) {
+                    return true;
+                }
+                broker.handle(ActionMessage::new(
+                    Action::CreateTopic(
+                        topic.to_string(),
+                        partitions,
+                        replication_factor,
+                        Vec::new(),
+                    ),
+                    String::new(),
+                ));
+            }
+            self.run(STEPS_PER_ROUND);
+        }
+        false
+    }
+
+    /// Sends a record to the first live broker, in a random order, that takes
+    /// it as the leader of `topic`. It's acknowledged once the high watermark
+    /// of that leader passes it, as with `Acks::All`.
+    pub fn produce(&mut self, topic: &TopicAddress) -> bool {
+        let value = format!("value-{}", self.next_value);
+        self.next_value += 1;
+
+        let mut live = self.live();
+        while !live.is_empty() {
+            let position = self.random.below(live.len() as u64) as usize;
+            let index = live.swap_remove(position);
+            let broker = &self.brokers[index];
+            let leader_epoch = match broker.partition_state(topic) {
+                Some(state) => state.leader_epoch,
+                None => continue,
+            };
+            let response_list = broker.handle(ActionMessage::new(
+                Action::Produce(
+                    topic.clone(),
+                    vec![Content::new(value.clone())],
+                    Acks::Leader,
+                    None,
+                ),
+                String::new(),
+            ));
+            if let Some(Response::Offset(offset)) =
+                response_list.into_iter().next().map(|r| r.response)
+            {
+                self.pending
+                    .push((index, leader_epoch, topic.clone(), offset, value));
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn acknowledged(&self) -> usize {
+        self.acknowledged.len()
+    }
+
+    /// Reads `topic` from the start through the first live broker, in a
+    /// random order, that answers as its leader. A record at an offset a
+    /// consumer saw another one at before fails the simulation.
+    pub fn consume(&mut self, topic: &TopicAddress) {
+        let mut live = self.live();
+        while !live.is_empty() {
+            let position = self.random.below(live.len() as u64) as usize;
+            let index = live.swap_remove(position);
+            let response_list = self.brokers[index].handle(ActionMessage::new(
+                Action::Consume(
+                    topic.clone(),
+                    OffsetValue(0),
+                    u32::MAX,
+                    Isolation::ReadUncommitted,
+                ),
+                String::new(),
+            ));
+            if response_list
+                .iter()
+                .any(|response| !matches!(response.response, Response::Content(..)))
+            {
+                continue;
+            }
+
+            for response in response_list {
+                if let Response::Content(offset, content) = response.response {
+                    let seen = self
+                        .consumed
+                        .entry((topic.clone(), offset.0))
+                        .or_insert_with(|| content.value.clone());
+                    assert_eq!(
+                        *seen, content.value,
+                        "seed {}: broker {} changed {}-{} at {} after it was consumed",
+                        self.seed, index, topic.name, topic.partition, offset.0
+                    );
+                }
+            }
+            return;
+        }
+    }
+
+    pub fn consumed(&self) -> usize {
+        self.consumed.len()
+    }
+
+    /// A live broker taking itself as the leader of `topic`.
+    pub fn leader(&self, topic: &TopicAddress) -> Option<usize> {
+        self.live().into_iter().find(|index| {
+            let broker = &self.brokers[*index];
+            broker
+                .partition_state(topic)
+                .is_some_and(|state| Some(state.leader) == broker.broker_id())
+        })
+    }
+
+    /// Checks that the live leader of each acknowledged or consumed record has it.
+    pub fn assert_acknowledged_kept(&self) {
+        for (topic, offset, value) in self.kept_records() {
+            if let Some(leader) = self.leader(topic) {
+                assert_eq!(
+                    self.read(leader, topic, offset).as_ref(),
+                    Some(value),
+                    "seed {}: broker {} lost {}-{} at {}",
+                    self.seed,
+                    leader,
+                    topic.name,
+                    topic.partition,
+                    offset.0
+                );
+            }
+        }
+    }
+
+    fn kept_records(&self) -> impl Iterator<Item = (&TopicAddress, OffsetValue, &String)> {
+        self.acknowledged
+            .iter()
+            .map(|(topic, offset, value)| (topic, *offset, value))
+            .chain(
+                self.consumed
+                    .iter()
+                    .map(|((topic, offset), value)| (topic, OffsetValue(*offset), value)),
+            )
+    }
+
+    /// Every step, for comparing runs.
+    pub fn trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    fn read(&self, index: usize, topic: &TopicAddress, offset: OffsetValue) -> Option<String> {
+        let partition = self.brokers[index].partition(topic)?;
+        partition
+            .read(offset, 1, Isolation::ReadUncommitted)
+            .into_iter()
+            .find(|(record_offset, _)| *record_offset == offset)
+            .map(|(_, content)| content.value)
+    }
+
+    fn high_watermark(&self, index: usize, topic: &TopicAddress) -> OffsetValue {
+        self.brokers[index]
+            .partition(topic)
+            .and_then(|partition| partition.high_watermark())
+            .unwrap_or(OffsetValue(0))
+    }
+
+    /// Records are acknowledged once their leader, still in the same leader
+    /// epoch, has a high watermark past them. Records whose leader crashed
+    /// or moved before that may be lost.
+    fn acknowledge(&mut self) {
+        let live = self.live();
+        let pending = std::mem::take(&mut self.pending);
+        for (index, leader_epoch, topic, offset, value) in pending {
+            let broker = &self.brokers[index];
+            let still_leader = live.contains(&index)
+                && broker.partition_state(&topic).is_some_and(|state| {
+                    Some(state.leader) == broker.broker_id() && state.leader_epoch == leader_epoch
+                });
+            if !still_leader {
+                continue;
+            }
+
+            if self.high_watermark(index, &topic).0 <= offset.0 {
+                self.pending
+                    .push((index, leader_epoch, topic, offset, value));
+            } else if self.read(index, &topic, offset).as_ref() == Some(&value) {
+                self.acknowledged.push((topic, offset, value));
+            }
+        }
+    }
+
+    fn check_invariants(&mut self) {
+        for index in self.live() {
+            let broker = &self.brokers[index];
+            let broker_id = match broker.broker_id() {
+                Some(broker_id) => broker_id,
+                None => continue,
+            };
+
+            if let Some(controller) = broker.controller() {
+                if controller.leader() == Some(broker_id) {
+                    let term = controller.term();
+                    let leader = *self.controller_leaders.entry(term).or_insert(broker_id);
+                    assert_eq!(
+                        leader, broker_id,
+                        "seed {}: two controllers in term {}",
+                        self.seed, term
+                    );
+                }
+            }
+
+            for state in broker.partition_states() {
+                if state.leader != broker_id {
+                    continue;
+                }
+                let key = (state.topic.clone(), state.leader_epoch);
+                let leader = *self.partition_leaders.entry(key).or_insert(broker_id);
+                assert_eq!(
+                    leader, broker_id,
+                    "seed {}: two leaders of {}-{} in epoch {}",
+                    self.seed, state.topic.name, state.topic.partition, state.leader_epoch
+                );
+            }
+
+            for (topic, offset, value) in self.kept_records() {
+                if self.high_watermark(index, topic).0 > offset.0 {
+                    assert_eq!(
+                        self.read(index, topic, offset).as_ref(),
+                        Some(value),
+                        "seed {}: broker {} diverged on {}-{} at {}",
+                        self.seed,
+                        index,
+                        topic.name,
+                        topic.partition,
+                        offset.0
+                    );
+                }
+            }
+        }
+    }
+
+    fn record_trace(&mut self) {
+        let mut line = format!("{}:", self.step);
+        for index in self.live() {
+            let broker = &self.brokers[index];
+            if let Some(controller) = broker.controller() {
+                line += &format!(
+                    " {}@{}",
+                    controller.leader().unwrap_or(u32::MAX),
+                    controller.term()
+                );
+            }
+            let mut states = broker.partition_states();
+            states.sort_by_key(|state| state.topic.partition);
+            for state in states {
+                line += &format!(
+                    " {}:{}/{}/{}",
+                    state.topic.partition,
+                    state.leader,
+                    state.leader_epoch,
+                    self.high_watermark(index, &state.topic).0
+                );
+            }
+        }
+        self.trace.push(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started(size: usize, seed: u64) -> Simulation {
+        let mut simulation = Simulation::new(size, seed);
+        assert!(
+            simulation.run_until(2000, |simulation| simulation.has_formed()),
+            "seed {}: cluster didn't form",
+            seed
+        );
+        assert!(simulation.create_topic("topic", 3, 3), "seed {}", seed);
+        simulation.run(STEPS_PER_ROUND * 2);
+        simulation
+    }
+
+    fn topic(partition: u32) -> TopicAddress {
+        TopicAddress::new(String::from("topic"), partition)
+    }
+
+    #[test]
+    fn should_replicate_records_in_fault_free_run() {
+        let mut simulation = started(3, 0);
+        for value in 0..30 {
+            assert!(simulation.produce(&topic(value % 3)));
+            simulation.run(5);
+        }
+        simulation.run(STEPS_PER_ROUND);
+
+        assert_eq!(simulation.acknowledged(), 30);
+        simulation.assert_acknowledged_kept();
+    }
+
+    #[test]
+    fn should_keep_one_leader_per_term_under_faults() {
+        for seed in 0..4 {
+            let mut simulation = started(5, seed);
+            let mut crashed = false;
+            for _ in 0..30 {
+                match simulation.random(5) {
+                    0 => {
+                        let isolated = simulation.random(simulation.size() as u64) as usize;
+                        simulation.isolate(&[isolated]);
+                    }
+                    1 => simulation.heal(),
+                    2 if !crashed => {
+                        let live = simulation.live();
+                        let index = live[simulation.random(live.len() as u64) as usize];
+                        simulation.crash(index);
+                        crashed = true;
+                    }
+                    3 => {
+                        let percent = simulation.random(30);
+                        simulation.set_loss(percent);
+                    }
+                    4 => {
+                        let percent = simulation.random(50);
+                        simulation.set_delay(percent);
+                    }
+                    _ => {}
+                }
+                simulation.run(STEPS_PER_ROUND * 2);
+            }
+
+            simulation.heal();
+            simulation.set_loss(0);
+            simulation.set_delay(0);
+            assert!(
+                simulation.run_until(2000, |simulation| {
+                    simulation.controller().is_some()
+                        && (0..3).all(|partition| simulation.leader(&topic(partition)).is_some())
+                }),
+                "seed {}: no controller or partition leader after healing",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn should_keep_acknowledged_records_while_followers_are_cut_off() {
+        for seed in 0..4 {
+            let mut simulation = started(3, seed);
+            simulation.set_delay(30);
+            for round in 0..20 {
+                // Leaders stay put while only one broker at a time, which
+                // leads none of the partitions then, is cut off.
+                if round % 4 == 0 {
+                    simulation.heal();
+                } else if round % 4 == 2 {
+                    let leaders: Vec<u32> = (0..3)
+                        .filter_map(|partition| {
+                            simulation.brokers[0].partition_state(&topic(partition))
+                        })
+                        .map(|state| state.leader)
+                        .collect();
+                    if let Some(follower) = (0..3).find(|index| !leaders.contains(&(*index as u32)))
+                    {
+                        simulation.isolate(&[follower]);
+                    }
+                }
+                for _ in 0..5 {
+                    let partition = simulation.random(3) as u32;
+                    simulation.produce(&topic(partition));
+                    simulation.run(4);
+                }
+            }
+
+            simulation.heal();
+            simulation.set_delay(0);
+            simulation.run(STEPS_PER_ROUND * 5);
+            assert!(simulation.acknowledged() > 0, "seed {}", seed);
+            simulation.assert_acknowledged_kept();
+        }
+    }
+
+    #[test]
+    fn should_never_show_consumers_records_that_disappear_when_leaders_move() {
+        for seed in 0..6 {
+            let mut simulation = started(3, seed);
+            for round in 0..24 {
+                // Cut a partition leader off, so it keeps taking records
+                // nobody copies while another broker takes over.
+                match round % 6 {
+                    0 => {
+                        let partition = simulation.random(3) as u32;
+                        if let Some(leader) = simulation.leader(&topic(partition)) {
+                            simulation.isolate(&[leader]);
+                        }
+                    }
+                    3 => simulation.heal(),
+                    _ => {}
+                }
+                for _ in 0..5 {
+                    let partition = simulation.random(3) as u32;
+                    simulation.produce(&topic(partition));
+                    simulation.run(3);
+                    simulation.consume(&topic(partition));
+                    simulation.run(3);
+                }
+            }
+
+            simulation.heal();
+            simulation.run(STEPS_PER_ROUND * 5);
+            for partition in 0..3 {
+                simulation.consume(&topic(partition));
+            }
+            assert!(simulation.consumed() > 0, "seed {}", seed);
+            simulation.assert_acknowledged_kept();
+        }
+    }
+
+    #[test]
+    fn should_fence_isolated_leader_until_it_registers_again() {
+        for seed in 0..4 {
+            let mut simulation = started(3, seed);
+            let controller = simulation.controller().unwrap();
+            let (partition, zombie) = (0..3)
+                .find_map(|partition| {
+                    simulation
+                        .leader(&topic(partition))
+                        .filter(|leader| *leader != controller)
+                        .map(|leader| (partition, leader))
+                })
+                .unwrap();
+            let broker = simulation.brokers[zombie].clone();
+            let broker_id = broker.broker_id().unwrap();
+            let broker_epoch = broker.broker_epoch().unwrap();
+            let high_watermark = simulation.high_watermark(zombie, &topic(partition));
+
+            // The cut off leader keeps taking records but can't drop the
+            // others from the ISR, so none of them is ever acknowledged.
+            simulation.isolate(&[zombie]);
+            for _ in 0..10 {
+                broker.handle(ActionMessage::new(
+                    Action::Produce(
+                        topic(partition),
+                        vec![Content::new(String::from("zombie"))],
+                        Acks::Leader,
+                        None,
+                    ),
+                    String::new(),
+                ));
+                simulation.run(STEPS_PER_ROUND);
+                assert_eq!(
+                    simulation.high_watermark(zombie, &topic(partition)).0,
+                    high_watermark.0,
+                    "seed {}: fenced leader acknowledged records alone",
+                    seed
+                );
+            }
+            let controller = simulation.controller().unwrap();
+            let metadata = simulation.brokers[controller]
+                .controller()
+                .unwrap()
+                .metadata();
+            assert!(!metadata.is_unfenced(broker_id), "seed {}", seed);
+
+            simulation.heal();
+            assert!(
+                simulation.run_until(2000, |simulation| simulation.brokers[zombie]
+                    .broker_epoch()
+                    .is_some_and(|epoch| epoch > broker_epoch)),
+                "seed {}: fenced broker didn't register again",
+                seed
+            );
+            for _ in 0..5 {
+                assert!(simulation.produce(&topic(partition)), "seed {}", seed);
+                simulation.run(STEPS_PER_ROUND);
+            }
+            assert!(simulation.acknowledged() > 0, "seed {}", seed);
+            simulation.assert_acknowledged_kept();
+        }
+    }
+
+    #[test]
+    fn should_replay_same_run_from_seed() {
+        let run = |seed| {
+            let mut simulation = started(3, seed);
+            simulation.set_loss(10);
+            simulation.set_delay(30);
+            for _ in 0..50 {
+                let partition = simulation.random(3) as u32;
+                simulation.produce(&topic(partition));
+                simulation.run(5);
+            }
+            simulation.trace().to_vec()
+        };
+
+        assert_eq!(run(11), run(11));
+        assert_ne!(run(11), run(12));
+    }
+}
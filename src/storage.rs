@@ -1,74 +1,1619 @@
-use crate::core::{Content, OffsetValue, TopicAddress};
-use std::collections::HashMap;
+use crate::communication::{write_record_batch, write_u32, Buffer};
+use crate::compression;
+use crate::config::{CleanupPolicy, CompressionType, LogConfig};
+use crate::core::{
+    BatchHeader, BatchKind, Content, Isolation, OffsetValue, RecordBatch, TopicAddress,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::{Mutex, RwLock};
+use std::sync::{Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const LOG_EXTENSION: &str = "log";
+const EPOCH_EXTENSION: &str = "epochs";
+// Added to the files of deleted topics until they are gone.
+const DELETED_EXTENSION: &str = "deleted";
+// A cleaned log is written here before it replaces the old one.
+const CLEANED_EXTENSION: &str = "cleaned";
+// How the batches of a log entry are stored, see `Partition::encode_batch`.
+const CODEC_NONE: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const PRODUCER_ID_FILE: &str = "producer_id";
+// Batches remembered per producer, so retries of any of them get the original offset back.
+const REMEMBERED_BATCHES: usize = 5;
+
+pub enum AppendResult {
+    Appended(OffsetValue),
+    /// The batch was appended before. Holds the offset of its last record,
+    /// unless the batch is too old to be remembered.
+    Duplicate(Option<OffsetValue>),
+    OutOfOrderSequence,
+}
 
 #[derive(Default)]
 pub struct Cluster {
-    topics: RwLock<HashMap<String, Vec<Arc<Partition>>>>,
+    partitions: RwLock<HashMap<TopicAddress, Arc<Partition>>>,
+    committed_offsets: RwLock<HashMap<(String, TopicAddress), OffsetValue>>,
+    log_configs: RwLock<HashMap<String, LogConfig>>,
+    next_producer_id: Mutex<u32>,
+    data_dir: Option<PathBuf>,
 }
 
 impl Cluster {
     pub fn new() -> Cluster {
         Cluster {
-            topics: RwLock::new(HashMap::new()),
+            partitions: RwLock::new(HashMap::new()),
+            committed_offsets: RwLock::new(HashMap::new()),
+            log_configs: RwLock::new(HashMap::new()),
+            next_producer_id: Mutex::new(0),
+            data_dir: None,
+        }
+    }
+
+    /// Keeps every partition in a `<topic>-<partition>.log` file inside
+    /// `data_dir`, loading whatever is already there.
+    pub fn open(data_dir: PathBuf) -> io::Result<Cluster> {
+        fs::create_dir_all(&data_dir)?;
+
+        let mut partitions = HashMap::new();
+        let mut next_producer_id = match fs::read_to_string(data_dir.join(PRODUCER_ID_FILE)) {
+            Ok(value) => value.trim().parse::<u32>().unwrap_or(0),
+            Err(_) => 0,
+        };
+
+        for entry in fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            if extension == Some(DELETED_EXTENSION) || extension == Some(CLEANED_EXTENSION) {
+                // Left over by a topic deletion or log cleaning that didn't finish.
+                fs::remove_file(&path)?;
+                continue;
+            }
+            if extension != Some(LOG_EXTENSION) {
+                continue;
+            }
+
+            let stem = path.file_stem().and_then(|stem| stem.to_str());
+            if let Some((topic_name, partition_number)) =
+                stem.and_then(|stem| stem.rsplit_once('-'))
+            {
+                if let Ok(partition_number) = partition_number.parse::<u32>() {
+                    let partition = Partition::open(&path)?;
+                    if let Some(producer_id) = partition.max_producer_id() {
+                        next_producer_id = u32::max(next_producer_id, producer_id + 1);
+                    }
+                    partitions.insert(
+                        TopicAddress::new(topic_name.to_string(), partition_number),
+                        Arc::new(partition),
+                    );
+                }
+            }
         }
+
+        Ok(Cluster {
+            partitions: RwLock::new(partitions),
+            committed_offsets: RwLock::new(HashMap::new()),
+            log_configs: RwLock::new(HashMap::new()),
+            next_producer_id: Mutex::new(next_producer_id),
+            data_dir: Some(data_dir),
+        })
     }
 
-    pub fn add_topic(&self, topic_name: String, partition_number: usize) {
-        let mut partitions = Vec::with_capacity(partition_number);
-        for _ in 0..partition_number {
-            partitions.push(Arc::new(Partition::new()));
+    fn log_path(data_dir: &Path, topic_name: &str, partition: u32) -> PathBuf {
+        let path = data_dir.join(format!("{}-{}.{}", topic_name, partition, LOG_EXTENSION));
+        assert!(
+            path.parent() == Some(data_dir),
+            "log of topic {} is outside the data directory",
+            topic_name
+        );
+        path
+    }
+
+    pub fn add_topic(&self, topic_name: String, partition_number: usize) -> io::Result<()> {
+        for partition in 0..partition_number {
+            self.add_partition(TopicAddress::new(topic_name.clone(), partition as u32))?;
         }
-        self.topics.write().unwrap().insert(topic_name, partitions);
+        Ok(())
     }
 
-    pub fn get_partition(&self, topic: TopicAddress) -> Option<Arc<Partition>> {
-        let topics = self.topics.read().unwrap();
-        match topics.get(&topic.name) {
-            Some(partition_list) => {
-                let partition = partition_list.get(topic.partition as usize).unwrap();
-                Some(partition.clone())
+    /// Creates a partition, unless this broker already has it.
+    pub fn add_partition(&self, topic: TopicAddress) -> io::Result<Arc<Partition>> {
+        let mut partitions = self.partitions.write().unwrap();
+        if let Some(partition) = partitions.get(&topic) {
+            return Ok(partition.clone());
+        }
+
+        let partition = match &self.data_dir {
+            Some(data_dir) => {
+                Partition::open(&Cluster::log_path(data_dir, &topic.name, topic.partition))?
             }
-            None => None,
+            None => Partition::new(),
+        };
+        partition.configure(self.log_config(&topic.name));
+        let partition = Arc::new(partition);
+        partitions.insert(topic, partition.clone());
+        Ok(partition)
+    }
+
+    fn log_config(&self, topic_name: &str) -> LogConfig {
+        self.log_configs
+            .read()
+            .unwrap()
+            .get(topic_name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Applies `config` to the partitions of a topic, those this broker
+    /// gets later included.
+    pub fn configure_topic(&self, topic_name: &str, config: LogConfig) {
+        self.log_configs
+            .write()
+            .unwrap()
+            .insert(topic_name.to_string(), config);
+        for (topic, partition) in self.partitions.read().unwrap().iter() {
+            if topic.name == topic_name {
+                partition.configure(config);
+            }
+        }
+    }
+
+    /// Drops a partition this broker no longer holds a replica of, deleting
+    /// its log and leader epochs.
+    pub fn remove_partition(&self, topic: &TopicAddress) -> io::Result<()> {
+        if self.partitions.write().unwrap().remove(topic).is_none() {
+            return Ok(());
+        }
+
+        if let Some(data_dir) = &self.data_dir {
+            let log_path = Cluster::log_path(data_dir, &topic.name, topic.partition);
+            fs::remove_file(&log_path)?;
+            match fs::remove_file(log_path.with_extension(EPOCH_EXTENSION)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops every partition of a deleted topic and the offsets consumers
+    /// committed for it. Its files are renamed right away, so the topic can
+    /// be created again, and deleted in the background.
+    pub fn remove_topic(&self, topic_name: &str) -> io::Result<()> {
+        let removed: Vec<TopicAddress> = {
+            let mut partitions = self.partitions.write().unwrap();
+            let removed = partitions
+                .keys()
+                .filter(|topic| topic.name == topic_name)
+                .cloned()
+                .collect();
+            partitions.retain(|topic, _| topic.name != topic_name);
+            removed
+        };
+        self.committed_offsets
+            .write()
+            .unwrap()
+            .retain(|(_, topic), _| topic.name != topic_name);
+        self.log_configs.write().unwrap().remove(topic_name);
+
+        let data_dir = match &self.data_dir {
+            Some(data_dir) => data_dir,
+            None => return Ok(()),
+        };
+        let mut deleted_list = Vec::new();
+        for topic in removed {
+            let log_path = Cluster::log_path(data_dir, &topic.name, topic.partition);
+            for path in [log_path.with_extension(EPOCH_EXTENSION), log_path] {
+                let mut deleted_path = path.clone().into_os_string();
+                deleted_path.push(".");
+                deleted_path.push(DELETED_EXTENSION);
+                match fs::rename(&path, &deleted_path) {
+                    Ok(()) => deleted_list.push(PathBuf::from(deleted_path)),
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+        }
+
+        thread::spawn(move || {
+            for path in deleted_list {
+                if let Err(err) = fs::remove_file(&path) {
+                    println!("Could not delete {}: {}", path.display(), err);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn get_partition(&self, topic: TopicAddress) -> Option<Arc<Partition>> {
+        self.partitions.read().unwrap().get(&topic).cloned()
+    }
+
+    /// Flushes the log of every partition, see `Partition::flush`.
+    pub fn flush(&self) -> io::Result<()> {
+        for partition in self.partitions.read().unwrap().values() {
+            partition.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Cleans the log of every partition, see `Partition::clean`.
+    pub fn clean(&self, now: SystemTime) -> io::Result<()> {
+        let partition_list: Vec<Arc<Partition>> =
+            self.partitions.read().unwrap().values().cloned().collect();
+        for partition in partition_list {
+            partition.clean(now)?;
         }
+        Ok(())
+    }
+
+    pub fn partition_count(&self, topic_name: &str) -> Option<u32> {
+        let count = self
+            .partitions
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|topic| topic.name == topic_name)
+            .count() as u32;
+        Some(count).filter(|count| *count > 0)
     }
 
     pub fn add_content(
         &self,
         topic: TopicAddress,
         content_list: Vec<Content>,
+        header: Option<BatchHeader>,
+    ) -> Option<io::Result<AppendResult>> {
+        self.get_partition(topic)
+            .map(|partition| partition.append(content_list, header))
+    }
+
+    pub fn commit_offset(&self, consumer_id: String, topic: TopicAddress, offset: OffsetValue) {
+        self.committed_offsets
+            .write()
+            .unwrap()
+            .insert((consumer_id, topic), offset);
+    }
+
+    pub fn committed_offset(
+        &self,
+        consumer_id: String,
+        topic: TopicAddress,
     ) -> Option<OffsetValue> {
-        let topics = self.topics.read().unwrap();
-        match topics.get(&topic.name) {
-            Some(partition_list) => {
-                let partition = partition_list.get(topic.partition as usize).unwrap();
-                let mut last_offset = OffsetValue(0);
-                for content in content_list {
-                    last_offset = partition.add_content(content);
+        self.committed_offsets
+            .read()
+            .unwrap()
+            .get(&(consumer_id, topic))
+            .copied()
+    }
+
+    /// Producer ids are never handed out twice, even across restarts.
+    pub fn next_producer_id(&self) -> io::Result<u32> {
+        let mut next_producer_id = self.next_producer_id.lock().unwrap();
+        let producer_id = *next_producer_id;
+
+        if let Some(data_dir) = &self.data_dir {
+            fs::write(
+                data_dir.join(PRODUCER_ID_FILE),
+                (producer_id + 1).to_string(),
+            )?;
+        }
+
+        *next_producer_id += 1;
+        Ok(producer_id)
+    }
+}
+
+/// A record as stored in a partition. Transaction markers take an offset
+/// like any other record but are never handed to consumers.
+#[derive(Clone)]
+pub struct LogRecord {
+    pub content: Content,
+    pub kind: BatchKind,
+    pub header: Option<BatchHeader>,
+    /// Whether the record opens its batch, so batches can be rebuilt for followers.
+    pub batch_start: bool,
+    pub leader_epoch: u32,
+    /// When the leader took the batch, see `RecordBatch::timestamp`.
+    pub timestamp: u64,
+    /// Whether compaction removed the record. Its offset stays taken but
+    /// its content is dropped and consumers no longer get it.
+    pub compacted: bool,
+}
+
+impl LogRecord {
+    /// Producer id of the transaction this record was written by.
+    pub fn transaction(&self) -> Option<u32> {
+        self.header
+            .filter(|header| header.transactional)
+            .map(|header| header.producer_id)
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.kind != BatchKind::Data
+    }
+}
+
+struct SequencedBatch {
+    first_sequence: u32,
+    last_sequence: u32,
+    last_offset: OffsetValue,
+}
+
+struct AbortedTransaction {
+    producer_id: u32,
+    first_offset: OffsetValue,
+    marker_offset: OffsetValue,
+}
+
+/// What the partition knows about its producers: recent batches for
+/// deduplication and where their transactions start and end.
+#[derive(Default)]
+struct ProducerIndex {
+    batches: HashMap<u32, VecDeque<SequencedBatch>>,
+    open_transactions: HashMap<u32, OffsetValue>,
+    aborted_transactions: Vec<AbortedTransaction>,
+}
+
+impl ProducerIndex {
+    fn apply_batch(
+        &mut self,
+        kind: BatchKind,
+        header: Option<BatchHeader>,
+        first_offset: OffsetValue,
+        record_count: usize,
+    ) {
+        let header = match header {
+            Some(header) => header,
+            None => return,
+        };
+
+        match kind {
+            BatchKind::Data => {
+                let batches = self.batches.entry(header.producer_id).or_default();
+                batches.push_back(SequencedBatch {
+                    first_sequence: header.sequence,
+                    last_sequence: header
+                        .sequence
+                        .wrapping_add(record_count as u32)
+                        .wrapping_sub(1),
+                    last_offset: OffsetValue(
+                        (first_offset.0 + record_count as u32).saturating_sub(1),
+                    ),
+                });
+
+                if batches.len() > REMEMBERED_BATCHES {
+                    batches.pop_front();
+                }
+
+                if header.transactional {
+                    self.open_transactions
+                        .entry(header.producer_id)
+                        .or_insert(first_offset);
                 }
-                Some(last_offset)
             }
+            BatchKind::Commit => {
+                self.open_transactions.remove(&header.producer_id);
+            }
+            BatchKind::Abort => {
+                if let Some(transaction_start) = self.open_transactions.remove(&header.producer_id)
+                {
+                    self.aborted_transactions.push(AbortedTransaction {
+                        producer_id: header.producer_id,
+                        first_offset: transaction_start,
+                        marker_offset: first_offset,
+                    });
+                }
+            }
+        }
+    }
+
+    fn check_sequence(&self, header: &BatchHeader) -> Option<AppendResult> {
+        match self.batches.get(&header.producer_id) {
+            Some(batches) => {
+                let expected_sequence = batches
+                    .back()
+                    .map(|batch| batch.last_sequence.wrapping_add(1))
+                    .unwrap_or(0);
+
+                if header.sequence < expected_sequence {
+                    let original = batches
+                        .iter()
+                        .find(|batch| batch.first_sequence == header.sequence)
+                        .map(|batch| batch.last_offset);
+                    Some(AppendResult::Duplicate(original))
+                } else if header.sequence > expected_sequence {
+                    Some(AppendResult::OutOfOrderSequence)
+                } else {
+                    None
+                }
+            }
+            None if header.sequence != 0 => Some(AppendResult::OutOfOrderSequence),
             None => None,
         }
     }
+
+    /// First offset a read-committed consumer can't see yet.
+    fn last_stable_offset(&self, end_offset: OffsetValue) -> OffsetValue {
+        self.open_transactions
+            .values()
+            .copied()
+            .fold(end_offset, |stable, first_offset| {
+                OffsetValue(u32::min(stable.0, first_offset.0))
+            })
+    }
+
+    fn is_aborted(&self, producer_id: u32, offset: OffsetValue) -> bool {
+        self.aborted_transactions.iter().any(|aborted| {
+            aborted.producer_id == producer_id
+                && aborted.first_offset.0 <= offset.0
+                && offset.0 < aborted.marker_offset.0
+        })
+    }
+}
+
+/// The offset each leader epoch of a partition starts at, so a follower can
+/// tell where its log stops matching the leader's. Kept in a file of its own
+/// next to the log, one `<epoch> <start offset>` line per epoch.
+#[derive(Default)]
+struct LeaderEpochCache {
+    entries: Vec<(u32, OffsetValue)>,
+    path: Option<PathBuf>,
+}
+
+impl LeaderEpochCache {
+    fn open(path: PathBuf) -> io::Result<LeaderEpochCache> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let entries = content
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .filter_map(|(epoch, offset)| {
+                Some((
+                    epoch.parse::<u32>().ok()?,
+                    OffsetValue(offset.parse().ok()?),
+                ))
+            })
+            .collect();
+        Ok(LeaderEpochCache {
+            entries,
+            path: Some(path),
+        })
+    }
+
+    fn latest_epoch(&self) -> Option<u32> {
+        self.entries.last().map(|(epoch, _)| *epoch)
+    }
+
+    /// Starts `epoch` at `offset`, unless a later epoch is known already.
+    fn assign(&mut self, epoch: u32, offset: OffsetValue) -> io::Result<()> {
+        if self.latest_epoch().is_some_and(|latest| latest >= epoch) {
+            return Ok(());
+        }
+        self.entries.push((epoch, offset));
+        self.store()
+    }
+
+    /// The largest epoch up to `epoch` and the offset the next one starts at,
+    /// or `end_offset` for the latest. When every epoch is later, `epoch` is
+    /// taken to end where the first of them starts.
+    fn end_offset_for(&self, epoch: u32, end_offset: OffsetValue) -> (u32, OffsetValue) {
+        let next = self.entries.iter().position(|(start, _)| *start > epoch);
+        let next_offset = next.map_or(end_offset, |next| self.entries[next].1);
+        match next.unwrap_or(self.entries.len()).checked_sub(1) {
+            Some(found) => (self.entries[found].0, next_offset),
+            None => (epoch, next_offset),
+        }
+    }
+
+    /// Forgets the epochs starting at or after `offset`.
+    fn truncate_from(&mut self, offset: OffsetValue) -> io::Result<()> {
+        let count = self.entries.len();
+        self.entries.retain(|(_, start)| start.0 < offset.0);
+        if self.entries.len() == count {
+            return Ok(());
+        }
+        self.store()
+    }
+
+    // Written to a new file first, so a crash never leaves half of it.
+    fn store(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let content: String = self
+            .entries
+            .iter()
+            .map(|(epoch, offset)| format!("{} {}\n", epoch, offset.0))
+            .collect();
+        let new_path = path.with_extension("new");
+        fs::write(&new_path, content)?;
+        fs::rename(new_path, path)
+    }
+}
+
+/// The records of a partition from the first one retention kept on.
+#[derive(Default)]
+pub struct RecordQueue {
+    pub start_offset: OffsetValue,
+    pub records: Vec<LogRecord>,
+}
+
+impl RecordQueue {
+    /// Offset that the next appended record will receive.
+    pub fn end_offset(&self) -> OffsetValue {
+        OffsetValue(self.start_offset.0 + self.records.len() as u32)
+    }
+
+    /// Index in `records` of `offset`, or of the first record when retention
+    /// deleted it, or the end of the log when it is past it.
+    fn index(&self, offset: OffsetValue) -> usize {
+        usize::min(
+            offset.0.saturating_sub(self.start_offset.0) as usize,
+            self.records.len(),
+        )
+    }
+}
+
+// The log file of a partition, opened again whenever cleaning rewrites it.
+struct LogFile {
+    file: File,
+    path: PathBuf,
 }
 
 #[derive(Default)]
 pub struct Partition {
-    pub queue: Mutex<Vec<Content>>,
+    pub queue: Mutex<RecordQueue>,
+    producers: Mutex<ProducerIndex>,
+    epochs: Mutex<LeaderEpochCache>,
+    log: Option<Mutex<LogFile>>,
+    config: Mutex<LogConfig>,
+    // Consumers only see records below it once the partition is replicated.
+    // Unset for partitions that nobody replicates.
+    high_watermark: Mutex<Option<OffsetValue>>,
+    high_watermark_changed: Condvar,
 }
 
 impl Partition {
     pub fn new() -> Partition {
         Partition {
-            queue: Mutex::new(Vec::new()),
+            queue: Mutex::new(RecordQueue::default()),
+            producers: Mutex::new(ProducerIndex::default()),
+            epochs: Mutex::new(LeaderEpochCache::default()),
+            log: None,
+            config: Mutex::new(LogConfig::default()),
+            high_watermark: Mutex::new(None),
+            high_watermark_changed: Condvar::new(),
         }
     }
 
-    pub fn add_content(&self, content: Content) -> OffsetValue {
+    /// Loads the records, producer sequences, transactions and leader epochs
+    /// stored in `path`. A batch that was only partially written before a
+    /// crash is dropped.
+    pub fn open(path: &Path) -> io::Result<Partition> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut queue = RecordQueue::default();
+        let mut producers = ProducerIndex::default();
+        let mut epochs = LeaderEpochCache::open(path.with_extension(EPOCH_EXTENSION))?;
+        let mut position = 0;
+
+        while position + 4 <= bytes.len() {
+            let mut size = Buffer::new(&bytes[position..position + 4]);
+            let batch_end = position + 4 + size.read_u32()? as usize;
+            if batch_end > bytes.len() {
+                break;
+            }
+
+            // The log starts wherever retention left it.
+            let batch = match Partition::decode_batch(&bytes[position + 4..batch_end]) {
+                Ok(batch)
+                    if queue.records.is_empty() || batch.first_offset == queue.end_offset() =>
+                {
+                    batch
+                }
+                _ => break,
+            };
+            if queue.records.is_empty() {
+                queue.start_offset = batch.first_offset;
+            }
+            producers.apply_batch(
+                batch.kind,
+                batch.header,
+                batch.first_offset,
+                batch.content_list.len(),
+            );
+            // Epochs are stored after the batches, so a crash in between
+            // may have left some out.
+            epochs.assign(batch.leader_epoch, batch.first_offset)?;
+            queue.records.extend(Partition::to_records(batch));
+            position = batch_end;
+        }
+
+        if position < bytes.len() {
+            file.set_len(position as u64)?;
+        }
+        epochs.truncate_from(OffsetValue(queue.end_offset().0 + 1))?;
+
+        Ok(Partition {
+            queue: Mutex::new(queue),
+            producers: Mutex::new(producers),
+            epochs: Mutex::new(epochs),
+            log: Some(Mutex::new(LogFile {
+                file,
+                path: path.to_path_buf(),
+            })),
+            config: Mutex::new(LogConfig::default()),
+            high_watermark: Mutex::new(None),
+            high_watermark_changed: Condvar::new(),
+        })
+    }
+
+    /// Changes how the log is cleaned and stored. Batches already stored
+    /// take the new compression the next time cleaning rewrites them.
+    pub fn configure(&self, config: LogConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    pub fn add_content(&self, content: Content) -> io::Result<OffsetValue> {
+        self.add_content_list(vec![content])
+    }
+
+    /// Appends the whole batch under a single lock, so the records get
+    /// consecutive offsets. Returns the offset of the last record.
+    pub fn add_content_list(&self, content_list: Vec<Content>) -> io::Result<OffsetValue> {
+        match self.append(content_list, None)? {
+            AppendResult::Appended(offset) => Ok(offset),
+            // Batches without a header are never checked for duplicates.
+            _ => unreachable!(),
+        }
+    }
+
+    /// Appends a batch, unless its header shows it is a retry of a batch
+    /// already stored or that batches from the same producer went missing.
+    pub fn append(
+        &self,
+        content_list: Vec<Content>,
+        header: Option<BatchHeader>,
+    ) -> io::Result<AppendResult> {
+        let mut producers = self.producers.lock().unwrap();
+
+        if let Some(result) = header.and_then(|header| producers.check_sequence(&header)) {
+            return Ok(result);
+        }
+
+        let record_count = content_list.len() as u32;
+        let batch = RecordBatch::new(OffsetValue(0), BatchKind::Data, header, content_list);
+        let first_offset = self.write_batch(&mut producers, batch, false)?;
+        Ok(AppendResult::Appended(OffsetValue(
+            (first_offset.0 + record_count).saturating_sub(1),
+        )))
+    }
+
+    /// Closes the open transaction of `producer_id` with a commit or abort
+    /// marker. Does nothing when that producer has no open transaction here.
+    pub fn append_marker(&self, producer_id: u32, commit: bool) -> io::Result<()> {
+        let mut producers = self.producers.lock().unwrap();
+        if !producers.open_transactions.contains_key(&producer_id) {
+            return Ok(());
+        }
+
+        let kind = if commit {
+            BatchKind::Commit
+        } else {
+            BatchKind::Abort
+        };
+        let header = Some(BatchHeader::transactional(producer_id, 0));
+        let batch = RecordBatch::new(OffsetValue(0), kind, header, vec![Content::default()]);
+        self.write_batch(&mut producers, batch, false)?;
+        Ok(())
+    }
+
+    /// Appends a batch copied from the partition leader, keeping its offsets,
+    /// leader epoch and timestamp. Returns false, appending nothing, when the
+    /// batch starts before the end of this log. One starting past it means
+    /// retention deleted those records on the leader, so this log is dropped
+    /// and starts over at the batch.
+    pub fn append_replicated(&self, batch: RecordBatch) -> io::Result<bool> {
+        let mut producers = self.producers.lock().unwrap();
+        let end_offset = self.end_offset();
+        if batch.first_offset.0 < end_offset.0 {
+            return Ok(false);
+        }
+
+        if batch.first_offset != end_offset {
+            let mut locked_queue = self.queue.lock().unwrap();
+            *locked_queue = RecordQueue {
+                start_offset: batch.first_offset,
+                records: Vec::new(),
+            };
+            *producers = ProducerIndex::default();
+            let compression = self.config.lock().unwrap().compression;
+            self.rewrite_log(&locked_queue, compression)?;
+        }
+        self.write_batch(&mut producers, batch, true)?;
+        Ok(true)
+    }
+
+    /// Writes `batch` at the end of the log. A `replicated` batch keeps the
+    /// leader epoch and timestamp the leader gave it, others get the latest
+    /// epoch of this partition and the current time.
+    fn write_batch(
+        &self,
+        producers: &mut ProducerIndex,
+        mut batch: RecordBatch,
+        replicated: bool,
+    ) -> io::Result<OffsetValue> {
+        let compression = self.config.lock().unwrap().compression;
         let mut locked_queue = self.queue.lock().unwrap();
-        locked_queue.push(content);
-        OffsetValue(locked_queue.len() as u32 - 1)
+        let mut epochs = self.epochs.lock().unwrap();
+        let first_offset = locked_queue.end_offset();
+        batch.first_offset = first_offset;
+        if !replicated {
+            batch.leader_epoch = epochs.latest_epoch().unwrap_or(0);
+            batch.timestamp = millis_since_epoch(SystemTime::now());
+        }
+
+        if let Some(log) = &self.log {
+            log.lock()
+                .unwrap()
+                .file
+                .write_all(&Partition::encode_batch(&batch, compression)[..])?;
+        }
+        epochs.assign(batch.leader_epoch, first_offset)?;
+
+        producers.apply_batch(
+            batch.kind,
+            batch.header,
+            first_offset,
+            batch.content_list.len(),
+        );
+        locked_queue.records.extend(Partition::to_records(batch));
+        Ok(first_offset)
+    }
+
+    /// Run when this broker starts leading the partition: the records it
+    /// appends from now on belong to `leader_epoch`.
+    pub fn assign_leader_epoch(&self, leader_epoch: u32) -> io::Result<()> {
+        let _producers = self.producers.lock().unwrap();
+        let end_offset = self.end_offset();
+        self.epochs.lock().unwrap().assign(leader_epoch, end_offset)
+    }
+
+    /// Makes sure every record written to the log reached the disk.
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.log {
+            Some(log) => log.lock().unwrap().file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Epoch of the last leader this partition took records from, if any.
+    pub fn latest_epoch(&self) -> Option<u32> {
+        self.epochs.lock().unwrap().latest_epoch()
+    }
+
+    /// The largest leader epoch up to `leader_epoch` found in this log and
+    /// the offset where the records of the next one start, or the end of the
+    /// log when there is none.
+    pub fn epoch_end_offset(&self, leader_epoch: u32) -> (u32, OffsetValue) {
+        let end_offset = self.end_offset();
+        self.epochs
+            .lock()
+            .unwrap()
+            .end_offset_for(leader_epoch, end_offset)
+    }
+
+    /// Drops every record from `offset` on, which a follower does with the
+    /// ones its leader doesn't have. Those were never replicated to the whole
+    /// ISR, so the high watermark is below them.
+    pub fn truncate_to(&self, offset: OffsetValue) -> io::Result<()> {
+        let mut producers = self.producers.lock().unwrap();
+        let mut locked_queue = self.queue.lock().unwrap();
+        if offset.0 >= locked_queue.end_offset().0 {
+            return Ok(());
+        }
+
+        let kept = locked_queue.index(offset);
+        locked_queue.records.truncate(kept);
+        if offset.0 < locked_queue.start_offset.0 {
+            locked_queue.start_offset = offset;
+        }
+        // Written again from the records kept, a batch cut in two included.
+        let compression = self.config.lock().unwrap().compression;
+        self.rewrite_log(&locked_queue, compression)?;
+
+        *producers = ProducerIndex::default();
+        for batch in Partition::collect_batches(&locked_queue, OffsetValue(0), u32::MAX) {
+            producers.apply_batch(
+                batch.kind,
+                batch.header,
+                batch.first_offset,
+                batch.content_list.len(),
+            );
+        }
+        self.epochs.lock().unwrap().truncate_from(offset)?;
+
+        let mut high_watermark = self.high_watermark.lock().unwrap();
+        if high_watermark.is_some_and(|high_watermark| high_watermark.0 > offset.0) {
+            high_watermark.replace(offset);
+        }
+        Ok(())
+    }
+
+    /// Cleans up the records every in-sync replica has and that no open
+    /// transaction holds back. With the `delete` policy, the batches older
+    /// than the retention time are deleted from the start of the log, but
+    /// never the last one, which tells where the log goes on after a
+    /// restart. With `compact`, records whose key has a later record are
+    /// compacted. The log is rewritten, with the configured compression,
+    /// when anything changed.
+    pub fn clean(&self, now: SystemTime) -> io::Result<()> {
+        let config = *self.config.lock().unwrap();
+        let high_watermark = *self.high_watermark.lock().unwrap();
+        let producers = self.producers.lock().unwrap();
+        let mut locked_queue = self.queue.lock().unwrap();
+
+        let end_offset = locked_queue.end_offset();
+        let visible_end = high_watermark.map_or(end_offset, |high_watermark| {
+            OffsetValue(u32::min(high_watermark.0, end_offset.0))
+        });
+        let clean_end = locked_queue.index(producers.last_stable_offset(visible_end));
+
+        let changed = match (config.cleanup_policy, config.retention) {
+            (CleanupPolicy::Delete, Some(retention)) => {
+                let expiry = millis_since_epoch(now).saturating_sub(retention.as_millis() as u64);
+                Partition::delete_expired(&mut locked_queue, clean_end, expiry)
+            }
+            (CleanupPolicy::Delete, None) => false,
+            (CleanupPolicy::Compact, _) => {
+                Partition::compact(&mut locked_queue, clean_end, &producers)
+            }
+        };
+        if changed {
+            self.rewrite_log(&locked_queue, config.compression)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the batches taken before `expiry` from the start of the
+    /// queue, up to the record at `clean_end`. Returns whether any were.
+    fn delete_expired(queue: &mut RecordQueue, clean_end: usize, expiry: u64) -> bool {
+        // Records before `deleted` go, the batch starting there is checked.
+        let mut deleted = 0;
+        for (index, record) in queue.records.iter().enumerate().skip(1) {
+            if index > clean_end || queue.records[deleted].timestamp > expiry {
+                break;
+            }
+            if record.batch_start {
+                deleted = index;
+            }
+        }
+
+        queue.records.drain(..deleted);
+        queue.start_offset = OffsetValue(queue.start_offset.0 + deleted as u32);
+        deleted > 0
+    }
+
+    /// Compacts the records up to `clean_end` that have a key a later
+    /// record of it also has. Records of aborted transactions never take
+    /// the place of earlier ones. Returns whether any record was compacted.
+    fn compact(queue: &mut RecordQueue, clean_end: usize, producers: &ProducerIndex) -> bool {
+        let start_offset = queue.start_offset.0;
+        let mut latest = HashMap::new();
+        for (index, record) in queue.records[..clean_end].iter().enumerate() {
+            let aborted = record.transaction().is_some_and(|producer_id| {
+                producers.is_aborted(producer_id, OffsetValue(start_offset + index as u32))
+            });
+            if let Some(key) = record.content.key.as_ref().filter(|_| !aborted) {
+                latest.insert(key.clone(), index);
+            }
+        }
+
+        let mut changed = false;
+        for (index, record) in queue.records[..clean_end].iter_mut().enumerate() {
+            let superseded = record
+                .content
+                .key
+                .as_ref()
+                .and_then(|key| latest.get(key))
+                .is_some_and(|latest| *latest > index);
+            if superseded && !record.compacted {
+                record.compacted = true;
+                record.content = Content::default();
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Replaces the log file with the batches of `queue`. The new log is
+    /// written next to the old one first, so a crash leaves either of them.
+    fn rewrite_log(&self, queue: &RecordQueue, compression: CompressionType) -> io::Result<()> {
+        let log = match &self.log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let mut log = log.lock().unwrap();
+
+        let mut bytes = Vec::new();
+        for batch in Partition::collect_batches(queue, OffsetValue(0), u32::MAX) {
+            bytes.extend(Partition::encode_batch(&batch, compression));
+        }
+        let cleaned_path = log.path.with_extension(CLEANED_EXTENSION);
+        fs::write(&cleaned_path, bytes)?;
+        fs::rename(&cleaned_path, &log.path)?;
+        log.file = OpenOptions::new().append(true).open(&log.path)?;
+        Ok(())
+    }
+
+    /// Whole batches starting at `offset`, or at the first record kept when
+    /// retention deleted it, stopping once `max_records` is reached. The
+    /// first batch is always returned complete.
+    pub fn read_batches(&self, offset: OffsetValue, max_records: u32) -> Vec<RecordBatch> {
+        let locked_queue = self.queue.lock().unwrap();
+        Partition::collect_batches(&locked_queue, offset, max_records)
+    }
+
+    fn collect_batches(
+        queue: &RecordQueue,
+        offset: OffsetValue,
+        max_records: u32,
+    ) -> Vec<RecordBatch> {
+        let mut batch_list: Vec<RecordBatch> = Vec::new();
+        let first_index = queue.index(offset);
+        let record_list =
+            (queue.start_offset.0 + first_index as u32..).zip(queue.records[first_index..].iter());
+
+        for (record_count, (position, record)) in record_list.enumerate() {
+            let current = match batch_list.last_mut() {
+                Some(batch) if !record.batch_start => batch,
+                _ => {
+                    if record_count as u32 >= max_records {
+                        break;
+                    }
+                    let mut batch = RecordBatch::new(
+                        OffsetValue(position),
+                        record.kind,
+                        record.header,
+                        Vec::new(),
+                    );
+                    batch.leader_epoch = record.leader_epoch;
+                    batch.timestamp = record.timestamp;
+                    batch_list.push(batch);
+                    batch_list.last_mut().unwrap()
+                }
+            };
+            if record.compacted {
+                current.compacted.push(current.content_list.len() as u32);
+            }
+            current.content_list.push(record.content.clone());
+        }
+
+        batch_list
+    }
+
+    /// Reads up to `limit` consumer-visible records starting at `offset`,
+    /// or at the first record kept when retention deleted it.
+    pub fn read(
+        &self,
+        offset: OffsetValue,
+        limit: u32,
+        isolation: Isolation,
+    ) -> Vec<(OffsetValue, Content)> {
+        let high_watermark = *self.high_watermark.lock().unwrap();
+        let producers = self.producers.lock().unwrap();
+        let locked_queue = self.queue.lock().unwrap();
+
+        let end_offset = locked_queue.end_offset();
+        let visible_end = match high_watermark {
+            Some(high_watermark) => OffsetValue(u32::min(high_watermark.0, end_offset.0)),
+            None => end_offset,
+        };
+        let end_index = locked_queue.index(match isolation {
+            Isolation::ReadUncommitted => visible_end,
+            Isolation::ReadCommitted => producers.last_stable_offset(visible_end),
+        });
+        let range_start = usize::min(locked_queue.index(offset), end_index);
+
+        (locked_queue.start_offset.0 + range_start as u32..)
+            .zip(locked_queue.records[range_start..end_index].iter())
+            .filter(|(position, record)| {
+                !record.is_control()
+                    && !record.compacted
+                    && match (isolation, record.transaction()) {
+                        (Isolation::ReadCommitted, Some(producer_id)) => {
+                            !producers.is_aborted(producer_id, OffsetValue(*position))
+                        }
+                        _ => true,
+                    }
+            })
+            .take(limit as usize)
+            .map(|(position, record)| (OffsetValue(position), record.content.clone()))
+            .collect()
+    }
+
+    /// Offset that the next appended record will receive.
+    pub fn end_offset(&self) -> OffsetValue {
+        self.queue.lock().unwrap().end_offset()
+    }
+
+    /// Offset of the first record kept, past those retention deleted.
+    pub fn start_offset(&self) -> OffsetValue {
+        self.queue.lock().unwrap().start_offset
+    }
+
+    /// Offset below which records are replicated to every in-sync replica,
+    /// or `None` if the partition isn't replicated.
+    pub fn high_watermark(&self) -> Option<OffsetValue> {
+        *self.high_watermark.lock().unwrap()
+    }
+
+    /// Moves the high watermark forward to `offset`. It never moves back.
+    pub fn advance_high_watermark(&self, offset: OffsetValue) {
+        let mut high_watermark = self.high_watermark.lock().unwrap();
+        if high_watermark.is_none_or(|current| current.0 < offset.0) {
+            high_watermark.replace(offset);
+            self.high_watermark_changed.notify_all();
+        }
+    }
+
+    /// Blocks until the high watermark reaches `offset`. Returns false if it
+    /// didn't within `timeout`.
+    pub fn wait_for_high_watermark(&self, offset: OffsetValue, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut high_watermark = self.high_watermark.lock().unwrap();
+
+        loop {
+            if high_watermark.is_none_or(|high_watermark| high_watermark.0 >= offset.0) {
+                return true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            high_watermark = self
+                .high_watermark_changed
+                .wait_timeout(high_watermark, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn max_producer_id(&self) -> Option<u32> {
+        self.producers.lock().unwrap().batches.keys().max().copied()
+    }
+
+    fn to_records(batch: RecordBatch) -> Vec<LogRecord> {
+        let kind = batch.kind;
+        let header = batch.header;
+        let leader_epoch = batch.leader_epoch;
+        let timestamp = batch.timestamp;
+        let compacted = batch.compacted;
+
+        batch
+            .content_list
+            .into_iter()
+            .enumerate()
+            .map(|(position, content)| LogRecord {
+                content,
+                kind,
+                header,
+                batch_start: position == 0,
+                leader_epoch,
+                timestamp,
+                compacted: compacted.contains(&(position as u32)),
+            })
+            .collect()
+    }
+
+    /// A log entry: its length, the offset of the batch, the codec and the
+    /// batch as `write_record_batch` writes it. Compressed batches are
+    /// preceded by their uncompressed length.
+    fn encode_batch(batch: &RecordBatch, compression: CompressionType) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_record_batch(&mut content, batch);
+
+        let mut payload = Vec::with_capacity(content.len() + 9);
+        write_u32(&mut payload, batch.first_offset.0);
+        match compression {
+            CompressionType::None => {
+                payload.push(CODEC_NONE);
+                payload.extend(content);
+            }
+            CompressionType::Lz4 => {
+                payload.push(CODEC_LZ4);
+                write_u32(&mut payload, content.len() as u32);
+                payload.extend(compression::compress(&content));
+            }
+        }
+
+        let mut entry = Vec::with_capacity(payload.len() + 4);
+        write_u32(&mut entry, payload.len() as u32);
+        entry.extend(payload);
+        entry
+    }
+
+    /// Reads a log entry written by `encode_batch`, without its length.
+    fn decode_batch(entry: &[u8]) -> io::Result<RecordBatch> {
+        let mut header = Buffer::new(entry);
+        let first_offset = OffsetValue(header.read_u32()?);
+        match header.read_u8()? {
+            CODEC_NONE => Buffer::new(&entry[5..]).read_record_batch(first_offset),
+            CODEC_LZ4 => {
+                let size = header.read_u32()? as usize;
+                let content = compression::decompress(&entry[9..], size)?;
+                Buffer::new(&content).read_record_batch(first_offset)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown batch codec",
+            )),
+        }
+    }
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn data_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("logstreamer-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn content_list(values: &[&str]) -> Vec<Content> {
+        values
+            .iter()
+            .map(|value| Content::new(value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn should_ignore_retried_batch() {
+        let partition = Partition::new();
+        let header = BatchHeader::new(1, 0);
+
+        let first = partition.append(content_list(&["a", "b"]), Some(header));
+        let retry = partition.append(content_list(&["a", "b"]), Some(header));
+
+        assert!(matches!(first, Ok(AppendResult::Appended(OffsetValue(1)))));
+        assert!(matches!(
+            retry,
+            Ok(AppendResult::Duplicate(Some(OffsetValue(1))))
+        ));
+        assert_eq!(partition.end_offset(), OffsetValue(2));
+    }
+
+    #[test]
+    fn should_reject_sequence_gap() {
+        let partition = Partition::new();
+
+        partition
+            .append(content_list(&["a", "b"]), Some(BatchHeader::new(1, 0)))
+            .unwrap();
+        let skipped = partition.append(content_list(&["d"]), Some(BatchHeader::new(1, 3)));
+        let unknown_producer = partition.append(content_list(&["e"]), Some(BatchHeader::new(2, 5)));
+
+        assert!(matches!(skipped, Ok(AppendResult::OutOfOrderSequence)));
+        assert!(matches!(
+            unknown_producer,
+            Ok(AppendResult::OutOfOrderSequence)
+        ));
+        assert_eq!(partition.end_offset(), OffsetValue(2));
+    }
+
+    #[test]
+    fn should_copy_batches_to_follower_with_their_offsets() {
+        let leader = Partition::new();
+        let follower = Partition::new();
+        leader
+            .append(content_list(&["a", "b"]), Some(BatchHeader::new(1, 0)))
+            .unwrap();
+        leader.append(content_list(&["c"]), None).unwrap();
+        leader
+            .append(content_list(&["d", "e"]), Some(BatchHeader::new(1, 2)))
+            .unwrap();
+
+        let first_fetch = leader.read_batches(OffsetValue(0), 2);
+        assert_eq!(first_fetch.len(), 1);
+        for batch in first_fetch {
+            assert!(follower.append_replicated(batch).unwrap());
+        }
+        let stale = leader.read_batches(OffsetValue(0), 1).remove(0);
+        assert!(!follower.append_replicated(stale).unwrap());
+        for batch in leader.read_batches(follower.end_offset(), 10) {
+            assert!(follower.append_replicated(batch).unwrap());
+        }
+
+        assert_eq!(follower.end_offset(), OffsetValue(5));
+        assert_eq!(follower.queue.lock().unwrap().records[3].content.value, "d");
+        assert!(matches!(
+            follower.append(content_list(&["d", "e"]), Some(BatchHeader::new(1, 2))),
+            Ok(AppendResult::Duplicate(Some(OffsetValue(4))))
+        ));
+    }
+
+    #[test]
+    fn should_recover_records_and_sequences_after_restart() {
+        let path = data_dir("recover");
+        {
+            let cluster = Cluster::open(path.clone()).unwrap();
+            cluster.add_topic(String::from("topic"), 2).unwrap();
+            let producer_id = cluster.next_producer_id().unwrap();
+            cluster.add_content(
+                TopicAddress::new(String::from("topic"), 1),
+                content_list(&["a", "b", "c"]),
+                Some(BatchHeader::new(producer_id, 0)),
+            );
+        }
+
+        let cluster = Cluster::open(path.clone()).unwrap();
+        let topic = TopicAddress::new(String::from("topic"), 1);
+        let partition = cluster.get_partition(topic.clone()).unwrap();
+
+        assert_eq!(cluster.partition_count("topic"), Some(2));
+        assert_eq!(
+            partition.queue.lock().unwrap().records[2].content.value,
+            "c"
+        );
+        assert!(matches!(
+            cluster.add_content(
+                topic,
+                content_list(&["a", "b", "c"]),
+                Some(BatchHeader::new(0, 0))
+            ),
+            Some(Ok(AppendResult::Duplicate(Some(OffsetValue(2)))))
+        ));
+        assert_eq!(cluster.next_producer_id().unwrap(), 1);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn should_delete_removed_partition() {
+        let path = data_dir("remove");
+        let topic = TopicAddress::new(String::from("topic"), 1);
+        {
+            let cluster = Cluster::open(path.clone()).unwrap();
+            cluster.add_topic(String::from("topic"), 2).unwrap();
+            cluster.add_content(topic.clone(), content_list(&["a"]), None);
+            cluster.remove_partition(&topic).unwrap();
+            assert!(cluster.get_partition(topic.clone()).is_none());
+        }
+
+        let cluster = Cluster::open(path.clone()).unwrap();
+        assert!(cluster.get_partition(topic).is_none());
+        assert_eq!(cluster.partition_count("topic"), Some(1));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn should_return_error_when_partition_log_cannot_be_created() {
+        let path = data_dir("unwritable");
+        let cluster = Cluster::open(path.clone()).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert!(cluster.add_topic(String::from("topic"), 1).is_err());
+        assert!(cluster
+            .get_partition(TopicAddress::new(String::from("topic"), 0))
+            .is_none());
+    }
+
+    #[test]
+    fn should_forget_deleted_topic_and_clean_up_its_files() {
+        let path = data_dir("delete_topic");
+        let topic = TopicAddress::new(String::from("topic"), 0);
+        let deleted_count = |path: &Path| {
+            fs::read_dir(path)
+                .unwrap()
+                .filter(|entry| {
+                    let path = entry.as_ref().unwrap().path();
+                    path.extension().and_then(|extension| extension.to_str())
+                        == Some(DELETED_EXTENSION)
+                })
+                .count()
+        };
+        {
+            let cluster = Cluster::open(path.clone()).unwrap();
+            cluster.add_topic(String::from("topic"), 2).unwrap();
+            cluster.add_content(topic.clone(), content_list(&["a"]), None);
+            cluster.commit_offset(String::from("consumer"), topic.clone(), OffsetValue(1));
+            cluster.remove_topic("topic").unwrap();
+
+            assert_eq!(cluster.partition_count("topic"), None);
+            assert_eq!(
+                cluster.committed_offset(String::from("consumer"), topic.clone()),
+                None
+            );
+            cluster.add_topic(String::from("topic"), 1).unwrap();
+            assert_eq!(
+                cluster.get_partition(topic.clone()).unwrap().end_offset(),
+                OffsetValue(0)
+            );
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while deleted_count(&path) > 0 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(deleted_count(&path), 0);
+        }
+
+        fs::write(path.join("topic-1.log.deleted"), "left over").unwrap();
+        let cluster = Cluster::open(path.clone()).unwrap();
+        assert_eq!(deleted_count(&path), 0);
+        assert_eq!(cluster.partition_count("topic"), Some(1));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn should_drop_partially_written_batch() {
+        let path = data_dir("partial");
+        {
+            let cluster = Cluster::open(path.clone()).unwrap();
+            cluster.add_topic(String::from("topic"), 1).unwrap();
+            cluster.add_content(
+                TopicAddress::new(String::from("topic"), 0),
+                content_list(&["a"]),
+                None,
+            );
+        }
+
+        let log_path = Cluster::log_path(&path, "topic", 0);
+        let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+        log.write_all(&[0, 0, 0, 50, 0, 0]).unwrap();
+
+        let partition = Partition::open(&log_path).unwrap();
+        partition
+            .add_content(Content::new(String::from("b")))
+            .unwrap();
+        let partition = Partition::open(&log_path).unwrap();
+
+        assert_eq!(partition.end_offset(), OffsetValue(2));
+        assert_eq!(
+            partition.queue.lock().unwrap().records[1].content.value,
+            "b"
+        );
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn should_find_where_leader_epochs_end() {
+        let partition = Partition::new();
+        partition.append(content_list(&["a", "b"]), None).unwrap();
+        partition.assign_leader_epoch(2).unwrap();
+        partition.append(content_list(&["c"]), None).unwrap();
+        partition.assign_leader_epoch(5).unwrap();
+
+        assert_eq!(partition.latest_epoch(), Some(5));
+        assert_eq!(partition.epoch_end_offset(0), (0, OffsetValue(2)));
+        assert_eq!(partition.epoch_end_offset(3), (2, OffsetValue(3)));
+        assert_eq!(partition.epoch_end_offset(7), (5, OffsetValue(3)));
+        assert_eq!(partition.read_batches(OffsetValue(2), 1)[0].leader_epoch, 2);
+
+        partition.assign_leader_epoch(4).unwrap();
+        assert_eq!(partition.latest_epoch(), Some(5));
+    }
+
+    #[test]
+    fn should_truncate_log_and_epochs_and_keep_them_after_restart() {
+        let path = data_dir("truncate");
+        fs::create_dir_all(&path).unwrap();
+        let log_path = Cluster::log_path(&path, "topic", 0);
+        {
+            let partition = Partition::open(&log_path).unwrap();
+            partition
+                .append(content_list(&["a", "b"]), Some(BatchHeader::new(1, 0)))
+                .unwrap();
+            partition.assign_leader_epoch(1).unwrap();
+            partition
+                .append(content_list(&["c", "d", "e"]), Some(BatchHeader::new(1, 2)))
+                .unwrap();
+            partition.assign_leader_epoch(2).unwrap();
+            partition.append(content_list(&["f"]), None).unwrap();
+
+            partition.truncate_to(OffsetValue(3)).unwrap();
+            assert_eq!(partition.latest_epoch(), Some(1));
+            assert!(matches!(
+                partition.append(content_list(&["d"]), Some(BatchHeader::new(1, 3))),
+                Ok(AppendResult::Appended(OffsetValue(3)))
+            ));
+        }
+
+        let partition = Partition::open(&log_path).unwrap();
+        let values: Vec<String> = partition
+            .read(OffsetValue(0), 10, Isolation::ReadUncommitted)
+            .into_iter()
+            .map(|(_, content)| content.value)
+            .collect();
+        assert_eq!(values, vec!["a", "b", "c", "d"]);
+        assert_eq!(partition.epoch_end_offset(0), (0, OffsetValue(2)));
+        assert_eq!(partition.epoch_end_offset(2), (1, OffsetValue(4)));
+        assert!(matches!(
+            partition.append(content_list(&["d"]), Some(BatchHeader::new(1, 3))),
+            Ok(AppendResult::Duplicate(Some(OffsetValue(3))))
+        ));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    fn read_values(partition: &Partition) -> Vec<(u32, String)> {
+        partition
+            .read(OffsetValue(0), 10, Isolation::ReadUncommitted)
+            .into_iter()
+            .map(|(offset, content)| (offset.0, content.value))
+            .collect()
+    }
+
+    fn log_config(cleanup_policy: CleanupPolicy, retention: Option<Duration>) -> LogConfig {
+        LogConfig {
+            retention,
+            cleanup_policy,
+            compression: CompressionType::None,
+        }
+    }
+
+    #[test]
+    fn should_delete_expired_batches_below_high_watermark_and_keep_start_after_restart() {
+        let path = data_dir("retention");
+        fs::create_dir_all(&path).unwrap();
+        let log_path = Cluster::log_path(&path, "topic", 0);
+        {
+            let partition = Partition::open(&log_path).unwrap();
+            partition.append(content_list(&["a", "b"]), None).unwrap();
+            partition.append(content_list(&["c"]), None).unwrap();
+            partition.append(content_list(&["d"]), None).unwrap();
+            partition.advance_high_watermark(OffsetValue(2));
+
+            partition.configure(log_config(
+                CleanupPolicy::Delete,
+                Some(Duration::from_secs(3600)),
+            ));
+            partition.clean(SystemTime::now()).unwrap();
+            assert_eq!(partition.start_offset(), OffsetValue(0));
+
+            partition.configure(log_config(CleanupPolicy::Delete, Some(Duration::ZERO)));
+            partition.clean(SystemTime::now()).unwrap();
+            assert_eq!(partition.start_offset(), OffsetValue(2));
+
+            partition.advance_high_watermark(OffsetValue(4));
+            partition.clean(SystemTime::now()).unwrap();
+            assert_eq!(partition.start_offset(), OffsetValue(3));
+        }
+
+        let partition = Partition::open(&log_path).unwrap();
+        assert_eq!(partition.start_offset(), OffsetValue(3));
+        assert_eq!(read_values(&partition), vec![(3, String::from("d"))]);
+        partition.append(content_list(&["e"]), None).unwrap();
+        assert_eq!(partition.end_offset(), OffsetValue(5));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn should_compact_superseded_keys_and_copy_them_to_followers() {
+        let path = data_dir("compaction");
+        fs::create_dir_all(&path).unwrap();
+        let log_path = Cluster::log_path(&path, "topic", 0);
+        let keyed = |key: &str, value: &str| Content::with_key(key.to_string(), value.to_string());
+        {
+            let partition = Partition::open(&log_path).unwrap();
+            partition.configure(log_config(CleanupPolicy::Compact, None));
+            partition
+                .append(vec![keyed("a", "a1"), keyed("b", "b1")], None)
+                .unwrap();
+            partition
+                .append(
+                    vec![keyed("a", "a2")],
+                    Some(BatchHeader::transactional(1, 0)),
+                )
+                .unwrap();
+            partition.append_marker(1, false).unwrap();
+            partition.append(vec![keyed("b", "b2")], None).unwrap();
+            partition.clean(SystemTime::now()).unwrap();
+        }
+
+        let partition = Partition::open(&log_path).unwrap();
+        // The aborted record doesn't take the place of the one before it.
+        let expected = vec![
+            (0, String::from("a1")),
+            (2, String::from("a2")),
+            (4, String::from("b2")),
+        ];
+        assert_eq!(read_values(&partition), expected);
+
+        let follower = Partition::new();
+        for batch in partition.read_batches(OffsetValue(0), 10) {
+            assert!(follower.append_replicated(batch).unwrap());
+        }
+        assert_eq!(read_values(&follower), expected);
+        assert_eq!(follower.end_offset(), OffsetValue(5));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn should_start_follower_over_where_leader_retention_left_the_log() {
+        let leader = Partition::new();
+        let follower = Partition::new();
+        leader.append(content_list(&["a", "b"]), None).unwrap();
+        follower
+            .append_replicated(leader.read_batches(OffsetValue(0), 10).remove(0))
+            .unwrap();
+        leader.append(content_list(&["c"]), None).unwrap();
+        leader.append(content_list(&["d"]), None).unwrap();
+        leader.configure(log_config(CleanupPolicy::Delete, Some(Duration::ZERO)));
+        leader.clean(SystemTime::now()).unwrap();
+        assert_eq!(leader.start_offset(), OffsetValue(3));
+
+        for batch in leader.read_batches(follower.end_offset(), 10) {
+            assert!(follower.append_replicated(batch).unwrap());
+        }
+
+        assert_eq!(follower.start_offset(), OffsetValue(3));
+        assert_eq!(read_values(&follower), vec![(3, String::from("d"))]);
+    }
+
+    #[test]
+    fn should_store_compressed_batches_and_read_them_after_restart() {
+        let path = data_dir("compression");
+        fs::create_dir_all(&path).unwrap();
+        let value = "compressible ".repeat(100);
+        let mut log_sizes = Vec::new();
+        for (partition_number, compression) in [CompressionType::None, CompressionType::Lz4]
+            .iter()
+            .enumerate()
+        {
+            let log_path = Cluster::log_path(&path, "topic", partition_number as u32);
+            {
+                let partition = Partition::open(&log_path).unwrap();
+                partition.configure(LogConfig {
+                    compression: *compression,
+                    ..LogConfig::default()
+                });
+                partition
+                    .append(content_list(&[value.as_str(), "a"]), None)
+                    .unwrap();
+            }
+            log_sizes.push(fs::metadata(&log_path).unwrap().len());
+
+            let partition = Partition::open(&log_path).unwrap();
+            assert_eq!(
+                read_values(&partition),
+                vec![(0, value.clone()), (1, String::from("a"))]
+            );
+        }
+
+        assert!(log_sizes[1] * 10 < log_sizes[0]);
+        fs::remove_dir_all(path).unwrap();
     }
 }
@@ -0,0 +1,513 @@
+use crate::communication::{write_string, write_u32, Buffer};
+use crate::core::{OffsetValue, TopicAddress};
+use crate::storage::Cluster;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const TRANSACTIONS_FILE: &str = "transactions";
+
+#[derive(Debug)]
+pub enum TransactionError {
+    /// The transaction is unknown or not in a state that allows the request.
+    InvalidState,
+    Storage(io::Error),
+}
+
+impl From<io::Error> for TransactionError {
+    fn from(err: io::Error) -> TransactionError {
+        TransactionError::Storage(err)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TransactionStatus {
+    Ongoing,
+    PrepareCommit,
+    PrepareAbort,
+}
+
+impl TransactionStatus {
+    fn from_u8(value: u8) -> TransactionStatus {
+        match value {
+            1 => TransactionStatus::PrepareCommit,
+            2 => TransactionStatus::PrepareAbort,
+            _ => TransactionStatus::Ongoing,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            TransactionStatus::Ongoing => 0,
+            TransactionStatus::PrepareCommit => 1,
+            TransactionStatus::PrepareAbort => 2,
+        }
+    }
+}
+
+struct Transaction {
+    producer_id: u32,
+    status: TransactionStatus,
+    partitions: Vec<TopicAddress>,
+    offsets: Vec<(String, TopicAddress, OffsetValue)>,
+}
+
+/// Tracks the transactions of every transactional producer and writes the
+/// commit or abort markers into the partitions they touched.
+///
+/// The decision to commit or abort is stored before any marker is written,
+/// so a broker that restarts halfway through finishes the job on `open`.
+#[derive(Default)]
+pub struct TransactionCoordinator {
+    transactions: Mutex<HashMap<String, Transaction>>,
+    state_path: Option<PathBuf>,
+}
+
+impl TransactionCoordinator {
+    pub fn new() -> TransactionCoordinator {
+        TransactionCoordinator {
+            transactions: Mutex::new(HashMap::new()),
+            state_path: None,
+        }
+    }
+
+    pub fn open(data_dir: &Path, cluster: &Cluster) -> io::Result<TransactionCoordinator> {
+        let state_path = data_dir.join(TRANSACTIONS_FILE);
+        let transactions = match fs::read(&state_path) {
+            Ok(bytes) => TransactionCoordinator::decode(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        let coordinator = TransactionCoordinator {
+            transactions: Mutex::new(transactions),
+            state_path: Some(state_path),
+        };
+
+        let prepared: Vec<(String, bool)> = coordinator
+            .transactions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, transaction)| transaction.status != TransactionStatus::Ongoing)
+            .map(|(transactional_id, transaction)| {
+                (
+                    transactional_id.clone(),
+                    transaction.status == TransactionStatus::PrepareCommit,
+                )
+            })
+            .collect();
+
+        for (transactional_id, commit) in prepared {
+            let mut transactions = coordinator.transactions.lock().unwrap();
+            coordinator
+                .complete(&mut transactions, cluster, &transactional_id, commit)
+                .map_err(|err| match err {
+                    TransactionError::Storage(err) => err,
+                    TransactionError::InvalidState => io::Error::other("invalid transaction"),
+                })?;
+        }
+
+        Ok(coordinator)
+    }
+
+    /// Starts a transaction under a fresh producer id. A transaction left
+    /// open by a previous instance of the same producer is aborted first.
+    pub fn begin(
+        &self,
+        cluster: &Cluster,
+        transactional_id: String,
+    ) -> Result<u32, TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+
+        if transactions.contains_key(&transactional_id) {
+            self.complete(&mut transactions, cluster, &transactional_id, false)?;
+        }
+
+        let producer_id = cluster.next_producer_id()?;
+        transactions.insert(
+            transactional_id,
+            Transaction {
+                producer_id,
+                status: TransactionStatus::Ongoing,
+                partitions: Vec::new(),
+                offsets: Vec::new(),
+            },
+        );
+        self.store(&transactions)?;
+
+        Ok(producer_id)
+    }
+
+    pub fn add_partitions(
+        &self,
+        transactional_id: &str,
+        partitions: Vec<TopicAddress>,
+    ) -> Result<(), TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let transaction = TransactionCoordinator::ongoing(&mut transactions, transactional_id)?;
+
+        for partition in partitions {
+            if !transaction.partitions.contains(&partition) {
+                transaction.partitions.push(partition);
+            }
+        }
+        self.store(&transactions)?;
+        Ok(())
+    }
+
+    /// Records a consumer offset that only gets committed with the transaction.
+    pub fn add_offset(
+        &self,
+        transactional_id: &str,
+        consumer_id: String,
+        topic: TopicAddress,
+        offset: OffsetValue,
+    ) -> Result<(), TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let transaction = TransactionCoordinator::ongoing(&mut transactions, transactional_id)?;
+
+        transaction
+            .offsets
+            .retain(|(group, stored_topic, _)| group != &consumer_id || stored_topic != &topic);
+        transaction.offsets.push((consumer_id, topic, offset));
+        self.store(&transactions)?;
+        Ok(())
+    }
+
+    pub fn end(
+        &self,
+        cluster: &Cluster,
+        transactional_id: &str,
+        commit: bool,
+    ) -> Result<(), TransactionError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        TransactionCoordinator::ongoing(&mut transactions, transactional_id)?;
+        self.complete(&mut transactions, cluster, transactional_id, commit)
+    }
+
+    /// Whether `producer_id` may write transactional records to `topic`.
+    pub fn is_part_of_transaction(&self, producer_id: u32, topic: &TopicAddress) -> bool {
+        self.transactions
+            .lock()
+            .unwrap()
+            .values()
+            .any(|transaction| {
+                transaction.producer_id == producer_id
+                    && transaction.status == TransactionStatus::Ongoing
+                    && transaction.partitions.contains(topic)
+            })
+    }
+
+    fn ongoing<'a>(
+        transactions: &'a mut HashMap<String, Transaction>,
+        transactional_id: &str,
+    ) -> Result<&'a mut Transaction, TransactionError> {
+        match transactions.get_mut(transactional_id) {
+            Some(transaction) if transaction.status == TransactionStatus::Ongoing => {
+                Ok(transaction)
+            }
+            _ => Err(TransactionError::InvalidState),
+        }
+    }
+
+    fn complete(
+        &self,
+        transactions: &mut HashMap<String, Transaction>,
+        cluster: &Cluster,
+        transactional_id: &str,
+        commit: bool,
+    ) -> Result<(), TransactionError> {
+        let transaction = match transactions.get_mut(transactional_id) {
+            Some(transaction) => transaction,
+            None => return Err(TransactionError::InvalidState),
+        };
+
+        // A transaction that is already being completed keeps its decision.
+        let commit = match transaction.status {
+            TransactionStatus::Ongoing => commit,
+            status => status == TransactionStatus::PrepareCommit,
+        };
+        transaction.status = if commit {
+            TransactionStatus::PrepareCommit
+        } else {
+            TransactionStatus::PrepareAbort
+        };
+        self.store(transactions)?;
+
+        let transaction = transactions.remove(transactional_id).unwrap();
+        for topic in transaction.partitions.iter() {
+            if let Some(partition) = cluster.get_partition(topic.clone()) {
+                partition.append_marker(transaction.producer_id, commit)?;
+            }
+        }
+
+        if commit {
+            for (consumer_id, topic, offset) in transaction.offsets {
+                cluster.commit_offset(consumer_id, topic, offset);
+            }
+        }
+
+        self.store(transactions)?;
+        Ok(())
+    }
+
+    fn store(&self, transactions: &HashMap<String, Transaction>) -> io::Result<()> {
+        let state_path = match &self.state_path {
+            Some(state_path) => state_path,
+            None => return Ok(()),
+        };
+
+        // Written aside and renamed, so a crash never leaves half a file behind.
+        let temporary_path = state_path.with_extension("tmp");
+        fs::write(
+            &temporary_path,
+            TransactionCoordinator::encode(transactions),
+        )?;
+        fs::rename(temporary_path, state_path)
+    }
+
+    fn encode(transactions: &HashMap<String, Transaction>) -> Vec<u8> {
+        let mut content = Vec::new();
+        write_u32(&mut content, transactions.len() as u32);
+        for (transactional_id, transaction) in transactions {
+            write_string(&mut content, transactional_id);
+            write_u32(&mut content, transaction.producer_id);
+            content.push(transaction.status.as_u8());
+
+            write_u32(&mut content, transaction.partitions.len() as u32);
+            for topic in transaction.partitions.iter() {
+                write_string(&mut content, &topic.name);
+                write_u32(&mut content, topic.partition);
+            }
+
+            write_u32(&mut content, transaction.offsets.len() as u32);
+            for (consumer_id, topic, offset) in transaction.offsets.iter() {
+                write_string(&mut content, consumer_id);
+                write_string(&mut content, &topic.name);
+                write_u32(&mut content, topic.partition);
+                write_u32(&mut content, offset.0);
+            }
+        }
+        content
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<HashMap<String, Transaction>> {
+        let mut transactions = HashMap::new();
+        let mut data = Buffer::new(bytes);
+
+        let size = data.read_u32()?;
+        for _ in 0..size {
+            let transactional_id = data.read_string()?;
+            let producer_id = data.read_u32()?;
+            let status = TransactionStatus::from_u8(data.read_u8()?);
+
+            let partition_count = data.read_u32()?;
+            let mut partitions = Vec::with_capacity(partition_count as usize);
+            for _ in 0..partition_count {
+                partitions.push(TopicAddress::new(data.read_string()?, data.read_u32()?));
+            }
+
+            let offset_count = data.read_u32()?;
+            let mut offsets = Vec::with_capacity(offset_count as usize);
+            for _ in 0..offset_count {
+                let consumer_id = data.read_string()?;
+                let topic = TopicAddress::new(data.read_string()?, data.read_u32()?);
+                offsets.push((consumer_id, topic, OffsetValue(data.read_u32()?)));
+            }
+
+            transactions.insert(
+                transactional_id,
+                Transaction {
+                    producer_id,
+                    status,
+                    partitions,
+                    offsets,
+                },
+            );
+        }
+
+        Ok(transactions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{BatchHeader, Content, Isolation};
+    use std::env;
+    use std::process;
+
+    fn topic(partition: u32) -> TopicAddress {
+        TopicAddress::new(String::from("topic"), partition)
+    }
+
+    fn produce(cluster: &Cluster, partition: u32, value: &str, header: Option<BatchHeader>) {
+        cluster
+            .add_content(
+                topic(partition),
+                vec![Content::new(value.to_string())],
+                header,
+            )
+            .unwrap()
+            .unwrap();
+    }
+
+    fn read(cluster: &Cluster, partition: u32, isolation: Isolation) -> Vec<String> {
+        cluster
+            .get_partition(topic(partition))
+            .unwrap()
+            .read(OffsetValue(0), 100, isolation)
+            .into_iter()
+            .map(|(_, content)| content.value)
+            .collect()
+    }
+
+    #[test]
+    fn should_never_show_aborted_records_to_read_committed_consumers() {
+        let cluster = Cluster::new();
+        cluster.add_topic(String::from("topic"), 2).unwrap();
+        let coordinator = TransactionCoordinator::new();
+
+        let producer_id = coordinator
+            .begin(&cluster, String::from("transfer"))
+            .unwrap();
+        coordinator
+            .add_partitions("transfer", vec![topic(0), topic(1)])
+            .unwrap();
+        produce(
+            &cluster,
+            0,
+            "debit",
+            Some(BatchHeader::transactional(producer_id, 0)),
+        );
+        produce(&cluster, 0, "plain", None);
+        produce(
+            &cluster,
+            1,
+            "credit",
+            Some(BatchHeader::transactional(producer_id, 0)),
+        );
+
+        assert!(read(&cluster, 0, Isolation::ReadCommitted).is_empty());
+        assert!(read(&cluster, 1, Isolation::ReadCommitted).is_empty());
+
+        coordinator.end(&cluster, "transfer", false).unwrap();
+        produce(&cluster, 1, "after", None);
+
+        assert_eq!(read(&cluster, 0, Isolation::ReadCommitted), vec!["plain"]);
+        assert_eq!(read(&cluster, 1, Isolation::ReadCommitted), vec!["after"]);
+        assert_eq!(
+            read(&cluster, 0, Isolation::ReadUncommitted),
+            vec!["debit", "plain"]
+        );
+    }
+
+    #[test]
+    fn should_show_committed_records_and_offsets_together() {
+        let cluster = Cluster::new();
+        cluster.add_topic(String::from("topic"), 2).unwrap();
+        let coordinator = TransactionCoordinator::new();
+
+        let producer_id = coordinator
+            .begin(&cluster, String::from("transform"))
+            .unwrap();
+        coordinator
+            .add_partitions("transform", vec![topic(1)])
+            .unwrap();
+        coordinator
+            .add_offset("transform", String::from("group"), topic(0), OffsetValue(8))
+            .unwrap();
+        produce(
+            &cluster,
+            1,
+            "output",
+            Some(BatchHeader::transactional(producer_id, 0)),
+        );
+
+        assert_eq!(
+            cluster.committed_offset(String::from("group"), topic(0)),
+            None
+        );
+
+        coordinator.end(&cluster, "transform", true).unwrap();
+
+        assert_eq!(read(&cluster, 1, Isolation::ReadCommitted), vec!["output"]);
+        assert_eq!(
+            cluster.committed_offset(String::from("group"), topic(0)),
+            Some(OffsetValue(8))
+        );
+        assert!(matches!(
+            coordinator.end(&cluster, "transform", true),
+            Err(TransactionError::InvalidState)
+        ));
+    }
+
+    #[test]
+    fn should_abort_previous_transaction_when_producer_begins_again() {
+        let cluster = Cluster::new();
+        cluster.add_topic(String::from("topic"), 1).unwrap();
+        let coordinator = TransactionCoordinator::new();
+
+        let first_id = coordinator.begin(&cluster, String::from("job")).unwrap();
+        coordinator.add_partitions("job", vec![topic(0)]).unwrap();
+        produce(
+            &cluster,
+            0,
+            "lost",
+            Some(BatchHeader::transactional(first_id, 0)),
+        );
+
+        let second_id = coordinator.begin(&cluster, String::from("job")).unwrap();
+        coordinator.add_partitions("job", vec![topic(0)]).unwrap();
+        produce(
+            &cluster,
+            0,
+            "kept",
+            Some(BatchHeader::transactional(second_id, 0)),
+        );
+        coordinator.end(&cluster, "job", true).unwrap();
+
+        assert_ne!(first_id, second_id);
+        assert!(!coordinator.is_part_of_transaction(first_id, &topic(0)));
+        assert_eq!(read(&cluster, 0, Isolation::ReadCommitted), vec!["kept"]);
+    }
+
+    #[test]
+    fn should_finish_prepared_commit_after_restart() {
+        let data_dir = env::temp_dir().join(format!("logstreamer-transaction-{}", process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        {
+            let cluster = Cluster::open(data_dir.clone()).unwrap();
+            cluster.add_topic(String::from("topic"), 1).unwrap();
+            let coordinator = TransactionCoordinator::open(&data_dir, &cluster).unwrap();
+
+            let producer_id = coordinator.begin(&cluster, String::from("job")).unwrap();
+            coordinator.add_partitions("job", vec![topic(0)]).unwrap();
+            produce(
+                &cluster,
+                0,
+                "value",
+                Some(BatchHeader::transactional(producer_id, 0)),
+            );
+
+            // Crash right after the commit decision was stored.
+            let mut transactions = coordinator.transactions.lock().unwrap();
+            transactions.get_mut("job").unwrap().status = TransactionStatus::PrepareCommit;
+            coordinator.store(&transactions).unwrap();
+        }
+
+        let cluster = Cluster::open(data_dir.clone()).unwrap();
+        assert!(read(&cluster, 0, Isolation::ReadCommitted).is_empty());
+
+        TransactionCoordinator::open(&data_dir, &cluster).unwrap();
+        assert_eq!(read(&cluster, 0, Isolation::ReadCommitted), vec!["value"]);
+
+        let cluster = Cluster::open(data_dir.clone()).unwrap();
+        assert_eq!(read(&cluster, 0, Isolation::ReadCommitted), vec!["value"]);
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}